@@ -0,0 +1,161 @@
+#![warn(trivial_numeric_casts)]
+
+use bfir::{parse_with_config, Instruction, EofBehaviour, ParseConfig};
+use execution::{execute, MAX_STEPS};
+use interpreter::{interpret_with_limits, CELL_WIDTHS, DEFAULT_MAX_CELLS};
+use llvm::{compile_to_ir, CodegenOptions};
+use peephole::optimize_for_eof;
+
+#[cfg(test)]
+use std::iter::repeat;
+
+/// Options for compiling and running a program.
+#[derive(Debug,Clone,PartialEq,Eq)]
+pub struct CompileOptions {
+    /// 0 runs the program as written, anything higher runs the
+    /// peephole optimisations first. When compiling, 2 or higher
+    /// also executes as much of the program as possible at compile
+    /// time.
+    pub opt_level: u8,
+    /// Stop with an error after running this many instructions, so
+    /// programs that loop forever can't hang the caller.
+    pub fuel: Option<u64>,
+    /// The number of bits in each cell: 8, 16 or 32.
+    pub cell_width: u32,
+    /// What a read stores in the cell at EOF.
+    pub eof: EofBehaviour,
+}
+
+impl Default for CompileOptions {
+    fn default() -> Self {
+        CompileOptions {
+            opt_level: 2,
+            fuel: None,
+            cell_width: 8,
+            eof: EofBehaviour::default(),
+        }
+    }
+}
+
+/// Parse `source` and apply the optimisations that `options` asks
+/// for.
+fn parse_and_optimize(source: &str, options: &CompileOptions) -> Result<Vec<Instruction>, String> {
+    if !CELL_WIDTHS.contains(&options.cell_width) {
+        return Err(format!("Invalid cell width (expected 8, 16 or 32): {}", options.cell_width));
+    }
+
+    // 8-bit increments would wrap too early in wider cells.
+    let config = ParseConfig {
+        combine_increments: options.cell_width == 8,
+        ..ParseConfig::default()
+    };
+    let mut instrs = try!(parse_with_config(source, &config));
+    // Our optimisations combine values as 8-bit cells.
+    if options.opt_level > 0 && options.cell_width == 8 {
+        // If the passes don't converge, the last iteration is still
+        // correct.
+        instrs = optimize_for_eof(instrs, true, options.eof != EofBehaviour::Unchanged, false)
+            .unwrap_or_else(|e| e.instrs);
+    }
+    Ok(instrs)
+}
+
+/// Parse, optimise and interpret `source` to completion with
+/// `input` as stdin, returning everything the program wrote. This is
+/// for embedders who just want to run a program, without files or
+/// LLVM.
+pub fn run(source: &str, input: &[u8], options: CompileOptions) -> Result<Vec<u8>, String> {
+    let instrs = try!(parse_and_optimize(source, &options));
+
+    let mut output = vec![];
+    try!(interpret_with_limits(&instrs, &mut &input[..], &mut output, options.fuel,
+                               DEFAULT_MAX_CELLS, options.cell_width, options.eof));
+    Ok(output)
+}
+
+/// Compile `source` to LLVM IR, as `bfc --dump-llvm` does with the
+/// equivalent options. `options.fuel` is ignored.
+pub fn compile(source: &str, options: &CompileOptions) -> Result<String, String> {
+    let instrs = try!(parse_and_optimize(source, options));
+
+    // Speculative execution only models 8-bit cells.
+    let state = if options.opt_level >= 2 && options.cell_width == 8 {
+        execute(&instrs, MAX_STEPS)
+    } else {
+        execute(&instrs, 0)
+    };
+    let cells: Vec<i8> = state.cells.iter().map(|cell| cell.0).collect();
+
+    let codegen_options = CodegenOptions {
+        eof: options.eof,
+        cell_width: if options.cell_width == 8 {
+            None
+        } else {
+            Some(options.cell_width)
+        },
+        ..CodegenOptions::default()
+    };
+    let llvm_ir = compile_to_ir("bf", &instrs[state.instr_ptr..], &cells, state.cell_ptr as i32,
+                                &state.outputs, &codegen_options);
+    Ok(String::from_utf8_lossy(llvm_ir.as_bytes()).into_owned())
+}
+
+#[test]
+fn run_prints_a() {
+    let output = run("++++++++[>++++++++<-]>+.", &[], CompileOptions::default());
+    assert_eq!(output, Ok(b"A".to_vec()));
+}
+
+#[test]
+fn run_unoptimized_with_input() {
+    let options = CompileOptions { opt_level: 0, ..CompileOptions::default() };
+    assert_eq!(run(",+.", b"a", options), Ok(b"b".to_vec()));
+}
+
+#[test]
+fn run_out_of_fuel() {
+    let options = CompileOptions { fuel: Some(100), ..CompileOptions::default() };
+    assert_eq!(run("+[]", &[], options), Err("Fuel exhausted.".to_owned()));
+}
+
+#[test]
+fn run_parse_error() {
+    assert!(run("[", &[], CompileOptions::default()).is_err());
+}
+
+#[test]
+fn run_with_eof_and_wide_cells() {
+    let options = CompileOptions { eof: EofBehaviour::Zero, ..CompileOptions::default() };
+    assert_eq!(run("+,.", &[], options), Ok(vec![0]));
+
+    let options = CompileOptions { cell_width: 16, ..CompileOptions::default() };
+    assert_eq!(run("-[-]+++.", &[], options), Ok(vec![3]));
+}
+
+#[test]
+fn run_long_increment_runs_with_wide_cells() {
+    // 256 `+` is zero in an 8-bit cell, but not in a 16-bit one.
+    let source = format!("{}[[-].]", repeat("+").take(256).collect::<String>());
+    let options = CompileOptions { cell_width: 16, ..CompileOptions::default() };
+    assert_eq!(run(&source, &[], options.clone()), Ok(vec![0]));
+    let options = CompileOptions { opt_level: 0, ..options };
+    assert_eq!(run(&source, &[], options), Ok(vec![0]));
+
+    let source = format!("{}.", repeat("+").take(200).collect::<String>());
+    let ir = compile(&source, &CompileOptions { cell_width: 16, ..CompileOptions::default() });
+    assert!(!ir.unwrap().contains("-56"));
+}
+
+#[test]
+fn compile_speculates_outputs() {
+    let ir = compile("++++++++[>++++++++<-]>+.", &CompileOptions::default()).unwrap();
+    // The whole program runs at compile time, so we just print "A".
+    assert!(ir.contains("define i32 @main"));
+    assert!(!ir.contains("loop0"));
+}
+
+#[test]
+fn compile_invalid_cell_width() {
+    let options = CompileOptions { cell_width: 12, ..CompileOptions::default() };
+    assert!(compile("+", &options).is_err());
+}
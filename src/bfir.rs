@@ -1,23 +1,61 @@
 
 use std::fmt;
+use std::hash::{Hash, Hasher};
 use std::num::Wrapping;
+use std::cmp::min;
 use std::collections::HashMap;
+#[cfg(test)]
+use std::collections::HashSet;
+use std::iter::repeat;
 
 use self::Instruction::*;
 
 pub type Cell = Wrapping<i8>;
 
+/// What a read stores in the cell at EOF.
+#[derive(Debug,Clone,Copy,PartialEq,Eq)]
+pub enum EofBehaviour {
+    /// Set every bit of the cell, as getchar() returns -1.
+    NegOne,
+    Zero,
+    Unchanged,
+}
+
+impl Default for EofBehaviour {
+    fn default() -> Self {
+        EofBehaviour::NegOne
+    }
+}
+
 #[derive(PartialEq, Eq, Debug, Clone)]
 pub enum Instruction {
-    Increment(Cell),
+    // Add an amount to the cell at this offset from the cell
+    // pointer. The parser always uses an offset of 0.
+    Increment(Cell, isize),
     PointerIncrement(isize),
-    Read,
-    Write,
-    Loop(Vec<Instruction>),
+    // Read and Write act on the cell at this offset from the cell
+    // pointer. The parser always uses an offset of 0.
+    Read(isize),
+    Write(isize),
+    // Loop while the cell at this offset from the cell pointer is
+    // non-zero. The parser always uses an offset of 0, the current
+    // cell, and most optimisations only apply to those loops.
+    Loop(isize, Vec<Instruction>),
+    // An increment inside a {wrap:saturate} region, which stops at 0
+    // and 255 rather than wrapping.
+    SaturatingIncrement(Cell),
     // These instruction have no direct equivalent in BF, but we
-    // generate them during optimisation.
-    Set(Cell),
+    // generate them during optimisation. Set acts on the cell at
+    // this offset from the cell pointer, like Increment.
+    Set(Cell, isize),
     MultiplyMove(HashMap<isize, Cell>),
+    // Move the cell pointer by this stride until the current cell is
+    // zero, i.e. a loop like [>] or [<<].
+    Scan(isize),
+    // Print the cell pointer and the cells around it to stderr. BF
+    // debuggers use `#` for this, and we only compile it with
+    // `--debug-cmd`.
+    Debug,
 }
 
 fn fmt_with_indent(instr: &Instruction, indent: i32, f: &mut fmt::Formatter) {
@@ -26,20 +64,216 @@ fn fmt_with_indent(instr: &Instruction, indent: i32, f: &mut fmt::Formatter) {
     }
 
     match instr {
-        &Loop(ref loop_body) => {
+        &Loop(offset, ref loop_body) => {
             let _ = write!(f, "Loop");
+            if offset != 0 {
+                let _ = write!(f, "({})", offset);
+            }
 
             for loop_instr in loop_body.iter() {
                 let _ = write!(f, "\n");
                 fmt_with_indent(loop_instr, indent + 1, f);
             }
         }
+        &MultiplyMove(ref changes) => {
+            // HashMap's Debug output depends on its iteration order,
+            // so sort by offset to keep dumps stable between runs.
+            let mut changes: Vec<_> = changes.iter().collect();
+            changes.sort();
+            let changes: Vec<_> = changes.iter()
+                .map(|&(offset, factor)| format!("{:?}: {:?}", offset, factor))
+                .collect();
+            let _ = write!(f, "MultiplyMove({{{}}})", changes.join(", "));
+        }
+        // As with loops, only show the offset if it isn't the
+        // current cell.
+        &Increment(amount, 0) => {
+            let _ = write!(f, "Increment({:?})", amount);
+        }
+        &Set(amount, 0) => {
+            let _ = write!(f, "Set({:?})", amount);
+        }
         instr @ _ => {
             let _ = write!(f, "{:?}", instr);
         }
     }
 }
 
+/// Add `amount` to `cell`, treating the cell as an unsigned byte and
+/// saturating at 0 and 255.
+pub fn saturating_add(cell: Cell, amount: Cell) -> Cell {
+    let value = cell.0 as u8;
+    let result = if amount.0 >= 0 {
+        value.saturating_add(amount.0 as u8)
+    } else {
+        value.saturating_sub(-(amount.0 as i16) as u8)
+    };
+    Wrapping(result as i8)
+}
+
+/// Call `f` on every instruction in `instrs`, including the
+/// instructions in loop bodies. A loop is visited before its body.
+pub fn walk<F>(instrs: &[Instruction], f: &mut F)
+    where F: FnMut(&Instruction)
+{
+    for instr in instrs {
+        f(instr);
+
+        if let &Loop(_, ref body) = instr {
+            walk(body, f);
+        }
+    }
+}
+
+/// As `walk`, but allows `f` to modify the instructions.
+pub fn walk_mut<F>(instrs: &mut [Instruction], f: &mut F)
+    where F: FnMut(&mut Instruction)
+{
+    for instr in instrs.iter_mut() {
+        f(instr);
+
+        if let &mut Loop(_, ref mut body) = instr {
+            walk_mut(body, f);
+        }
+    }
+}
+
+/// Split an instruction into its name and its operand, for
+/// `format_aligned`.
+pub fn name_and_operand(instr: &Instruction) -> (&'static str, String) {
+    match instr {
+        &Increment(amount, 0) => ("Increment", format!("{}", amount.0)),
+        &Increment(amount, offset) => ("Increment", format!("{} at {}", amount.0, offset)),
+        &PointerIncrement(amount) => ("PointerIncrement", format!("{}", amount)),
+        &Read(offset) => ("Read", format!("{}", offset)),
+        &Write(offset) => ("Write", format!("{}", offset)),
+        &Loop(0, _) => ("Loop", String::new()),
+        &Loop(offset, _) => ("Loop", format!("{}", offset)),
+        &SaturatingIncrement(amount) => ("SaturatingIncrement", format!("{}", amount.0)),
+        &Set(amount, 0) => ("Set", format!("{}", amount.0)),
+        &Set(amount, offset) => ("Set", format!("{} at {}", amount.0, offset)),
+        &Scan(stride) => ("Scan", format!("{}", stride)),
+        &Debug => ("Debug", String::new()),
+        &MultiplyMove(ref changes) => {
+            let mut offsets: Vec<_> = changes.keys().collect();
+            offsets.sort();
+            let changes: Vec<_> = offsets.iter()
+                .map(|offset| format!("{}: {}", offset, changes[*offset].0))
+                .collect();
+            ("MultiplyMove", format!("{{{}}}", changes.join(", ")))
+        }
+    }
+}
+
+/// Flatten `instrs` to (indent, name, operand) for every instruction,
+/// including loop bodies.
+fn dump_rows(instrs: &[Instruction], indent: usize, rows: &mut Vec<(usize, &'static str, String)>) {
+    for instr in instrs {
+        let (name, operand) = name_and_operand(instr);
+        rows.push((indent, name, operand));
+
+        if let &Loop(_, ref body) = instr {
+            dump_rows(body, indent + 1, rows);
+        }
+    }
+}
+
+/// Format `instrs` one per line, like `Display`, but as a table:
+/// operands are right-aligned in a column after the instruction
+/// names. This makes long dumps easier to scan.
+pub fn format_aligned(instrs: &[Instruction]) -> String {
+    let mut rows = vec![];
+    dump_rows(instrs, 0, &mut rows);
+
+    let name_width = rows.iter().map(|&(indent, name, _)| indent * 2 + name.len()).max().unwrap_or(0);
+    let operand_width = rows.iter().map(|&(_, _, ref operand)| operand.len()).max().unwrap_or(0);
+
+    let mut result = String::new();
+    for (indent, name, operand) in rows {
+        let line = format!("{:indent$}{:name_width$} {:>operand_width$}",
+                           "", name, operand,
+                           indent = indent * 2,
+                           name_width = name_width - indent * 2,
+                           operand_width = operand_width);
+        result.push_str(line.trim_right());
+        result.push('\n');
+    }
+    result
+}
+
+/// Does this program read or write anywhere? Printing the tape for
+/// `Debug` counts as writing.
+pub fn has_io(instrs: &[Instruction]) -> bool {
+    let mut found_io = false;
+    walk(instrs, &mut |instr| {
+        match instr {
+            &Read(_) | &Write(_) | &Debug => found_io = true,
+            _ => {}
+        }
+    });
+    found_io
+}
+
+/// Does this program read input anywhere?
+pub fn has_reads(instrs: &[Instruction]) -> bool {
+    let mut found_read = false;
+    walk(instrs, &mut |instr| {
+        if let &Read(_) = instr {
+            found_read = true;
+        }
+    });
+    found_read
+}
+
+/// Return a canonical form of `instrs`, so programs that only differ
+/// trivially compare equal. We combine adjacent increments and
+/// pointer increments, and remove changes of zero, including
+/// `MultiplyMove` targets with a factor of zero.
+///
+/// `MultiplyMove` entries are a `HashMap`, so they already compare
+/// equal regardless of insertion order.
+pub fn canonicalize(instrs: &[Instruction]) -> Vec<Instruction> {
+    let mut result: Vec<Instruction> = vec![];
+    for instr in instrs {
+        let instr = match instr {
+            &Loop(offset, ref body) => Loop(offset, canonicalize(body)),
+            &MultiplyMove(ref changes) => {
+                MultiplyMove(changes.iter()
+                                    .filter(|&(_, factor)| factor.0 != 0)
+                                    .map(|(&offset, &factor)| (offset, factor))
+                                    .collect())
+            }
+            other => other.clone(),
+        };
+
+        let combined = match (result.last(), &instr) {
+            (Some(&Increment(prev, prev_offset)), &Increment(amount, offset))
+                if prev_offset == offset => Some(Increment(prev + amount, offset)),
+            (Some(&PointerIncrement(prev)), &PointerIncrement(amount)) => {
+                Some(PointerIncrement(prev + amount))
+            }
+            _ => None,
+        };
+        match combined {
+            Some(combined) => {
+                result.pop();
+                result.push(combined);
+            }
+            None => result.push(instr),
+        }
+
+        // Drop the instruction if it no longer does anything.
+        let no_op = match result.last() {
+            Some(&Increment(Wrapping(0), _)) | Some(&PointerIncrement(0)) => true,
+            _ => false,
+        };
+        if no_op {
+            result.pop();
+        }
+    }
+    result
+}
+
 impl fmt::Display for Instruction {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         fmt_with_indent(self, 0, f);
@@ -47,83 +281,444 @@ impl fmt::Display for Instruction {
     }
 }
 
+impl Hash for Instruction {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        match *self {
+            Increment(amount, offset) => {
+                0u8.hash(state);
+                amount.hash(state);
+                offset.hash(state);
+            }
+            PointerIncrement(amount) => {
+                1u8.hash(state);
+                amount.hash(state);
+            }
+            Read(offset) => {
+                2u8.hash(state);
+                offset.hash(state);
+            }
+            Write(offset) => {
+                3u8.hash(state);
+                offset.hash(state);
+            }
+            Loop(offset, ref body) => {
+                4u8.hash(state);
+                offset.hash(state);
+                body.hash(state);
+            }
+            Set(amount, offset) => {
+                5u8.hash(state);
+                amount.hash(state);
+                offset.hash(state);
+            }
+            SaturatingIncrement(amount) => {
+                7u8.hash(state);
+                amount.hash(state);
+            }
+            Scan(stride) => {
+                8u8.hash(state);
+                stride.hash(state);
+            }
+            Debug => {
+                9u8.hash(state);
+            }
+            MultiplyMove(ref changes) => {
+                // HashMap doesn't implement Hash, and its iteration
+                // order varies, so hash the changes in order.
+                6u8.hash(state);
+                let mut changes: Vec<_> = changes.iter().collect();
+                changes.sort();
+                changes.hash(state);
+            }
+        }
+    }
+}
+
+/// The eight BF commands, and the `#` debug command, independent of
+/// the characters used to write them.
+#[derive(Debug,Clone,Copy,PartialEq,Eq)]
+pub enum Command {
+    Increment,
+    Decrement,
+    PointerIncrement,
+    PointerDecrement,
+    Read,
+    Write,
+    LoopStart,
+    LoopEnd,
+    Debug,
+}
+
+/// Configures the BF dialect we parse: which characters are commands,
+/// and what we do with other characters.
+#[derive(Debug,Clone)]
+pub struct ParseConfig {
+    pub commands: HashMap<char, Command>,
+    /// If true, characters that aren't commands are an error rather
+    /// than a comment.
+    pub reject_unknown: bool,
+    /// With `reject_unknown`, still allow whitespace between
+    /// commands.
+    pub allow_whitespace: bool,
+    /// Combine runs of `+` and `-` into a single 8-bit increment.
+    /// Programs with wider cells need this off, as 256 `+` isn't a
+    /// no-op for them.
+    pub combine_increments: bool,
+    /// Parse `#` as `Command::Debug`, unless `commands` already says
+    /// what `#` means. Otherwise `#` is a comment, as in classic BF.
+    pub debug_command: bool,
+}
+
+impl ParseConfig {
+    /// The command that `c` is in this dialect, if any.
+    pub fn command(&self, c: char) -> Option<Command> {
+        match self.commands.get(&c) {
+            Some(&command) => Some(command),
+            None if c == '#' && self.debug_command => Some(Command::Debug),
+            None => None,
+        }
+    }
+}
+
+impl Default for ParseConfig {
+    /// Classic BF: the usual eight commands, and everything else is a
+    /// comment.
+    fn default() -> ParseConfig {
+        let mut commands = HashMap::new();
+        commands.insert('+', Command::Increment);
+        commands.insert('-', Command::Decrement);
+        commands.insert('>', Command::PointerIncrement);
+        commands.insert('<', Command::PointerDecrement);
+        commands.insert(',', Command::Read);
+        commands.insert('.', Command::Write);
+        commands.insert('[', Command::LoopStart);
+        commands.insert(']', Command::LoopEnd);
+
+        ParseConfig {
+            commands: commands,
+            reject_unknown: false,
+            allow_whitespace: false,
+            combine_increments: true,
+            debug_command: false,
+        }
+    }
+}
+
+/// Return the commands in `source`, in the dialect described by
+/// `config`, along with their character index. Comments are skipped.
+pub fn tokens(source: &str, config: &ParseConfig) -> Vec<(usize, char)> {
+    source.chars().enumerate().filter(|&(_, c)| config.command(c).is_some()).collect()
+}
+
 /// Given a string of BF source code, parse and return our BF IR
 /// representation.
 pub fn parse(source: &str) -> Result<Vec<Instruction>, String> {
-    parse_between(source, 0, source.chars().count())
+    parse_with_config(source, &ParseConfig::default())
 }
 
-/// Parse BF source code from index `start` up to (but excluding)
-/// index `end`.
-fn parse_between(source: &str, start: usize, end: usize) -> Result<Vec<Instruction>, String> {
-    let chars: Vec<_> = source.chars().collect();
-    assert!(start <= end);
-    assert!(end <= chars.len());
+/// Parse BF source code written in the dialect described by `config`.
+///
+/// We parse in a single pass, keeping a stack of the loops we're
+/// inside, so parsing takes linear time however deeply loops nest.
+/// Runs of `+`/`-` (unless `config` says otherwise) and of `>`/`<`
+/// are combined as we go, so the IR starts small.
+pub fn parse_with_config(source: &str, config: &ParseConfig) -> Result<Vec<Instruction>, String> {
+    parse_with_positions(source, config).map(|(instrs, _)| instrs)
+}
 
-    let mut instructions = Vec::new();
-    let mut index = start;
+/// As `parse_with_config`, but also return the character offset in
+/// `source` where each instruction starts, in the same order as
+/// `walk` visits them. A combined run like `+++` starts at its first
+/// character, and a loop starts at its `[`.
+pub fn parse_with_positions(source: &str,
+                            config: &ParseConfig)
+                            -> Result<(Vec<Instruction>, Vec<usize>), String> {
+    let saturating = saturating_chars(source);
 
-    while index < end {
-        match chars[index] {
-            '+' => instructions.push(Increment(Wrapping(1))),
-            '-' => instructions.push(Increment(Wrapping(-1))),
-            '>' => instructions.push(PointerIncrement(1)),
-            '<' => instructions.push(PointerIncrement(-1)),
-            ',' => instructions.push(Read),
-            '.' => instructions.push(Write),
-            '[' => {
-                let close_index = try!(find_close(source, index));
-                let loop_body = try!(parse_between(source, index + 1, close_index));
-                instructions.push(Loop(loop_body));
+    // The index of each loop start we haven't matched yet, along with
+    // the instructions we'd parsed before it.
+    let mut open_loops: Vec<(usize, Vec<Instruction>)> = vec![];
+    let mut instructions = vec![];
+    // Instructions start in source order, so we can record every
+    // position in one list, whatever loop it's in.
+    let mut positions = vec![];
 
-                index = close_index;
+    for (index, c) in source.chars().enumerate() {
+        let len_before = instructions.len();
+        match config.command(c) {
+            Some(Command::Increment) if saturating[index] => {
+                instructions.push(SaturatingIncrement(Wrapping(1)))
+            }
+            Some(Command::Decrement) if saturating[index] => {
+                instructions.push(SaturatingIncrement(Wrapping(-1)))
+            }
+            Some(Command::Increment) if config.combine_increments => {
+                push_increment(&mut instructions, Wrapping(1))
             }
-            ']' => {
-                return Err(format!("Unmatched ] at index {}.", index));
+            Some(Command::Decrement) if config.combine_increments => {
+                push_increment(&mut instructions, Wrapping(-1))
             }
-            _ => (),
+            Some(Command::Increment) => instructions.push(Increment(Wrapping(1), 0)),
+            Some(Command::Decrement) => instructions.push(Increment(Wrapping(-1), 0)),
+            Some(Command::PointerIncrement) => push_ptr_increment(&mut instructions, 1),
+            Some(Command::PointerDecrement) => push_ptr_increment(&mut instructions, -1),
+            Some(Command::Read) => instructions.push(Read(0)),
+            Some(Command::Write) => instructions.push(Write(0)),
+            Some(Command::Debug) => instructions.push(Debug),
+            Some(Command::LoopStart) => {
+                open_loops.push((index, instructions));
+                instructions = vec![];
+                positions.push(index);
+                continue;
+            }
+            Some(Command::LoopEnd) => {
+                match open_loops.pop() {
+                    Some((_, mut outer_instructions)) => {
+                        outer_instructions.push(Loop(0, instructions));
+                        instructions = outer_instructions;
+                        continue;
+                    }
+                    None => {
+                        return Err(error_at(source, index, &format!("Unmatched {}", c)));
+                    }
+                }
+            }
+            None => {
+                if config.reject_unknown {
+                    let is_whitespace = match c {
+                        ' ' | '\t' | '\n' | '\r' => true,
+                        _ => false,
+                    };
+                    if !(config.allow_whitespace && is_whitespace) {
+                        return Err(error_at(source, index,
+                                            &format!("Unknown character {:?}", c)));
+                    }
+                }
+            }
+        }
+
+        // A combined run keeps the position of its first character,
+        // and a run that cancelled out has no position at all. Runs
+        // only combine with the instruction just before, so that's
+        // always the last position.
+        if instructions.len() > len_before {
+            positions.push(index);
+        } else if instructions.len() < len_before {
+            positions.pop();
         }
+    }
+
+    // Report the outermost loop start we never matched.
+    if let Some(&(index, _)) = open_loops.first() {
+        let c = source.chars().nth(index).unwrap();
+        return Err(error_at(source, index, &format!("Unmatched {}", c)));
+    }
 
-        index += 1;
+    Ok((instructions, positions))
+}
+
+/// Add an increment of `amount` to `instrs`, combining it with the
+/// previous instruction if that's an increment too. If they cancel
+/// out, we remove both.
+fn push_increment(instrs: &mut Vec<Instruction>, amount: Cell) {
+    let total = match instrs.last() {
+        Some(&Increment(prev_amount, 0)) => Some(prev_amount + amount),
+        _ => None,
+    };
+    match total {
+        Some(total) => {
+            instrs.pop();
+            if total != Wrapping(0) {
+                instrs.push(Increment(total, 0));
+            }
+        }
+        None => instrs.push(Increment(amount, 0)),
     }
+}
 
-    Ok(instructions)
+/// As `push_increment`, but for pointer increments.
+fn push_ptr_increment(instrs: &mut Vec<Instruction>, amount: isize) {
+    let total = match instrs.last() {
+        Some(&PointerIncrement(prev_amount)) => Some(prev_amount + amount),
+        _ => None,
+    };
+    match total {
+        Some(total) => {
+            instrs.pop();
+            if total != 0 {
+                instrs.push(PointerIncrement(total));
+            }
+        }
+        None => instrs.push(PointerIncrement(amount)),
+    }
 }
 
-/// Find the index of the `]` that matches the `[` at `open_index`.
-fn find_close(source: &str, open_index: usize) -> Result<usize, String> {
-    assert_eq!(source.chars().nth(open_index), Some('['));
+/// Starts a region of the source where `+` and `-` saturate.
+const SATURATE_PRAGMA: &'static str = "{wrap:saturate}";
+/// Ends a region started by `SATURATE_PRAGMA`.
+const WRAP_PRAGMA: &'static str = "{wrap:default}";
 
-    let mut nesting_depth = 0;
-    for (index, c) in source.chars().enumerate() {
-        if index < open_index {
-            continue;
+/// For each character in `source`, return whether it's inside a
+/// region where arithmetic saturates rather than wraps.
+fn saturating_chars(source: &str) -> Vec<bool> {
+    let mut result = vec![];
+    let mut saturating = false;
+    for (byte_index, _) in source.char_indices() {
+        let rest = &source[byte_index..];
+        if rest.starts_with(SATURATE_PRAGMA) {
+            saturating = true;
+        } else if rest.starts_with(WRAP_PRAGMA) {
+            saturating = false;
+        }
+        result.push(saturating);
+    }
+    result
+}
+
+/// Convert the character index `offset` in `source` to a line and
+/// column, both starting from 1.
+pub fn offset_to_line_col(source: &str, offset: usize) -> (usize, usize) {
+    let mut line = 1;
+    let mut column = 1;
+    for c in source.chars().take(offset) {
+        if c == '\n' {
+            line += 1;
+            column = 1;
+        } else {
+            column += 1;
         }
+    }
+    (line, column)
+}
+
+/// The line of `source` containing the character at `offset`, with
+/// a caret under that character, as rustc shows spans:
+///
+/// ```text
+/// foo [-
+///     ^
+/// ```
+///
+/// Tabs before the character are copied into the caret's indent, so
+/// the caret lines up however wide tabs are drawn.
+///
+/// Long lines (minified BF is often a single line) are cut to
+/// `SNIPPET_CONTEXT` characters either side of the caret, with `...`
+/// marking what we've left out.
+pub fn source_snippet(source: &str, offset: usize) -> String {
+    let (line, column) = offset_to_line_col(source, offset);
+    let text: Vec<char> = source.split('\n')
+        .nth(line - 1)
+        .unwrap_or("")
+        .trim_right_matches('\r')
+        .chars()
+        .collect();
 
-        match c {
-            '[' => nesting_depth += 1,
-            ']' => nesting_depth -= 1,
-            _ => (),
+    let caret_index = column - 1;
+    let start = caret_index.saturating_sub(SNIPPET_CONTEXT);
+    let end = min(caret_index + SNIPPET_CONTEXT + 1, text.len());
+    let prefix = if start > 0 { "..." } else { "" };
+    let suffix = if end < text.len() { "..." } else { "" };
+
+    let shown: String = text[start..end].iter().cloned().collect();
+    let indent: String = text[start..caret_index]
+        .iter()
+        .map(|&c| if c == '\t' { '\t' } else { ' ' })
+        .collect();
+    let prefix_indent: String = repeat(' ').take(prefix.len()).collect();
+    format!("{}{}{}\n{}{}^", prefix, shown, suffix, prefix_indent, indent)
+}
+
+/// How many characters `source_snippet` shows either side of the
+/// caret.
+const SNIPPET_CONTEXT: usize = 30;
+
+/// Describe an error at the character `offset` in `source`, giving
+/// its line and column, and showing it with `source_snippet`.
+fn error_at(source: &str, offset: usize, description: &str) -> String {
+    let (line, column) = offset_to_line_col(source, offset);
+    format!("{} at line {}, column {}.\n{}",
+            description, line, column, source_snippet(source, offset))
+}
+
+/// The line number (starting from 1) of each character offset in
+/// `offsets`, which must be in increasing order, as
+/// `parse_with_positions` returns them.
+pub fn offsets_to_lines(source: &str, offsets: &[usize]) -> Vec<usize> {
+    let mut result = vec![];
+    let mut line = 1;
+    let mut chars = source.chars().enumerate();
+    for &offset in offsets {
+        while let Some((index, c)) = chars.next() {
+            if index == offset {
+                result.push(line);
+                if c == '\n' {
+                    line += 1;
+                }
+                break;
+            }
+            if c == '\n' {
+                line += 1;
+            }
         }
+    }
+    result
+}
 
-        if nesting_depth == 0 {
-            return Ok(index)
+/// The character offsets of the start and end of every loop in
+/// `source`, written in the dialect described by `config`, in the
+/// same order as `walk` visits them. `source` must have balanced
+/// loops.
+pub fn loop_spans(source: &str, config: &ParseConfig) -> Vec<(usize, usize)> {
+    let mut result = vec![];
+    let mut open = vec![];
+    for (offset, c) in source.chars().enumerate() {
+        match config.command(c) {
+            Some(Command::LoopStart) => {
+                open.push(result.len());
+                result.push((offset, offset));
+            }
+            Some(Command::LoopEnd) => {
+                if let Some(index) = open.pop() {
+                    result[index].1 = offset;
+                }
+            }
+            _ => {}
         }
     }
-    // TODO: show line number
-    Err(format!("Could not find matching ] for [ at index {}.", open_index))
+    result
 }
 
 #[test]
 fn parse_increment() {
-    assert_eq!(parse("+").unwrap(), [Increment(Wrapping(1))]);
-    assert_eq!(parse("++").unwrap(), [Increment(Wrapping(1)),
-                                      Increment(Wrapping(1))]);
+    assert_eq!(parse("+").unwrap(), [Increment(Wrapping(1), 0)]);
+    assert_eq!(parse("++").unwrap(), [Increment(Wrapping(2), 0)]);
+}
+
+#[test]
+fn parse_collapses_runs() {
+    assert_eq!(parse("+++--").unwrap(), [Increment(Wrapping(1), 0)]);
+    assert_eq!(parse(">><").unwrap(), [PointerIncrement(1)]);
+    assert_eq!(parse("+-").unwrap(), vec![]);
+    assert_eq!(parse("+>+<<").unwrap(), [Increment(Wrapping(1), 0), PointerIncrement(1),
+                                         Increment(Wrapping(1), 0), PointerIncrement(-2)]);
+    // Comments don't break up a run, but loops do.
+    assert_eq!(parse("+ comment +[+]+").unwrap(),
+               [Increment(Wrapping(2), 0), Loop(0, vec![Increment(Wrapping(1), 0)]),
+                Increment(Wrapping(1), 0)]);
+}
+
+#[test]
+fn parse_without_combining_increments() {
+    let config = ParseConfig { combine_increments: false, ..ParseConfig::default() };
+    assert_eq!(parse_with_config("++->>", &config).unwrap(),
+               [Increment(Wrapping(1), 0), Increment(Wrapping(1), 0), Increment(Wrapping(-1), 0),
+                PointerIncrement(2)]);
 }
 
 #[test]
 fn parse_decrement() {
-    assert_eq!(parse("-").unwrap(), [Increment(Wrapping(-1))]);
+    assert_eq!(parse("-").unwrap(), [Increment(Wrapping(-1), 0)]);
 }
 
 #[test]
@@ -138,33 +733,33 @@ fn parse_pointer_decrement() {
 
 #[test]
 fn parse_read() {
-    assert_eq!(parse(",").unwrap(), [Read]);
+    assert_eq!(parse(",").unwrap(), [Read(0)]);
 }
 
 #[test]
 fn parse_write() {
-    assert_eq!(parse(".").unwrap(), [Write]);
+    assert_eq!(parse(".").unwrap(), [Write(0)]);
 }
 
 #[test]
 fn parse_empty_loop() {
-    let expected = [Loop(vec![])];
+    let expected = [Loop(0, vec![])];
     assert_eq!(parse("[]").unwrap(), expected);
 }
 
 #[test]
 fn parse_simple_loop() {
-    let loop_body = vec![Increment(Wrapping(1))];
-    let expected = [Loop(loop_body)];
+    let loop_body = vec![Increment(Wrapping(1), 0)];
+    let expected = [Loop(0, loop_body)];
     assert_eq!(parse("[+]").unwrap(), expected);
 }
 
 #[test]
 fn parse_complex_loop() {
-    let loop_body = vec![Read, Increment(Wrapping(1))];
-    let expected = [Write,
-                    Loop(loop_body),
-                    Increment(Wrapping(-1))];
+    let loop_body = vec![Read(0), Increment(Wrapping(1), 0)];
+    let expected = [Write(0),
+                    Loop(0, loop_body),
+                    Increment(Wrapping(-1), 0)];
     assert_eq!(parse(".[,+]-").unwrap(), expected);
 }
 
@@ -174,7 +769,368 @@ fn parse_unbalanced_loop() {
     assert!(parse("]").is_err());
 }
 
+#[test]
+fn parse_unbalanced_loop_position() {
+    assert_eq!(parse("+\nfoo [-\n"),
+               Err("Unmatched [ at line 2, column 5.\nfoo [-\n    ^".to_owned()));
+    assert_eq!(parse("[-]\n\n  comment ]"),
+               Err("Unmatched ] at line 3, column 11.\n  comment ]\n          ^".to_owned()));
+}
+
+#[test]
+fn parse_unbalanced_nested_loop_position() {
+    // We report the outermost loop that isn't closed.
+    assert_eq!(parse("+[[-]"), Err("Unmatched [ at line 1, column 2.\n+[[-]\n ^".to_owned()));
+}
+
+#[test]
+fn parse_deeply_nested_loops() {
+    // Parsing must be linear in the size of the source. Rescanning
+    // the source for every loop start makes this very slow.
+    let depth = 2000;
+    let source = format!("{}{}{}", repeat("[").take(depth).collect::<String>(),
+                         repeat(",").take(200000).collect::<String>(),
+                         repeat("]").take(depth).collect::<String>());
+    let instrs = parse(&source).unwrap();
+
+    let mut loops = 0;
+    let mut reads = 0;
+    walk(&instrs, &mut |instr| {
+        match *instr {
+            Loop(_, _) => loops += 1,
+            Read(_) => reads += 1,
+            _ => {}
+        }
+    });
+    assert_eq!(loops, depth);
+    assert_eq!(reads, 200000);
+}
+
+#[test]
+fn offset_line_col() {
+    assert_eq!(offset_to_line_col("abc", 0), (1, 1));
+    assert_eq!(offset_to_line_col("abc", 2), (1, 3));
+    assert_eq!(offset_to_line_col("a\nbc", 1), (1, 2));
+    assert_eq!(offset_to_line_col("a\nbc", 2), (2, 1));
+    assert_eq!(offset_to_line_col("a\nbc", 3), (2, 2));
+}
+
+#[test]
+fn snippet_for_offset() {
+    assert_eq!(source_snippet("+[\n>]", 1), "+[\n ^");
+    assert_eq!(source_snippet("+[\r\n>]", 5), ">]\n ^");
+}
+
+#[test]
+fn snippet_truncates_long_lines() {
+    let line: String = repeat('+').take(100).collect();
+    let source = format!("{}[{}", line, line);
+    let plusses: String = repeat('+').take(30).collect();
+    let indent: String = repeat(' ').take(33).collect();
+    assert_eq!(source_snippet(&source, 100),
+               format!("...{}[{}...\n{}^", plusses, plusses, indent));
+
+    // Only cut the side that's too long.
+    assert_eq!(source_snippet(&source[95..], 5),
+               format!("+++++[{}...\n     ^", plusses));
+}
+
+#[test]
+fn snippet_keeps_tabs() {
+    assert_eq!(source_snippet("+\n\t\t[-", 4), "\t\t[-\n\t\t^");
+    assert_eq!(source_snippet("a\tb [", 4), "a\tb [\n \t  ^");
+}
+
+#[test]
+fn parse_unbalanced_loop_snippet() {
+    let source = "+++\n\t[->+<]\n\t[\n.";
+    assert_eq!(parse(source),
+               Err("Unmatched [ at line 3, column 2.\n\t[\n\t^".to_owned()));
+}
+
 #[test]
 fn parse_comment() {
     assert_eq!(parse("foo! ").unwrap(), []);
 }
+
+#[cfg(test)]
+fn debug_config() -> ParseConfig {
+    ParseConfig { debug_command: true, ..ParseConfig::default() }
+}
+
+#[test]
+fn parse_debug() {
+    assert_eq!(parse_with_config("+#[#]", &debug_config()).unwrap(),
+               [Increment(Wrapping(1), 0), Debug, Loop(0, vec![Debug])]);
+}
+
+#[test]
+fn parse_debug_breaks_runs() {
+    assert_eq!(parse_with_config("+#+", &debug_config()).unwrap(),
+               [Increment(Wrapping(1), 0), Debug, Increment(Wrapping(1), 0)]);
+}
+
+#[test]
+fn parse_debug_is_a_comment_by_default() {
+    assert_eq!(parse("+#+").unwrap(), [Increment(Wrapping(2), 0)]);
+    assert_eq!(tokens("+#", &ParseConfig::default()), vec![(0, '+')]);
+}
+
+#[test]
+fn tokens_skip_comments() {
+    assert_eq!(tokens("a+b[>]", &ParseConfig::default()),
+               vec![(1, '+'), (3, '['), (4, '>'), (5, ']')]);
+}
+
+#[test]
+fn tokens_use_config() {
+    let mut config = debug_config();
+    config.commands.insert('a', Command::Increment);
+    config.commands.remove(&'+');
+    assert_eq!(tokens("a+#.", &config), vec![(0, 'a'), (2, '#'), (3, '.')]);
+}
+
+#[test]
+fn tokens_unbalanced() {
+    // We don't check brackets when tokenizing.
+    assert_eq!(tokens("].", &ParseConfig::default()), vec![(0, ']'), (1, '.')]);
+}
+
+#[test]
+fn walk_visits_nested_instructions() {
+    let instrs = parse("+[>[-]]").unwrap();
+
+    let mut visited = vec![];
+    walk(&instrs, &mut |instr| visited.push(instr.clone()));
+
+    assert_eq!(visited, vec![
+        Increment(Wrapping(1), 0),
+        Loop(0, vec![PointerIncrement(1), Loop(0, vec![Increment(Wrapping(-1), 0)])]),
+        PointerIncrement(1),
+        Loop(0, vec![Increment(Wrapping(-1), 0)]),
+        Increment(Wrapping(-1), 0)]);
+}
+
+#[test]
+fn walk_mut_modifies_nested_instructions() {
+    let mut instrs = parse(".[.]").unwrap();
+
+    walk_mut(&mut instrs, &mut |instr| {
+        if let &mut Write(ref mut offset) = instr {
+            *offset = 2;
+        }
+    });
+
+    assert_eq!(instrs, vec![Write(2), Loop(0, vec![Write(2)])]);
+}
+
+#[test]
+fn format_aligned_columns() {
+    let instrs = vec![Increment(Wrapping(3), 0),
+                      PointerIncrement(-10),
+                      Set(Wrapping(100), 0),
+                      Loop(0, vec![Write(0)])];
+    let expected = ["Increment          3",
+                    "PointerIncrement -10",
+                    "Set              100",
+                    "Loop",
+                    "  Write            0"];
+    assert_eq!(format_aligned(&instrs), expected.join("\n") + "\n");
+}
+
+#[test]
+fn format_aligned_multiply_move() {
+    let mut changes = HashMap::new();
+    changes.insert(2, Wrapping(-1));
+    changes.insert(-1, Wrapping(3));
+    let instrs = vec![MultiplyMove(changes), Read(0)];
+    let expected = ["MultiplyMove {-1: 3, 2: -1}",
+                    "Read                      0"];
+    assert_eq!(format_aligned(&instrs), expected.join("\n") + "\n");
+}
+
+#[test]
+fn format_loop_condition() {
+    // Loops on the current cell print as before.
+    assert_eq!(format!("{}", Loop(0, vec![])), "Loop");
+    assert_eq!(format!("{}", Loop(-2, vec![])), "Loop(-2)");
+    assert_eq!(format_aligned(&[Loop(3, vec![])]), "Loop 3\n");
+}
+
+#[test]
+fn format_increment_offset() {
+    assert_eq!(format!("{}", Increment(Wrapping(1), 0)), format!("Increment({:?})", Wrapping(1)));
+    assert_eq!(format!("{}", Set(Wrapping(2), -1)),
+               format!("Set({:?}, -1)", Wrapping(2)));
+    assert_eq!(format_aligned(&[Increment(Wrapping(1), 2), Set(Wrapping(0), 0)]),
+               "Increment 1 at 2\nSet            0\n");
+}
+
+#[test]
+fn format_multiply_move_sorted() {
+    let mut changes = HashMap::new();
+    for offset in (-5..6).rev() {
+        changes.insert(offset, Wrapping(1));
+    }
+    let expected = format!("MultiplyMove({{{}}})",
+                           (-5..6).map(|offset| format!("{}: Wrapping(1)", offset))
+                                  .collect::<Vec<_>>()
+                                  .join(", "));
+    assert_eq!(format!("{}", MultiplyMove(changes)), expected);
+}
+
+#[test]
+fn has_io_nested() {
+    assert!(!has_io(&parse("+[>-]").unwrap()));
+    assert!(has_io(&parse("+[>[.]]").unwrap()));
+    assert!(has_io(&parse(",").unwrap()));
+    assert!(has_io(&parse_with_config("+#", &debug_config()).unwrap()));
+}
+
+#[test]
+fn parse_with_alternate_command() {
+    let mut config = ParseConfig::default();
+    config.commands.insert(';', Command::Write);
+
+    assert_eq!(parse_with_config("+;.", &config).unwrap(),
+               [Increment(Wrapping(1), 0), Write(0), Write(0)]);
+    // The default config treats ; as a comment.
+    assert_eq!(parse("+;.").unwrap(), [Increment(Wrapping(1), 0), Write(0)]);
+}
+
+#[test]
+fn parse_with_replaced_loop_commands() {
+    let mut config = ParseConfig::default();
+    config.commands.remove(&'[');
+    config.commands.remove(&']');
+    config.commands.insert('(', Command::LoopStart);
+    config.commands.insert(')', Command::LoopEnd);
+
+    assert_eq!(parse_with_config("([-])", &config).unwrap(),
+               [Loop(0, vec![Increment(Wrapping(-1), 0)])]);
+    assert!(parse_with_config(")", &config).is_err());
+}
+
+#[test]
+fn parse_rejecting_unknown_characters() {
+    let config = ParseConfig { reject_unknown: true, ..ParseConfig::default() };
+
+    assert!(parse_with_config("+.", &config).is_ok());
+    assert!(parse_with_config("+ .", &config).is_err());
+}
+
+#[test]
+fn parse_allowing_only_whitespace() {
+    let config = ParseConfig {
+        reject_unknown: true,
+        allow_whitespace: true,
+        ..ParseConfig::default()
+    };
+
+    assert_eq!(parse_with_config("+ [-]\n\t.\r\n", &config).unwrap(),
+               [Increment(Wrapping(1), 0), Loop(0, vec![Increment(Wrapping(-1), 0)]), Write(0)]);
+    assert_eq!(parse_with_config("+ a", &config),
+               Err("Unknown character 'a' at line 1, column 3.\n+ a\n  ^".to_owned()));
+}
+
+#[test]
+fn hash_multiply_move() {
+    let mut changes = HashMap::new();
+    let mut same_changes = HashMap::new();
+    for i in 1..20 {
+        changes.insert(i, Wrapping(i as i8));
+        same_changes.insert(20 - i, Wrapping((20 - i) as i8));
+    }
+
+    let mut instrs = HashSet::new();
+    instrs.insert(MultiplyMove(changes));
+    instrs.insert(MultiplyMove(same_changes));
+    instrs.insert(Loop(0, vec![Increment(Wrapping(1), 0)]));
+    instrs.insert(Loop(0, vec![Increment(Wrapping(1), 0)]));
+    assert_eq!(instrs.len(), 2);
+}
+
+#[test]
+fn parse_saturating_region() {
+    let instrs = parse("+{wrap:saturate}+-{wrap:default}-").unwrap();
+    assert_eq!(instrs, vec![Increment(Wrapping(1), 0),
+                            SaturatingIncrement(Wrapping(1)),
+                            SaturatingIncrement(Wrapping(-1)),
+                            Increment(Wrapping(-1), 0)]);
+}
+
+#[test]
+fn parse_saturating_region_in_loop() {
+    let instrs = parse("[{wrap:saturate}-]+").unwrap();
+    assert_eq!(instrs, vec![Loop(0, vec![SaturatingIncrement(Wrapping(-1))]),
+                            SaturatingIncrement(Wrapping(1))]);
+}
+
+#[test]
+fn saturating_add_stops_at_limits() {
+    assert_eq!(saturating_add(Wrapping(-2), Wrapping(5)), Wrapping(-1));
+    assert_eq!(saturating_add(Wrapping(2), Wrapping(-5)), Wrapping(0));
+    assert_eq!(saturating_add(Wrapping(2), Wrapping(-128)), Wrapping(0));
+    assert_eq!(saturating_add(Wrapping(127), Wrapping(1)), Wrapping(-128));
+}
+
+#[test]
+fn parse_positions() {
+    let (instrs, positions) = parse_with_positions("a++[>-<]\n.", &ParseConfig::default())
+                                  .unwrap();
+    assert_eq!(instrs.len(), 3);
+    assert_eq!(positions, vec![1, 3, 4, 5, 6, 9]);
+    assert_eq!(offsets_to_lines("a++[>-<]\n.", &positions), vec![1, 1, 1, 1, 1, 2]);
+}
+
+#[test]
+fn parse_positions_cancelled_run() {
+    let (instrs, positions) = parse_with_positions("+-[+>-<]", &ParseConfig::default())
+                                  .unwrap();
+    assert_eq!(instrs,
+               vec![Loop(0, vec![Increment(Wrapping(1), 0), PointerIncrement(1),
+                                 Increment(Wrapping(-1), 0), PointerIncrement(-1)])]);
+    assert_eq!(positions, vec![2, 3, 4, 5, 6]);
+}
+
+#[test]
+fn loop_spans_nested() {
+    let config = ParseConfig::default();
+    assert_eq!(loop_spans("[[]] x[]", &config), vec![(0, 3), (1, 2), (6, 7)]);
+    assert_eq!(loop_spans("+[-]\n[]", &config), vec![(1, 3), (5, 6)]);
+}
+
+#[test]
+fn loop_spans_with_replaced_loop_commands() {
+    let mut config = ParseConfig::default();
+    config.commands.remove(&'[');
+    config.commands.remove(&']');
+    config.commands.insert('(', Command::LoopStart);
+    config.commands.insert(')', Command::LoopEnd);
+    assert_eq!(loop_spans("[(-)]", &config), vec![(1, 3)]);
+}
+
+#[test]
+fn canonicalize_multiply_move_order() {
+    let mut changes = HashMap::new();
+    changes.insert(1, Wrapping(2));
+    changes.insert(3, Wrapping(-1));
+    changes.insert(2, Wrapping(0));
+
+    let mut reordered = HashMap::new();
+    reordered.insert(3, Wrapping(-1));
+    reordered.insert(1, Wrapping(2));
+
+    assert_eq!(canonicalize(&[MultiplyMove(changes)]),
+               canonicalize(&[MultiplyMove(reordered)]));
+}
+
+#[test]
+fn canonicalize_removes_no_ops() {
+    let instrs = parse("+-[>+<<>]++>-<").unwrap();
+    assert_eq!(canonicalize(&instrs),
+               vec![Loop(0, vec![PointerIncrement(1), Increment(Wrapping(1), 0),
+                                 PointerIncrement(-1)]),
+                    Increment(Wrapping(2), 0), PointerIncrement(1), Increment(Wrapping(-1), 0),
+                    PointerIncrement(-1)]);
+}
@@ -34,6 +34,152 @@ pub fn highest_cell_index(instrs: &[Instruction]) -> usize {
     }
 }
 
+/// The number of cells the program needs, if we can bound how far
+/// right it moves. Unlike `highest_cell_index`, this isn't limited
+/// to our usual tape size.
+pub fn min_tape_size(instrs: &[Instruction]) -> Option<usize> {
+    match overall_movement(instrs).0 {
+        SaturatingInt::Number(x) => Some(x as usize + 1),
+        SaturatingInt::Max => None,
+    }
+}
+
+/// Does this program move the cell pointer below cell #0 before it
+/// reaches any loops? We only look at the straight-line code at the
+/// start of the program, so when this returns true the program is
+/// definitely broken.
+pub fn starts_out_of_bounds(instrs: &[Instruction]) -> bool {
+    let mut cell_ptr: isize = 0;
+    for instr in instrs {
+        match instr {
+            &PointerIncrement(amount) => {
+                cell_ptr = cell_ptr.saturating_add(amount);
+                if cell_ptr < 0 {
+                    return true;
+                }
+            }
+            &Read(offset) | &Write(offset) | &Increment(_, offset) | &Set(_, offset) => {
+                if cell_ptr.saturating_add(offset) < 0 {
+                    return true;
+                }
+            }
+            &Loop(offset, _) => {
+                return cell_ptr.saturating_add(offset) < 0;
+            }
+            &Scan(_) => return false,
+            _ => {}
+        }
+    }
+    false
+}
+
+/// Find the instructions whose cell accesses we can't prove are
+/// inside a tape of `num_cells` cells, if the cell pointer starts at
+/// `cell_ptr`. We return a flag for every instruction, in the order
+/// `walk` visits them. A loop's flag is for the cell its condition
+/// tests.
+///
+/// We know where the cell pointer is until we reach a loop that
+/// doesn't return to the cell it started at (e.g. "[>]").
+pub fn unproven_accesses(instrs: &[Instruction], cell_ptr: isize, num_cells: usize) -> Vec<bool> {
+    let mut result = vec![];
+    unproven_accesses_inner(instrs, Some(cell_ptr), num_cells as isize, &mut result);
+    result
+}
+
+/// Add flags for `instrs` to `result`, and return the cell pointer
+/// afterwards, if we know it.
+fn unproven_accesses_inner(instrs: &[Instruction],
+                           cell_ptr: Option<isize>,
+                           num_cells: isize,
+                           result: &mut Vec<bool>)
+                           -> Option<isize> {
+    let mut cell_ptr = cell_ptr;
+    for instr in instrs {
+        let balanced = match instr {
+            &Loop(_, ref body) => net_movement(body) == Some(0),
+            &Scan(_) => false,
+            _ => true,
+        };
+
+        let proven = match cell_ptr {
+            Some(ptr) if balanced => {
+                accessed_offsets(instr).iter().all(|&offset| {
+                    match ptr.checked_add(offset) {
+                        Some(index) => index >= 0 && index < num_cells,
+                        None => false,
+                    }
+                })
+            }
+            _ => accessed_offsets(instr).is_empty(),
+        };
+        result.push(!proven);
+
+        match instr {
+            &PointerIncrement(amount) => {
+                // If we can't represent the cell pointer, we don't
+                // know where it is.
+                cell_ptr = cell_ptr.and_then(|ptr| ptr.checked_add(amount));
+            }
+            &Loop(_, ref body) => {
+                // A balanced loop starts every iteration at the same
+                // cell, and finishes there too.
+                if !balanced {
+                    cell_ptr = None;
+                }
+                unproven_accesses_inner(body, cell_ptr, num_cells, result);
+            }
+            &Scan(_) => {
+                cell_ptr = None;
+            }
+            _ => {}
+        }
+    }
+    cell_ptr
+}
+
+/// The offsets from the cell pointer of the cells that `instr` reads
+/// or writes. Loops only access the cell they test; their bodies are
+/// separate instructions. `Debug` only prints cells that are on the
+/// tape, so it never accesses anything out of bounds.
+pub fn accessed_offsets(instr: &Instruction) -> Vec<isize> {
+    match instr {
+        &SaturatingIncrement(_) | &Scan(_) => vec![0],
+        &Increment(_, offset) | &Set(_, offset) | &Read(offset) | &Write(offset) |
+        &Loop(offset, _) => vec![offset],
+        &MultiplyMove(ref changes) => {
+            let mut offsets: Vec<isize> = changes.keys().cloned().collect();
+            offsets.push(0);
+            offsets
+        }
+        &PointerIncrement(_) | &Debug => vec![],
+    }
+}
+
+/// The net movement of the cell pointer after running `instrs`, or
+/// None if it depends on how often a loop runs.
+pub fn net_movement(instrs: &[Instruction]) -> Option<isize> {
+    let mut movement: isize = 0;
+    for instr in instrs {
+        match instr {
+            &PointerIncrement(amount) => {
+                movement = match movement.checked_add(amount) {
+                    Some(movement) => movement,
+                    None => return None,
+                };
+            }
+            &Loop(_, ref body) => {
+                if net_movement(body) != Some(0) {
+                    return None;
+                }
+            }
+            &Scan(_) => return None,
+            _ => {}
+        }
+    }
+    Some(movement)
+}
+
 /// Saturating arithmetic: we have normal integers that work as
 /// expected, but Max is bigger than any Number.
 #[derive(Eq,PartialEq,Clone,Copy,Debug)]
@@ -45,10 +191,16 @@ enum SaturatingInt {
 impl Add for SaturatingInt {
     type Output = SaturatingInt;
     fn add(self, rhs: SaturatingInt) -> SaturatingInt {
-        if let (&SaturatingInt::Number(x), &SaturatingInt::Number(y)) = (&self, &rhs) {
-            SaturatingInt::Number(x + y)
-        } else {
-            SaturatingInt::Max
+        // If the sum overflows, we can't bound it, so we treat it as
+        // Max too.
+        match (self, rhs) {
+            (SaturatingInt::Number(x), SaturatingInt::Number(y)) => {
+                match x.checked_add(y) {
+                    Some(sum) => SaturatingInt::Number(sum),
+                    None => SaturatingInt::Max,
+                }
+            }
+            _ => SaturatingInt::Max,
         }
     }
 }
@@ -92,6 +244,19 @@ fn movement(instr: &Instruction) -> (SaturatingInt, SaturatingInt) {
         } else {
             (SaturatingInt::Number(amount as i64), SaturatingInt::Number(amount as i64))
         },
+        &Read(offset) | &Write(offset) | &Increment(_, offset) | &Set(_, offset) => {
+            (SaturatingInt::Number(max(offset, 0) as i64), SaturatingInt::Number(0))
+        }
+        &Scan(stride) => {
+            if stride > 0 {
+                // We can't tell how far right we'll go.
+                (SaturatingInt::Max, SaturatingInt::Max)
+            } else {
+                // As with loops, conservatively assume we don't move
+                // left at all.
+                (SaturatingInt::Number(0), SaturatingInt::Number(0))
+            }
+        }
         &MultiplyMove(ref changes) => {
             let mut highest_affected = 0;
             for cell in changes.keys() {
@@ -101,8 +266,11 @@ fn movement(instr: &Instruction) -> (SaturatingInt, SaturatingInt) {
             }
             (SaturatingInt::Number(highest_affected as i64), SaturatingInt::Number(0))
         }
-        &Loop(ref body) => {
+        &Loop(offset, ref body) => {
             let (max_in_body, net_in_body) = overall_movement(body);
+            // The loop condition may test a cell further right than
+            // the body reaches.
+            let max_in_body = max(max_in_body, SaturatingInt::Number(max(offset, 0) as i64));
 
             match net_in_body {
                 SaturatingInt::Number(net_loop_movement) => {
@@ -150,6 +318,30 @@ fn ptr_increment_sequence_bounds() {
     assert_eq!(highest_cell_index(&instrs), 3);
 }
 
+#[test]
+fn min_tape_size_bounds() {
+    assert_eq!(min_tape_size(&parse(">>[-]<.").unwrap()), Some(3));
+    assert_eq!(min_tape_size(&parse("").unwrap()), Some(1));
+    assert_eq!(min_tape_size(&parse(",[>,]").unwrap()), None);
+
+    // Larger than our usual tape.
+    let instrs = vec![PointerIncrement(MAX_CELL_INDEX as isize + 1)];
+    assert_eq!(min_tape_size(&instrs), Some(MAX_CELL_INDEX + 2));
+}
+
+#[test]
+fn io_offset_bounds() {
+    let instrs = vec![Write(3), Read(-1)];
+    assert_eq!(highest_cell_index(&instrs), 3);
+}
+
+#[test]
+fn increment_offset_bounds() {
+    let instrs = vec![Increment(Wrapping(1), 4), Set(Wrapping(2), -1)];
+    assert_eq!(highest_cell_index(&instrs), 4);
+    assert!(starts_out_of_bounds(&instrs));
+}
+
 #[test]
 fn multiple_ptr_increment_bounds() {
     let instrs = vec![PointerIncrement(2)];
@@ -213,6 +405,53 @@ fn excessive_bounds_truncated() {
     assert_eq!(highest_cell_index(&instrs), MAX_CELL_INDEX);
 }
 
+#[test]
+fn huge_movement_doesnt_overflow() {
+    let huge = ::std::isize::MAX;
+    let instrs = vec![PointerIncrement(huge), PointerIncrement(huge), Write(0)];
+    assert_eq!(highest_cell_index(&instrs), MAX_CELL_INDEX);
+    assert_eq!(min_tape_size(&instrs), None);
+    assert_eq!(net_movement(&instrs), None);
+    assert_eq!(unproven_accesses(&instrs, 0, 10), vec![false, false, true]);
+
+    let instrs = vec![PointerIncrement(-huge), PointerIncrement(-huge), Write(0)];
+    assert!(starts_out_of_bounds(&instrs));
+}
+
+#[test]
+fn out_of_bounds_at_start() {
+    assert!(starts_out_of_bounds(&parse("<").unwrap()));
+    assert!(starts_out_of_bounds(&parse("+>.<<").unwrap()));
+    assert!(starts_out_of_bounds(&[Write(-1)]));
+
+    assert!(!starts_out_of_bounds(&parse("><").unwrap()));
+    // We only look before the first loop.
+    assert!(!starts_out_of_bounds(&parse("[>]<<").unwrap()));
+}
+
+#[test]
+fn unproven_accesses_static() {
+    let instrs = parse(">+<").unwrap();
+    assert_eq!(unproven_accesses(&instrs, 0, 2), vec![false, false, false]);
+
+    // Balanced loops don't lose track of the cell pointer.
+    let instrs = parse("+[->+<]>.").unwrap();
+    assert_eq!(unproven_accesses(&instrs, 0, 2), vec![false; 8]);
+}
+
+#[test]
+fn unproven_accesses_after_runtime_movement() {
+    // How far "[>]" moves depends on the input.
+    let instrs = parse(",[>]+").unwrap();
+    assert_eq!(unproven_accesses(&instrs, 0, 30000), vec![false, true, false, true]);
+}
+
+#[test]
+fn unproven_accesses_outside_tape() {
+    let instrs = parse("+>>+").unwrap();
+    assert_eq!(unproven_accesses(&instrs, 0, 2), vec![false, false, true]);
+}
+
 #[test]
 fn loop_with_no_net_movement() {
     // Max cell index 1, final cell position 0.
@@ -0,0 +1,52 @@
+use std::fs::{self, File};
+use std::io::prelude::Read;
+use std::path::Path;
+
+use bfir::parse;
+use execution::{execute, MAX_STEPS};
+use peephole::optimize;
+
+/// The directory containing our corpus. Each `foo.bf` program has a
+/// `foo.out` file containing its expected output.
+const CORPUS_DIR: &'static str = "tests/programs";
+
+fn read_file(path: &Path) -> Vec<u8> {
+    let mut contents = vec![];
+    File::open(path).unwrap().read_to_end(&mut contents).unwrap();
+    contents
+}
+
+/// Run this program to completion in our interpreter, and return
+/// everything it wrote.
+fn interpret(source: &str, optimized: bool) -> Vec<u8> {
+    let mut instrs = parse(source).unwrap();
+    if optimized {
+        instrs = optimize(instrs);
+    }
+
+    let state = execute(&instrs, MAX_STEPS);
+    assert_eq!(state.instr_ptr, instrs.len());
+
+    state.outputs.iter().map(|&byte| byte as u8).collect()
+}
+
+#[test]
+fn corpus_programs_produce_expected_output() {
+    let mut programs_run = 0;
+
+    for entry in fs::read_dir(CORPUS_DIR).unwrap() {
+        let path = entry.unwrap().path();
+        if path.extension().and_then(|ext| ext.to_str()) != Some("bf") {
+            continue;
+        }
+
+        let source = String::from_utf8(read_file(&path)).unwrap();
+        let expected = read_file(&path.with_extension("out"));
+
+        assert_eq!(interpret(&source, false), expected);
+        assert_eq!(interpret(&source, true), expected);
+        programs_run += 1;
+    }
+
+    assert!(programs_run > 0);
+}
@@ -0,0 +1,247 @@
+#![warn(trivial_numeric_casts)]
+
+use std::collections::HashSet;
+use std::io;
+use std::io::Write;
+
+use bfir::{Instruction, walk, offset_to_line_col};
+use bfir::Instruction::*;
+use bounds;
+use execution::never_exits;
+
+#[cfg(test)]
+use bfir::{parse, parse_with_positions, ParseConfig};
+
+/// A problem in a BF program that doesn't stop us compiling it.
+#[derive(Debug,Clone,PartialEq,Eq)]
+pub struct Warning {
+    pub message: String,
+}
+
+/// Find everything we want to warn the user about in this program.
+pub fn check(instrs: &[Instruction]) -> Vec<Warning> {
+    let mut warnings = vec![];
+
+    if bounds::starts_out_of_bounds(instrs) {
+        warnings.push(Warning {
+            message: String::from("this program always moves the cell pointer below cell #0"),
+        });
+    }
+
+    warnings
+}
+
+/// Find loops that can never exit once they've started, because
+/// their body leaves the cell pointer where it was and never changes
+/// the cell the loop tests. `positions` is the source offset of every
+/// instruction, in the order `walk` visits them.
+///
+/// We only report loops we're sure about. We give up on bodies with
+/// loops or scans inside, so a loop that moves away, changes the
+/// tested cell in an inner loop and comes back isn't reported.
+pub fn infinite_loops(instrs: &[Instruction], positions: &[usize], source: &str) -> Vec<Warning> {
+    let mut warnings = vec![];
+    let mut index = 0;
+    walk(instrs, &mut |instr| {
+        if never_exits(instr) {
+            let (line, column) = offset_to_line_col(source, positions[index]);
+            warnings.push(Warning {
+                message: format!("loop at line {}, column {} never terminates once entered",
+                                 line, column),
+            });
+        }
+        index += 1;
+    });
+    warnings
+}
+
+/// Find writes and loop conditions that use a cell before anything
+/// has changed it, so it still has its initial value of zero. This is
+/// usually intended, so these are only notes.
+///
+/// We stop at the first loop whose effect on the cell pointer we
+/// can't predict, and don't look inside loop bodies.
+pub fn unchanged_reads(instrs: &[Instruction]) -> Vec<Warning> {
+    let mut changed = HashSet::new();
+    let mut notes = vec![];
+    let mut cell_ptr = 0;
+
+    for instr in instrs {
+        match instr {
+            &Write(offset) => {
+                if !changed.contains(&(cell_ptr + offset)) {
+                    notes.push(Warning {
+                        message: format!("cell #{} is written before anything changes it, \
+                                          so it's always zero",
+                                         cell_ptr + offset),
+                    });
+                }
+            }
+            &Loop(offset, ref body) => {
+                if !changed.contains(&(cell_ptr + offset)) {
+                    notes.push(Warning {
+                        message: format!("loop tests cell #{} before anything changes it, \
+                                          so it never runs",
+                                         cell_ptr + offset),
+                    });
+                    continue;
+                }
+                if bounds::net_movement(body) != Some(0) {
+                    break;
+                }
+                mark_changed(body, cell_ptr, &mut changed);
+            }
+            &Scan(_) => {
+                if !changed.contains(&cell_ptr) {
+                    notes.push(Warning {
+                        message: format!("loop tests cell #{} before anything changes it, \
+                                          so it never runs",
+                                         cell_ptr),
+                    });
+                    continue;
+                }
+                break;
+            }
+            &PointerIncrement(amount) => cell_ptr += amount,
+            other => mark_changed(&[other.clone()], cell_ptr, &mut changed),
+        }
+    }
+    notes
+}
+
+/// Add every cell that `instrs` may change to `changed`. Any loops in
+/// `instrs` must leave the cell pointer where it started.
+fn mark_changed(instrs: &[Instruction], cell_ptr: isize, changed: &mut HashSet<isize>) {
+    let mut cell_ptr = cell_ptr;
+    for instr in instrs {
+        match instr {
+            &SaturatingIncrement(_) => {
+                changed.insert(cell_ptr);
+            }
+            &Increment(_, offset) | &Set(_, offset) | &Read(offset) => {
+                changed.insert(cell_ptr + offset);
+            }
+            &MultiplyMove(ref changes) => {
+                changed.insert(cell_ptr);
+                for offset in changes.keys() {
+                    changed.insert(cell_ptr + offset);
+                }
+            }
+            &PointerIncrement(amount) => cell_ptr += amount,
+            &Loop(_, ref body) => mark_changed(body, cell_ptr, changed),
+            &Write(_) | &Scan(_) | &Debug => {}
+        }
+    }
+}
+
+/// Print `notes` to stderr. Unlike warnings, these never fail the
+/// build.
+pub fn report_notes(notes: &[Warning]) {
+    for note in notes {
+        let _ = writeln!(&mut io::stderr(), "note: {}", note.message);
+    }
+}
+
+/// Print all `warnings` to stderr. If `warnings_as_errors` is set,
+/// any warning is an error.
+pub fn report(warnings: &[Warning], warnings_as_errors: bool) -> Result<(), String> {
+    for warning in warnings {
+        let _ = writeln!(&mut io::stderr(), "warning: {}", warning.message);
+    }
+
+    if warnings_as_errors && !warnings.is_empty() {
+        return Err(format!("{} warning(s) treated as errors", warnings.len()));
+    }
+    Ok(())
+}
+
+#[test]
+fn no_warnings() {
+    let instrs = parse("+>-<.").unwrap();
+    assert_eq!(check(&instrs), vec![]);
+}
+
+#[test]
+fn warn_out_of_bounds_at_start() {
+    let instrs = parse("<+.").unwrap();
+    assert_eq!(check(&instrs).len(), 1);
+}
+
+#[test]
+fn warnings_only_fail_when_errors() {
+    let instrs = parse("<+.").unwrap();
+    let warnings = check(&instrs);
+
+    assert!(report(&warnings, false).is_ok());
+    assert!(report(&warnings, true).is_err());
+}
+
+#[test]
+fn no_warnings_never_fail() {
+    assert!(report(&[], true).is_ok());
+}
+
+#[test]
+fn note_write_of_unchanged_cell() {
+    // An off-by-one: we meant to write cell #0.
+    let instrs = parse("+++>.").unwrap();
+    let notes = unchanged_reads(&instrs);
+    assert_eq!(notes.len(), 1);
+    assert!(notes[0].message.contains("cell #1"));
+}
+
+#[test]
+fn note_loop_on_unchanged_cell() {
+    let instrs = parse("[comment.]+.").unwrap();
+    let notes = unchanged_reads(&instrs);
+    assert_eq!(notes.len(), 1);
+    assert!(notes[0].message.contains("loop tests cell #0"));
+}
+
+#[test]
+fn no_notes_after_changes() {
+    let instrs = parse(",.>+[->+<]>.").unwrap();
+    assert_eq!(unchanged_reads(&instrs), vec![]);
+}
+
+#[test]
+fn no_notes_after_unpredictable_loop() {
+    let instrs = parse("+[>]>.").unwrap();
+    assert_eq!(unchanged_reads(&instrs), vec![]);
+}
+
+#[cfg(test)]
+fn infinite_loops_in(source: &str) -> Vec<Warning> {
+    let (instrs, positions) = parse_with_positions(source, &ParseConfig::default()).unwrap();
+    infinite_loops(&instrs, &positions, source)
+}
+
+#[test]
+fn warn_empty_loop() {
+    let warnings = infinite_loops_in("++[]");
+    assert_eq!(warnings.len(), 1);
+    assert_eq!(warnings[0].message, "loop at line 1, column 3 never terminates once entered");
+}
+
+#[test]
+fn warn_loop_changing_other_cells() {
+    assert_eq!(infinite_loops_in("+[>+<.]").len(), 1);
+    assert_eq!(infinite_loops_in("+[>,>-<<]").len(), 1);
+
+    let warnings = infinite_loops_in("+[-]\n+[>+[]<-]");
+    assert_eq!(warnings.len(), 1);
+    assert!(warnings[0].message.starts_with("loop at line 2, column 5 "));
+}
+
+#[test]
+fn no_warning_for_loops_that_may_exit() {
+    // These change the tested cell.
+    assert_eq!(infinite_loops_in("+[-]"), vec![]);
+    assert_eq!(infinite_loops_in("+[,]"), vec![]);
+    assert_eq!(infinite_loops_in("+[>+<-]"), vec![]);
+    // These end on a different cell, so a zero may stop them.
+    assert_eq!(infinite_loops_in("+[>]"), vec![]);
+    assert_eq!(infinite_loops_in("+[>+]"), vec![]);
+    // This could change the tested cell in its inner loop.
+    assert_eq!(infinite_loops_in("+[>[<-]<]"), vec![]);
+}
@@ -1,19 +1,22 @@
 #![warn(trivial_numeric_casts)]
 
-#[cfg(test)]
 use std::collections::HashMap;
+use std::fmt;
 use std::num::Wrapping;
 
 #[cfg(test)]
 use bfir::parse;
 
-use bfir::{Instruction, Cell};
+use bfir::{Instruction, Cell, walk, saturating_add};
 use bfir::Instruction::*;
 
 #[cfg(test)]
 use bounds::MAX_CELL_INDEX;
 
 use bounds::highest_cell_index;
+use peephole::always_terminates;
+#[cfg(test)]
+use peephole::optimize;
 
 #[derive(Debug,Clone,PartialEq,Eq)]
 pub struct ExecutionState {
@@ -21,54 +24,432 @@ pub struct ExecutionState {
     pub cells: Vec<Cell>,
     pub cell_ptr: isize,
     pub outputs: Vec<i8>,
+    /// How many bytes of pre-supplied input we've read.
+    pub input_ptr: usize,
+}
+
+/// How speculative execution treats reads and writes.
+#[derive(Debug,Clone,Copy,PartialEq,Eq)]
+pub enum IoMode {
+    /// Stop at reads, and record writes as outputs.
+    Normal,
+    /// Every read produces zero. This is for analysis only: real
+    /// input will differ, so the resulting cells and outputs must
+    /// never be compiled into the program.
+    ZeroInput,
+    /// Stop at both reads and writes, so we never produce outputs.
+    NoOutput,
+}
+
+/// Speculative execution found a bug: the program tried to access a
+/// cell outside the tape.
+#[derive(Debug,Clone,PartialEq,Eq)]
+pub struct RuntimeError {
+    /// The top-level instruction we were executing. If the error
+    /// happened inside a loop, this is the index of the loop.
+    pub instr_ptr: usize,
+    /// The cell we tried to access.
+    pub cell_ptr: isize,
 }
 
-#[derive(Debug,PartialEq,Eq)]
-enum Outcome {
-    // Return the number of steps remaining at completion.
-    Completed(u64),
+/// Why speculative execution stopped.
+#[derive(Debug,Clone,PartialEq,Eq)]
+pub enum Outcome {
+    Completed,
     ReachedRuntimeValue,
-    RuntimeError,
+    RuntimeError(RuntimeError),
     OutOfSteps,
 }
 
+impl fmt::Display for Outcome {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let description = match *self {
+            Outcome::Completed => "completed",
+            Outcome::ReachedRuntimeValue => "reached runtime value",
+            Outcome::RuntimeError(_) => "runtime error",
+            Outcome::OutOfSteps => "out of steps",
+        };
+        write!(f, "{}", description)
+    }
+}
+
 // It takes around 1 million steps to finish executing bottles.bf at
 // compile time. This is intolerably slow for debug builds of bfc, but
 // instant on a release build.
 pub const MAX_STEPS: u64 = 10000000;
 
+/// The state before executing any instructions: all the cells these
+/// instructions can reach are zero.
+pub fn initial_state(instrs: &[Instruction]) -> ExecutionState {
+    initial_state_filled(instrs, Wrapping(0))
+}
+
+/// As `initial_state`, but every cell starts with `value`.
+pub fn initial_state_filled(instrs: &[Instruction], value: Cell) -> ExecutionState {
+    let cells = vec![value; highest_cell_index(instrs) + 1];
+    ExecutionState { instr_ptr: 0, cells: cells, cell_ptr: 0, outputs: vec![], input_ptr: 0 }
+}
+
 /// Compile time speculative execution of instructions. We return the
 /// final state of the cells, any print side effects, and the point in
 /// the code we reached.
 pub fn execute(instrs: &[Instruction], steps: u64) -> ExecutionState {
-    let cells = vec![Wrapping(0); highest_cell_index(instrs) + 1];
-    let state = ExecutionState { instr_ptr: 0, cells: cells, cell_ptr: 0, outputs: vec![] };
-    let (final_state, _) = execute_inner(instrs, state, steps);
-    final_state
+    execute_with_mode(instrs, steps, IoMode::Normal)
 }
 
-fn execute_inner(instrs: &[Instruction],
-                 state: ExecutionState,
-                 steps: u64)
-                 -> (ExecutionState, Outcome) {
-    let mut steps_left = steps;
+/// Speculative execution as with `execute`, but we stop at the first
+/// write, so the final state never has any outputs.
+pub fn execute_without_outputs(instrs: &[Instruction], steps: u64) -> ExecutionState {
+    execute_with_mode(instrs, steps, IoMode::NoOutput)
+}
+
+/// Speculative execution as with `execute`, but reads consume bytes
+/// from `input`. We only stop at a read once `input` is exhausted.
+///
+/// The compiled program must not read these bytes again, so this is
+/// only useful when the input is known ahead of time.
+pub fn execute_with_input(instrs: &[Instruction], steps: u64, input: &[u8]) -> ExecutionState {
+    execute_from(instrs, initial_state(instrs), steps, IoMode::Normal, input).0
+}
+
+fn execute_with_mode(instrs: &[Instruction], steps: u64, io_mode: IoMode) -> ExecutionState {
+    execute_with_outcome(instrs, initial_state(instrs), steps, io_mode).0
+}
+
+/// Speculatively execute with `io_mode`, starting from `state`. We
+/// return the final state, why we stopped, and how many steps we
+/// used.
+pub fn execute_with_outcome(instrs: &[Instruction],
+                            state: ExecutionState,
+                            steps: u64,
+                            io_mode: IoMode)
+                            -> (ExecutionState, Outcome, u64) {
+    execute_from(instrs, state, steps, io_mode, &[])
+}
+
+/// As `execute_with_outcome`, but reads consume `input` until it's
+/// exhausted.
+fn execute_from(instrs: &[Instruction],
+                state: ExecutionState,
+                steps: u64,
+                io_mode: IoMode,
+                input: &[u8])
+                -> (ExecutionState, Outcome, u64) {
     let mut state = state;
+    let mut undo_log = vec![];
+    let mut cache = LoopCache::new();
+    let mut steps_left = steps;
+    let outcome = execute_inner(instrs, &mut state, &mut steps_left, io_mode, input,
+                                &mut undo_log, false, &mut cache);
+    (state, outcome, steps - steps_left)
+}
+
+/// What we can prove about whether a program terminates.
+#[derive(Debug,Clone,Copy,PartialEq,Eq)]
+pub enum Halting {
+    Terminates,
+    MayNotTerminate,
+    /// The top-level loop at this index never exits.
+    LoopsForever(usize),
+}
+
+impl fmt::Display for Halting {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let description = match *self {
+            Halting::Terminates => "terminates",
+            Halting::MayNotTerminate => "may not terminate",
+            Halting::LoopsForever(_) => "provably loops forever",
+        };
+        write!(f, "{}", description)
+    }
+}
+
+/// Classify whether this program terminates, by speculatively
+/// executing it for up to `steps` steps and then looking at the
+/// loops we didn't execute.
+pub fn halting(instrs: &[Instruction], steps: u64) -> Halting {
+    let (state, outcome, _) = execute_with_outcome(instrs, initial_state(instrs), steps,
+                                                   IoMode::Normal);
+    match outcome {
+        Outcome::Completed => Halting::Terminates,
+        Outcome::OutOfSteps => {
+            // We always stop at the start of a top-level loop when
+            // we're out of steps. If we'll enter that loop, and it
+            // can't change its condition, it will run forever.
+            match instrs.get(state.instr_ptr) {
+                Some(instr @ &Loop(offset, _)) if never_exits(instr) => {
+                    let tested = state.cell_ptr.checked_add(offset)
+                        .and_then(|index| state.cells.get(index as usize));
+                    match tested {
+                        Some(&Wrapping(value)) if value != 0 => {
+                            Halting::LoopsForever(state.instr_ptr)
+                        }
+                        _ => Halting::MayNotTerminate,
+                    }
+                }
+                _ => Halting::MayNotTerminate,
+            }
+        }
+        Outcome::ReachedRuntimeValue => {
+            // Whatever the input, the rest of the program terminates
+            // if all its loops do.
+            let mut all_terminate = true;
+            walk(&instrs[state.instr_ptr..], &mut |instr| {
+                match instr {
+                    &Loop(_, _) if !always_terminates(instr) => all_terminate = false,
+                    &Scan(_) => all_terminate = false,
+                    _ => {}
+                }
+            });
+            if all_terminate {
+                Halting::Terminates
+            } else {
+                Halting::MayNotTerminate
+            }
+        }
+        Outcome::RuntimeError(_) => Halting::MayNotTerminate,
+    }
+}
+
+/// Is this a loop that never changes the cell it tests, and ends
+/// each iteration where it started? Once we've entered it, we can
+/// never leave.
+///
+/// We give up on bodies with loops or scans inside, so this has false
+/// negatives but no false positives.
+pub fn never_exits(instr: &Instruction) -> bool {
+    let (tested, body) = match instr {
+        &Loop(offset, ref body) => (offset, body),
+        _ => return false,
+    };
+    // Is the cell at `offset` from `cell_ptr` the one we test? If we
+    // can't tell, assume it is.
+    let is_tested = |cell_ptr: isize, offset: isize| {
+        cell_ptr.checked_add(offset).map_or(true, |cell| cell == tested)
+    };
+
+    let mut cell_ptr: isize = 0;
+    for body_instr in body {
+        match body_instr {
+            &PointerIncrement(amount) => {
+                cell_ptr = match cell_ptr.checked_add(amount) {
+                    Some(cell_ptr) => cell_ptr,
+                    None => return false,
+                };
+            }
+            &SaturatingIncrement(_) if !is_tested(cell_ptr, 0) => {}
+            &Increment(_, offset) | &Set(_, offset) if !is_tested(cell_ptr, offset) => {}
+            &Increment(Wrapping(0), _) => {}
+            &Read(offset) if !is_tested(cell_ptr, offset) => {}
+            &MultiplyMove(ref changes) if !is_tested(cell_ptr, 0)
+                && changes.keys().all(|&offset| !is_tested(cell_ptr, offset)) => {}
+            &Write(_) | &Debug => {}
+            _ => return false,
+        }
+    }
+    cell_ptr == 0
+}
+
+/// Set the value of a cell. Inside a loop body, we record the old
+/// value, so we can undo the iteration if we can't finish it.
+fn set_cell(state: &mut ExecutionState,
+            index: usize,
+            value: Cell,
+            undo_log: &mut Vec<(usize, Cell)>,
+            in_loop: bool) {
+    if in_loop {
+        undo_log.push((index, state.cells[index]));
+    }
+    state.cells[index] = value;
+}
+
+/// The overall effect of running a self-contained loop until it
+/// exits. A loop is self-contained if it only contains increments and
+/// pointer increments, and returns to the cell it started at.
+#[derive(Debug,Clone)]
+struct LoopEffect {
+    iterations: u64,
+    /// How much each cell changes, by offset from the loop's cell.
+    changes: Vec<(isize, Cell)>,
+    /// The lowest and highest offsets the cell pointer reaches or the
+    /// loop increments.
+    min_offset: isize,
+    max_offset: isize,
+}
+
+/// Self-contained loops we've already evaluated, keyed by the loop
+/// body and the value of the cell it tests. A loop's effect doesn't
+/// depend on any other cells, so entries never go stale.
+struct LoopCache<'a> {
+    effects: HashMap<(&'a [Instruction], i8), Option<LoopEffect>>,
+    enabled: bool,
+}
+
+impl<'a> LoopCache<'a> {
+    fn new() -> Self {
+        LoopCache { effects: HashMap::new(), enabled: true }
+    }
+}
+
+/// Work out the effect of running the loop `body` when the current
+/// cell starts at `cell_value`. Returns None if the loop isn't
+/// self-contained or never exits.
+fn loop_effect(body: &[Instruction], cell_value: Cell) -> Option<LoopEffect> {
+    let mut changes: HashMap<isize, Cell> = HashMap::new();
+    let mut offset: isize = 0;
+    let mut min_offset = 0;
+    let mut max_offset = 0;
+    for instr in body {
+        match instr {
+            &Increment(amount, inc_offset) => {
+                let cell = match offset.checked_add(inc_offset) {
+                    Some(cell) => cell,
+                    None => return None,
+                };
+                let current_amount = *changes.get(&cell).unwrap_or(&Wrapping(0));
+                changes.insert(cell, current_amount + amount);
+                min_offset = ::std::cmp::min(min_offset, cell);
+                max_offset = ::std::cmp::max(max_offset, cell);
+            }
+            &PointerIncrement(amount) => {
+                offset = match offset.checked_add(amount) {
+                    Some(offset) => offset,
+                    None => return None,
+                };
+                min_offset = ::std::cmp::min(min_offset, offset);
+                max_offset = ::std::cmp::max(max_offset, offset);
+            }
+            _ => return None,
+        }
+    }
+    if offset != 0 {
+        return None;
+    }
+
+    // The cell value repeats after at most 256 iterations, so if it
+    // hasn't reached zero by then, it never will.
+    let step = *changes.get(&0).unwrap_or(&Wrapping(0));
+    let mut value = cell_value;
+    let mut iterations: u64 = 0;
+    while value.0 != 0 {
+        if iterations == 256 {
+            return None;
+        }
+        value = value + step;
+        iterations += 1;
+    }
+
+    let factor = Wrapping(iterations as u8 as i8);
+    Some(LoopEffect {
+        iterations: iterations,
+        changes: changes.into_iter().map(|(offset, amount)| (offset, amount * factor)).collect(),
+        min_offset: min_offset,
+        max_offset: max_offset,
+    })
+}
+
+/// If `body` is a self-contained loop, apply all its iterations at
+/// once, using `cache` to avoid evaluating identical loops again. We
+/// return the steps left, or None if the loop must be executed
+/// normally (e.g. it goes out of bounds or runs out of steps).
+fn execute_cached_loop<'a>(condition_offset: isize,
+                           body: &'a [Instruction],
+                           state: &mut ExecutionState,
+                           steps_left: u64,
+                           undo_log: &mut Vec<(usize, Cell)>,
+                           in_loop: bool,
+                           cache: &mut LoopCache<'a>)
+                           -> Option<u64> {
+    // The loop effects we compute assume the loop tests the current
+    // cell.
+    if !cache.enabled || condition_offset != 0 {
+        return None;
+    }
+
+    let cell_value = state.cells[state.cell_ptr as usize];
+    let effect = match cache.effects
+                            .entry((body, cell_value.0))
+                            .or_insert_with(|| loop_effect(body, cell_value))
+                            .clone() {
+        Some(effect) => effect,
+        None => return None,
+    };
+
+    if state.cell_ptr.saturating_add(effect.min_offset) < 0 ||
+       state.cell_ptr.saturating_add(effect.max_offset) >= state.cells.len() as isize {
+        return None;
+    }
 
-    while state.instr_ptr < instrs.len() && steps_left > 0 {
+    // Every iteration runs the body, then the loop instruction.
+    let steps = effect.iterations * (body.len() as u64 + 1);
+    if steps >= steps_left {
+        return None;
+    }
+
+    for &(offset, amount) in &effect.changes {
+        let index = (state.cell_ptr + offset) as usize;
+        let new_value = state.cells[index] + amount;
+        set_cell(state, index, new_value, undo_log, in_loop);
+    }
+    Some(steps_left - steps)
+}
+
+fn runtime_error(state: &ExecutionState, cell_ptr: isize) -> Outcome {
+    Outcome::RuntimeError(RuntimeError { instr_ptr: state.instr_ptr, cell_ptr: cell_ptr })
+}
+
+/// Execute `instrs`, updating `state` and `steps_left` in place.
+///
+/// If we can't finish an iteration of a top-level loop, we undo its
+/// effects, so `state` is left at the start of that loop. Nested loops
+/// don't need to do anything: the enclosing top-level loop undoes
+/// them too. This saves us copying all the cells for every loop
+/// iteration.
+///
+/// Self-contained loops are evaluated in one go, and remembered in
+/// `cache`, so programs with many identical loops are fast.
+fn execute_inner<'a>(instrs: &'a [Instruction],
+                     state: &mut ExecutionState,
+                     steps_left: &mut u64,
+                     io_mode: IoMode,
+                     input: &[u8],
+                     undo_log: &mut Vec<(usize, Cell)>,
+                     in_loop: bool,
+                     cache: &mut LoopCache<'a>)
+                     -> Outcome {
+    while state.instr_ptr < instrs.len() && *steps_left > 0 {
         let cell_ptr = state.cell_ptr as usize;
         match &instrs[state.instr_ptr] {
-            &Increment(amount) => {
-                state.cells[cell_ptr] = state.cells[cell_ptr] + amount;
+            &Increment(amount, offset) => {
+                let target_ptr = state.cell_ptr.saturating_add(offset);
+                if target_ptr < 0 || target_ptr >= state.cells.len() as isize {
+                    return runtime_error(state, target_ptr);
+                }
+
+                let new_value = state.cells[target_ptr as usize] + amount;
+                set_cell(state, target_ptr as usize, new_value, undo_log, in_loop);
                 state.instr_ptr += 1;
             }
-            &Set(amount) => {
-                state.cells[cell_ptr] = amount;
+            &SaturatingIncrement(amount) => {
+                let new_value = saturating_add(state.cells[cell_ptr], amount);
+                set_cell(state, cell_ptr, new_value, undo_log, in_loop);
+                state.instr_ptr += 1;
+            }
+            &Set(amount, offset) => {
+                let target_ptr = state.cell_ptr.saturating_add(offset);
+                if target_ptr < 0 || target_ptr >= state.cells.len() as isize {
+                    return runtime_error(state, target_ptr);
+                }
+
+                set_cell(state, target_ptr as usize, amount, undo_log, in_loop);
                 state.instr_ptr += 1;
             }
             &PointerIncrement(amount) => {
-                let new_cell_ptr = state.cell_ptr + amount;
+                // Saturate rather than overflow, so huge moves are
+                // still out of bounds.
+                let new_cell_ptr = state.cell_ptr.saturating_add(amount);
                 if new_cell_ptr < 0 || new_cell_ptr >= state.cells.len() as isize {
-                    return (state, Outcome::RuntimeError);
+                    return runtime_error(state, new_cell_ptr);
                 } else {
                     state.cell_ptr = new_cell_ptr;
                     state.instr_ptr += 1;
@@ -79,64 +460,207 @@ fn execute_inner(instrs: &[Instruction],
                 let cell_value = state.cells[cell_ptr];
 
                 for (cell_offset, factor) in changes.iter() {
-                    let dest_ptr = cell_ptr as isize + *cell_offset;
+                    let dest_ptr = (cell_ptr as isize).saturating_add(*cell_offset);
                     if dest_ptr < 0 {
                         // Tried to access a cell before cell #0.
-                        return (state, Outcome::RuntimeError);
+                        return runtime_error(state, dest_ptr);
                     }
                     if dest_ptr as usize >= state.cells.len() {
-                        return (state, Outcome::RuntimeError);
+                        return runtime_error(state, dest_ptr);
                     }
 
                     let current_val = state.cells[dest_ptr as usize];
-                    state.cells[dest_ptr as usize] = current_val + cell_value * (*factor);
+                    set_cell(state, dest_ptr as usize, current_val + cell_value * (*factor),
+                             undo_log, in_loop);
                 }
 
                 // Finally, zero the cell we used.
-                state.cells[cell_ptr] = Wrapping(0);
+                set_cell(state, cell_ptr, Wrapping(0), undo_log, in_loop);
 
                 state.instr_ptr += 1;
             }
-            &Write => {
-                let cell_value = state.cells[state.cell_ptr as usize];
+            &Scan(stride) => {
+                // Each cell we move past counts as a step, just like
+                // an iteration of the loop we replaced.
+                let mut new_cell_ptr = state.cell_ptr;
+                let mut steps = 0;
+                while state.cells[new_cell_ptr as usize].0 != 0 {
+                    new_cell_ptr = new_cell_ptr.saturating_add(stride);
+                    if new_cell_ptr < 0 || new_cell_ptr >= state.cells.len() as isize {
+                        return runtime_error(state, new_cell_ptr);
+                    }
+                    steps += 1;
+                    if steps >= *steps_left {
+                        return Outcome::OutOfSteps;
+                    }
+                }
+                *steps_left -= steps;
+                state.cell_ptr = new_cell_ptr;
+                state.instr_ptr += 1;
+            }
+            &Debug => {
+                // We can't print at compile time, so we treat `#` as
+                // a comment.
+                state.instr_ptr += 1;
+            }
+            &Write(offset) => {
+                if io_mode == IoMode::NoOutput {
+                    return Outcome::ReachedRuntimeValue;
+                }
+
+                let target_ptr = state.cell_ptr.saturating_add(offset);
+                if target_ptr < 0 || target_ptr >= state.cells.len() as isize {
+                    return runtime_error(state, target_ptr);
+                }
+
+                let cell_value = state.cells[target_ptr as usize];
                 state.outputs.push(cell_value.0);
                 state.instr_ptr += 1;
             }
-            &Read => {
-                return (state, Outcome::ReachedRuntimeValue);
+            &Read(offset) => {
+                let have_input = state.input_ptr < input.len();
+                if io_mode != IoMode::ZeroInput && !have_input {
+                    return Outcome::ReachedRuntimeValue;
+                }
+
+                let target_ptr = state.cell_ptr.saturating_add(offset);
+                if target_ptr < 0 || target_ptr >= state.cells.len() as isize {
+                    return runtime_error(state, target_ptr);
+                }
+
+                let value = if have_input {
+                    state.input_ptr += 1;
+                    Wrapping(input[state.input_ptr - 1] as i8)
+                } else {
+                    Wrapping(0)
+                };
+                set_cell(state, target_ptr as usize, value, undo_log, in_loop);
+                state.instr_ptr += 1;
             }
-            &Loop(ref body) => {
-                if state.cells[state.cell_ptr as usize].0 == 0 {
-                    // Step over the loop because the current cell is
+            &Loop(offset, ref body) => {
+                let test_ptr = state.cell_ptr.saturating_add(offset);
+                if test_ptr < 0 || test_ptr >= state.cells.len() as isize {
+                    return runtime_error(state, test_ptr);
+                }
+
+                if state.cells[test_ptr as usize].0 == 0 {
+                    // Step over the loop because the cell it tests is
                     // zero.
                     state.instr_ptr += 1;
+                } else if let Some(remaining_steps) = execute_cached_loop(
+                        offset, body, state, *steps_left, undo_log, in_loop, cache) {
+                    // We've run every iteration. Leave the instruction
+                    // pointer at the loop, so we step over it next, as
+                    // if we'd executed each iteration. The final
+                    // iteration's loop step is counted below.
+                    *steps_left = remaining_steps + 1;
                 } else {
-                    // Execute the loop body.
-                    let loop_body_state = ExecutionState { instr_ptr: 0, .. state.clone() };
-                    let (state_after, loop_outcome) = execute_inner(body, loop_body_state, steps_left);
-                    if let &Outcome::Completed(remaining_steps) = &loop_outcome {
-                        // We finished executing a loop iteration, so store its side effects.
-                        state.cells = state_after.cells;
-                        state.outputs = state_after.outputs;
-                        state.cell_ptr = state_after.cell_ptr;
-                        // We've run several steps during the loop body, so update for that too.
-                        steps_left = remaining_steps;
-                    } else {
-                        // We couldn't evaluate the loop body.
-                        return (state, loop_outcome);
+                    // Execute the loop body, remembering enough to
+                    // undo this iteration.
+                    let loop_instr_ptr = state.instr_ptr;
+                    let loop_cell_ptr = state.cell_ptr;
+                    let loop_outputs_len = state.outputs.len();
+                    let loop_input_ptr = state.input_ptr;
+                    if !in_loop {
+                        undo_log.clear();
+                    }
+
+                    state.instr_ptr = 0;
+                    let loop_outcome = execute_inner(body, state, steps_left, io_mode, input,
+                                                     undo_log, true, cache);
+                    state.instr_ptr = loop_instr_ptr;
+
+                    match loop_outcome {
+                        // The loop body has already counted its steps.
+                        Outcome::Completed => {}
+                        mut loop_outcome => {
+                            // We couldn't evaluate the loop body, so undo
+                            // this iteration.
+                            if let Outcome::RuntimeError(ref mut error) = loop_outcome {
+                                error.instr_ptr = loop_instr_ptr;
+                            }
+                            if !in_loop {
+                                while let Some((index, value)) = undo_log.pop() {
+                                    state.cells[index] = value;
+                                }
+                                state.cell_ptr = loop_cell_ptr;
+                                state.outputs.truncate(loop_outputs_len);
+                                state.input_ptr = loop_input_ptr;
+                            }
+                            return loop_outcome;
+                        }
                     }
                 }
             }
         }
 
-        steps_left -= 1;
+        *steps_left -= 1;
     }
 
-    if steps_left == 0 {
-        (state, Outcome::OutOfSteps)
+    if *steps_left == 0 {
+        Outcome::OutOfSteps
     } else {
-        (state, Outcome::Completed(steps_left))
+        Outcome::Completed
+    }
+}
+
+#[test]
+fn halting_terminates() {
+    let instrs = parse("+.").unwrap();
+    assert_eq!(halting(&instrs, MAX_STEPS), Halting::Terminates);
+}
+
+#[test]
+fn halting_terminates_with_input() {
+    let instrs = parse(",.[---]").unwrap();
+    assert_eq!(halting(&instrs, MAX_STEPS), Halting::Terminates);
+}
+
+#[test]
+fn halting_loops_forever() {
+    let instrs = parse("+[]").unwrap();
+    assert_eq!(halting(&instrs, 1000), Halting::LoopsForever(1));
+
+    let instrs = parse("+[>+<]").unwrap();
+    assert_eq!(halting(&instrs, 1000), Halting::LoopsForever(1));
+}
+
+#[test]
+fn halting_out_of_steps_before_skipped_loop() {
+    // We run out of steps before the loop, but its cell is zero, so
+    // we'd skip it.
+    let instrs = parse("+>[]").unwrap();
+    assert_eq!(halting(&instrs, 2), Halting::MayNotTerminate);
+
+    let instrs = vec![Increment(Wrapping(1), 0), Loop(1, vec![])];
+    assert_eq!(halting(&instrs, 1), Halting::MayNotTerminate);
+}
+
+#[test]
+fn never_exits_loops() {
+    for source in &["[]", "[>+<.]", "[>,>-<<]", "[>[-]<]"] {
+        let instrs = parse(source).unwrap();
+        assert!(never_exits(&instrs[0]) == (*source != "[>[-]<]"), "{}", source);
     }
+    // A loop testing another cell.
+    assert!(never_exits(&Loop(1, vec![Increment(Wrapping(1), 0)])));
+    assert!(!never_exits(&Loop(1, vec![PointerIncrement(1), Read(0), PointerIncrement(-1)])));
+    let moves_into = |offset| {
+        Loop(0, vec![PointerIncrement(1),
+                     MultiplyMove(vec![(offset, Wrapping(1))].into_iter().collect()),
+                     PointerIncrement(-1)])
+    };
+    assert!(never_exits(&moves_into(1)));
+    assert!(!never_exits(&moves_into(-1)));
+    // These end on a different cell, or change the tested one.
+    assert!(!never_exits(&parse("[>]").unwrap()[0]));
+    assert!(!never_exits(&parse("[-]").unwrap()[0]));
+}
+
+#[test]
+fn halting_may_not_terminate() {
+    let instrs = parse(",[.]").unwrap();
+    assert_eq!(halting(&instrs, MAX_STEPS), Halting::MayNotTerminate);
 }
 
 /// We can't evaluate outputs of runtime values at compile time.
@@ -148,6 +672,83 @@ fn cant_evaluate_inputs() {
     assert_eq!(
         final_state, ExecutionState {
             instr_ptr: 0, cells: vec![Wrapping(0)], cell_ptr: 0, outputs: vec![],
+            input_ptr: 0,
+        });
+}
+
+#[test]
+fn supplied_input_partially_consumed() {
+    let instrs = parse(",>,>,.").unwrap();
+    let final_state = execute_with_input(&instrs, MAX_STEPS, &[1, 2]);
+
+    // We stop at the third read.
+    assert_eq!(
+        final_state, ExecutionState {
+            instr_ptr: 4, cells: vec![Wrapping(1), Wrapping(2), Wrapping(0)], cell_ptr: 2,
+            outputs: vec![], input_ptr: 2,
+        });
+}
+
+#[test]
+fn supplied_input_exhausted_mid_loop() {
+    let instrs = parse("+[,.,.]").unwrap();
+    let final_state = execute_with_input(&instrs, MAX_STEPS, &[1, 2, 3]);
+
+    // The second iteration runs out of input, so we undo it,
+    // including the byte it read.
+    assert_eq!(
+        final_state, ExecutionState {
+            instr_ptr: 1, cells: vec![Wrapping(2)], cell_ptr: 0, outputs: vec![1, 2],
+            input_ptr: 2,
+        });
+}
+
+#[test]
+fn supplied_input_completes() {
+    let instrs = parse(",[.,]").unwrap();
+    let final_state = execute_with_input(&instrs, MAX_STEPS, &[7, 8, 0]);
+    assert_eq!(final_state.outputs, vec![7, 8]);
+    assert_eq!(final_state.instr_ptr, 2);
+}
+
+#[test]
+fn zero_input_reads_zero() {
+    let instrs = parse("+,+.").unwrap();
+
+    // Normally we stop at the read.
+    let final_state = execute(&instrs, MAX_STEPS);
+    assert_eq!(final_state.instr_ptr, 1);
+
+    let (final_state, _, _) = execute_with_outcome(&instrs, initial_state(&instrs), MAX_STEPS,
+                                                   IoMode::ZeroInput);
+    assert_eq!(
+        final_state, ExecutionState {
+            instr_ptr: 4, cells: vec![Wrapping(1)], cell_ptr: 0, outputs: vec![1],
+            input_ptr: 0,
+        });
+}
+
+#[test]
+fn without_outputs_stops_at_write() {
+    let instrs = parse("+.+").unwrap();
+    let final_state = execute_without_outputs(&instrs, MAX_STEPS);
+
+    assert_eq!(
+        final_state, ExecutionState {
+            instr_ptr: 1, cells: vec![Wrapping(1)], cell_ptr: 0, outputs: vec![],
+            input_ptr: 0,
+        });
+}
+
+#[test]
+fn without_outputs_skips_loops_with_writes() {
+    let instrs = parse("+[.-]").unwrap();
+    let final_state = execute_without_outputs(&instrs, MAX_STEPS);
+
+    assert_eq!(
+        final_state, ExecutionState {
+            instr_ptr: 1, cells: vec![Wrapping(1)], cell_ptr: 0, outputs: vec![],
+            input_ptr: 0,
         });
 }
 
@@ -159,6 +760,7 @@ fn increment_executed() {
     assert_eq!(
         final_state, ExecutionState {
             instr_ptr: 1, cells: vec![Wrapping(1)], cell_ptr: 0, outputs: vec![],
+            input_ptr: 0,
         });
 }
 
@@ -169,9 +771,9 @@ fn multiply_move_executed() {
     changes.insert(3, Wrapping(3));
     let instrs = vec![
         // Initial cells: [2, 1, 0, 0]
-        Increment(Wrapping(2)),
+        Increment(Wrapping(2), 0),
         PointerIncrement(1),
-        Increment(Wrapping(1)),
+        Increment(Wrapping(1), 0),
         PointerIncrement(-1),
 
         MultiplyMove(changes)];
@@ -180,6 +782,7 @@ fn multiply_move_executed() {
     assert_eq!(
         final_state, ExecutionState {
             instr_ptr: 5, cells: vec![Wrapping(0), Wrapping(5), Wrapping(0), Wrapping(6)], cell_ptr: 0, outputs: vec![],
+            input_ptr: 0,
         });
 }
 
@@ -188,7 +791,7 @@ fn multiply_move_wrapping() {
     let mut changes = HashMap::new();
     changes.insert(1, Wrapping(3));
     let instrs = vec![
-        Increment(Wrapping(100)),
+        Increment(Wrapping(100), 0),
         MultiplyMove(changes)];
 
     let final_state = execute(&instrs, MAX_STEPS);
@@ -196,9 +799,38 @@ fn multiply_move_wrapping() {
         final_state, ExecutionState {
             // 100 * 3 mod 256 == 44
             instr_ptr: 2, cells: vec![Wrapping(0), Wrapping(44)], cell_ptr: 0, outputs: vec![],
+            input_ptr: 0,
         });
 }
 
+#[test]
+fn multiply_move_from_optimizer() {
+    let instrs = optimize(parse("+++[->++<]>.").unwrap());
+
+    let mut found_multiply = false;
+    walk(&instrs, &mut |instr| {
+        if let &MultiplyMove(_) = instr {
+            found_multiply = true;
+        }
+    });
+    assert!(found_multiply);
+
+    let final_state = execute(&instrs, MAX_STEPS);
+    assert_eq!(final_state.outputs, vec![6]);
+    assert_eq!(final_state.instr_ptr, instrs.len());
+}
+
+#[test]
+fn multiply_move_from_optimizer_out_of_bounds() {
+    let instrs = optimize(parse("+[-<+>].").unwrap());
+    let (_, outcome, _) = execute_with_outcome(&instrs, initial_state(&instrs), MAX_STEPS,
+                                               IoMode::Normal);
+    match outcome {
+        Outcome::RuntimeError(error) => assert_eq!(error.cell_ptr, -1),
+        outcome => panic!("Expected a runtime error, got {:?}", outcome),
+    }
+}
+
 #[test]
 fn multiply_move_offset_too_high() {
     let mut changes: HashMap<isize,Cell> = HashMap::new();
@@ -210,6 +842,7 @@ fn multiply_move_offset_too_high() {
         final_state, ExecutionState {
             instr_ptr: 0, cells: vec![Wrapping(0); MAX_CELL_INDEX + 1],
             cell_ptr: 0, outputs: vec![],
+            input_ptr: 0,
         });
 }
 
@@ -224,28 +857,31 @@ fn multiply_move_offset_too_low() {
         final_state, ExecutionState {
             instr_ptr: 0, cells: vec![Wrapping(0)],
             cell_ptr: 0, outputs: vec![],
+            input_ptr: 0,
         });
 }
 
 #[test]
 fn set_executed() {
-    let instrs = vec![Set(Wrapping(2))];
+    let instrs = vec![Set(Wrapping(2), 0)];
     let final_state = execute(&instrs, MAX_STEPS);
 
     assert_eq!(
         final_state, ExecutionState {
             instr_ptr: 1, cells: vec![Wrapping(2)], cell_ptr: 0, outputs: vec![],
+            input_ptr: 0,
         });
 }
 
 #[test]
 fn set_wraps() {
-    let instrs = vec![Set(Wrapping(-1))];
+    let instrs = vec![Set(Wrapping(-1), 0)];
     let final_state = execute(&instrs, MAX_STEPS);
 
     assert_eq!(
         final_state, ExecutionState {
             instr_ptr: 1, cells: vec![Wrapping(-1)], cell_ptr: 0, outputs: vec![],
+            input_ptr: 0,
         });
 }
 
@@ -257,17 +893,31 @@ fn decrement_executed() {
     assert_eq!(
         final_state, ExecutionState {
             instr_ptr: 1, cells: vec![Wrapping(-1)], cell_ptr: 0, outputs: vec![],
+            input_ptr: 0,
         });
 }
 
 #[test]
 fn increment_wraps() {
-    let instrs = vec![Increment(Wrapping(-1)), Increment(Wrapping(1))];
+    let instrs = vec![Increment(Wrapping(-1), 0), Increment(Wrapping(1), 0)];
     let final_state = execute(&instrs, MAX_STEPS);
 
     assert_eq!(
         final_state, ExecutionState {
             instr_ptr: 2, cells: vec![Wrapping(0)], cell_ptr: 0, outputs: vec![],
+            input_ptr: 0,
+        });
+}
+
+#[test]
+fn saturating_increment_executed() {
+    let instrs = parse("{wrap:saturate}-{wrap:default}>-").unwrap();
+    let final_state = execute(&instrs, MAX_STEPS);
+
+    assert_eq!(
+        final_state, ExecutionState {
+            instr_ptr: 3, cells: vec![Wrapping(0), Wrapping(-1)], cell_ptr: 1, outputs: vec![],
+            input_ptr: 0,
         });
 }
 
@@ -279,11 +929,13 @@ fn ptr_increment_executed() {
     assert_eq!(
         final_state, ExecutionState {
             instr_ptr: 1, cells: vec![Wrapping(0), Wrapping(0)], cell_ptr: 1, outputs: vec![],
+            input_ptr: 0,
         });
 }
 
-// TODO: it would be nice to emit a warning in this case, as it's
-// clearly a user error.
+// We stop at the instruction that left the tape, so the compiled
+// program fails there too. bfc also warns about this, using the
+// outcome from `execute_with_outcome`.
 #[test]
 fn ptr_out_of_range() {
     let instrs = parse("<").unwrap();
@@ -292,17 +944,37 @@ fn ptr_out_of_range() {
     assert_eq!(
         final_state, ExecutionState {
             instr_ptr: 0, cells: vec![Wrapping(0)], cell_ptr: 0, outputs: vec![],
+            input_ptr: 0,
         });
 }
 
+#[test]
+fn ptr_out_of_range_error() {
+    let instrs = parse("+<").unwrap();
+    let (_, outcome, _) = execute_with_outcome(&instrs, initial_state(&instrs), MAX_STEPS,
+                                               IoMode::Normal);
+    assert_eq!(outcome, Outcome::RuntimeError(RuntimeError { instr_ptr: 1, cell_ptr: -1 }));
+}
+
+#[test]
+fn ptr_out_of_range_error_in_loop() {
+    // We report the top-level loop, not the instruction in its body.
+    let instrs = parse(">+[[<<]]").unwrap();
+    let (state, outcome, _) = execute_with_outcome(&instrs, initial_state(&instrs), MAX_STEPS,
+                                                   IoMode::Normal);
+    assert_eq!(state.instr_ptr, 2);
+    assert_eq!(outcome, Outcome::RuntimeError(RuntimeError { instr_ptr: 2, cell_ptr: -1 }));
+}
+
 #[test]
 fn limit_to_steps_specified() {
-    let instrs = parse("++++").unwrap();
+    let instrs = vec![Increment(Wrapping(1), 0); 4];
     let final_state = execute(&instrs, 2);
 
     assert_eq!(
         final_state, ExecutionState {
             instr_ptr: 2, cells: vec![Wrapping(2)], cell_ptr: 0, outputs: vec![],
+            input_ptr: 0,
         });
 }
 
@@ -314,6 +986,56 @@ fn write_executed() {
     assert_eq!(
         final_state, ExecutionState {
             instr_ptr: 2, cells: vec![Wrapping(1)], cell_ptr: 0, outputs: vec![1],
+            input_ptr: 0,
+        });
+}
+
+#[test]
+fn write_at_offset_executed() {
+    let instrs = vec![PointerIncrement(1), Increment(Wrapping(3), 0), PointerIncrement(-1),
+                      Write(1)];
+    let final_state = execute(&instrs, MAX_STEPS);
+
+    assert_eq!(
+        final_state, ExecutionState {
+            instr_ptr: 4, cells: vec![Wrapping(0), Wrapping(3)], cell_ptr: 0, outputs: vec![3],
+            input_ptr: 0,
+        });
+}
+
+#[test]
+fn increment_and_set_at_offset_executed() {
+    let instrs = vec![Increment(Wrapping(3), 1), Set(Wrapping(5), 2), Increment(Wrapping(1), 2)];
+    let final_state = execute(&instrs, MAX_STEPS);
+
+    assert_eq!(
+        final_state, ExecutionState {
+            instr_ptr: 3, cells: vec![Wrapping(0), Wrapping(3), Wrapping(6)], cell_ptr: 0,
+            outputs: vec![], input_ptr: 0,
+        });
+}
+
+#[test]
+fn set_at_offset_out_of_range() {
+    let instrs = vec![Set(Wrapping(1), -1)];
+    let final_state = execute(&instrs, MAX_STEPS);
+
+    assert_eq!(
+        final_state, ExecutionState {
+            instr_ptr: 0, cells: vec![Wrapping(0)], cell_ptr: 0, outputs: vec![],
+            input_ptr: 0,
+        });
+}
+
+#[test]
+fn write_at_offset_out_of_range() {
+    let instrs = vec![Write(-1)];
+    let final_state = execute(&instrs, MAX_STEPS);
+
+    assert_eq!(
+        final_state, ExecutionState {
+            instr_ptr: 0, cells: vec![Wrapping(0)], cell_ptr: 0, outputs: vec![],
+            input_ptr: 0,
         });
 }
 
@@ -324,13 +1046,15 @@ fn loop_executed() {
 
     assert_eq!(
         final_state, ExecutionState {
-            instr_ptr: 3, cells: vec![Wrapping(0)], cell_ptr: 0, outputs: vec![],
+            instr_ptr: 2, cells: vec![Wrapping(0)], cell_ptr: 0, outputs: vec![],
+            input_ptr: 0,
         });
 }
 
 #[test]
 fn loop_up_to_step_limit() {
-    let instrs = parse("++[-]").unwrap();
+    let instrs = vec![Increment(Wrapping(1), 0), Increment(Wrapping(1), 0),
+                      Loop(0, vec![Increment(Wrapping(-1), 0)])];
     // Assuming we take one step to enter the loop, we will execute
     // the loop body once.
     let final_state = execute(&instrs, 4);
@@ -338,9 +1062,44 @@ fn loop_up_to_step_limit() {
     assert_eq!(
         final_state, ExecutionState {
             instr_ptr: 2, cells: vec![Wrapping(1)], cell_ptr: 0, outputs: vec![],
+            input_ptr: 0,
         });
 }
 
+#[test]
+fn scan_executed() {
+    // Equivalent to "+>+>>+<<<[>]".
+    let instrs = vec![Increment(Wrapping(1), 0), PointerIncrement(1), Increment(Wrapping(1), 0),
+                      PointerIncrement(2), Increment(Wrapping(1), 0), PointerIncrement(-3),
+                      Scan(1)];
+    let final_state = execute(&instrs, MAX_STEPS);
+
+    assert_eq!(final_state.instr_ptr, 7);
+    assert_eq!(final_state.cell_ptr, 2);
+}
+
+#[test]
+fn scan_with_stride_executed() {
+    // Equivalent to "+>>+>>>>+<<<<<<[>>]".
+    let instrs = vec![Increment(Wrapping(1), 0), PointerIncrement(2), Increment(Wrapping(1), 0),
+                      PointerIncrement(4), Increment(Wrapping(1), 0), PointerIncrement(-6),
+                      Scan(2)];
+    let final_state = execute(&instrs, MAX_STEPS);
+
+    assert_eq!(final_state.instr_ptr, 7);
+    assert_eq!(final_state.cell_ptr, 4);
+}
+
+#[test]
+fn scan_out_of_range() {
+    let instrs = vec![PointerIncrement(1), Increment(Wrapping(1), 0), Scan(-2)];
+    let (state, outcome, _) = execute_with_outcome(&instrs, initial_state(&instrs), MAX_STEPS,
+                                                   IoMode::Normal);
+    assert_eq!(state.instr_ptr, 2);
+    assert_eq!(state.cell_ptr, 1);
+    assert_eq!(outcome, Outcome::RuntimeError(RuntimeError { instr_ptr: 2, cell_ptr: -1 }));
+}
+
 #[test]
 fn loop_with_read_body() {
     // We should return the state before the loop is executed, since
@@ -351,9 +1110,64 @@ fn loop_with_read_body() {
     assert_eq!(
         final_state, ExecutionState {
             instr_ptr: 1, cells: vec![Wrapping(1)], cell_ptr: 0, outputs: vec![],
+            input_ptr: 0,
+        });
+}
+
+#[test]
+fn loop_iteration_undone() {
+    // We should undo the changes to cells, the cell pointer and
+    // outputs from the loop iteration we couldn't finish.
+    let instrs = parse("++[-.>+<[,]]").unwrap();
+    let final_state = execute(&instrs, MAX_STEPS);
+
+    assert_eq!(
+        final_state, ExecutionState {
+            instr_ptr: 1, cells: vec![Wrapping(2), Wrapping(0)], cell_ptr: 0, outputs: vec![],
+            input_ptr: 0,
         });
 }
 
+#[test]
+fn loop_iteration_undone_keeps_earlier_iterations() {
+    // The first iteration completes, but the second iteration reads.
+    let instrs = parse("++[.>[,]+<-]").unwrap();
+    let final_state = execute(&instrs, MAX_STEPS);
+
+    assert_eq!(
+        final_state, ExecutionState {
+            instr_ptr: 1, cells: vec![Wrapping(1), Wrapping(1)], cell_ptr: 0, outputs: vec![2],
+            input_ptr: 0,
+        });
+}
+
+#[test]
+fn nested_loop_iteration_undone() {
+    let instrs = parse("+[->+[-,]]").unwrap();
+    let final_state = execute(&instrs, MAX_STEPS);
+
+    // The outer loop moves right each iteration, so we can't bound
+    // the tape, and only check the cells it reached.
+    assert_eq!(final_state.instr_ptr, 1);
+    assert_eq!(final_state.cells[..2].to_vec(), vec![Wrapping(1), Wrapping(0)]);
+    assert_eq!(final_state.cell_ptr, 0);
+    assert_eq!(final_state.outputs, vec![]);
+}
+
+#[test]
+fn nested_loops_on_large_tape() {
+    // "[>]" never runs, but means we use every cell. We then run the
+    // inner loop ~65,000 times, which was very slow when we copied
+    // the whole tape for every loop iteration.
+    let instrs = parse("[>]-[>-[-]<-]").unwrap();
+    let final_state = execute(&instrs, MAX_STEPS);
+
+    assert_eq!(final_state.instr_ptr, instrs.len());
+    assert_eq!(final_state.cells.len(), MAX_CELL_INDEX + 1);
+    assert_eq!(final_state.cells[0], Wrapping(0));
+    assert_eq!(final_state.cells[1], Wrapping(0));
+}
+
 #[test]
 fn up_to_infinite_loop_executed() {
     let instrs = parse("++[]").unwrap();
@@ -361,7 +1175,8 @@ fn up_to_infinite_loop_executed() {
 
     assert_eq!(
         final_state, ExecutionState {
-            instr_ptr: 2, cells: vec![Wrapping(2)], cell_ptr: 0, outputs: vec![],
+            instr_ptr: 1, cells: vec![Wrapping(2)], cell_ptr: 0, outputs: vec![],
+            input_ptr: 0,
         });
 }
 
@@ -377,6 +1192,44 @@ fn cell_ptr_in_bounds(instrs: Vec<Instruction>) -> bool {
     (state.cell_ptr >= 0) && (state.cell_ptr <= state.cells.len() as isize)
 }
 
+/// Move `amount` to within `amount` of the largest or smallest
+/// isize, so adding it to a cell pointer could overflow.
+#[cfg(test)]
+fn huge(amount: isize) -> isize {
+    if amount >= 0 {
+        ::std::isize::MAX - amount
+    } else {
+        ::std::isize::MIN - amount
+    }
+}
+
+#[quickcheck]
+fn huge_offsets_dont_overflow(amounts: Vec<(isize, u8)>) -> bool {
+    // Start with a non-zero cell, so loops and scans run.
+    let mut instrs = vec![Increment(Wrapping(1), 0)];
+    for (amount, kind) in amounts {
+        let amount = huge(amount);
+        instrs.push(match kind % 7 {
+            0 => PointerIncrement(amount),
+            1 => Write(amount),
+            5 => Increment(Wrapping(1), amount),
+            2 => Loop(0, vec![PointerIncrement(amount), PointerIncrement(amount)]),
+            3 => Loop(amount, vec![]),
+            4 => Scan(amount),
+            _ => {
+                let mut changes = HashMap::new();
+                changes.insert(amount, Wrapping(1));
+                MultiplyMove(changes)
+            }
+        });
+    }
+
+    // We should stop with an error, rather than panicking.
+    let state = execute(&instrs, MAX_STEPS);
+    highest_cell_index(&instrs);
+    (state.cell_ptr >= 0) && (state.cell_ptr <= state.cells.len() as isize)
+}
+
 #[test]
 fn arithmetic_error_nested_loops() {
     // Regression test, based on a snippet from
@@ -385,3 +1238,54 @@ fn arithmetic_error_nested_loops() {
     let instrs = parse("+[[>>>>>>>>>]+>>>>>>>>>-]").unwrap();
     execute(&instrs, MAX_STEPS);
 }
+
+#[cfg(test)]
+fn execute_with_cache(instrs: &[Instruction], enabled: bool) -> (ExecutionState, Outcome, usize) {
+    let mut state = initial_state(instrs);
+    let mut undo_log = vec![];
+    let mut cache = LoopCache::new();
+    cache.enabled = enabled;
+    let mut steps_left = MAX_STEPS;
+    let outcome = execute_inner(instrs, &mut state, &mut steps_left, IoMode::Normal, &[],
+                                &mut undo_log, false, &mut cache);
+    (state, outcome, cache.effects.len())
+}
+
+#[test]
+fn identical_loops_memoized() {
+    // "[--->++<]" isn't a multiply loop, and runs 87 times from 5.
+    let source: String = (0..100).map(|_| ">>+++++[--->++<]").collect();
+    let instrs = parse(&source).unwrap();
+
+    let (memoized_state, memoized_outcome, cached) = execute_with_cache(&instrs, true);
+    let (state, outcome, _) = execute_with_cache(&instrs, false);
+    assert_eq!(memoized_state, state);
+    assert_eq!(memoized_outcome, outcome);
+
+    // We only evaluated the loop once.
+    assert_eq!(cached, 1);
+    // 87 * 2 is -82, mod 256.
+    assert_eq!(state.cells[3], Wrapping(-82));
+}
+
+#[test]
+fn memoized_loop_out_of_steps() {
+    // We fall back to executing normally if the loop needs more steps
+    // than we have left.
+    let instrs = parse("+++++[--->++<]").unwrap();
+    let mut state = initial_state(&instrs);
+    let mut undo_log = vec![];
+    let mut cache = LoopCache::new();
+    let outcome = execute_inner(&instrs, &mut state, &mut 100, IoMode::Normal, &[],
+                                &mut undo_log, false, &mut cache);
+    assert_eq!(outcome, Outcome::OutOfSteps);
+    assert_eq!(state.instr_ptr, 1);
+}
+
+#[test]
+fn memoized_loop_never_exits() {
+    let instrs = parse("+[-->+<]").unwrap();
+    let (state, outcome, _) = execute_with_cache(&instrs, true);
+    assert_eq!(outcome, Outcome::OutOfSteps);
+    assert_eq!(state.instr_ptr, 1);
+}
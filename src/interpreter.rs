@@ -0,0 +1,863 @@
+#![warn(trivial_numeric_casts)]
+
+use std::cmp::{min, max};
+use std::io::{Read, Write};
+
+use bfir::{Instruction, EofBehaviour, parse, name_and_operand};
+use bfir::Instruction::*;
+use bounds::{highest_cell_index, MAX_CELL_INDEX};
+
+#[cfg(test)]
+use std::num::Wrapping;
+#[cfg(test)]
+use peephole::optimize;
+#[cfg(test)]
+use std::io::{self, BufReader};
+
+/// Run `instrs` to completion, reading from `input` and writing to
+/// `output`. Unlike speculative execution, we don't stop at reads or
+/// limit the number of steps.
+///
+/// At EOF, a read sets the cell to -1, the same as the compiled
+/// program calling getchar().
+pub fn interpret<R: Read, W: Write>(instrs: &[Instruction],
+                                    input: &mut R,
+                                    output: &mut W)
+                                    -> Result<(), String> {
+    interpret_with_fuel(instrs, input, output, None)
+}
+
+/// As `interpret`, but if `fuel` is given, stop with an error after
+/// running that many instructions. This lets us safely run programs
+/// that may not terminate.
+pub fn interpret_with_fuel<R: Read, W: Write>(instrs: &[Instruction],
+                                              input: &mut R,
+                                              output: &mut W,
+                                              fuel: Option<u64>)
+                                              -> Result<(), String> {
+    interpret_with_limits(instrs, input, output, fuel, DEFAULT_MAX_CELLS, 8,
+                          EofBehaviour::NegOne)
+}
+
+/// The most cells the tape can grow to by default. This is the same
+/// as the tape of a compiled program.
+pub const DEFAULT_MAX_CELLS: usize = MAX_CELL_INDEX + 1;
+
+/// How many cells we start with when we can't bound how far right
+/// the program moves.
+const INITIAL_CELLS: usize = 1000;
+
+/// The cell widths we can interpret, in bits.
+pub const CELL_WIDTHS: &'static [u32] = &[8, 16, 32];
+
+/// As `interpret_with_fuel`, but the tape may grow to `max_cells`. We
+/// start with the cells we know the program can reach, and add more
+/// if the input takes the program further right.
+///
+/// Cells are `cell_width` bits wide, and wrap at that width. Amounts
+/// in `Set` and `MultiplyMove` are signed, so `Set(-1)` sets the
+/// largest value. We only write the low byte of a cell. At EOF, a
+/// read does what `eof` says.
+pub fn interpret_with_limits<R: Read, W: Write>(instrs: &[Instruction],
+                                                input: &mut R,
+                                                output: &mut W,
+                                                fuel: Option<u64>,
+                                                max_cells: usize,
+                                                cell_width: u32,
+                                                eof: EofBehaviour)
+                                                -> Result<(), String> {
+    interpret_on_tape(instrs, input, output, fuel,
+                      Tape::for_program(instrs, max_cells, cell_width).with_eof(eof))
+}
+
+/// As `interpret_with_fuel`, but start with `tape`, so the cells
+/// can start with values other than zero.
+pub fn interpret_on_tape<R: Read, W: Write>(instrs: &[Instruction],
+                                            input: &mut R,
+                                            output: &mut W,
+                                            fuel: Option<u64>,
+                                            tape: Tape)
+                                            -> Result<(), String> {
+    let mut tape = tape;
+    let mut cell_ptr = 0;
+    let mut fuel = fuel;
+    interpret_inner(instrs, &mut tape, &mut cell_ptr, input, output, &mut fuel,
+                    &mut Tracer::none())
+}
+
+/// The cells of a running program, which grow as the cell pointer
+/// moves right. We store every cell as a u32, and wrap values to the
+/// cell width.
+pub struct Tape {
+    cells: Vec<u32>,
+    max_cells: usize,
+    /// The largest cell value, with every bit of the cell set.
+    max_value: u32,
+    /// What a read stores at EOF.
+    eof: EofBehaviour,
+    /// The value of cells we haven't reached yet.
+    fill: u32,
+}
+
+impl Tape {
+    /// A tape with the cells we know `instrs` can reach, which may
+    /// grow to `max_cells`. Cells are `cell_width` bits wide.
+    pub fn for_program(instrs: &[Instruction], max_cells: usize, cell_width: u32) -> Self {
+        assert!(CELL_WIDTHS.contains(&cell_width));
+
+        let highest_index = highest_cell_index(instrs);
+        let num_cells = if highest_index == MAX_CELL_INDEX {
+            INITIAL_CELLS
+        } else {
+            highest_index + 1
+        };
+        Tape::new(min(num_cells, max_cells), max_cells, cell_width)
+    }
+
+    fn new(num_cells: usize, max_cells: usize, cell_width: u32) -> Self {
+        Tape {
+            cells: vec![0; num_cells],
+            max_cells: max_cells,
+            max_value: (((1u64 << cell_width) - 1) as u32),
+            eof: EofBehaviour::NegOne,
+            fill: 0,
+        }
+    }
+
+    /// At EOF, a read does what `eof` says.
+    pub fn with_eof(mut self, eof: EofBehaviour) -> Self {
+        self.eof = eof;
+        self
+    }
+
+    /// Start with `init_cells`, followed by cells set to `fill`, as
+    /// `--init-hex` and `--init-cell-value` do for compiled programs.
+    pub fn with_initial_cells(mut self, init_cells: &[i8], fill: i8) -> Self {
+        self.fill = fill as u8 as u32;
+        let num_cells = min(max(self.cells.len(), init_cells.len()), self.max_cells);
+        self.cells = vec![self.fill; num_cells];
+        for (cell, &value) in self.cells.iter_mut().zip(init_cells) {
+            *cell = value as u8 as u32;
+        }
+        self
+    }
+
+    /// Wrap `value` to the cell width. Negative values wrap around
+    /// from the largest value, so -1 becomes `max_value`.
+    fn wrap(&self, value: i64) -> u32 {
+        (value as u32) & self.max_value
+    }
+
+    /// Clamp `value` between zero and the largest value.
+    fn saturate(&self, value: i64) -> u32 {
+        if value < 0 {
+            0
+        } else if value > self.max_value as i64 {
+            self.max_value
+        } else {
+            value as u32
+        }
+    }
+
+    /// Return the index of the cell at `offset` from `cell_ptr`,
+    /// growing the tape if necessary. Returns an error if the cell
+    /// is left of cell #0 or beyond `max_cells`.
+    fn index(&mut self, cell_ptr: isize, offset: isize) -> Result<usize, String> {
+        let index = cell_ptr.saturating_add(offset);
+        if index < 0 || index >= self.max_cells as isize {
+            return Err(format!("Cell pointer moved outside the tape to cell #{}.", index));
+        }
+
+        let index = index as usize;
+        if index >= self.cells.len() {
+            let fill = self.fill;
+            self.cells.resize(index + 1, fill);
+        }
+        Ok(index)
+    }
+
+    /// The value of the cell at `index`, without growing the tape.
+    fn get(&self, index: isize) -> u32 {
+        if index < 0 {
+            return 0;
+        }
+        self.cells.get(index as usize).cloned().unwrap_or(self.fill)
+    }
+}
+
+/// As `interpret`, but also write a line to `trace` for every
+/// instruction we run, for `--emit=interp-trace`. Each line is
+///
+/// ```text
+/// STEP NAME CELL_PTR VALUE
+/// ```
+///
+/// where VALUE is the cell the instruction affected (or tested, for
+/// loops) afterwards, from 0 to 255. The format is stable, so traces
+/// can be diffed. As with `interpret_with_fuel`, we stop with an
+/// error after running `fuel` instructions.
+pub fn interpret_with_trace<R: Read, W: Write, T: Write>(instrs: &[Instruction],
+                                                         input: &mut R,
+                                                         output: &mut W,
+                                                         trace: &mut T,
+                                                         fuel: Option<u64>)
+                                                         -> Result<(), String> {
+    let mut tape = Tape::new(highest_cell_index(instrs) + 1, DEFAULT_MAX_CELLS, 8);
+    let mut cell_ptr = 0;
+    let mut fuel = fuel;
+    let mut tracer = Tracer { out: Some(trace as &mut Write), step: 0 };
+    interpret_inner(instrs, &mut tape, &mut cell_ptr, input, output, &mut fuel, &mut tracer)
+}
+
+/// Records the instructions we run, if we're tracing.
+struct Tracer<'a> {
+    out: Option<&'a mut Write>,
+    step: u64,
+}
+
+impl<'a> Tracer<'a> {
+    fn none() -> Self {
+        Tracer { out: None, step: 0 }
+    }
+
+    fn record(&mut self, instr: &Instruction, cell_ptr: isize, value: u32) -> Result<(), String> {
+        if let Some(ref mut out) = self.out {
+            let (name, _) = name_and_operand(instr);
+            try!(writeln!(out, "{} {} {} {}", self.step, name, cell_ptr, value)
+                     .map_err(|e| format!("{}", e)));
+        }
+        self.step += 1;
+        Ok(())
+    }
+}
+
+/// Use one unit of fuel, or return an error if we've run out.
+fn burn_fuel(fuel: &mut Option<u64>) -> Result<(), String> {
+    if let Some(ref mut remaining) = *fuel {
+        if *remaining == 0 {
+            return Err("Fuel exhausted.".to_owned());
+        }
+        *remaining -= 1;
+    }
+    Ok(())
+}
+
+fn interpret_inner<R: Read, W: Write>(instrs: &[Instruction],
+                                      tape: &mut Tape,
+                                      cell_ptr: &mut isize,
+                                      input: &mut R,
+                                      output: &mut W,
+                                      fuel: &mut Option<u64>,
+                                      tracer: &mut Tracer)
+                                      -> Result<(), String> {
+    for instr in instrs {
+        try!(burn_fuel(fuel));
+
+        match instr {
+            &Increment(amount, offset) => {
+                let index = try!(tape.index(*cell_ptr, offset));
+                tape.cells[index] = tape.wrap(tape.cells[index] as i64 + amount.0 as i64);
+            }
+            &SaturatingIncrement(amount) => {
+                let index = try!(tape.index(*cell_ptr, 0));
+                tape.cells[index] = tape.saturate(tape.cells[index] as i64 + amount.0 as i64);
+            }
+            &Set(amount, offset) => {
+                let index = try!(tape.index(*cell_ptr, offset));
+                tape.cells[index] = tape.wrap(amount.0 as i64);
+            }
+            &PointerIncrement(amount) => {
+                // Saturate rather than overflow: the pointer is
+                // already off the tape, so the next access fails.
+                *cell_ptr = cell_ptr.saturating_add(amount);
+            }
+            &MultiplyMove(ref changes) => {
+                let index = try!(tape.index(*cell_ptr, 0));
+                let cell_value = tape.cells[index];
+
+                for (cell_offset, factor) in changes.iter() {
+                    let dest_index = try!(tape.index(*cell_ptr, *cell_offset));
+                    let change = cell_value as i64 * factor.0 as i64;
+                    tape.cells[dest_index] = tape.wrap(tape.cells[dest_index] as i64 + change);
+                }
+                tape.cells[index] = 0;
+            }
+            &Scan(stride) => {
+                loop {
+                    let index = try!(tape.index(*cell_ptr, 0));
+                    if tape.cells[index] == 0 {
+                        break;
+                    }
+                    try!(burn_fuel(fuel));
+                    *cell_ptr = cell_ptr.saturating_add(stride);
+                }
+            }
+            &Debug => {}
+            &Read(offset) => {
+                let index = try!(tape.index(*cell_ptr, offset));
+                let mut buf = [0];
+                let bytes_read = try!(input.read(&mut buf).map_err(|e| format!("{}", e)));
+                tape.cells[index] = if bytes_read == 0 {
+                    match tape.eof {
+                        EofBehaviour::NegOne => tape.wrap(-1),
+                        EofBehaviour::Zero => 0,
+                        EofBehaviour::Unchanged => tape.cells[index],
+                    }
+                } else {
+                    buf[0] as u32
+                };
+            }
+            &Write(offset) => {
+                let index = try!(tape.index(*cell_ptr, offset));
+                try!(output.write_all(&[tape.cells[index] as u8]).map_err(|e| format!("{}", e)));
+            }
+            &Loop(offset, ref body) => {
+                loop {
+                    let index = try!(tape.index(*cell_ptr, offset));
+                    try!(tracer.record(instr, *cell_ptr, tape.cells[index]));
+                    if tape.cells[index] == 0 {
+                        break;
+                    }
+                    // Entering the body counts as a step, so even
+                    // "[]" uses fuel.
+                    try!(burn_fuel(fuel));
+                    try!(interpret_inner(body, tape, cell_ptr, input, output, fuel, tracer));
+                }
+                continue;
+            }
+        }
+
+        // The cell this instruction affected. Pointer increments may
+        // leave the tape, as long as we move back before accessing a
+        // cell.
+        let affected = match instr {
+            &Read(offset) | &Write(offset) | &Increment(_, offset) | &Set(_, offset) => {
+                cell_ptr.saturating_add(offset)
+            }
+            _ => *cell_ptr,
+        };
+        try!(tracer.record(instr, *cell_ptr, tape.get(affected)));
+    }
+    Ok(())
+}
+
+/// The number of cells in a REPL session. We can't know in advance
+/// how far the user will move, so use the traditional size.
+pub const REPL_CELLS: usize = 30000;
+
+/// The most instructions we run for each fragment in a REPL session
+/// by default, so a fragment that never finishes can't hang the
+/// session.
+pub const DEFAULT_REPL_FUEL: u64 = 10000000;
+
+/// An interactive session where cells and the cell pointer persist
+/// between fragments of BF.
+pub struct Repl {
+    tape: Tape,
+    cell_ptr: isize,
+    /// Source we haven't run yet, because its loops aren't closed.
+    pending: String,
+    /// The most instructions we run for each fragment.
+    fuel: u64,
+}
+
+impl Repl {
+    pub fn new() -> Self {
+        Repl::with_fuel(DEFAULT_REPL_FUEL)
+    }
+
+    /// A session that stops each fragment after running `fuel`
+    /// instructions.
+    pub fn with_fuel(fuel: u64) -> Self {
+        Repl {
+            tape: Tape::new(REPL_CELLS, REPL_CELLS, 8),
+            cell_ptr: 0,
+            pending: String::new(),
+            fuel: fuel,
+        }
+    }
+
+    /// Are we waiting for more source to close a loop?
+    pub fn is_pending(&self) -> bool {
+        !self.pending.is_empty()
+    }
+
+    /// Add `line` to the source so far. If the brackets balance, run
+    /// it and return true. Otherwise, wait for more lines.
+    ///
+    /// If the fragment runs out of fuel, we return an error, and the
+    /// cells and cell pointer stay where it stopped.
+    pub fn feed<R: Read, W: Write>(&mut self,
+                                   line: &str,
+                                   input: &mut R,
+                                   output: &mut W)
+                                   -> Result<bool, String> {
+        self.pending.push_str(line);
+
+        let mut depth = 0;
+        for c in self.pending.chars() {
+            match c {
+                '[' => depth += 1,
+                ']' => depth -= 1,
+                _ => {}
+            }
+        }
+        if depth > 0 {
+            return Ok(false);
+        }
+
+        let source = self.pending.clone();
+        self.pending.clear();
+        let instrs = try!(parse(&source));
+
+        let old_cell_ptr = self.cell_ptr;
+        let mut fuel = Some(self.fuel);
+        let result = interpret_inner(&instrs, &mut self.tape, &mut self.cell_ptr, input, output,
+                                     &mut fuel, &mut Tracer::none());
+        if result.is_err() && fuel == Some(0) {
+            return Err(format!("Stopped after running {} instructions, as this may never \
+                                finish.",
+                               self.fuel));
+        }
+        if result.is_err() {
+            // Don't leave the pointer outside the tape.
+            self.cell_ptr = old_cell_ptr;
+        }
+        try!(result);
+        Ok(true)
+    }
+
+    /// The cells near the cell pointer, with the current cell in
+    /// brackets, e.g. "0 [5] 0 0".
+    pub fn cell_window(&self) -> String {
+        let ptr = self.cell_ptr as usize;
+        let start = ptr.saturating_sub(5);
+        let end = min(ptr + 6, self.tape.cells.len());
+
+        let cells: Vec<_> = (start..end).map(|i| {
+            let value = self.tape.cells[i];
+            if i == ptr {
+                format!("[{}]", value)
+            } else {
+                format!("{}", value)
+            }
+        }).collect();
+        format!("#{}: {}", ptr, cells.join(" "))
+    }
+}
+
+/// The most inputs `search_inputs` will try, so a large alphabet or
+/// length can't make us run forever.
+pub const MAX_SEARCH_CANDIDATES: u64 = 1000000;
+
+/// Find the shortest input, made of bytes from `alphabet` and no longer
+/// than `max_len`, that makes the program write `target` somewhere in
+/// its output.
+///
+/// `fuel` is the total number of steps for the whole search, shared
+/// between every input we try, so the search time is bounded however
+/// many inputs there are. Returns an error if there are too many
+/// candidate inputs, or if we run out of fuel before trying them all.
+pub fn search_inputs(instrs: &[Instruction],
+                     alphabet: &[u8],
+                     max_len: usize,
+                     target: &[u8],
+                     fuel: u64)
+                     -> Result<Option<Vec<u8>>, String> {
+    let mut candidates: u64 = 0;
+    let mut candidates_of_len: u64 = 1;
+    for _ in 0..max_len + 1 {
+        candidates = candidates.saturating_add(candidates_of_len);
+        candidates_of_len = candidates_of_len.saturating_mul(alphabet.len() as u64);
+    }
+    if candidates > MAX_SEARCH_CANDIDATES {
+        return Err(format!("Search space is too large ({} inputs, maximum {}).",
+                           candidates, MAX_SEARCH_CANDIDATES));
+    }
+
+    let mut fuel = Some(fuel);
+    let mut tried: u64 = 0;
+    for len in 0..max_len + 1 {
+        if len > 0 && alphabet.is_empty() {
+            break;
+        }
+
+        // Treat the input as a number in base alphabet.len(), where
+        // each digit is an index into the alphabet.
+        let mut digits = vec![0; len];
+        loop {
+            let input: Vec<u8> = digits.iter().map(|&digit| alphabet[digit]).collect();
+            let mut output = vec![];
+            let mut tape = Tape::for_program(instrs, DEFAULT_MAX_CELLS, 8);
+            let mut cell_ptr = 0;
+            // Programs that fail just don't match.
+            let result = interpret_inner(instrs, &mut tape, &mut cell_ptr, &mut &input[..],
+                                         &mut output, &mut fuel, &mut Tracer::none());
+            if contains(&output, target) {
+                return Ok(Some(input));
+            }
+            tried += 1;
+            // If this input used up the last of the fuel, we don't
+            // know whether it would have matched.
+            if fuel == Some(0) && result.is_err() {
+                return Err(format!("Search ran out of fuel after trying {} of {} inputs.",
+                                   tried, candidates));
+            }
+
+            if !increment_digits(&mut digits, alphabet.len()) {
+                break;
+            }
+        }
+    }
+    Ok(None)
+}
+
+/// Move `digits` to the next number in base `base`. Return false if
+/// we've wrapped around to zero.
+fn increment_digits(digits: &mut [usize], base: usize) -> bool {
+    for digit in digits.iter_mut().rev() {
+        *digit += 1;
+        if *digit < base {
+            return true;
+        }
+        *digit = 0;
+    }
+    false
+}
+
+/// Does `haystack` contain `needle`?
+fn contains(haystack: &[u8], needle: &[u8]) -> bool {
+    needle.is_empty() || haystack.windows(needle.len()).any(|window| window == needle)
+}
+
+#[cfg(test)]
+fn interpret_str(source: &str, input: &[u8]) -> Result<Vec<u8>, String> {
+    let instrs = parse(source).unwrap();
+    let mut output = vec![];
+    try!(interpret(&instrs, &mut &input[..], &mut output));
+    Ok(output)
+}
+
+#[test]
+fn interpret_hello_world() {
+    let source = "++++++++[>++++[>++>+++>+++>+<<<<-]>+>+>->>+[<]<-]>>.>---.+++++++..+++.>>.<-.<.+++.------.--------.>>+.>++.";
+    assert_eq!(interpret_str(source, b"").unwrap(), b"Hello World!\n".to_vec());
+}
+
+#[test]
+fn interpret_echoes_input() {
+    assert_eq!(interpret_str(",.,.", b"hi").unwrap(), b"hi".to_vec());
+}
+
+#[test]
+fn interpret_eof() {
+    assert_eq!(interpret_str(",+.", b"").unwrap(), vec![0]);
+}
+
+/// A reader that counts how often we call `read`, which is a system
+/// call when reading stdin.
+#[cfg(test)]
+struct CountingReader<'a> {
+    bytes: &'a [u8],
+    reads: usize,
+}
+
+#[cfg(test)]
+impl<'a> Read for CountingReader<'a> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.reads += 1;
+        self.bytes.read(buf)
+    }
+}
+
+#[test]
+fn interpret_buffered_input() {
+    // Echo every byte until EOF.
+    let instrs = optimize(parse(",+[-.,+]").unwrap());
+    let input: Vec<u8> = (0..100000).map(|i| b'a' + (i % 26) as u8).collect();
+
+    let mut unbuffered = CountingReader { bytes: &input, reads: 0 };
+    let mut output = vec![];
+    interpret(&instrs, &mut unbuffered, &mut output).unwrap();
+    assert_eq!(output, input);
+
+    let mut buffered = BufReader::with_capacity(4096, CountingReader { bytes: &input, reads: 0 });
+    let mut output = vec![];
+    interpret(&instrs, &mut buffered, &mut output).unwrap();
+    assert_eq!(output, input);
+
+    assert_eq!(unbuffered.reads, 100001);
+    assert!(buffered.get_ref().reads < 100);
+}
+
+#[test]
+fn interpret_saturating_region() {
+    assert_eq!(interpret_str("{wrap:saturate}-.{wrap:default}-.", b"").unwrap(), vec![0, 255]);
+}
+
+#[test]
+fn repl_keeps_state() {
+    let mut repl = Repl::new();
+    let mut output = vec![];
+    assert_eq!(repl.feed("+++>++\n", &mut &b""[..], &mut output), Ok(true));
+    assert_eq!(repl.feed("<.\n", &mut &b""[..], &mut output), Ok(true));
+
+    assert_eq!(output, vec![3]);
+    assert_eq!(repl.cell_window(), "#0: [3] 2 0 0 0 0");
+}
+
+#[test]
+fn repl_waits_for_balanced_loops() {
+    let mut repl = Repl::new();
+    let mut output = vec![];
+    assert_eq!(repl.feed("++[>+\n", &mut &b""[..], &mut output), Ok(false));
+    assert!(repl.is_pending());
+    assert_eq!(repl.feed("<-]>.\n", &mut &b""[..], &mut output), Ok(true));
+
+    assert!(!repl.is_pending());
+    assert_eq!(output, vec![2]);
+    assert_eq!(repl.cell_window(), "#1: 0 [2] 0 0 0 0 0");
+}
+
+#[test]
+fn repl_out_of_fuel() {
+    let mut repl = Repl::with_fuel(100);
+    let mut output = vec![];
+    assert_eq!(repl.feed("+>+[]\n", &mut &b""[..], &mut output),
+               Err("Stopped after running 100 instructions, as this may never finish."
+                       .to_owned()));
+
+    // The session carries on from where we stopped.
+    assert_eq!(repl.feed("[-]<\n", &mut &b""[..], &mut output), Ok(true));
+    assert_eq!(repl.cell_window(), "#0: [1] 0 0 0 0 0");
+}
+
+#[test]
+fn repl_out_of_bounds() {
+    let mut repl = Repl::new();
+    let mut output = vec![];
+    assert!(repl.feed("+<-\n", &mut &b""[..], &mut output).is_err());
+    assert_eq!(repl.cell_window(), "#0: [1] 0 0 0 0 0");
+}
+
+#[test]
+fn interpret_trace_golden() {
+    let instrs = vec![Increment(Wrapping(1), 0), Increment(Wrapping(1), 0), Write(0)];
+    let mut output = vec![];
+    let mut trace = vec![];
+    interpret_with_trace(&instrs, &mut &b""[..], &mut output, &mut trace, None).unwrap();
+
+    assert_eq!(output, vec![2]);
+    assert_eq!(String::from_utf8(trace).unwrap(),
+               include_str!("../tests/traces/inc_inc_write.trace"));
+}
+
+#[test]
+fn interpret_trace_loop() {
+    let instrs = parse("+[-]>").unwrap();
+    let mut trace = vec![];
+    interpret_with_trace(&instrs, &mut &b""[..], &mut vec![], &mut trace, None).unwrap();
+
+    assert_eq!(String::from_utf8(trace).unwrap(),
+               "0 Increment 0 1\n1 Loop 0 1\n2 Increment 0 0\n3 Loop 0 0\n4 PointerIncrement 1 0\n");
+}
+
+#[test]
+fn interpret_trace_out_of_fuel() {
+    let instrs = parse("+[]").unwrap();
+    let mut trace = vec![];
+    let result = interpret_with_trace(&instrs, &mut &b""[..], &mut vec![], &mut trace, Some(5));
+
+    assert_eq!(result, Err("Fuel exhausted.".to_owned()));
+    assert_eq!(String::from_utf8(trace).unwrap().lines().count(), 5);
+}
+
+#[test]
+fn interpret_grows_tape() {
+    // Move right once for each byte of input, then write a cell. A
+    // read at EOF must store zero to end the loop.
+    let instrs = parse(",[>,]+.").unwrap();
+    let input = vec![b'x'; INITIAL_CELLS + 500];
+
+    let mut output = vec![];
+    interpret_with_limits(&instrs, &mut &input[..], &mut output, None, DEFAULT_MAX_CELLS, 8,
+                          EofBehaviour::Zero).unwrap();
+    assert_eq!(output, vec![1]);
+}
+
+#[test]
+fn interpret_tape_limit() {
+    let instrs = parse(",[>,]+.").unwrap();
+    let input = vec![b'x'; 50];
+
+    let mut output = vec![];
+    let result = interpret_with_limits(&instrs, &mut &input[..], &mut output, None, 20, 8,
+                                       EofBehaviour::NegOne);
+    assert_eq!(result, Err("Cell pointer moved outside the tape to cell #20.".to_owned()));
+}
+
+#[test]
+fn interpret_initial_cells() {
+    let instrs = parse(".>.>.>>>>.").unwrap();
+    let tape = Tape::for_program(&instrs, DEFAULT_MAX_CELLS, 8).with_initial_cells(&[65, -1], 7);
+
+    let mut output = vec![];
+    interpret_on_tape(&instrs, &mut &b""[..], &mut output, None, tape).unwrap();
+    assert_eq!(output, vec![65, 255, 7, 7]);
+}
+
+#[test]
+fn interpret_grows_tape_with_fill() {
+    let instrs = parse(",[>,]>.").unwrap();
+    let input = vec![b'x'; INITIAL_CELLS + 500];
+    let tape = Tape::for_program(&instrs, DEFAULT_MAX_CELLS, 8)
+        .with_eof(EofBehaviour::Zero)
+        .with_initial_cells(&[], 3);
+
+    let mut output = vec![];
+    interpret_on_tape(&instrs, &mut &input[..], &mut output, None, tape).unwrap();
+    assert_eq!(output, vec![3]);
+}
+
+#[cfg(test)]
+fn interpret_str_with_width(source: &str, input: &[u8], cell_width: u32) -> Vec<u8> {
+    let instrs = parse(source).unwrap();
+    let mut output = vec![];
+    interpret_with_limits(&instrs, &mut &input[..], &mut output, None, DEFAULT_MAX_CELLS,
+                          cell_width, EofBehaviour::NegOne).unwrap();
+    output
+}
+
+#[test]
+fn interpret_16_bit_cells() {
+    // 16 * 16 = 256 doesn't wrap in a 16-bit cell, so we enter the
+    // loop and set cell #2.
+    let source = "++++++++++++++++[>++++++++++++++++<-]>[[-]>+<]>.";
+    assert_eq!(interpret_str_with_width(source, b"", 16), vec![1]);
+    assert_eq!(interpret_str_with_width(source, b"", 8), vec![0]);
+}
+
+#[test]
+fn interpret_16_bit_wraps_at_65536() {
+    // Decrementing zero gives 65535, whose low byte is 255.
+    assert_eq!(interpret_str_with_width("-.", b"", 16), vec![255]);
+    // Incrementing 65535 wraps back to 0, so the loop is skipped.
+    assert_eq!(interpret_str_with_width("-+[.]+.", b"", 16), vec![1]);
+    // At EOF, a read sets every bit of the cell.
+    assert_eq!(interpret_str_with_width(",+[.]+.", b"", 16), vec![1]);
+}
+
+#[test]
+fn interpret_eof_behaviours() {
+    let instrs = parse("+,.").unwrap();
+    let eof_output = |eof| {
+        let mut output = vec![];
+        interpret_with_limits(&instrs, &mut &b""[..], &mut output, None, DEFAULT_MAX_CELLS, 8,
+                              eof).unwrap();
+        output
+    };
+    assert_eq!(eof_output(EofBehaviour::NegOne), vec![255]);
+    assert_eq!(eof_output(EofBehaviour::Zero), vec![0]);
+    assert_eq!(eof_output(EofBehaviour::Unchanged), vec![1]);
+}
+
+#[test]
+fn interpret_out_of_bounds() {
+    assert!(interpret_str("<+", b"").is_err());
+}
+
+#[test]
+fn interpret_loop_condition() {
+    // Move cell #1 to cell #0, testing cell #1 from cell #0.
+    let instrs = vec![PointerIncrement(1),
+                      Increment(Wrapping(3), 0),
+                      PointerIncrement(-1),
+                      Loop(1, vec![Increment(Wrapping(1), 0),
+                                   PointerIncrement(1),
+                                   Increment(Wrapping(-1), 0),
+                                   PointerIncrement(-1)]),
+                      Write(0)];
+    let mut output = vec![];
+    interpret(&instrs, &mut &b""[..], &mut output).unwrap();
+    assert_eq!(output, vec![3]);
+}
+
+#[test]
+fn interpret_increment_and_set_at_offset() {
+    let instrs = vec![Set(Wrapping(60), 2), Increment(Wrapping(5), 2), Write(2)];
+    let mut output = vec![];
+    interpret(&instrs, &mut &b""[..], &mut output).unwrap();
+    assert_eq!(output, vec![65]);
+
+    let instrs = vec![Increment(Wrapping(1), -1)];
+    assert!(interpret(&instrs, &mut &b""[..], &mut vec![]).is_err());
+}
+
+#[test]
+fn interpret_out_of_fuel() {
+    let instrs = parse("+[]").unwrap();
+    let mut output = vec![];
+    let result = interpret_with_fuel(&instrs, &mut &b""[..], &mut output, Some(100));
+    assert_eq!(result, Err("Fuel exhausted.".to_owned()));
+}
+
+#[test]
+fn interpret_with_enough_fuel() {
+    let instrs = parse("++[-].").unwrap();
+    let mut output = vec![];
+    interpret_with_fuel(&instrs, &mut &b""[..], &mut output, Some(100)).unwrap();
+    assert_eq!(output, vec![0]);
+}
+
+#[test]
+fn search_single_byte() {
+    // "+." adds one to the input byte. Each input takes 3 steps, and
+    // we try 66 inputs, including the empty one.
+    let instrs = parse(",+.").unwrap();
+    let alphabet: Vec<u8> = (0..128).collect();
+    assert_eq!(search_inputs(&instrs, &alphabet, 1, b"B", 1000), Ok(Some(vec![b'A'])));
+}
+
+#[test]
+fn search_finds_shortest() {
+    let instrs = parse(",.,.").unwrap();
+    assert_eq!(search_inputs(&instrs, b"ab", 3, b"ba", 100), Ok(Some(b"ba".to_vec())));
+}
+
+#[test]
+fn search_not_found() {
+    let instrs = parse(",.").unwrap();
+    assert_eq!(search_inputs(&instrs, b"ab", 2, b"c", 100), Ok(None));
+}
+
+#[test]
+fn search_too_large() {
+    let instrs = parse(",.").unwrap();
+    let alphabet: Vec<u8> = (0..255).collect();
+    assert!(search_inputs(&instrs, &alphabet, 4, b"x", 100).is_err());
+}
+
+#[test]
+fn search_infinite_loop() {
+    // We should run out of fuel rather than hang.
+    let instrs = parse("+[]").unwrap();
+    assert_eq!(search_inputs(&instrs, b"a", 1, b"a", 100),
+               Err("Search ran out of fuel after trying 1 of 2 inputs.".to_owned()));
+}
+
+#[test]
+fn search_shares_fuel_between_inputs() {
+    // Each input takes 2 steps, so 5 steps isn't enough to try "",
+    // "a" and then "b".
+    let instrs = parse(",.").unwrap();
+    assert_eq!(search_inputs(&instrs, b"ab", 1, b"b", 6), Ok(Some(b"b".to_vec())));
+    assert!(search_inputs(&instrs, b"ab", 1, b"b", 5).is_err());
+}
+
+#[test]
+fn interpret_optimized() {
+    let instrs = optimize(parse("++[->+++<]>.").unwrap());
+    let mut output = vec![];
+    interpret(&instrs, &mut &b""[..], &mut output).unwrap();
+    assert_eq!(output, vec![6]);
+}
@@ -0,0 +1,275 @@
+use std::collections::HashMap;
+use std::num::Wrapping;
+
+use bfir::Instruction;
+use bfir::Instruction::*;
+
+/// Every serialized program starts with this magic number and a
+/// format version, so we don't try to load arbitrary files. The
+/// version is followed by a 64-bit cache key, identifying the source
+/// and options we compiled.
+const MAGIC: &'static [u8] = b"BFIR";
+const VERSION: u8 = 7;
+const HEADER_LEN: usize = 4 + 1 + 8;
+
+// One tag byte per instruction. Loops are written as a start tag,
+// their body, then an end tag.
+const INCREMENT: u8 = 0;
+const POINTER_INCREMENT: u8 = 1;
+const READ: u8 = 2;
+const WRITE: u8 = 3;
+const LOOP_START: u8 = 4;
+const LOOP_END: u8 = 5;
+const SET: u8 = 6;
+const MULTIPLY_MOVE: u8 = 7;
+const SATURATING_INCREMENT: u8 = 8;
+const SCAN: u8 = 9;
+const DEBUG: u8 = 10;
+
+/// Serialize a sequence of instructions to our compact binary
+/// format.
+pub fn encode(instrs: &[Instruction], cache_key: u64) -> Vec<u8> {
+    let mut bytes = vec![];
+    bytes.extend(MAGIC.iter().cloned());
+    bytes.push(VERSION);
+    push_i64(cache_key as i64, &mut bytes);
+    encode_instrs(instrs, &mut bytes);
+    bytes
+}
+
+fn encode_instrs(instrs: &[Instruction], bytes: &mut Vec<u8>) {
+    for instr in instrs {
+        match instr {
+            &Increment(amount, offset) => {
+                bytes.push(INCREMENT);
+                bytes.push(amount.0 as u8);
+                push_i64(offset as i64, bytes);
+            }
+            &PointerIncrement(amount) => {
+                bytes.push(POINTER_INCREMENT);
+                push_i64(amount as i64, bytes);
+            }
+            &Read(offset) => {
+                bytes.push(READ);
+                push_i64(offset as i64, bytes);
+            }
+            &Write(offset) => {
+                bytes.push(WRITE);
+                push_i64(offset as i64, bytes);
+            }
+            &Loop(offset, ref body) => {
+                bytes.push(LOOP_START);
+                push_i64(offset as i64, bytes);
+                encode_instrs(body, bytes);
+                bytes.push(LOOP_END);
+            }
+            &Set(amount, offset) => {
+                bytes.push(SET);
+                bytes.push(amount.0 as u8);
+                push_i64(offset as i64, bytes);
+            }
+            &SaturatingIncrement(amount) => {
+                bytes.push(SATURATING_INCREMENT);
+                bytes.push(amount.0 as u8);
+            }
+            &Scan(stride) => {
+                bytes.push(SCAN);
+                push_i64(stride as i64, bytes);
+            }
+            &Debug => bytes.push(DEBUG),
+            &MultiplyMove(ref changes) => {
+                bytes.push(MULTIPLY_MOVE);
+                push_u32(changes.len() as u32, bytes);
+
+                // Sort the offsets so the output is deterministic.
+                let mut offsets: Vec<_> = changes.keys().collect();
+                offsets.sort();
+                for offset in offsets {
+                    push_i64(*offset as i64, bytes);
+                    bytes.push(changes[offset].0 as u8);
+                }
+            }
+        }
+    }
+}
+
+fn push_u32(value: u32, bytes: &mut Vec<u8>) {
+    for i in 0..4 {
+        bytes.push((value >> (8 * i)) as u8);
+    }
+}
+
+fn push_i64(value: i64, bytes: &mut Vec<u8>) {
+    for i in 0..8 {
+        bytes.push((value >> (8 * i)) as u8);
+    }
+}
+
+/// Return the cache key that was passed to `encode`, without
+/// decoding any instructions.
+pub fn cache_key(bytes: &[u8]) -> Result<u64, String> {
+    try!(check_header(bytes));
+
+    let mut decoder = Decoder { bytes: bytes, index: MAGIC.len() + 1 };
+    decoder.next_i64().map(|key| key as u64)
+}
+
+/// Deserialize instructions previously written by `encode`.
+pub fn decode(bytes: &[u8]) -> Result<Vec<Instruction>, String> {
+    try!(check_header(bytes));
+
+    let mut decoder = Decoder { bytes: bytes, index: HEADER_LEN };
+    decoder.decode_instrs(false)
+}
+
+fn check_header(bytes: &[u8]) -> Result<(), String> {
+    if bytes.len() < MAGIC.len() + 1 || &bytes[..MAGIC.len()] != MAGIC {
+        return Err("Not a BF IR file.".to_owned());
+    }
+    if bytes[MAGIC.len()] != VERSION {
+        return Err(format!("Unsupported BF IR version {}.", bytes[MAGIC.len()]));
+    }
+    if bytes.len() < HEADER_LEN {
+        return Err("Unexpected end of BF IR file.".to_owned());
+    }
+    Ok(())
+}
+
+struct Decoder<'a> {
+    bytes: &'a [u8],
+    index: usize,
+}
+
+impl<'a> Decoder<'a> {
+    fn next_byte(&mut self) -> Result<u8, String> {
+        match self.bytes.get(self.index) {
+            Some(&byte) => {
+                self.index += 1;
+                Ok(byte)
+            }
+            None => Err("Unexpected end of BF IR file.".to_owned()),
+        }
+    }
+
+    fn next_u32(&mut self) -> Result<u32, String> {
+        let mut value = 0;
+        for i in 0..4 {
+            value |= (try!(self.next_byte()) as u32) << (8 * i);
+        }
+        Ok(value)
+    }
+
+    fn next_i64(&mut self) -> Result<i64, String> {
+        let mut value = 0;
+        for i in 0..8 {
+            value |= (try!(self.next_byte()) as i64) << (8 * i);
+        }
+        Ok(value)
+    }
+
+    /// Decode instructions until the end of the input, or the end of
+    /// the current loop if `in_loop` is true.
+    fn decode_instrs(&mut self, in_loop: bool) -> Result<Vec<Instruction>, String> {
+        let mut instrs = vec![];
+
+        loop {
+            if self.index == self.bytes.len() && !in_loop {
+                return Ok(instrs);
+            }
+
+            let tag = try!(self.next_byte());
+            let instr = match tag {
+                INCREMENT => {
+                    let amount = Wrapping(try!(self.next_byte()) as i8);
+                    Increment(amount, try!(self.next_i64()) as isize)
+                }
+                POINTER_INCREMENT => PointerIncrement(try!(self.next_i64()) as isize),
+                READ => Read(try!(self.next_i64()) as isize),
+                WRITE => Write(try!(self.next_i64()) as isize),
+                LOOP_START => {
+                    let offset = try!(self.next_i64()) as isize;
+                    Loop(offset, try!(self.decode_instrs(true)))
+                }
+                LOOP_END => {
+                    if in_loop {
+                        return Ok(instrs);
+                    }
+                    return Err("Unmatched loop end in BF IR file.".to_owned());
+                }
+                SET => {
+                    let amount = Wrapping(try!(self.next_byte()) as i8);
+                    Set(amount, try!(self.next_i64()) as isize)
+                }
+                SATURATING_INCREMENT => SaturatingIncrement(Wrapping(try!(self.next_byte()) as i8)),
+                SCAN => Scan(try!(self.next_i64()) as isize),
+                DEBUG => Debug,
+                MULTIPLY_MOVE => {
+                    let num_changes = try!(self.next_u32());
+                    let mut changes = HashMap::new();
+                    for _ in 0..num_changes {
+                        let offset = try!(self.next_i64()) as isize;
+                        let factor = Wrapping(try!(self.next_byte()) as i8);
+                        changes.insert(offset, factor);
+                    }
+                    MultiplyMove(changes)
+                }
+                _ => return Err(format!("Unknown instruction tag {} in BF IR file.", tag)),
+            };
+            instrs.push(instr);
+        }
+    }
+}
+
+#[test]
+fn round_trip_simple() {
+    let instrs = vec![Increment(Wrapping(-3), 0), PointerIncrement(-100000), Read(0),
+                      Write(-2), SaturatingIncrement(Wrapping(-1))];
+    assert_eq!(decode(&encode(&instrs, 0)), Ok(instrs));
+}
+
+#[test]
+fn round_trip_loops() {
+    let instrs = vec![Loop(0, vec![]),
+                      Loop(0, vec![Loop(0, vec![Write(0)]), PointerIncrement(1)])];
+    assert_eq!(decode(&encode(&instrs, 0)), Ok(instrs));
+}
+
+#[test]
+fn round_trip_loop_condition() {
+    let instrs = vec![Loop(-3, vec![Loop(2, vec![]), Write(0)])];
+    assert_eq!(decode(&encode(&instrs, 0)), Ok(instrs));
+}
+
+#[test]
+fn round_trip_set_and_multiply_move() {
+    let mut changes = HashMap::new();
+    changes.insert(1, Wrapping(2));
+    changes.insert(-4, Wrapping(-1));
+    let instrs = vec![Set(Wrapping(5), 0), Loop(0, vec![MultiplyMove(changes)]), Scan(-2), Debug,
+                      Set(Wrapping(-1), 3), Increment(Wrapping(2), -7)];
+    assert_eq!(decode(&encode(&instrs, 0)), Ok(instrs));
+}
+
+#[quickcheck]
+fn round_trip_arbitrary(instrs: Vec<Instruction>) -> bool {
+    decode(&encode(&instrs, 0)) == Ok(instrs)
+}
+
+#[test]
+fn decode_truncated() {
+    let instrs = vec![Loop(0, vec![PointerIncrement(2)])];
+    let bytes = encode(&instrs, 0);
+    assert!(decode(&bytes[..bytes.len() - 1]).is_err());
+}
+
+#[test]
+fn read_cache_key() {
+    let bytes = encode(&[Write(0)], 0xfedcba9876543210);
+    assert_eq!(cache_key(&bytes), Ok(0xfedcba9876543210));
+    assert!(cache_key(&bytes[..HEADER_LEN - 1]).is_err());
+}
+
+#[test]
+fn decode_not_bf_ir() {
+    assert!(decode(b"+++").is_err());
+}
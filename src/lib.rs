@@ -0,0 +1,45 @@
+//! bfc compiles BF programs to LLVM IR and native executables. The
+//! `bfc` command line tool is a wrapper around this library.
+//!
+//! Most embedders want `compile`, which returns LLVM IR, or `run`,
+//! which interprets a program.
+
+#![feature(plugin)]
+#![plugin(quickcheck_macros)]
+
+#![warn(trivial_numeric_casts)]
+
+extern crate libc;
+extern crate llvm_sys;
+extern crate itertools;
+extern crate quickcheck;
+extern crate rand;
+#[cfg(feature = "json")]
+extern crate serde_json;
+
+pub mod bfir;
+pub mod peephole;
+pub mod bounds;
+pub mod execution;
+pub mod api;
+
+// The bfc binary uses these too, but they aren't a stable API.
+#[doc(hidden)]
+pub mod llvm;
+#[doc(hidden)]
+pub mod irbin;
+#[doc(hidden)]
+pub mod diagnostics;
+#[doc(hidden)]
+pub mod interpreter;
+#[doc(hidden)]
+pub mod stats;
+
+pub use api::{compile, run, CompileOptions};
+
+#[cfg(test)]
+mod peephole_tests;
+#[cfg(test)]
+mod llvm_tests;
+#[cfg(test)]
+mod corpus_tests;
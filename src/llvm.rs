@@ -1,26 +1,137 @@
 
 use itertools::Itertools;
 use llvm_sys::core::*;
-use llvm_sys::{LLVMModule, LLVMBasicBlock, LLVMIntPredicate, LLVMBuilder};
+use llvm_sys::{LLVMModule, LLVMBasicBlock, LLVMIntPredicate, LLVMBuilder, LLVMLinkage};
 use llvm_sys::prelude::*;
+use llvm_sys::execution_engine::*;
+use llvm_sys::target::*;
+use llvm_sys::target_machine::LLVMGetTargetFromTriple;
 
 use libc::types::os::arch::c99::c_ulonglong;
 use libc::types::os::arch::c95::c_uint;
 use std::ffi::{CString, CStr};
+use std::ptr;
 
+use std::cmp::min;
 use std::collections::HashMap;
+use std::num::Wrapping;
 
-use bfir::{Instruction, Cell};
+use bfir::{Instruction, Cell, EofBehaviour};
 use bfir::Instruction::*;
+use peephole::scan_stride;
+use bounds;
 
 const LLVM_FALSE: LLVMBool = 0;
 const LLVM_TRUE: LLVMBool = 1;
 
+/// Options that control the LLVM IR we generate.
+#[derive(Debug, Clone, Default)]
+pub struct CodegenOptions {
+    /// Copy the bytes of the first command line argument into the
+    /// cells (starting at cell #0) before running the program.
+    pub argv_tape: bool,
+    /// Read input from this file descriptor rather than using
+    /// getchar(). The cell is unchanged at EOF.
+    pub input_fd: Option<i32>,
+    /// What getchar() reads store at EOF.
+    pub eof: EofBehaviour,
+    /// Write output to this file descriptor rather than using
+    /// putchar().
+    pub output_fd: Option<i32>,
+    /// Identifies the source and options we compiled, so build
+    /// systems can tell if the IR is up to date. Written as a comment
+    /// at the start of the IR.
+    pub cache_key: Option<u64>,
+    /// Give every non-void value and loop block a descriptive name
+    /// (e.g. `%loop3.cond`), so tools can map the IR back to BF.
+    pub named_values: bool,
+    /// Compile each loop as a separate function, rather than as
+    /// nested basic blocks in its parent. This keeps the code for
+    /// deeply nested loops small, at some cost in speed.
+    pub loop_functions: bool,
+    /// Abort the program if it accesses a cell outside the tape. We
+    /// only check accesses that we can't prove are safe at compile
+    /// time.
+    pub debug_assert: bool,
+    /// Unroll scan loops such as `[>]` so each iteration of the
+    /// search checks this many cells. 0 or 1 means no unrolling.
+    pub max_loop_unroll_factor: usize,
+    /// Align the tape to this many bytes, e.g. 64 to start it on a
+    /// cache line. Otherwise LLVM chooses. Only applies to tapes on
+    /// the stack.
+    pub tape_align: Option<u32>,
+    /// Allocate the tape with calloc() rather than on the stack. We
+    /// always do this for tapes of `HEAP_TAPE_THRESHOLD` cells or
+    /// more.
+    pub heap_tape: bool,
+    /// Store the initial cell values as a constant and copy them onto
+    /// the tape at startup, rather than setting each run of equal
+    /// cells with memset. This keeps large precomputed tapes out of
+    /// the code and the writable segment.
+    pub rodata_cells: bool,
+    /// Before exiting, print this many cells from the start of the
+    /// tape, and the cell pointer, to stderr. We never print more
+    /// cells than the tape has, so callers should size the tape to
+    /// fit.
+    pub print_cells: Option<usize>,
+    /// Compile the program as a function `bf_main` that runs at
+    /// startup, registered in `llvm.global_ctors`, rather than as
+    /// `main`. This lets us link the program into another binary.
+    pub global_ctor: bool,
+    /// Use cells of this many bits (16 or 32) rather than bytes. The
+    /// amounts in our instructions are still `Cell`s, so the
+    /// instructions must not have been combined as 8-bit values, and
+    /// the tape must start zeroed. Reads and writes use the low byte.
+    pub cell_width: Option<u32>,
+    /// The source line of each instruction, in the order we compile
+    /// them (the order `bfir::walk` visits them). If set, we emit
+    /// DWARF line tables, so debuggers can step through the BF
+    /// source. Not supported with `loop_functions`.
+    pub debug_lines: Option<Vec<usize>>,
+    /// Compile for this target triple, e.g. `armv7-linux-gnueabihf`,
+    /// rather than leaving LLVM to use the host.
+    pub target_triple: Option<String>,
+    /// Compile `Debug` instructions to print the cells around the
+    /// cell pointer to stderr. Otherwise they do nothing.
+    pub debug_cmd: bool,
+    /// Stop the program after this many loop iterations, printing
+    /// "Fuel exhausted." to stderr and exiting with
+    /// `FUEL_EXHAUSTED_EXIT_CODE`. Scans aren't counted, as they
+    /// always stop at the end of the tape.
+    pub fuel: Option<u64>,
+}
+
+/// The exit code of a program that runs out of `fuel`. This matches
+/// bfc's own exit code when it can't run a program.
+pub const FUEL_EXHAUSTED_EXIT_CODE: c_ulonglong = 2;
+
+/// Tapes this large could overflow the stack, so we allocate them on
+/// the heap instead.
+pub const HEAP_TAPE_THRESHOLD: usize = 1024 * 1024;
+
 /// A struct that keeps ownership of all the strings we've passed to
 /// the LLVM API until we destroy the LLVMModule.
 struct Module {
     module: *mut LLVMModule,
     strings: Vec<CString>,
+    /// The number of loops we've compiled so far.
+    loop_count: usize,
+    /// With `debug_assert`, whether each instruction's cell accesses
+    /// need checking, in the order we compile them.
+    access_checks: Vec<bool>,
+    /// The number of instructions we've compiled so far.
+    instr_count: usize,
+    /// The number of cells on the tape.
+    num_cells: usize,
+    /// The LLVM type of each cell.
+    cell_type: LLVMTypeRef,
+    /// With `debug_lines`, the DWARF subprogram for the function
+    /// we're compiling, which our variables and source locations
+    /// refer to. Null otherwise.
+    debug_scope: LLVMValueRef,
+    /// With `debug_lines`, the DWARF descriptor of the source file.
+    /// Null otherwise.
+    debug_file: LLVMValueRef,
 }
 
 impl Module {
@@ -66,6 +177,17 @@ impl Drop for Builder {
 unsafe fn int8(val: c_ulonglong) -> LLVMValueRef {
     LLVMConstInt(LLVMInt8Type(), val, LLVM_FALSE)
 }
+/// Convert this cell value to a constant of our cell type. Negative
+/// values are sign extended, so -1 is the largest cell value.
+unsafe fn cell_const(module: &Module, val: Cell) -> LLVMValueRef {
+    LLVMConstInt(module.cell_type, val.0 as c_ulonglong, LLVM_FALSE)
+}
+
+/// The number of bytes in each cell.
+unsafe fn cell_bytes(module: &Module) -> usize {
+    LLVMGetIntTypeWidth(module.cell_type) as usize / 8
+}
+
 /// Convert this integer to LLVM's representation of a constant
 /// integer.
 unsafe fn int32(val: c_ulonglong) -> LLVMValueRef {
@@ -102,6 +224,109 @@ unsafe fn add_c_declarations(module: &mut Module) {
     add_function(module, "getchar", &mut vec![], LLVMInt32Type());
 }
 
+/// Declare read(), which we only need when reading from a file
+/// descriptor.
+unsafe fn add_read_declaration(module: &mut Module) {
+    if LLVMGetNamedFunction(module.module, module.new_string_ptr("read")).is_null() {
+        let byte_pointer = LLVMPointerType(LLVMInt8Type(), 0);
+        add_function(module,
+                     "read",
+                     &mut vec![LLVMInt32Type(), byte_pointer, LLVMInt32Type()],
+                     LLVMInt32Type());
+    }
+}
+
+/// Declare llvm.memcpy, which we only need to copy initial cell values
+/// from a constant.
+unsafe fn add_memcpy_declaration(module: &mut Module) {
+    let name = "llvm.memcpy.p0i8.p0i8.i32";
+    if LLVMGetNamedFunction(module.module, module.new_string_ptr(name)).is_null() {
+        let byte_pointer = LLVMPointerType(LLVMInt8Type(), 0);
+        add_function(module,
+                     name,
+                     &mut vec![byte_pointer, byte_pointer, LLVMInt32Type(),
+                               LLVMInt32Type(), LLVMInt1Type()],
+                     LLVMVoidType());
+    }
+}
+
+/// Copy `init_values` onto the tape at `cells_ptr` from a constant
+/// global.
+unsafe fn add_cells_copy(init_values: &[i8],
+                         cells_ptr: LLVMValueRef,
+                         module: &mut Module,
+                         bb: &mut LLVMBasicBlock) {
+    let builder = Builder::new();
+    builder.position_at_end(bb);
+
+    let mut llvm_values: Vec<_> = init_values.iter().map(|value| {
+        int8(*value as c_ulonglong)
+    }).collect();
+    let initial_cells_type = LLVMArrayType(LLVMInt8Type(), llvm_values.len() as c_uint);
+    let initial_cells = LLVMAddGlobal(module.module,
+                                      initial_cells_type,
+                                      module.new_string_ptr("initial_cells"));
+    LLVMSetInitializer(initial_cells, LLVMConstArray(LLVMInt8Type(),
+                                                     llvm_values.as_mut_ptr(),
+                                                     llvm_values.len() as c_uint));
+    LLVMSetGlobalConstant(initial_cells, LLVM_TRUE);
+
+    let byte_pointer = LLVMPointerType(LLVMInt8Type(), 0);
+    let initial_cells_ptr = LLVMBuildPointerCast(builder.builder,
+                                                 initial_cells,
+                                                 byte_pointer,
+                                                 module.new_string_ptr("initial_cells_ptr"));
+
+    add_memcpy_declaration(module);
+    let mut memcpy_args = vec![cells_ptr, initial_cells_ptr,
+                               int32(init_values.len() as c_ulonglong), int32(1),
+                               LLVMConstInt(LLVMInt1Type(), 0, LLVM_FALSE)];
+    add_function_call(module, bb, "llvm.memcpy.p0i8.p0i8.i32", &mut memcpy_args, "");
+}
+
+/// Declare calloc(), which we only need for tapes on the heap.
+/// `size_t` is the target's `size_t` type.
+unsafe fn add_calloc_declaration(module: &mut Module, size_t: LLVMTypeRef) {
+    if LLVMGetNamedFunction(module.module, module.new_string_ptr("calloc")).is_null() {
+        let byte_pointer = LLVMPointerType(LLVMInt8Type(), 0);
+        add_function(module, "calloc", &mut vec![size_t, size_t], byte_pointer);
+    }
+}
+
+/// Declare fflush(), which we only need to flush putchar() output
+/// before reading.
+unsafe fn add_fflush_declaration(module: &mut Module) {
+    if LLVMGetNamedFunction(module.module, module.new_string_ptr("fflush")).is_null() {
+        let byte_pointer = LLVMPointerType(LLVMInt8Type(), 0);
+        add_function(module, "fflush", &mut vec![byte_pointer], LLVMInt32Type());
+    }
+}
+
+/// Declare dprintf(), which we only need for `print_cells`.
+unsafe fn add_dprintf_declaration(module: &mut Module) {
+    if LLVMGetNamedFunction(module.module, module.new_string_ptr("dprintf")).is_null() {
+        let byte_pointer = LLVMPointerType(LLVMInt8Type(), 0);
+        let mut args = vec![LLVMInt32Type(), byte_pointer];
+        let fn_type =
+            LLVMFunctionType(LLVMInt32Type(), args.as_mut_ptr(), args.len() as u32, LLVM_TRUE);
+        LLVMAddFunction(module.module, module.new_string_ptr("dprintf"), fn_type);
+    }
+}
+
+/// Declare exit(), which we only need when we run out of fuel.
+unsafe fn add_exit_declaration(module: &mut Module) {
+    if LLVMGetNamedFunction(module.module, module.new_string_ptr("exit")).is_null() {
+        add_function(module, "exit", &mut vec![LLVMInt32Type()], LLVMVoidType());
+    }
+}
+
+/// Declare abort(), which we only need for bounds checks.
+unsafe fn add_abort_declaration(module: &mut Module) {
+    if LLVMGetNamedFunction(module.module, module.new_string_ptr("abort")).is_null() {
+        add_function(module, "abort", &mut vec![], LLVMVoidType());
+    }
+}
+
 // TODO: take slice here rather than Vec.
 unsafe fn add_function_call(module: &mut Module,
                             bb: &mut LLVMBasicBlock,
@@ -136,27 +361,128 @@ fn run_length_encode<T>(cells: &[T]) -> Vec<(T, usize)>
     }).collect()
 }
 
-unsafe fn add_cells_init(init_values: &[i8],
-                         module: &mut Module,
-                         bb: &mut LLVMBasicBlock)
-                         -> LLVMValueRef {
+/// Do we allocate a tape of `num_cells` cells on the heap?
+fn tape_on_heap(options: &CodegenOptions, num_cells: usize) -> bool {
+    options.heap_tape || num_cells >= HEAP_TAPE_THRESHOLD
+}
+
+/// Allocate a zeroed tape of `num_cells` cells with calloc(), and
+/// abort if that fails. With `tape_align`, we allocate `align - 1`
+/// extra bytes and round the pointer up. We never free the tape, as
+/// the OS reclaims it when the program exits.
+unsafe fn add_heap_tape<'a>(module: &mut Module,
+                            bb: &'a mut LLVMBasicBlock,
+                            num_cells: usize,
+                            options: &CodegenOptions)
+                            -> (LLVMValueRef, &'a mut LLVMBasicBlock) {
+    let size_t = LLVMIntType(pointer_bits(&options.target_triple));
+    add_calloc_declaration(module, size_t);
+    add_abort_declaration(module);
+
+    let align = options.tape_align.unwrap_or(1) as c_ulonglong;
+    let num_bytes = (num_cells * cell_bytes(module)) as c_ulonglong + align - 1;
+    let mut calloc_args = vec![LLVMConstInt(size_t, num_bytes, LLVM_FALSE),
+                               LLVMConstInt(size_t, 1, LLVM_FALSE)];
+    let tape = add_function_call(module, bb, "calloc", &mut calloc_args, "cells");
+
     let builder = Builder::new();
     builder.position_at_end(bb);
+    let tape_is_null = LLVMBuildIsNull(builder.builder,
+                                       tape,
+                                       module.new_string_ptr("tape_is_null"));
+
+    let main_fn = LLVMGetBasicBlockParent(bb);
+    let failed_bb = LLVMAppendBasicBlock(main_fn, module.new_string_ptr("tape_alloc_failed"));
+    let allocated_bb = LLVMAppendBasicBlock(main_fn, module.new_string_ptr("tape_allocated"));
+    LLVMBuildCondBr(builder.builder, tape_is_null, failed_bb, allocated_bb);
+
+    add_function_call(module, &mut *failed_bb, "abort", &mut vec![], "");
+    builder.position_at_end(failed_bb);
+    LLVMBuildUnreachable(builder.builder);
+
+    builder.position_at_end(allocated_bb);
+    if align == 1 {
+        return (tape, &mut *allocated_bb);
+    }
 
-    // Allocate stack memory for our cells.
+    // aligned = (tape + align - 1) & -align
+    let address = LLVMBuildPtrToInt(builder.builder,
+                                    tape,
+                                    size_t,
+                                    module.new_string_ptr("tape_address"));
+    let rounded_up = LLVMBuildAdd(builder.builder,
+                                  address,
+                                  LLVMConstInt(size_t, align - 1, LLVM_FALSE),
+                                  module.new_string_ptr("tape_address_rounded_up"));
+    let aligned = LLVMBuildAnd(builder.builder,
+                               rounded_up,
+                               LLVMConstInt(size_t, !(align - 1), LLVM_FALSE),
+                               module.new_string_ptr("aligned_tape_address"));
+    let aligned_tape = LLVMBuildIntToPtr(builder.builder,
+                                         aligned,
+                                         LLVMPointerType(LLVMInt8Type(), 0),
+                                         module.new_string_ptr("aligned_cells"));
+    (aligned_tape, &mut *allocated_bb)
+}
+
+unsafe fn add_cells_init<'a>(init_values: &[i8],
+                             options: &CodegenOptions,
+                             module: &mut Module,
+                             bb: &'a mut LLVMBasicBlock)
+                             -> (LLVMValueRef, &'a mut LLVMBasicBlock) {
     let num_cells = int32(init_values.len() as c_ulonglong);
-    let cells_ptr = LLVMBuildArrayAlloca(builder.builder,
-                                         LLVMInt8Type(),
-                                         num_cells,
-                                         module.new_string_ptr("cells"));
+    let on_heap = tape_on_heap(options, init_values.len());
+    let cell_bytes = cell_bytes(module);
+    let byte_pointer = LLVMPointerType(LLVMInt8Type(), 0);
+
+    let mut bb = bb as *mut LLVMBasicBlock;
+    let builder = Builder::new();
+    builder.position_at_end(bb);
+
+    let cells_ptr = if on_heap {
+        // calloc() zeroes the cells for us.
+        let (tape, allocated_bb) = add_heap_tape(module, &mut *bb, init_values.len(), options);
+        bb = allocated_bb;
+        builder.position_at_end(bb);
+        if cell_bytes == 1 {
+            tape
+        } else {
+            LLVMBuildPointerCast(builder.builder,
+                                 tape,
+                                 LLVMPointerType(module.cell_type, 0),
+                                 module.new_string_ptr("wide_cells"))
+        }
+    } else {
+        // Allocate stack memory for our cells.
+        let cells_ptr = LLVMBuildArrayAlloca(builder.builder,
+                                             module.cell_type,
+                                             num_cells,
+                                             module.new_string_ptr("cells"));
+        if let Some(align) = options.tape_align {
+            LLVMSetAlignment(cells_ptr, align);
+        }
+        cells_ptr
+    };
+
+    if options.rodata_cells && init_values.iter().any(|&value| value != 0) {
+        add_cells_copy(init_values, cells_ptr, module, &mut *bb);
+        return (cells_ptr, &mut *bb);
+    }
 
     let one = int32(1);
     let false_ = LLVMConstInt(LLVMInt1Type(), 1, LLVM_FALSE);
 
     let mut offset = 0;
     for (cell_val, cell_count) in run_length_encode(init_values) {
+        if on_heap && cell_val == 0 {
+            offset += cell_count;
+            continue;
+        }
+
+        // memset can only fill wide cells with zero.
+        assert!(cell_bytes == 1 || cell_val == 0);
         let llvm_cell_val = int8(cell_val as c_ulonglong);
-        let llvm_cell_count = int32(cell_count as c_ulonglong);
+        let llvm_cell_count = int32((cell_count * cell_bytes) as c_ulonglong);
 
         // TODO: factor out a build_gep function.
         let mut offset_vec = vec![int32(offset as c_ulonglong)];
@@ -165,15 +491,23 @@ unsafe fn add_cells_init(init_values: &[i8],
                                            offset_vec.as_mut_ptr(),
                                            offset_vec.len() as u32,
                                            module.new_string_ptr("offset_cell_ptr"));
+        let offset_cell_ptr = if cell_bytes == 1 {
+            offset_cell_ptr
+        } else {
+            LLVMBuildPointerCast(builder.builder,
+                                 offset_cell_ptr,
+                                 byte_pointer,
+                                 module.new_string_ptr("offset_byte_ptr"))
+        };
 
         let mut memset_args = vec![
             offset_cell_ptr, llvm_cell_val, llvm_cell_count, one, false_];
-        add_function_call(module, bb, "llvm.memset.p0i8.i32", &mut memset_args, "");
+        add_function_call(module, &mut *bb, "llvm.memset.p0i8.i32", &mut memset_args, "");
 
         offset += cell_count;
     }
 
-    cells_ptr
+    (cells_ptr, &mut *bb)
 }
 
 unsafe fn create_module(module_name: &str) -> Module {
@@ -181,21 +515,166 @@ unsafe fn create_module(module_name: &str) -> Module {
     
     let llvm_module = LLVMModuleCreateWithName(
         c_module_name.to_bytes_with_nul().as_ptr() as *const _);
-    let mut module = Module { module: llvm_module, strings: vec![c_module_name] };
+    let mut module = Module {
+        module: llvm_module,
+        strings: vec![c_module_name],
+        loop_count: 0,
+        access_checks: vec![],
+        instr_count: 0,
+        num_cells: 0,
+        cell_type: LLVMInt8Type(),
+        debug_scope: ptr::null_mut(),
+        debug_file: ptr::null_mut(),
+    };
     add_c_declarations(&mut module);
 
     module
 }
 
-unsafe fn add_main_fn(module: &mut Module) -> LLVMValueRef {
-    let mut main_args = vec![];
-    let main_type = LLVMFunctionType(LLVMInt32Type(), main_args.as_mut_ptr(), 0, LLVM_FALSE);
+/// The name of the function we compile the program to with
+/// `global_ctor`.
+const CTOR_FN_NAME: &'static str = "bf_main";
+
+/// Add a `void bf_main()` function, which runs before `main`.
+unsafe fn add_ctor_fn(module: &mut Module) -> LLVMValueRef {
+    let mut args = vec![];
+    let ctor_type = LLVMFunctionType(LLVMVoidType(), args.as_mut_ptr(), 0, LLVM_FALSE);
+    let ctor_fn = LLVMAddFunction(module.module, module.new_string_ptr(CTOR_FN_NAME), ctor_type);
+
+    // @llvm.global_ctors = appending global [1 x { i32, void ()* }]
+    //     [{ i32, void ()* } { i32 65535, void ()* @bf_main }]
+    let mut entry_types = vec![LLVMInt32Type(), LLVMPointerType(ctor_type, 0)];
+    let entry_type = LLVMStructType(entry_types.as_mut_ptr(), entry_types.len() as c_uint,
+                                    LLVM_FALSE);
+    let mut entry_fields = vec![int32(65535), ctor_fn];
+    let mut entries = vec![LLVMConstStruct(entry_fields.as_mut_ptr(),
+                                           entry_fields.len() as c_uint,
+                                           LLVM_FALSE)];
+
+    let ctors = LLVMAddGlobal(module.module,
+                              LLVMArrayType(entry_type, 1),
+                              module.new_string_ptr("llvm.global_ctors"));
+    LLVMSetInitializer(ctors, LLVMConstArray(entry_type, entries.as_mut_ptr(), 1));
+    LLVMSetLinkage(ctors, LLVMLinkage::LLVMAppendingLinkage);
+
+    LLVMAppendBasicBlock(ctor_fn, module.new_string_ptr("entry"));
+    ctor_fn
+}
+
+/// Add a main function, taking argc and argv if `with_args` is true.
+unsafe fn add_main_fn(module: &mut Module, with_args: bool) -> LLVMValueRef {
+    let mut main_args = if with_args {
+        let byte_pointer = LLVMPointerType(LLVMInt8Type(), 0);
+        vec![LLVMInt32Type(), LLVMPointerType(byte_pointer, 0)]
+    } else {
+        vec![]
+    };
+    let main_type = LLVMFunctionType(LLVMInt32Type(),
+                                     main_args.as_mut_ptr(),
+                                     main_args.len() as c_uint,
+                                     LLVM_FALSE);
     let main_fn = LLVMAddFunction(module.module, module.new_string_ptr("main"), main_type);
 
+    if with_args {
+        LLVMSetValueName(LLVMGetParam(main_fn, 0), module.new_string_ptr("argc"));
+        LLVMSetValueName(LLVMGetParam(main_fn, 1), module.new_string_ptr("argv"));
+    }
+
     LLVMAppendBasicBlock(main_fn, module.new_string_ptr("entry"));
     main_fn
 }
 
+/// Copy the bytes of argv[1] (if present) into the cells, stopping
+/// at the terminating NUL or when we run out of cells. Returns the
+/// basic block to continue compiling in.
+unsafe fn add_argv_copy(module: &mut Module,
+                        bb: *mut LLVMBasicBlock,
+                        main_fn: LLVMValueRef,
+                        cells: LLVMValueRef,
+                        num_cells: usize)
+                        -> *mut LLVMBasicBlock {
+    let builder = Builder::new();
+    builder.position_at_end(bb);
+
+    let argc = LLVMGetParam(main_fn, 0);
+    let argv = LLVMGetParam(main_fn, 1);
+
+    let setup_bb = LLVMAppendBasicBlock(main_fn, module.new_string_ptr("argv_setup"));
+    let header_bb = LLVMAppendBasicBlock(main_fn, module.new_string_ptr("argv_header"));
+    let char_bb = LLVMAppendBasicBlock(main_fn, module.new_string_ptr("argv_char"));
+    let store_bb = LLVMAppendBasicBlock(main_fn, module.new_string_ptr("argv_store"));
+    let after_bb = LLVMAppendBasicBlock(main_fn, module.new_string_ptr("argv_after"));
+
+    // if (argc > 1) { ... }
+    let has_arg = LLVMBuildICmp(builder.builder,
+                                LLVMIntPredicate::LLVMIntSGT,
+                                argc,
+                                int32(1),
+                                module.new_string_ptr("has_arg"));
+    LLVMBuildCondBr(builder.builder, has_arg, setup_bb, after_bb);
+
+    // char *arg = argv[1]; int argv_index = 0;
+    builder.position_at_end(setup_bb);
+    let mut indices = vec![int32(1)];
+    let arg_ptr = LLVMBuildGEP(builder.builder,
+                               argv,
+                               indices.as_mut_ptr(),
+                               indices.len() as c_uint,
+                               module.new_string_ptr("arg_ptr"));
+    let arg = LLVMBuildLoad(builder.builder, arg_ptr, module.new_string_ptr("arg"));
+    let argv_index_ptr = LLVMBuildAlloca(builder.builder,
+                                         LLVMInt32Type(),
+                                         module.new_string_ptr("argv_index_ptr"));
+    LLVMBuildStore(builder.builder, int32(0), argv_index_ptr);
+    LLVMBuildBr(builder.builder, header_bb);
+
+    // while (argv_index < num_cells) { ... }
+    builder.position_at_end(header_bb);
+    let argv_index = LLVMBuildLoad(builder.builder,
+                                   argv_index_ptr,
+                                   module.new_string_ptr("argv_index"));
+    let in_bounds = LLVMBuildICmp(builder.builder,
+                                  LLVMIntPredicate::LLVMIntSLT,
+                                  argv_index,
+                                  int32(num_cells as c_ulonglong),
+                                  module.new_string_ptr("argv_index_in_bounds"));
+    LLVMBuildCondBr(builder.builder, in_bounds, char_bb, after_bb);
+
+    // if (arg[argv_index] == 0) break;
+    builder.position_at_end(char_bb);
+    let mut indices = vec![argv_index];
+    let char_ptr = LLVMBuildGEP(builder.builder,
+                                arg,
+                                indices.as_mut_ptr(),
+                                indices.len() as c_uint,
+                                module.new_string_ptr("argv_char_ptr"));
+    let arg_char = LLVMBuildLoad(builder.builder, char_ptr, module.new_string_ptr("argv_char"));
+    let is_nul = LLVMBuildICmp(builder.builder,
+                               LLVMIntPredicate::LLVMIntEQ,
+                               arg_char,
+                               int8(0),
+                               module.new_string_ptr("argv_char_is_nul"));
+    LLVMBuildCondBr(builder.builder, is_nul, after_bb, store_bb);
+
+    // cells[argv_index] = arg[argv_index]; argv_index++;
+    builder.position_at_end(store_bb);
+    let mut indices = vec![argv_index];
+    let cell_ptr = LLVMBuildGEP(builder.builder,
+                                cells,
+                                indices.as_mut_ptr(),
+                                indices.len() as c_uint,
+                                module.new_string_ptr("argv_cell_ptr"));
+    LLVMBuildStore(builder.builder, arg_char, cell_ptr);
+    let next_index = LLVMBuildAdd(builder.builder,
+                                  argv_index,
+                                  int32(1),
+                                  module.new_string_ptr("next_argv_index"));
+    LLVMBuildStore(builder.builder, next_index, argv_index_ptr);
+    LLVMBuildBr(builder.builder, header_bb);
+
+    after_bb
+}
+
 // TODO: name our pointers cell_base and
 // cell_offset_ptr.
 /// Initialise the value that contains the current cell index.
@@ -216,13 +695,49 @@ unsafe fn add_cell_index_init(init_value: i32,
     cell_index_ptr
 }
 
-/// Add prologue to main function.
-unsafe fn add_main_cleanup(bb: *mut LLVMBasicBlock) {
+/// Add prologue to main function. Constructors don't return
+/// anything.
+unsafe fn add_main_cleanup(bb: *mut LLVMBasicBlock, global_ctor: bool) {
     let builder = Builder::new();
     builder.position_at_end(bb);
 
-    let zero = int32(0);
-    LLVMBuildRet(builder.builder, zero);
+    if global_ctor {
+        LLVMBuildRetVoid(builder.builder);
+    } else {
+        let zero = int32(0);
+        LLVMBuildRet(builder.builder, zero);
+    }
+}
+
+/// Add LLVM IR instructions for calculating a pointer to the cell at
+/// `offset` from the current cell.
+unsafe fn add_cell_ptr(module: &mut Module,
+                       bb: &mut LLVMBasicBlock,
+                       cells: LLVMValueRef,
+                       cell_index_ptr: LLVMValueRef,
+                       offset: isize)
+                       -> LLVMValueRef {
+    let builder = Builder::new();
+    builder.position_at_end(bb);
+
+    let cell_index = LLVMBuildLoad(builder.builder,
+                                   cell_index_ptr,
+                                   module.new_string_ptr("cell_index"));
+    let target_index = if offset == 0 {
+        cell_index
+    } else {
+        LLVMBuildAdd(builder.builder,
+                     cell_index,
+                     int32(offset as c_ulonglong),
+                     module.new_string_ptr("offset_cell_index"))
+    };
+
+    let mut indices = vec![target_index];
+    LLVMBuildGEP(builder.builder,
+                 cells,
+                 indices.as_mut_ptr(),
+                 indices.len() as u32,
+                 module.new_string_ptr("current_cell_ptr"))
 }
 
 /// Add LLVM IR instructions for accessing the current cell, and
@@ -235,16 +750,7 @@ unsafe fn add_current_cell_access(module: &mut Module,
     let builder = Builder::new();
     builder.position_at_end(bb);
 
-    let cell_index = LLVMBuildLoad(builder.builder,
-                                   cell_index_ptr,
-                                   module.new_string_ptr("cell_index"));
-
-    let mut indices = vec![cell_index];
-    let current_cell_ptr = LLVMBuildGEP(builder.builder,
-                                        cells,
-                                        indices.as_mut_ptr(),
-                                        indices.len() as u32,
-                                        module.new_string_ptr("current_cell_ptr"));
+    let current_cell_ptr = add_cell_ptr(module, bb, cells, cell_index_ptr, 0);
     let current_cell = LLVMBuildLoad(builder.builder,
                                      current_cell_ptr,
                                      module.new_string_ptr("cell_value"));
@@ -252,7 +758,61 @@ unsafe fn add_current_cell_access(module: &mut Module,
     (current_cell, current_cell_ptr)
 }
 
+/// Check that the cells at `offsets` from the current cell are on the
+/// tape, and call abort() if not. Returns the block to continue
+/// compiling in.
+unsafe fn add_bounds_check<'a>(module: &mut Module,
+                               bb: &'a mut LLVMBasicBlock,
+                               main_fn: LLVMValueRef,
+                               cell_index_ptr: LLVMValueRef,
+                               offsets: &[isize])
+                               -> &'a mut LLVMBasicBlock {
+    add_abort_declaration(module);
+
+    let builder = Builder::new();
+    builder.position_at_end(bb);
+
+    let cell_index = LLVMBuildLoad(builder.builder,
+                                   cell_index_ptr,
+                                   module.new_string_ptr("cell_index"));
+    let num_cells = int32(module.num_cells as c_ulonglong);
+
+    let mut in_bounds = LLVMConstInt(LLVMInt1Type(), 1, LLVM_FALSE);
+    for &offset in offsets {
+        let target_index = if offset == 0 {
+            cell_index
+        } else {
+            LLVMBuildAdd(builder.builder,
+                         cell_index,
+                         int32(offset as c_ulonglong),
+                         module.new_string_ptr("offset_cell_index"))
+        };
+        // Negative indexes are large unsigned numbers, so one
+        // unsigned comparison checks both ends of the tape.
+        let index_in_bounds = LLVMBuildICmp(builder.builder,
+                                            LLVMIntPredicate::LLVMIntULT,
+                                            target_index,
+                                            num_cells,
+                                            module.new_string_ptr("index_in_bounds"));
+        in_bounds = LLVMBuildAnd(builder.builder,
+                                 in_bounds,
+                                 index_in_bounds,
+                                 module.new_string_ptr("in_bounds"));
+    }
+
+    let out_of_bounds_bb = LLVMAppendBasicBlock(main_fn, module.new_string_ptr("out_of_bounds"));
+    let in_bounds_bb = LLVMAppendBasicBlock(main_fn, module.new_string_ptr("in_bounds"));
+    LLVMBuildCondBr(builder.builder, in_bounds, in_bounds_bb, out_of_bounds_bb);
+
+    add_function_call(module, &mut *out_of_bounds_bb, "abort", &mut vec![], "");
+    builder.position_at_end(out_of_bounds_bb);
+    LLVMBuildUnreachable(builder.builder);
+
+    &mut *in_bounds_bb
+}
+
 unsafe fn compile_increment<'a>(amount: Cell,
+                                offset: isize,
                                 module: &mut Module,
                                 bb: &'a mut LLVMBasicBlock,
                                 cells: LLVMValueRef,
@@ -261,9 +821,12 @@ unsafe fn compile_increment<'a>(amount: Cell,
     let builder = Builder::new();
     builder.position_at_end(bb);
 
-    let (cell_val, cell_val_ptr) = add_current_cell_access(module, bb, cells, cell_index_ptr);
+    let cell_val_ptr = add_cell_ptr(module, bb, cells, cell_index_ptr, offset);
+    let cell_val = LLVMBuildLoad(builder.builder,
+                                 cell_val_ptr,
+                                 module.new_string_ptr("cell_value"));
 
-    let increment_amount = int8(amount.0 as c_ulonglong);
+    let increment_amount = cell_const(module, amount);
     let new_cell_val = LLVMBuildAdd(builder.builder,
                                     cell_val,
                                     increment_amount,
@@ -273,7 +836,50 @@ unsafe fn compile_increment<'a>(amount: Cell,
     bb
 }
 
+/// Add `amount` to the current cell, stopping at 0 or 255 rather
+/// than wrapping around.
+unsafe fn compile_saturating_increment<'a>(amount: Cell,
+                                           module: &mut Module,
+                                           bb: &'a mut LLVMBasicBlock,
+                                           cells: LLVMValueRef,
+                                           cell_index_ptr: LLVMValueRef)
+                                           -> &'a mut LLVMBasicBlock {
+    let builder = Builder::new();
+    builder.position_at_end(bb);
+
+    let (cell_val, cell_val_ptr) = add_current_cell_access(module, bb, cells, cell_index_ptr);
+
+    let increment_amount = cell_const(module, amount);
+    let wrapped_val = LLVMBuildAdd(builder.builder,
+                                   cell_val,
+                                   increment_amount,
+                                   module.new_string_ptr("wrapped_cell_value"));
+
+    // Treating the cell as unsigned, we would overflow if the old
+    // value is above max - amount, or underflow if it's below -amount.
+    let max_value = (1u64 << (cell_bytes(module) * 8)) - 1;
+    let (predicate, threshold, limit) = if amount.0 >= 0 {
+        (LLVMIntPredicate::LLVMIntUGT, max_value - amount.0 as u64, max_value)
+    } else {
+        (LLVMIntPredicate::LLVMIntULT, -(amount.0 as i64) as u64, 0)
+    };
+    let out_of_range = LLVMBuildICmp(builder.builder,
+                                     predicate,
+                                     cell_val,
+                                     LLVMConstInt(module.cell_type, threshold, LLVM_FALSE),
+                                     module.new_string_ptr("out_of_range"));
+    let new_cell_val = LLVMBuildSelect(builder.builder,
+                                       out_of_range,
+                                       LLVMConstInt(module.cell_type, limit, LLVM_FALSE),
+                                       wrapped_val,
+                                       module.new_string_ptr("new_cell_value"));
+
+    LLVMBuildStore(builder.builder, new_cell_val, cell_val_ptr);
+    bb
+}
+
 unsafe fn compile_set<'a>(amount: Cell,
+                          offset: isize,
                           module: &mut Module,
                           bb: &'a mut LLVMBasicBlock,
                           cells: LLVMValueRef,
@@ -282,18 +888,9 @@ unsafe fn compile_set<'a>(amount: Cell,
     let builder = Builder::new();
     builder.position_at_end(bb);
 
-    let cell_index = LLVMBuildLoad(builder.builder,
-                                   cell_index_ptr,
-                                   module.new_string_ptr("cell_index"));
+    let current_cell_ptr = add_cell_ptr(module, bb, cells, cell_index_ptr, offset);
 
-    let mut indices = vec![cell_index];
-    let current_cell_ptr = LLVMBuildGEP(builder.builder,
-                                        cells,
-                                        indices.as_mut_ptr(),
-                                        indices.len() as c_uint,
-                                        module.new_string_ptr("current_cell_ptr"));
-
-    LLVMBuildStore(builder.builder, int8(amount.0 as c_ulonglong), current_cell_ptr);
+    LLVMBuildStore(builder.builder, cell_const(module, amount), current_cell_ptr);
     bb
 }
 
@@ -310,7 +907,7 @@ unsafe fn compile_multiply_move<'a>(changes: &HashMap<isize, Cell>,
     let (cell_val, cell_val_ptr) = add_current_cell_access(module, bb, cells, cell_index_ptr);
 
     // Zero the current cell.
-    LLVMBuildStore(builder.builder, int8(0), cell_val_ptr);
+    LLVMBuildStore(builder.builder, cell_const(module, Wrapping(0)), cell_val_ptr);
 
     let mut targets: Vec<_> = changes.keys().collect();
     targets.sort();
@@ -335,7 +932,7 @@ unsafe fn compile_multiply_move<'a>(changes: &HashMap<isize, Cell>,
         let factor = *changes.get(target).unwrap();
         let additional_val = LLVMBuildMul(builder.builder,
                                           cell_val,
-                                          int8(factor.0 as c_ulonglong),
+                                          cell_const(module, factor),
                                           module.new_string_ptr("additional_val"));
         let new_target_val = LLVMBuildAdd(builder.builder,
                                           target_cell_val,
@@ -368,82 +965,251 @@ unsafe fn compile_ptr_increment<'a>(amount: isize,
     bb
 }
 
-unsafe fn compile_read<'a>(module: &mut Module,
+unsafe fn compile_read<'a>(offset: isize,
+                           module: &mut Module,
                            bb: &'a mut LLVMBasicBlock,
                            cells: LLVMValueRef,
-                           cell_index_ptr: LLVMValueRef)
+                           cell_index_ptr: LLVMValueRef,
+                           options: &CodegenOptions)
                            -> &'a mut LLVMBasicBlock {
     let builder = Builder::new();
     builder.position_at_end(bb);
 
-    let cell_index = LLVMBuildLoad(builder.builder,
-                                   cell_index_ptr,
-                                   module.new_string_ptr("cell_index"));
+    let current_cell_ptr = add_cell_ptr(module, bb, cells, cell_index_ptr, offset);
+
+    // putchar() output is buffered, so flush it before we block on
+    // input. Otherwise an interactive program may wait for input
+    // before the user has seen its prompt. fflush(NULL) flushes
+    // stdout without us needing to know how libc names it.
+    if options.output_fd.is_none() {
+        add_fflush_declaration(module);
+        let byte_pointer = LLVMPointerType(LLVMInt8Type(), 0);
+        let mut fflush_args = vec![LLVMConstNull(byte_pointer)];
+        add_function_call(module, bb, "fflush", &mut fflush_args, "");
+    }
 
-    let mut indices = vec![cell_index];
-    let current_cell_ptr = LLVMBuildGEP(builder.builder,
-                                        cells,
-                                        indices.as_mut_ptr(),
-                                        indices.len() as u32,
-                                        module.new_string_ptr("current_cell_ptr"));
+    if let Some(fd) = options.input_fd {
+        // read(fd, current_cell_ptr, 1)
+        add_read_declaration(module);
+        let mut read_args = vec![int32(fd as c_ulonglong), current_cell_ptr, int32(1)];
+        add_function_call(module, bb, "read", &mut read_args, "bytes_read");
+        return bb;
+    }
 
     let mut getchar_args = vec![];
     let input_char = add_function_call(module, bb, "getchar", &mut getchar_args, "input_char");
     let input_byte = LLVMBuildTrunc(builder.builder,
                                     input_char,
-                                    LLVMInt8Type(),
+                                    module.cell_type,
                                     module.new_string_ptr("input_byte"));
 
+    // getchar() returns -1 at EOF, so the truncated value already
+    // has every bit set. Otherwise, choose the value to store.
+    let eof_value = match options.eof {
+        EofBehaviour::NegOne => None,
+        EofBehaviour::Zero => Some(cell_const(module, Wrapping(0))),
+        EofBehaviour::Unchanged => {
+            Some(LLVMBuildLoad(builder.builder,
+                               current_cell_ptr,
+                               module.new_string_ptr("old_cell_value")))
+        }
+    };
+    let input_byte = match eof_value {
+        Some(eof_value) => {
+            let is_eof = LLVMBuildICmp(builder.builder,
+                                       LLVMIntPredicate::LLVMIntEQ,
+                                       input_char,
+                                       int32(-1i32 as c_ulonglong),
+                                       module.new_string_ptr("is_eof"));
+            LLVMBuildSelect(builder.builder,
+                            is_eof,
+                            eof_value,
+                            input_byte,
+                            module.new_string_ptr("input_value"))
+        }
+        None => input_byte,
+    };
+
     LLVMBuildStore(builder.builder, input_byte, current_cell_ptr);
     bb
 }
 
-unsafe fn compile_write<'a>(module: &mut Module,
+unsafe fn compile_write<'a>(offset: isize,
+                            module: &mut Module,
                             bb: &'a mut LLVMBasicBlock,
                             cells: LLVMValueRef,
-                            cell_index_ptr: LLVMValueRef)
+                            cell_index_ptr: LLVMValueRef,
+                            options: &CodegenOptions)
                             -> &'a mut LLVMBasicBlock {
     let builder = Builder::new();
     builder.position_at_end(bb);
 
-    let cell_val = add_current_cell_access(module, bb, cells, cell_index_ptr).0;
+    let cell_val_ptr = add_cell_ptr(module, bb, cells, cell_index_ptr, offset);
+
+    if let Some(fd) = options.output_fd {
+        // write(fd, cell_val_ptr, 1)
+        let mut write_args = vec![int32(fd as c_ulonglong), cell_val_ptr, int32(1)];
+        let result_name = if options.named_values { "write_result" } else { "" };
+        add_function_call(module, bb, "write", &mut write_args, result_name);
+        return bb;
+    }
+    let cell_val = LLVMBuildLoad(builder.builder,
+                                 cell_val_ptr,
+                                 module.new_string_ptr("cell_value"));
     let cell_val_as_char = LLVMBuildSExt(builder.builder,
                                          cell_val,
                                          LLVMInt32Type(),
                                          module.new_string_ptr("cell_val_as_char"));
 
     let mut putchar_args = vec![cell_val_as_char];
-    add_function_call(module, bb, "putchar", &mut putchar_args, "");
+    let result_name = if options.named_values { "putchar_result" } else { "" };
+    add_function_call(module, bb, "putchar", &mut putchar_args, result_name);
+    bb
+}
+
+/// Compile a loop as a function `void @loopN(i8* cells, i32*
+/// cell_index_ptr)`, which runs the whole loop, and call it from `bb`.
+///
+/// The loop branches back to its header rather than recursing, so
+/// long-running loops don't grow the stack, however llc optimises.
+unsafe fn compile_loop_function<'a>(module: &mut Module,
+                                    bb: &'a mut LLVMBasicBlock,
+                                    loop_body: &[Instruction],
+                                    cells: LLVMValueRef,
+                                    cell_index_ptr: LLVMValueRef,
+                                    check_condition: bool,
+                                    options: &CodegenOptions)
+                                    -> &'a mut LLVMBasicBlock {
+    let builder = Builder::new();
+
+    let fn_name = format!("loop{}", module.loop_count);
+    module.loop_count += 1;
+
+    let cell_pointer = LLVMPointerType(module.cell_type, 0);
+    let mut param_types = vec![cell_pointer, LLVMPointerType(LLVMInt32Type(), 0)];
+    let fn_type = LLVMFunctionType(LLVMVoidType(),
+                                   param_types.as_mut_ptr(),
+                                   param_types.len() as c_uint,
+                                   LLVM_FALSE);
+    let loop_fn = LLVMAddFunction(module.module, module.new_string_ptr(&fn_name), fn_type);
+    LLVMSetLinkage(loop_fn, LLVMLinkage::LLVMInternalLinkage);
+
+    let fn_cells = LLVMGetParam(loop_fn, 0);
+    LLVMSetValueName(fn_cells, module.new_string_ptr("cells"));
+    let fn_cell_index_ptr = LLVMGetParam(loop_fn, 1);
+    LLVMSetValueName(fn_cell_index_ptr, module.new_string_ptr("cell_index_ptr"));
+
+    // entry:
+    //   br %loop_header
+    // loop_header:
+    //   %cell_value = ...
+    //   %cell_value_is_zero = icmp ...
+    //   br %cell_value_is_zero, %loop_after, %loop_body
+    let entry_bb = LLVMAppendBasicBlock(loop_fn, module.new_string_ptr("entry"));
+    let loop_header_bb = LLVMAppendBasicBlock(loop_fn, module.new_string_ptr("loop_header"));
+    let mut loop_body_bb = LLVMAppendBasicBlock(loop_fn, module.new_string_ptr("loop_body"));
+    let loop_after = LLVMAppendBasicBlock(loop_fn, module.new_string_ptr("loop_after"));
+
+    builder.position_at_end(entry_bb);
+    LLVMBuildBr(builder.builder, loop_header_bb);
+
+    let condition_bb = if check_condition {
+        add_bounds_check(module, &mut *loop_header_bb, loop_fn, fn_cell_index_ptr, &[0])
+            as *mut LLVMBasicBlock
+    } else {
+        loop_header_bb
+    };
+    builder.position_at_end(condition_bb);
+    let cell_val = add_current_cell_access(module, &mut *condition_bb, fn_cells,
+                                           fn_cell_index_ptr).0;
+    let cell_val_is_zero = LLVMBuildICmp(builder.builder,
+                                         LLVMIntPredicate::LLVMIntEQ,
+                                         cell_const(module, Wrapping(0)),
+                                         cell_val,
+                                         module.new_string_ptr("cell_value_is_zero"));
+    LLVMBuildCondBr(builder.builder, cell_val_is_zero, loop_after, loop_body_bb);
+
+    for instr in loop_body {
+        loop_body_bb = compile_instr(instr, module, &mut *loop_body_bb, loop_fn, fn_cells,
+                                     fn_cell_index_ptr, options);
+    }
+
+    if options.fuel.is_some() {
+        loop_body_bb = add_fuel_check(module, &mut *loop_body_bb, loop_fn);
+    }
+
+    // loop_body:
+    //   ...
+    //   br %loop_header
+    builder.position_at_end(loop_body_bb);
+    LLVMBuildBr(builder.builder, loop_header_bb);
+
+    builder.position_at_end(loop_after);
+    LLVMBuildRetVoid(builder.builder);
+
+    // Finally, call the loop function from the current block.
+    builder.position_at_end(bb);
+    let mut args = vec![cells, cell_index_ptr];
+    LLVMBuildCall(builder.builder,
+                  loop_fn,
+                  args.as_mut_ptr(),
+                  args.len() as c_uint,
+                  module.new_string_ptr(""));
     bb
 }
 
 unsafe fn compile_loop<'a>(module: &mut Module,
                            bb: &'a mut LLVMBasicBlock,
+                           condition_offset: isize,
                            loop_body: &[Instruction],
                            main_fn: LLVMValueRef,
                            cells: LLVMValueRef,
-                           cell_index_ptr: LLVMValueRef)
+                           cell_index_ptr: LLVMValueRef,
+                           check_condition: bool,
+                           options: &CodegenOptions)
                            -> &'a mut LLVMBasicBlock {
     let builder = Builder::new();
 
+    let (header_name, body_name, after_name) = if options.named_values {
+        let loop_id = module.loop_count;
+        (format!("loop{}.cond", loop_id),
+         format!("loop{}.body", loop_id),
+         format!("loop{}.after", loop_id))
+    } else {
+        ("loop_header".to_owned(), "loop_body".to_owned(), "loop_after".to_owned())
+    };
+    module.loop_count += 1;
+
     // First, we branch into the loop header from the previous basic
     // block.
-    let loop_header_bb = LLVMAppendBasicBlock(main_fn, module.new_string_ptr("loop_header"));
+    let loop_header_bb = LLVMAppendBasicBlock(main_fn, module.new_string_ptr(&header_name));
     builder.position_at_end(bb);
     LLVMBuildBr(builder.builder, loop_header_bb);
 
-    let mut loop_body_bb = LLVMAppendBasicBlock(main_fn, module.new_string_ptr("loop_body"));
-    let loop_after = LLVMAppendBasicBlock(main_fn, module.new_string_ptr("loop_after"));
+    let mut loop_body_bb = LLVMAppendBasicBlock(main_fn, module.new_string_ptr(&body_name));
+    let loop_after = LLVMAppendBasicBlock(main_fn, module.new_string_ptr(&after_name));
 
     // loop_header:
     //   %cell_value = ...
     //   %cell_value_is_zero = icmp ...
     //   br %cell_value_is_zero, %loop_after, %loop_body
-    builder.position_at_end(loop_header_bb);
-
-    let cell_val = add_current_cell_access(module, &mut *loop_header_bb, cells, cell_index_ptr).0;
-
-    let zero = int8(0);
+    // The loop tests its condition cell every iteration, so the check
+    // goes in the header.
+    let condition_bb = if check_condition {
+        add_bounds_check(module, &mut *loop_header_bb, main_fn, cell_index_ptr,
+                         &[condition_offset]) as *mut LLVMBasicBlock
+    } else {
+        loop_header_bb
+    };
+
+    let condition_cell_ptr = add_cell_ptr(module, &mut *condition_bb, cells, cell_index_ptr,
+                                          condition_offset);
+    builder.position_at_end(condition_bb);
+    let cell_val = LLVMBuildLoad(builder.builder,
+                                 condition_cell_ptr,
+                                 module.new_string_ptr("cell_value"));
+
+    let zero = cell_const(module, Wrapping(0));
     let cell_val_is_zero = LLVMBuildICmp(builder.builder,
                                          LLVMIntPredicate::LLVMIntEQ,
                                          zero,
@@ -454,42 +1220,427 @@ unsafe fn compile_loop<'a>(module: &mut Module,
     // Recursively compile instructions in the loop body.
     for instr in loop_body {
         loop_body_bb = compile_instr(instr, module, &mut *loop_body_bb, main_fn, cells,
-                                     cell_index_ptr);
+                                     cell_index_ptr, options);
+    }
+
+    if options.fuel.is_some() {
+        loop_body_bb = add_fuel_check(module, &mut *loop_body_bb, main_fn);
     }
 
     // When the loop is finished, jump back to the beginning of the
     // loop.
     builder.position_at_end(loop_body_bb);
-    LLVMBuildBr(builder.builder, loop_header_bb);
+    let latch = LLVMBuildBr(builder.builder, loop_header_bb);
+    add_loop_metadata(module, latch);
 
     &mut *loop_after
 }
 
+/// Attach `!llvm.loop` metadata to the branch at the end of a loop
+/// body. We don't give any optimisation hints yet, but this gives
+/// each loop a distinct ID that later passes can refer to.
+unsafe fn add_loop_metadata(module: &mut Module, latch: LLVMValueRef) {
+    // A loop ID must refer to itself as its first operand. We can't
+    // build a cycle directly, so we build the node with a
+    // placeholder operand, then replace the placeholder.
+    let mut placeholder_operands = vec![];
+    let placeholder = LLVMMDNode(placeholder_operands.as_mut_ptr(), 0);
+
+    let mut loop_id_operands = vec![placeholder];
+    let loop_id = LLVMMDNode(loop_id_operands.as_mut_ptr(),
+                             loop_id_operands.len() as c_uint);
+    LLVMReplaceAllUsesWith(placeholder, loop_id);
+
+    let kind_name = "llvm.loop";
+    let kind_id = LLVMGetMDKindID(module.new_string_ptr(kind_name), kind_name.len() as c_uint);
+    LLVMSetMetadata(latch, kind_id, loop_id);
+}
+
+/// Compile a scan loop, which moves the cell pointer by `stride`
+/// until it finds a zero cell, checking `unroll_factor` cells per
+/// iteration. If `check_access` is set, we also check every cell we
+/// move to is on the tape:
+///
+/// ```text
+/// scan_header:
+///   br %cell_value_is_zero, %scan_after, %scan_body
+/// scan_body:
+///   ; move and check, unroll_factor times
+///   br %cell_value_is_zero, %scan_after, %scan_step
+/// scan_step:
+///   ...
+///   br %cell_value_is_zero, %scan_after, %scan_body
+/// ```
+unsafe fn compile_scan_loop<'a>(module: &mut Module,
+                                bb: &'a mut LLVMBasicBlock,
+                                stride: isize,
+                                unroll_factor: usize,
+                                check_access: bool,
+                                main_fn: LLVMValueRef,
+                                cells: LLVMValueRef,
+                                cell_index_ptr: LLVMValueRef)
+                                -> &'a mut LLVMBasicBlock {
+    let builder = Builder::new();
+    module.loop_count += 1;
+
+    let scan_header = LLVMAppendBasicBlock(main_fn, module.new_string_ptr("scan_header"));
+    builder.position_at_end(bb);
+    LLVMBuildBr(builder.builder, scan_header);
+
+    let scan_body = LLVMAppendBasicBlock(main_fn, module.new_string_ptr("scan_body"));
+    let scan_after = LLVMAppendBasicBlock(main_fn, module.new_string_ptr("scan_after"));
+
+    // We only need to check the starting cell once. Every other
+    // check happens straight after a move.
+    let cell_val = add_current_cell_access(module, &mut *scan_header, cells, cell_index_ptr).0;
+    builder.position_at_end(scan_header);
+    let cell_val_is_zero = LLVMBuildICmp(builder.builder,
+                                         LLVMIntPredicate::LLVMIntEQ,
+                                         cell_const(module, Wrapping(0)),
+                                         cell_val,
+                                         module.new_string_ptr("cell_value_is_zero"));
+    LLVMBuildCondBr(builder.builder, cell_val_is_zero, scan_after, scan_body);
+
+    let mut step_bb = scan_body;
+    for step in 0..unroll_factor {
+        step_bb = compile_ptr_increment(stride, module, &mut *step_bb, cell_index_ptr);
+        if check_access {
+            step_bb = add_bounds_check(module, &mut *step_bb, main_fn, cell_index_ptr, &[0]);
+        }
+        let cell_val = add_current_cell_access(module, &mut *step_bb, cells, cell_index_ptr).0;
+
+        builder.position_at_end(step_bb);
+        let cell_val_is_zero = LLVMBuildICmp(builder.builder,
+                                             LLVMIntPredicate::LLVMIntEQ,
+                                             cell_const(module, Wrapping(0)),
+                                             cell_val,
+                                             module.new_string_ptr("cell_value_is_zero"));
+
+        if step + 1 == unroll_factor {
+            // After the last step, go round again.
+            let latch = LLVMBuildCondBr(builder.builder, cell_val_is_zero, scan_after, scan_body);
+            add_loop_metadata(module, latch);
+        } else {
+            let next_bb = LLVMAppendBasicBlock(main_fn, module.new_string_ptr("scan_step"));
+            LLVMBuildCondBr(builder.builder, cell_val_is_zero, scan_after, next_bb);
+            step_bb = next_bb;
+        }
+    }
+
+    &mut *scan_after
+}
+
+/// A metadata string. LLVM copies the bytes, and they may contain
+/// NULs.
+unsafe fn md_string(s: &str) -> LLVMValueRef {
+    LLVMMDString(s.as_ptr() as *const _, s.len() as c_uint)
+}
+
+unsafe fn md_node(operands: &[LLVMValueRef]) -> LLVMValueRef {
+    let mut operands = operands.to_vec();
+    LLVMMDNode(operands.as_mut_ptr(), operands.len() as c_uint)
+}
+
+/// Describe `main_fn` and the source file `file_name` as DWARF
+/// metadata, and set `module.debug_scope` so we can declare
+/// variables and give instructions source locations.
+///
+/// We don't have a DIBuilder in the C API, so we build the metadata
+/// nodes ourselves, in the format LLVM 3.6 expects: each descriptor
+/// starts with a header string of NUL-separated fields.
+unsafe fn add_debug_info(module: &mut Module, main_fn: LLVMValueRef, file_name: &str) {
+    let directory = match ::std::env::current_dir() {
+        Ok(dir) => dir.to_string_lossy().into_owned(),
+        Err(_) => String::from("."),
+    };
+    let file_pair = md_node(&[md_string(file_name), md_string(&directory)]);
+    // DW_TAG_file_type
+    let file = md_node(&[md_string("0x29"), file_pair]);
+    let empty = md_node(&[]);
+
+    // DW_TAG_subroutine_type. We don't describe the argument or
+    // return types.
+    let fn_type = md_node(&[md_string("0x15\0\00\00\00\00\00\00"),
+                            ptr::null_mut(),
+                            ptr::null_mut(),
+                            ptr::null_mut(),
+                            md_node(&[ptr::null_mut()]),
+                            ptr::null_mut(),
+                            ptr::null_mut(),
+                            ptr::null_mut()]);
+
+    // DW_TAG_subprogram, a definition on line 1 that isn't local or
+    // optimised.
+    let fn_name = CStr::from_ptr(LLVMGetValueName(main_fn)).to_string_lossy().into_owned();
+    let subprogram_header = format!("0x2e\0{}\0{}\0\01\00\01\00\00\00\00\01",
+                                    fn_name,
+                                    fn_name);
+    let subprogram = md_node(&[md_string(&subprogram_header),
+                               file_pair,
+                               file,
+                               fn_type,
+                               ptr::null_mut(),
+                               main_fn,
+                               ptr::null_mut(),
+                               ptr::null_mut(),
+                               empty]);
+
+    // DW_TAG_compile_unit. There's no DWARF language code for BF, so
+    // we claim to be C99 (0xc), which debuggers handle well. The last
+    // field asks for full debug info.
+    let compile_unit = md_node(&[md_string("0x11\012\0bfc\00\0\00\0\01"),
+                                 file_pair,
+                                 empty,
+                                 empty,
+                                 md_node(&[subprogram]),
+                                 empty,
+                                 empty]);
+    LLVMAddNamedMetadataOperand(module.module, module.new_string_ptr("llvm.dbg.cu"),
+                                compile_unit);
+
+    // Module flags with behaviour 2 (warn) if modules disagree.
+    let dwarf_version = md_node(&[int32(2), md_string("Dwarf Version"), int32(4)]);
+    let debug_info_version = md_node(&[int32(2), md_string("Debug Info Version"), int32(2)]);
+    LLVMAddNamedMetadataOperand(module.module, module.new_string_ptr("llvm.module.flags"),
+                                dwarf_version);
+    LLVMAddNamedMetadataOperand(module.module, module.new_string_ptr("llvm.module.flags"),
+                                debug_info_version);
+
+    module.debug_scope = subprogram;
+    module.debug_file = file;
+}
+
+/// The kind ID of `!dbg` metadata.
+unsafe fn dbg_kind_id(module: &mut Module) -> c_uint {
+    let kind_name = "dbg";
+    LLVMGetMDKindID(module.new_string_ptr(kind_name), kind_name.len() as c_uint)
+}
+
+/// The width of pointers and `size_t` on the target, in bits. Without
+/// a target triple, we compile for the host.
+pub fn pointer_bits(target_triple: &Option<String>) -> u32 {
+    match *target_triple {
+        Some(ref triple) => {
+            let arch = triple.split('-').next().unwrap_or("");
+            let is_32_bit = arch.ends_with("86") || arch.starts_with("arm")
+                || arch.starts_with("thumb") || arch == "mips" || arch == "mipsel"
+                || arch == "powerpc" || arch == "sparc" || arch == "wasm32";
+            if is_32_bit { 32 } else { 64 }
+        }
+        None => (::std::mem::size_of::<usize>() * 8) as u32,
+    }
+}
+
+/// A DW_TAG_base_type of `bits` bits with DWARF encoding `encoding`.
+unsafe fn debug_base_type(name: &str, bits: usize, encoding: u32) -> LLVMValueRef {
+    let header = format!("0x24\0{}\00\0{}\0{}\00\00\0{}", name, bits, bits, encoding);
+    md_node(&[md_string(&header),
+              ptr::null_mut(),
+              ptr::null_mut()])
+}
+
+/// Describe the tape and the cell index as the variables `tape` and
+/// `ptr` of the function we're compiling, so debuggers can `print
+/// tape[5]`. A tape on the heap is a pointer to the cells, which we
+/// keep in a stack slot so we can declare it.
+unsafe fn add_debug_variables(module: &mut Module,
+                              bb: &mut LLVMBasicBlock,
+                              cells: LLVMValueRef,
+                              cell_index_ptr: LLVMValueRef,
+                              num_cells: usize,
+                              options: &CodegenOptions) {
+    let builder = Builder::new();
+    builder.position_at_end(bb);
+
+    // DW_ATE_unsigned_char is 8, DW_ATE_unsigned is 7 and
+    // DW_ATE_signed is 5.
+    let cell_bits = LLVMGetIntTypeWidth(module.cell_type) as usize;
+    let cell_type = match cell_bits {
+        8 => debug_base_type("unsigned char", cell_bits, 8),
+        16 => debug_base_type("unsigned short", cell_bits, 7),
+        _ => debug_base_type("unsigned int", cell_bits, 7),
+    };
+    let index_type = debug_base_type("int", 32, 5);
+
+    // DW_TAG_array_type, indexed by a DW_TAG_subrange_type of
+    // `num_cells` elements from 0.
+    let subrange = md_node(&[md_string(&format!("0x21\00\0{}", num_cells))]);
+    let array_type = md_node(&[md_string(&format!("0x1\0\00\0{}\0{}\00\00\00",
+                                                  num_cells * cell_bits,
+                                                  cell_bits)),
+                               ptr::null_mut(),
+                               ptr::null_mut(),
+                               cell_type,
+                               md_node(&[subrange]),
+                               ptr::null_mut(),
+                               ptr::null_mut(),
+                               ptr::null_mut()]);
+
+    let (tape_address, tape_type) = if tape_on_heap(options, num_cells) {
+        let tape_ptr = LLVMBuildAlloca(builder.builder,
+                                       LLVMTypeOf(cells),
+                                       module.new_string_ptr("tape_ptr"));
+        LLVMBuildStore(builder.builder, cells, tape_ptr);
+
+        // DW_TAG_pointer_type
+        let bits = pointer_bits(&options.target_triple);
+        let pointer_type = md_node(&[md_string(&format!("0xf\0\00\0{}\0{}\00\00",
+                                                        bits,
+                                                        bits)),
+                                     ptr::null_mut(),
+                                     ptr::null_mut(),
+                                     array_type]);
+        (tape_ptr, pointer_type)
+    } else {
+        (cells, array_type)
+    };
+
+    // There's no metadata type in the C API, but metadata values
+    // have it.
+    let empty_expression = md_node(&[md_string("0x102")]);
+    let metadata_type = LLVMTypeOf(empty_expression);
+    if LLVMGetNamedFunction(module.module, module.new_string_ptr("llvm.dbg.declare")).is_null() {
+        add_function(module,
+                     "llvm.dbg.declare",
+                     &mut vec![metadata_type, metadata_type, metadata_type],
+                     LLVMVoidType());
+    }
+
+    let location = md_node(&[int32(1), int32(0), module.debug_scope, ptr::null_mut()]);
+    let kind_id = dbg_kind_id(module);
+    for &(name, address, var_type) in &[("tape", tape_address, tape_type),
+                                        ("ptr", cell_index_ptr, index_type)] {
+        // DW_TAG_auto_variable, declared on line 1.
+        let variable = md_node(&[md_string(&format!("0x100\0{}\01\00", name)),
+                                 module.debug_scope,
+                                 module.debug_file,
+                                 var_type]);
+        let mut args = vec![md_node(&[address]), variable, empty_expression];
+        let call = add_function_call(module, bb, "llvm.dbg.declare", &mut args, "");
+        LLVMSetMetadata(call, kind_id, location);
+    }
+}
+
+/// Give source line `line` to every LLVM instruction we've added
+/// since `start`, unless it already has a location from an
+/// instruction nested inside this one. `start` is the block we began
+/// compiling in, its last instruction at that point (or null), and
+/// the function's last block at that point.
+unsafe fn add_debug_location(module: &mut Module,
+                             start: (LLVMBasicBlockRef, LLVMValueRef, LLVMBasicBlockRef),
+                             line: usize) {
+    let (start_bb, last_instr, last_bb) = start;
+    let location = md_node(&[int32(line as c_ulonglong),
+                             int32(0),
+                             module.debug_scope,
+                             ptr::null_mut()]);
+    let kind_id = dbg_kind_id(module);
+
+    // First any new instructions in the block we started in, then
+    // every block we've added since.
+    let mut llvm_instr = if last_instr.is_null() {
+        LLVMGetFirstInstruction(start_bb)
+    } else {
+        LLVMGetNextInstruction(last_instr)
+    };
+    let mut bb = last_bb;
+    loop {
+        while !llvm_instr.is_null() {
+            if LLVMGetMetadata(llvm_instr, kind_id).is_null() {
+                LLVMSetMetadata(llvm_instr, kind_id, location);
+            }
+            llvm_instr = LLVMGetNextInstruction(llvm_instr);
+        }
+
+        bb = LLVMGetNextBasicBlock(bb);
+        if bb.is_null() {
+            break;
+        }
+        llvm_instr = LLVMGetFirstInstruction(bb);
+    }
+}
+
 // TODO: just take * instead of & to save all the casting.
 unsafe fn compile_instr<'a>(instr: &Instruction,
                             module: &mut Module,
                             bb: &'a mut LLVMBasicBlock,
                             main_fn: LLVMValueRef,
                             cells: LLVMValueRef,
-                            cell_index_ptr: LLVMValueRef)
+                            cell_index_ptr: LLVMValueRef,
+                            options: &CodegenOptions)
                             -> &'a mut LLVMBasicBlock {
-    match instr {
-        &Increment(amount) => compile_increment(amount, module, bb, cells, cell_index_ptr),
-        &Set(amount) => compile_set(amount, module, bb, cells, cell_index_ptr),
+    let debug_line = match options.debug_lines {
+        Some(ref lines) => Some(lines[module.instr_count]),
+        None => None,
+    };
+    let check_access = options.debug_assert && module.access_checks[module.instr_count];
+    module.instr_count += 1;
+
+    // Remember where this instruction's code starts, so we can give
+    // it a source location afterwards.
+    let debug_start = (&mut *bb as LLVMBasicBlockRef,
+                       LLVMGetLastInstruction(&mut *bb),
+                       LLVMGetLastBasicBlock(main_fn));
+
+    // Loops check their condition in the loop header instead.
+    let bb = match instr {
+        &Loop(_, _) => bb,
+        _ if check_access => {
+            add_bounds_check(module, bb, main_fn, cell_index_ptr, &bounds::accessed_offsets(instr))
+        }
+        _ => bb,
+    };
+
+    let bb = match instr {
+        &Increment(amount, offset) => {
+            compile_increment(amount, offset, module, bb, cells, cell_index_ptr)
+        }
+        &Set(amount, offset) => compile_set(amount, offset, module, bb, cells, cell_index_ptr),
+        &SaturatingIncrement(amount) => {
+            compile_saturating_increment(amount, module, bb, cells, cell_index_ptr)
+        }
         &MultiplyMove(ref changes) => {
             compile_multiply_move(changes, module, bb, cells, cell_index_ptr)
         }
         &PointerIncrement(amount) => compile_ptr_increment(amount, module, bb, cell_index_ptr),
-        &Read => compile_read(module, bb, cells, cell_index_ptr),
-        &Write => compile_write(module, bb, cells, cell_index_ptr),
-        &Loop(ref body) => {
+        &Read(offset) => compile_read(offset, module, bb, cells, cell_index_ptr, options),
+        &Write(offset) => compile_write(offset, module, bb, cells, cell_index_ptr, options),
+        &Debug if options.debug_cmd => compile_debug(module, bb, main_fn, cells, cell_index_ptr),
+        &Debug => bb,
+        &Loop(0, ref body) if options.max_loop_unroll_factor > 1 && !check_access &&
+                           scan_stride(body).is_some() => {
+            // The body isn't compiled as a separate instruction, but
+            // we still need to count it.
+            module.instr_count += body.len();
+            compile_scan_loop(module, bb, scan_stride(body).unwrap(),
+                              options.max_loop_unroll_factor, false, main_fn, cells,
+                              cell_index_ptr)
+        }
+        &Scan(stride) => {
+            let unroll_factor = ::std::cmp::max(options.max_loop_unroll_factor, 1);
+            compile_scan_loop(module, bb, stride, unroll_factor, check_access, main_fn, cells,
+                              cell_index_ptr)
+        }
+        &Loop(0, ref body) if options.loop_functions => {
+            compile_loop_function(module, bb, body, cells, cell_index_ptr, check_access, options)
+        }
+        &Loop(offset, ref body) => {
             // TODO: we should pass arguments in a consistent order.
-            compile_loop(module, bb, body, main_fn, cells, cell_index_ptr)
+            compile_loop(module, bb, offset, body, main_fn, cells, cell_index_ptr, check_access,
+                         options)
         }
+    };
+
+    if let Some(line) = debug_line {
+        add_debug_location(module, debug_start, line);
     }
+    bb
 }
 
-unsafe fn compile_static_outputs(module: &mut Module, bb: &mut LLVMBasicBlock, outputs: &[i8]) {
+unsafe fn compile_static_outputs(module: &mut Module,
+                                 bb: &mut LLVMBasicBlock,
+                                 outputs: &[i8],
+                                 options: &CodegenOptions) {
     let builder = Builder::new();
     builder.position_at_end(bb);
 
@@ -509,7 +1660,7 @@ unsafe fn compile_static_outputs(module: &mut Module, bb: &mut LLVMBasicBlock, o
     LLVMSetInitializer(known_outputs, llvm_outputs_arr);
     LLVMSetGlobalConstant(known_outputs, LLVM_TRUE);
 
-    let stdout_fd = int32(1);
+    let stdout_fd = int32(options.output_fd.unwrap_or(1) as c_ulonglong);
     let llvm_num_outputs = int32(outputs.len() as c_ulonglong);
 
     // TODO: worth factoring out this type too.
@@ -519,44 +1670,349 @@ unsafe fn compile_static_outputs(module: &mut Module, bb: &mut LLVMBasicBlock, o
                                                  byte_pointer,
                                                  module.new_string_ptr("known_outputs_ptr"));
 
+    let result_name = if options.named_values { "write_result" } else { "" };
     add_function_call(module,
                       bb,
                       "write",
                       &mut vec![stdout_fd, known_outputs_ptr, llvm_num_outputs],
-                      "");
+                      result_name);
+}
+
+/// Print the first `num_cells` cells and the final cell pointer to
+/// stderr, e.g. `cells: 1 0 72\npointer: 2\n`.
+unsafe fn add_print_cells<'a>(module: &mut Module,
+                              bb: &'a mut LLVMBasicBlock,
+                              main_fn: LLVMValueRef,
+                              cells: LLVMValueRef,
+                              cell_index_ptr: LLVMValueRef,
+                              num_cells: usize)
+                              -> &'a mut LLVMBasicBlock {
+    let builder = Builder::new();
+    builder.position_at_end(bb);
+    add_dprintf_declaration(module);
+
+    let header_fmt = LLVMBuildGlobalStringPtr(builder.builder,
+                                              module.new_string_ptr("cells:"),
+                                              module.new_string_ptr("print_cells_header_fmt"));
+    add_function_call(module, bb, "dprintf", &mut vec![int32(2), header_fmt], "");
+
+    add_print_cell_range(module, bb, main_fn, cells, cell_index_ptr,
+                         int32(0), int32(num_cells as c_ulonglong))
+}
+
+/// Print the cells from index `start` up to (but not including)
+/// `end`, then the cell pointer, to stderr. The caller prints a
+/// header first.
+unsafe fn add_print_cell_range<'a>(module: &mut Module,
+                                   bb: &'a mut LLVMBasicBlock,
+                                   main_fn: LLVMValueRef,
+                                   cells: LLVMValueRef,
+                                   cell_index_ptr: LLVMValueRef,
+                                   start: LLVMValueRef,
+                                   end: LLVMValueRef)
+                                   -> &'a mut LLVMBasicBlock {
+    let builder = Builder::new();
+    let stderr_fd = int32(2);
+
+    // int print_index = start;
+    //
+    // We allocate the index in the entry block, so printing inside a
+    // loop doesn't grow the stack on every iteration.
+    let entry_bb = LLVMGetEntryBasicBlock(main_fn);
+    let first_instr = LLVMGetFirstInstruction(entry_bb);
+    if first_instr.is_null() {
+        builder.position_at_end(entry_bb);
+    } else {
+        LLVMPositionBuilderBefore(builder.builder, first_instr);
+    }
+    let print_index_ptr = LLVMBuildAlloca(builder.builder,
+                                          LLVMInt32Type(),
+                                          module.new_string_ptr("print_index_ptr"));
+    builder.position_at_end(bb);
+    LLVMBuildStore(builder.builder, start, print_index_ptr);
+
+    let print_header = LLVMAppendBasicBlock(main_fn, module.new_string_ptr("print_cells_header"));
+    let print_body = LLVMAppendBasicBlock(main_fn, module.new_string_ptr("print_cells_body"));
+    let print_after = LLVMAppendBasicBlock(main_fn, module.new_string_ptr("print_cells_after"));
+    LLVMBuildBr(builder.builder, print_header);
+
+    // while (print_index < end)
+    builder.position_at_end(print_header);
+    let print_index = LLVMBuildLoad(builder.builder,
+                                    print_index_ptr,
+                                    module.new_string_ptr("print_index"));
+    let more_cells = LLVMBuildICmp(builder.builder,
+                                   LLVMIntPredicate::LLVMIntULT,
+                                   print_index,
+                                   end,
+                                   module.new_string_ptr("more_cells"));
+    LLVMBuildCondBr(builder.builder, more_cells, print_body, print_after);
+
+    // dprintf(2, " %d", cells[print_index]);
+    let cell_ptr = add_cell_ptr(module, &mut *print_body, cells, print_index_ptr, 0);
+    builder.position_at_end(print_body);
+    let cell_value = LLVMBuildLoad(builder.builder, cell_ptr, module.new_string_ptr("cell_value"));
+    let cell_value = LLVMBuildZExt(builder.builder,
+                                   cell_value,
+                                   LLVMInt32Type(),
+                                   module.new_string_ptr("cell_value_as_int"));
+    let cell_fmt = LLVMBuildGlobalStringPtr(builder.builder,
+                                            module.new_string_ptr(" %d"),
+                                            module.new_string_ptr("print_cells_cell_fmt"));
+    add_function_call(module, &mut *print_body, "dprintf",
+                      &mut vec![stderr_fd, cell_fmt, cell_value], "");
+
+    builder.position_at_end(print_body);
+    let print_index = LLVMBuildLoad(builder.builder,
+                                    print_index_ptr,
+                                    module.new_string_ptr("print_index"));
+    let next_index = LLVMBuildAdd(builder.builder,
+                                  print_index,
+                                  int32(1),
+                                  module.new_string_ptr("next_print_index"));
+    LLVMBuildStore(builder.builder, next_index, print_index_ptr);
+    LLVMBuildBr(builder.builder, print_header);
+
+    // dprintf(2, "\npointer: %d\n", cell_index);
+    builder.position_at_end(print_after);
+    let cell_index = LLVMBuildLoad(builder.builder,
+                                   cell_index_ptr,
+                                   module.new_string_ptr("cell_index"));
+    let pointer_fmt = LLVMBuildGlobalStringPtr(builder.builder,
+                                               module.new_string_ptr("\npointer: %d\n"),
+                                               module.new_string_ptr("print_cells_pointer_fmt"));
+    add_function_call(module, &mut *print_after, "dprintf",
+                      &mut vec![stderr_fd, pointer_fmt, cell_index], "");
+
+    &mut *print_after
+}
+
+/// How many cells either side of the cell pointer `#` prints.
+const DEBUG_CELL_WINDOW: c_ulonglong = 8;
+
+/// Print the cells near the cell pointer, and the cell pointer, to
+/// stderr, e.g. `cells 0..11: 0 0 72 ...\npointer: 2\n`. We only
+/// print cells that are on the tape, so this is safe even if the cell
+/// pointer has left the tape.
+unsafe fn compile_debug<'a>(module: &mut Module,
+                            bb: &'a mut LLVMBasicBlock,
+                            main_fn: LLVMValueRef,
+                            cells: LLVMValueRef,
+                            cell_index_ptr: LLVMValueRef)
+                            -> &'a mut LLVMBasicBlock {
+    let builder = Builder::new();
+    builder.position_at_end(bb);
+    add_dprintf_declaration(module);
+
+    let num_cells = int32(module.num_cells as c_ulonglong);
+    let cell_index = LLVMBuildLoad(builder.builder,
+                                   cell_index_ptr,
+                                   module.new_string_ptr("cell_index"));
+
+    // start = max(cell_index - DEBUG_CELL_WINDOW, 0);
+    let start = LLVMBuildSub(builder.builder,
+                             cell_index,
+                             int32(DEBUG_CELL_WINDOW),
+                             module.new_string_ptr("debug_start"));
+    let before_tape = LLVMBuildICmp(builder.builder,
+                                    LLVMIntPredicate::LLVMIntSLT,
+                                    start,
+                                    int32(0),
+                                    module.new_string_ptr("debug_before_tape"));
+    let start = LLVMBuildSelect(builder.builder,
+                                before_tape,
+                                int32(0),
+                                start,
+                                module.new_string_ptr("debug_start"));
+
+    // end = max(min(cell_index + DEBUG_CELL_WINDOW + 1, num_cells), start);
+    let end = LLVMBuildAdd(builder.builder,
+                           cell_index,
+                           int32(DEBUG_CELL_WINDOW + 1),
+                           module.new_string_ptr("debug_end"));
+    let after_tape = LLVMBuildICmp(builder.builder,
+                                   LLVMIntPredicate::LLVMIntSGT,
+                                   end,
+                                   num_cells,
+                                   module.new_string_ptr("debug_after_tape"));
+    let end = LLVMBuildSelect(builder.builder,
+                              after_tape,
+                              num_cells,
+                              end,
+                              module.new_string_ptr("debug_end"));
+    let before_start = LLVMBuildICmp(builder.builder,
+                                     LLVMIntPredicate::LLVMIntSLT,
+                                     end,
+                                     start,
+                                     module.new_string_ptr("debug_before_start"));
+    let end = LLVMBuildSelect(builder.builder,
+                              before_start,
+                              start,
+                              end,
+                              module.new_string_ptr("debug_end"));
+
+    // dprintf(2, "cells %d..%d:", start, end);
+    let header_fmt = LLVMBuildGlobalStringPtr(builder.builder,
+                                              module.new_string_ptr("cells %d..%d:"),
+                                              module.new_string_ptr("debug_header_fmt"));
+    add_function_call(module, bb, "dprintf", &mut vec![int32(2), header_fmt, start, end], "");
+
+    add_print_cell_range(module, bb, main_fn, cells, cell_index_ptr, start, end)
 }
 
+/// Add `@bf_fuel`, the number of loop iterations the program may
+/// still run, starting at `fuel`.
+unsafe fn add_fuel_global(module: &mut Module, fuel: u64) {
+    let fuel_global = LLVMAddGlobal(module.module,
+                                    LLVMInt64Type(),
+                                    module.new_string_ptr("bf_fuel"));
+    LLVMSetInitializer(fuel_global, LLVMConstInt(LLVMInt64Type(), fuel, LLVM_FALSE));
+    LLVMSetLinkage(fuel_global, LLVMLinkage::LLVMInternalLinkage);
+}
+
+/// Use up one unit of fuel at the end of a loop iteration. If there's
+/// none left, print "Fuel exhausted." to stderr and exit. Returns the
+/// basic block to continue compiling in.
+unsafe fn add_fuel_check<'a>(module: &mut Module,
+                             bb: &'a mut LLVMBasicBlock,
+                             function: LLVMValueRef)
+                             -> &'a mut LLVMBasicBlock {
+    add_dprintf_declaration(module);
+    add_exit_declaration(module);
+
+    let builder = Builder::new();
+    builder.position_at_end(bb);
+
+    // if (bf_fuel == 0) { ... }
+    let fuel_ptr = LLVMGetNamedGlobal(module.module, module.new_string_ptr("bf_fuel"));
+    let fuel = LLVMBuildLoad(builder.builder, fuel_ptr, module.new_string_ptr("fuel"));
+    let out_of_fuel = LLVMBuildICmp(builder.builder,
+                                    LLVMIntPredicate::LLVMIntEQ,
+                                    fuel,
+                                    LLVMConstInt(LLVMInt64Type(), 0, LLVM_FALSE),
+                                    module.new_string_ptr("out_of_fuel"));
+    let exhausted_bb = LLVMAppendBasicBlock(function, module.new_string_ptr("fuel_exhausted"));
+    let fuel_left_bb = LLVMAppendBasicBlock(function, module.new_string_ptr("fuel_left"));
+    LLVMBuildCondBr(builder.builder, out_of_fuel, exhausted_bb, fuel_left_bb);
+
+    // dprintf(2, "Fuel exhausted.\n");
+    // exit(2);
+    builder.position_at_end(exhausted_bb);
+    let message = LLVMBuildGlobalStringPtr(builder.builder,
+                                           module.new_string_ptr("Fuel exhausted.\n"),
+                                           module.new_string_ptr("fuel_exhausted_msg"));
+    add_function_call(module, &mut *exhausted_bb, "dprintf", &mut vec![int32(2), message], "");
+    add_function_call(module, &mut *exhausted_bb, "exit",
+                      &mut vec![int32(FUEL_EXHAUSTED_EXIT_CODE)], "");
+    builder.position_at_end(exhausted_bb);
+    LLVMBuildUnreachable(builder.builder);
+
+    // bf_fuel = fuel - 1;
+    builder.position_at_end(fuel_left_bb);
+    let remaining = LLVMBuildSub(builder.builder,
+                                 fuel,
+                                 LLVMConstInt(LLVMInt64Type(), 1, LLVM_FALSE),
+                                 module.new_string_ptr("remaining_fuel"));
+    LLVMBuildStore(builder.builder, remaining, fuel_ptr);
+
+    &mut *fuel_left_bb
+}
+
+/// Record the number of cells as the constant `@bf_tape_size`, so
+/// tools inspecting the compiled program can find its memory
+/// footprint.
+unsafe fn add_tape_size_global(module: &mut Module, num_cells: usize) {
+    let tape_size = LLVMAddGlobal(module.module,
+                                  LLVMInt32Type(),
+                                  module.new_string_ptr("bf_tape_size"));
+    LLVMSetInitializer(tape_size, int32(num_cells as c_ulonglong));
+    LLVMSetGlobalConstant(tape_size, LLVM_TRUE);
+}
+
+/// Build an LLVM module with a main function that runs
+/// `instrs`. The caller must dispose of the module.
 // TODO: use init_values terminology consistently for names here.
-pub fn compile_to_ir(module_name: &str,
-                     instrs: &[Instruction],
-                     cells: &[i8],
-                     cell_ptr: i32,
-                     static_outputs: &[i8])
-                     -> CString {
-    let llvm_ir_owned;
-    unsafe {
-        let mut module = create_module(module_name);
+unsafe fn build_module(module_name: &str,
+                       instrs: &[Instruction],
+                       cells: &[i8],
+                       cell_ptr: i32,
+                       static_outputs: &[i8],
+                       options: &CodegenOptions)
+                       -> Module {
+    let mut module = create_module(module_name);
+    if let Some(ref triple) = options.target_triple {
+        LLVMSetTarget(module.module, module.new_string_ptr(triple));
+    }
+    if let Some(width) = options.cell_width {
+        module.cell_type = LLVMIntType(width);
+    }
 
-        let main_fn = add_main_fn(&mut module);
-        let mut bb = LLVMGetLastBasicBlock(main_fn);
+    let main_fn = if options.global_ctor {
+        add_ctor_fn(&mut module)
+    } else {
+        add_main_fn(&mut module, options.argv_tape)
+    };
+    if options.debug_lines.is_some() {
+        add_debug_info(&mut module, main_fn, module_name);
+    }
+    let mut bb = LLVMGetLastBasicBlock(main_fn);
 
-        if static_outputs.len() > 0 {
-            compile_static_outputs(&mut module, &mut *bb, static_outputs);
+    if static_outputs.len() > 0 {
+        compile_static_outputs(&mut module, &mut *bb, static_outputs, options);
+    }
+
+    // If we executed the whole program at compile time, we only
+    // need a tape to print it.
+    if instrs.len() > 0 || options.print_cells.is_some() {
+        // TODO: decide on a consistent order between module and bb as
+        // parameters.
+        if options.debug_assert {
+            module.access_checks = bounds::unproven_accesses(instrs, cell_ptr as isize,
+                                                             cells.len());
         }
+        module.num_cells = cells.len();
 
-        if instrs.len() > 0 {
-            // TODO: decide on a consistent order between module and bb as
-            // parameters.
-            let llvm_cells = add_cells_init(cells, &mut module, &mut *bb);
-            let llvm_cell_index = add_cell_index_init(cell_ptr, bb, &mut module);
+        add_tape_size_global(&mut module, cells.len());
+        if let Some(fuel) = options.fuel {
+            add_fuel_global(&mut module, fuel);
+        }
+        let (llvm_cells, cells_bb) = add_cells_init(cells, options, &mut module, &mut *bb);
+        bb = cells_bb;
+        let llvm_cell_index = add_cell_index_init(cell_ptr, bb, &mut module);
+        if options.debug_lines.is_some() {
+            add_debug_variables(&mut module, &mut *bb, llvm_cells, llvm_cell_index,
+                                cells.len(), options);
+        }
 
-            for instr in instrs {
-                bb = compile_instr(instr, &mut module, &mut *bb, main_fn,
-                                   llvm_cells, llvm_cell_index);
-            }
+        if options.argv_tape {
+            bb = add_argv_copy(&mut module, bb, main_fn, llvm_cells, cells.len());
         }
 
-        add_main_cleanup(bb);
+        for instr in instrs {
+            bb = compile_instr(instr, &mut module, &mut *bb, main_fn,
+                               llvm_cells, llvm_cell_index, options);
+        }
+
+        if let Some(num_cells) = options.print_cells {
+            bb = add_print_cells(&mut module, &mut *bb, main_fn, llvm_cells,
+                                 llvm_cell_index, min(num_cells, cells.len()));
+        }
+    }
+
+    add_main_cleanup(bb, options.global_ctor);
+    module
+}
+
+pub fn compile_to_ir(module_name: &str,
+                     instrs: &[Instruction],
+                     cells: &[i8],
+                     cell_ptr: i32,
+                     static_outputs: &[i8],
+                     options: &CodegenOptions)
+                     -> CString {
+    let llvm_ir_owned;
+    unsafe {
+        let module = build_module(module_name, instrs, cells, cell_ptr, static_outputs, options);
 
         // LLVM gives us a *char pointer, so wrap it in a CStr to mark it
         // as borrowed.
@@ -564,7 +2020,12 @@ pub fn compile_to_ir(module_name: &str,
         let llvm_ir = CStr::from_ptr(llvm_ir_ptr);
 
         // Make an owned copy of the string in our memory space.
-        llvm_ir_owned = CString::new(llvm_ir.to_bytes().clone()).unwrap();
+        let mut llvm_ir_bytes = vec![];
+        if let Some(cache_key) = options.cache_key {
+            llvm_ir_bytes.extend(format!("; bfc cache key: {:016x}\n", cache_key).bytes());
+        }
+        llvm_ir_bytes.extend(llvm_ir.to_bytes().iter().cloned());
+        llvm_ir_owned = CString::new(llvm_ir_bytes).unwrap();
 
         // Cleanup module and borrowed string.
         LLVMDisposeModule(module.module);
@@ -573,3 +2034,74 @@ pub fn compile_to_ir(module_name: &str,
 
     llvm_ir_owned
 }
+
+/// Check that the installed LLVM can generate code for `triple`, so
+/// we fail with a clear message before running llc.
+pub fn check_target(triple: &str) -> Result<(), String> {
+    unsafe {
+        LLVM_InitializeAllTargetInfos();
+
+        let c_triple = CString::new(triple).unwrap();
+        let mut target = ptr::null_mut();
+        let mut error = ptr::null_mut();
+        if LLVMGetTargetFromTriple(c_triple.as_ptr(), &mut target, &mut error) != LLVM_FALSE {
+            let message = CStr::from_ptr(error).to_string_lossy().into_owned();
+            LLVMDisposeMessage(error);
+            return Err(format!("Target '{}' is not available in this LLVM: {}",
+                               triple, message));
+        }
+    }
+    Ok(())
+}
+
+/// Compile `instrs` in memory with MCJIT and run the resulting main
+/// function in this process, passing `args` as the program's
+/// arguments. Returns main's exit code.
+pub fn run_jit(module_name: &str,
+               instrs: &[Instruction],
+               cells: &[i8],
+               cell_ptr: i32,
+               static_outputs: &[i8],
+               options: &CodegenOptions,
+               args: &[String])
+               -> Result<i32, String> {
+    if options.global_ctor {
+        return Err("--global-ctor programs have no main to run.".to_owned());
+    }
+
+    unsafe {
+        let mut module = build_module(module_name, instrs, cells, cell_ptr, static_outputs,
+                                      options);
+
+        LLVMLinkInMCJIT();
+        if LLVM_InitializeNativeTarget() != LLVM_FALSE ||
+           LLVM_InitializeNativeAsmPrinter() != LLVM_FALSE {
+            LLVMDisposeModule(module.module);
+            return Err("Could not initialise LLVM for this machine.".to_owned());
+        }
+
+        // The execution engine owns the module from now on.
+        let mut engine = ptr::null_mut();
+        let mut error = ptr::null_mut();
+        if LLVMCreateExecutionEngineForModule(&mut engine, module.module, &mut error) !=
+           LLVM_FALSE {
+            let message = CStr::from_ptr(error).to_string_lossy().into_owned();
+            LLVMDisposeMessage(error);
+            LLVMDisposeModule(module.module);
+            return Err(format!("Could not create an LLVM execution engine: {}", message));
+        }
+
+        let main_fn = LLVMGetNamedFunction(module.module, module.new_string_ptr("main"));
+
+        let mut argv = vec![module.new_string_ptr(module_name)];
+        for arg in args {
+            argv.push(module.new_string_ptr(arg));
+        }
+        let envp = [ptr::null()];
+        let exit_code = LLVMRunFunctionAsMain(engine, main_fn, argv.len() as c_uint,
+                                              argv.as_ptr(), envp.as_ptr());
+
+        LLVMDisposeExecutionEngine(engine);
+        Ok(exit_code)
+    }
+}
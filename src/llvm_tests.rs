@@ -2,15 +2,20 @@ use std::collections::HashMap;
 use std::ffi::CString;
 use std::num::Wrapping;
 
-use llvm::compile_to_ir;
+use llvm::{compile_to_ir, run_jit, check_target, pointer_bits, CodegenOptions,
+           HEAP_TAPE_THRESHOLD};
+use bfir::{Instruction, EofBehaviour};
 use bfir::Instruction::*;
 
 #[test]
 fn compile_loop() {
-    let result = compile_to_ir("foo", &vec![Loop(vec![Increment(Wrapping(1))])],
-                               &vec![0], 0, &vec![]);
+    let result = compile_to_ir("foo", &vec![Loop(0, vec![Increment(Wrapping(1), 0)])],
+                               &vec![0], 0, &vec![],
+                               &CodegenOptions::default());
     let expected = "; ModuleID = \'foo\'
 
+@bf_tape_size = constant i32 1
+
 ; Function Attrs: nounwind
 declare void @llvm.memset.p0i8.i32(i8* nocapture, i8, i32, i32, i1) #0
 
@@ -42,20 +47,23 @@ loop_body:                                        ; preds = %loop_header
   %cell_value3 = load i8* %current_cell_ptr2
   %new_cell_value = add i8 %cell_value3, 1
   store i8 %new_cell_value, i8* %current_cell_ptr2
-  br label %loop_header
+  br label %loop_header, !llvm.loop !0
 
 loop_after:                                       ; preds = %loop_header
   ret i32 0
 }
 
 attributes #0 = { nounwind }
+
+!0 = metadata !{metadata !0}
 ";
     assert_eq!(result, CString::new(expected).unwrap());
 }
 
 #[test]
 fn compile_empty_program() {
-    let result = compile_to_ir("foo", &vec![], &vec![0; 10], 0, &vec![]);
+    let result = compile_to_ir("foo", &vec![], &vec![0; 10], 0, &vec![],
+                               &CodegenOptions::default());
     let expected = "; ModuleID = \'foo\'
 
 ; Function Attrs: nounwind
@@ -79,9 +87,12 @@ attributes #0 = { nounwind }
 
 #[test]
 fn compile_set() {
-    let result = compile_to_ir("foo", &vec![Set(Wrapping(1))], &vec![0], 0, &vec![]);
+    let result = compile_to_ir("foo", &vec![Set(Wrapping(1), 0)], &vec![0], 0, &vec![],
+                               &CodegenOptions::default());
     let expected = "; ModuleID = \'foo\'
 
+@bf_tape_size = constant i32 1
+
 ; Function Attrs: nounwind
 declare void @llvm.memset.p0i8.i32(i8* nocapture, i8, i32, i32, i1) #0
 
@@ -112,9 +123,12 @@ attributes #0 = { nounwind }
 
 #[test]
 fn respect_initial_cell_ptr() {
-    let result = compile_to_ir("foo", &vec![PointerIncrement(1)], &vec![0; 10], 8, &vec![]);
+    let result = compile_to_ir("foo", &vec![PointerIncrement(1)], &vec![0; 10], 8, &vec![],
+                               &CodegenOptions::default());
     let expected = "; ModuleID = \'foo\'
 
+@bf_tape_size = constant i32 10
+
 ; Function Attrs: nounwind
 declare void @llvm.memset.p0i8.i32(i8* nocapture, i8, i32, i32, i1) #0
 
@@ -148,9 +162,12 @@ fn compile_multiply_move() {
     let mut changes = HashMap::new();
     changes.insert(1, Wrapping(2));
     changes.insert(2, Wrapping(3));
-    let result = compile_to_ir("foo", &vec![MultiplyMove(changes)], &vec![0, 0, 0], 0, &vec![]);
+    let result = compile_to_ir("foo", &vec![MultiplyMove(changes)], &vec![0, 0, 0], 0, &vec![],
+                               &CodegenOptions::default());
     let expected = "; ModuleID = \'foo\'
 
+@bf_tape_size = constant i32 3
+
 ; Function Attrs: nounwind
 declare void @llvm.memset.p0i8.i32(i8* nocapture, i8, i32, i32, i1) #0
 
@@ -194,9 +211,12 @@ attributes #0 = { nounwind }
 
 #[test]
 fn set_initial_cell_values() {
-    let result = compile_to_ir("foo", &vec![PointerIncrement(1)], &vec![1, 1, 2, 0, 0, 0], 0, &vec![]);
+    let result = compile_to_ir("foo", &vec![PointerIncrement(1)], &vec![1, 1, 2, 0, 0, 0], 0, &vec![],
+                               &CodegenOptions::default());
     let expected = "; ModuleID = \'foo\'
 
+@bf_tape_size = constant i32 6
+
 ; Function Attrs: nounwind
 declare void @llvm.memset.p0i8.i32(i8* nocapture, i8, i32, i32, i1) #0
 
@@ -231,7 +251,8 @@ attributes #0 = { nounwind }
 
 #[test]
 fn compile_static_outputs() {
-    let result = compile_to_ir("foo", &vec![], &vec![], 0, &vec![5, 10]);
+    let result = compile_to_ir("foo", &vec![], &vec![], 0, &vec![5, 10],
+                               &CodegenOptions::default());
     let expected = "; ModuleID = \'foo\'
 
 @known_outputs = constant [2 x i8] c\"\\05\\0A\"
@@ -259,9 +280,12 @@ attributes #0 = { nounwind }
 
 #[test]
 fn compile_ptr_increment() {
-    let result = compile_to_ir("foo", &vec![PointerIncrement(1)], &vec![0, 0], 0, &vec![]);
+    let result = compile_to_ir("foo", &vec![PointerIncrement(1)], &vec![0, 0], 0, &vec![],
+                               &CodegenOptions::default());
     let expected = "; ModuleID = \'foo\'
 
+@bf_tape_size = constant i32 2
+
 ; Function Attrs: nounwind
 declare void @llvm.memset.p0i8.i32(i8* nocapture, i8, i32, i32, i1) #0
 
@@ -292,9 +316,12 @@ attributes #0 = { nounwind }
 
 #[test]
 fn compile_increment() {
-    let result = compile_to_ir("foo", &vec![Increment(Wrapping(1))], &vec![0], 0, &vec![]);
+    let result = compile_to_ir("foo", &vec![Increment(Wrapping(1), 0)], &vec![0], 0, &vec![],
+                               &CodegenOptions::default());
     let expected = "; ModuleID = \'foo\'
 
+@bf_tape_size = constant i32 1
+
 ; Function Attrs: nounwind
 declare void @llvm.memset.p0i8.i32(i8* nocapture, i8, i32, i32, i1) #0
 
@@ -324,3 +351,611 @@ attributes #0 = { nounwind }
 
     assert_eq!(result, CString::new(expected).unwrap());
 }
+
+#[test]
+fn compile_argv_tape() {
+    let options = CodegenOptions { argv_tape: true, .. CodegenOptions::default() };
+    let result = compile_to_ir("foo", &vec![Write(0)], &vec![0; 4], 0, &vec![], &options);
+    let result = String::from_utf8_lossy(result.as_bytes());
+
+    assert!(result.contains("define i32 @main(i32 %argc, i8** %argv)"));
+    // We should check argc, then loop over argv[1] storing into cells.
+    assert!(result.contains("%has_arg = icmp sgt i32 %argc, 1"));
+    assert!(result.contains("%argv_index_in_bounds = icmp slt i32 %argv_index, 4"));
+    assert!(result.contains("store i8 %argv_char, i8* %argv_cell_ptr"));
+    assert!(result.contains("br label %argv_header"));
+}
+
+#[test]
+fn compile_without_argv_tape() {
+    let result = compile_to_ir("foo", &vec![Write(0)], &vec![0], 0, &vec![],
+                               &CodegenOptions::default());
+    let result = String::from_utf8_lossy(result.as_bytes());
+
+    assert!(result.contains("define i32 @main()"));
+    assert!(!result.contains("argv"));
+}
+
+#[test]
+fn compile_nested_loop_metadata() {
+    let instrs = vec![Loop(0, vec![Loop(0, vec![Increment(Wrapping(1), 0)])])];
+    let result = compile_to_ir("foo", &instrs, &vec![0], 0, &vec![],
+                               &CodegenOptions::default());
+    let result = String::from_utf8_lossy(result.as_bytes());
+
+    // Each loop should get its own loop ID.
+    assert!(result.contains("!llvm.loop !0"));
+    assert!(result.contains("!llvm.loop !1"));
+    assert!(result.contains("!0 = metadata !{metadata !0}"));
+    assert!(result.contains("!1 = metadata !{metadata !1}"));
+}
+
+#[test]
+fn compile_write_at_offset() {
+    let result = compile_to_ir("foo", &vec![Write(2)], &vec![0; 3], 0, &vec![],
+                               &CodegenOptions::default());
+    let result = String::from_utf8_lossy(result.as_bytes());
+
+    assert!(result.contains("%offset_cell_index = add i32 %cell_index, 2"));
+    assert!(result.contains("%current_cell_ptr = getelementptr i8* %cells, i32 %offset_cell_index"));
+    // We shouldn't move the cell pointer.
+    assert!(!result.contains("new_cell_index"));
+}
+
+#[test]
+fn compile_increment_at_offset() {
+    let result = compile_to_ir("foo", &vec![Increment(Wrapping(1), -1), Set(Wrapping(2), 1)],
+                               &vec![0; 3], 1, &vec![], &CodegenOptions::default());
+    let result = String::from_utf8_lossy(result.as_bytes());
+
+    assert!(result.contains("%offset_cell_index = add i32 %cell_index, -1"));
+    assert!(result.contains("add i32 %cell_index1, 1"));
+    // We shouldn't move the cell pointer.
+    assert!(!result.contains("new_cell_index"));
+}
+
+#[test]
+fn compile_io_with_fds() {
+    let options = CodegenOptions { input_fd: Some(3), output_fd: Some(4), ..CodegenOptions::default() };
+    let result = compile_to_ir("foo", &vec![Read(0), Write(0)], &vec![0], 0, &vec![5], &options);
+    let result = String::from_utf8_lossy(result.as_bytes());
+
+    assert!(result.contains("call i32 @read(i32 3, i8* %current_cell_ptr, i32 1)"));
+    assert!(result.contains("call i32 @write(i32 4, i8* %current_cell_ptr"));
+    // Static outputs should use the same fd.
+    assert!(result.contains("call i32 @write(i32 4, i8* getelementptr"));
+    // getchar() and putchar() are always declared, but never called.
+    assert!(!result.contains("call i32 @getchar()"));
+    assert!(!result.contains("call i32 @putchar(i32"));
+    // write() isn't buffered, so there's nothing to flush.
+    assert!(!result.contains("@fflush"));
+}
+
+#[test]
+fn compile_io_without_fds() {
+    let result = compile_to_ir("foo", &vec![Read(0), Write(0)], &vec![0], 0, &vec![],
+                               &CodegenOptions::default());
+    let result = String::from_utf8_lossy(result.as_bytes());
+
+    assert!(result.contains("@getchar()"));
+    assert!(!result.contains("@read("));
+}
+
+#[test]
+fn compile_with_cache_key() {
+    let options = CodegenOptions { cache_key: Some(0xabc), ..CodegenOptions::default() };
+    let result = compile_to_ir("foo", &vec![], &vec![], 0, &vec![], &options);
+    let result = String::from_utf8_lossy(result.as_bytes());
+
+    assert!(result.starts_with("; bfc cache key: 0000000000000abc\n"));
+}
+
+#[test]
+fn compile_named_values() {
+    let options = CodegenOptions { named_values: true, ..CodegenOptions::default() };
+    let result = compile_to_ir("foo", &vec![Loop(0, vec![Write(0), Loop(0, vec![])])], &vec![1], 0,
+                               &vec![], &options);
+    let result = String::from_utf8_lossy(result.as_bytes());
+
+    assert!(result.contains("loop0.cond:"));
+    assert!(result.contains("loop0.body:"));
+    assert!(result.contains("loop0.after:"));
+    assert!(result.contains("loop1.cond:"));
+    assert!(result.contains("%current_cell_ptr = getelementptr"));
+    assert!(result.contains("%putchar_result = call i32 @putchar"));
+}
+
+fn nested_loops(depth: usize) -> Vec<Instruction> {
+    let mut instrs = vec![Increment(Wrapping(1), 0), Write(0)];
+    for _ in 0..depth {
+        instrs = vec![Increment(Wrapping(1), 0), Loop(0, instrs)];
+    }
+    instrs
+}
+
+#[test]
+fn compile_loop_functions() {
+    let options = CodegenOptions { loop_functions: true, ..CodegenOptions::default() };
+    let result = compile_to_ir("foo", &nested_loops(1), &vec![0], 0, &vec![], &options);
+    let result = String::from_utf8_lossy(result.as_bytes());
+
+    assert!(result.contains("define internal void @loop0(i8* %cells, i32* %cell_index_ptr)"));
+    assert!(result.contains("call void @loop0(i8* %cells, i32* %cell_index_ptr)"));
+    // The loop jumps back to its header rather than recursing.
+    assert!(result.contains("br label %loop_header"));
+    assert!(!result.contains("tail call"));
+    assert_eq!(result.matches("call void @loop0(").count(), 1);
+}
+
+#[test]
+fn compile_loop_functions_deeply_nested() {
+    let options = CodegenOptions { loop_functions: true, ..CodegenOptions::default() };
+    let shallow = compile_to_ir("foo", &nested_loops(10), &vec![0], 0, &vec![], &options);
+    let deep = compile_to_ir("foo", &nested_loops(20), &vec![0], 0, &vec![], &options);
+    let deep = String::from_utf8_lossy(deep.as_bytes());
+
+    assert!(deep.contains("define internal void @loop19("));
+
+    // Every function is a fixed size no matter how deep it's nested,
+    // so doubling the nesting at most doubles the code.
+    assert!(deep.len() < 2 * shallow.as_bytes().len());
+    for function in deep.split("\ndefine ").skip(1) {
+        // Block labels are the only lines ending in ':', ignoring
+        // "; preds = ..." comments.
+        let blocks = function.lines()
+                             .filter(|line| {
+                                 line.split(';').next().unwrap().trim_right().ends_with(':')
+                             })
+                             .count();
+        // entry, loop_header, loop_body and loop_after.
+        assert!(blocks <= 4, "function has {} blocks:\n{}", blocks, function);
+    }
+}
+
+#[test]
+fn compile_debug_assert_static_access() {
+    let options = CodegenOptions { debug_assert: true, ..CodegenOptions::default() };
+    let instrs = vec![PointerIncrement(1), Increment(Wrapping(1), 0), PointerIncrement(-1)];
+    let result = compile_to_ir("foo", &instrs, &vec![0, 0], 0, &vec![], &options);
+    let result = String::from_utf8_lossy(result.as_bytes());
+
+    assert!(!result.contains("@abort"));
+}
+
+#[test]
+fn compile_debug_lines() {
+    let options = CodegenOptions { debug_lines: Some(vec![1, 2, 3]), ..CodegenOptions::default() };
+    let instrs = vec![Increment(Wrapping(1), 0), Loop(0, vec![Write(0)])];
+    let result = compile_to_ir("foo.bf", &instrs, &vec![0], 0, &vec![], &options);
+    let result = String::from_utf8_lossy(result.as_bytes());
+
+    assert!(result.contains("!llvm.dbg.cu = !{"));
+    assert!(result.contains("!\"Debug Info Version\""));
+    for line in 1..4 {
+        assert!(result.contains(&format!("!{{i32 {}, i32 0, ", line)));
+    }
+    // Every instruction in the loop body has a location.
+    let write = result.lines().find(|line| line.contains("call i32 @putchar")).unwrap();
+    assert!(write.contains(", !dbg !"));
+}
+
+#[test]
+fn compile_without_debug_lines() {
+    let instrs = vec![Increment(Wrapping(1), 0), Write(0)];
+    let result = compile_to_ir("foo.bf", &instrs, &vec![0], 0, &vec![],
+                               &CodegenOptions::default());
+    let result = String::from_utf8_lossy(result.as_bytes());
+
+    assert!(!result.contains("!dbg"));
+    assert!(!result.contains("llvm.dbg.cu"));
+}
+
+#[test]
+fn compile_target_triple() {
+    let options = CodegenOptions {
+        target_triple: Some(String::from("armv7-unknown-linux-gnueabihf")),
+        ..CodegenOptions::default()
+    };
+    let result = compile_to_ir("foo", &vec![Write(0)], &vec![0], 0, &vec![], &options);
+    let result = String::from_utf8_lossy(result.as_bytes());
+
+    assert!(result.contains("target triple = \"armv7-unknown-linux-gnueabihf\""));
+}
+
+#[test]
+fn compile_native_target_by_default() {
+    let result = compile_to_ir("foo", &vec![Write(0)], &vec![0], 0, &vec![],
+                               &CodegenOptions::default());
+    let result = String::from_utf8_lossy(result.as_bytes());
+
+    assert!(!result.contains("target triple"));
+}
+
+#[test]
+fn check_unknown_target() {
+    let result = check_target("nosucharch-unknown-linux-gnu");
+    assert!(result.unwrap_err().starts_with("Target 'nosucharch-unknown-linux-gnu' is not \
+                                             available in this LLVM"));
+}
+
+#[test]
+fn compile_debug_assert_runtime_access() {
+    let options = CodegenOptions { debug_assert: true, ..CodegenOptions::default() };
+    // After ",[>]" the cell pointer depends on the input.
+    let instrs = vec![Read(0), Loop(0, vec![PointerIncrement(1)]), Increment(Wrapping(1), 0)];
+    let result = compile_to_ir("foo", &instrs, &vec![0; 10], 0, &vec![], &options);
+    let result = String::from_utf8_lossy(result.as_bytes());
+
+    assert!(result.contains("declare void @abort()"));
+    assert!(result.contains("icmp ult i32 %cell_index"));
+    assert!(result.contains("call void @abort()"));
+}
+
+#[test]
+fn compile_saturating_increment() {
+    let result = compile_to_ir("foo", &vec![SaturatingIncrement(Wrapping(3))], &vec![0], 0,
+                               &vec![], &CodegenOptions::default());
+    let ir = String::from_utf8_lossy(result.as_bytes());
+
+    assert!(ir.contains("%out_of_range = icmp ugt i8 %cell_value, -4"));
+    assert!(ir.contains("%new_cell_value = select i1 %out_of_range, i8 -1, i8 %wrapped_cell_value"));
+}
+
+#[test]
+fn compile_saturating_decrement() {
+    let result = compile_to_ir("foo", &vec![SaturatingIncrement(Wrapping(-3))], &vec![0], 0,
+                               &vec![], &CodegenOptions::default());
+    let ir = String::from_utf8_lossy(result.as_bytes());
+
+    assert!(ir.contains("%out_of_range = icmp ult i8 %cell_value, 3"));
+    assert!(ir.contains("%new_cell_value = select i1 %out_of_range, i8 0, i8 %wrapped_cell_value"));
+}
+
+#[test]
+fn compile_scan_loop_unrolled() {
+    let mut options = CodegenOptions::default();
+    options.max_loop_unroll_factor = 4;
+    let instrs = vec![Loop(0, vec![PointerIncrement(2)])];
+    let result = compile_to_ir("foo", &instrs, &vec![0; 10], 0, &vec![], &options);
+    let result = String::from_utf8_lossy(result.as_bytes());
+
+    // One check of the starting cell, then four moves and checks per
+    // iteration.
+    assert_eq!(result.matches("= icmp eq i8 0, %cell_value").count(), 5);
+    let moves = result.lines().filter(|line| {
+        line.contains("= add i32 %cell_index") && line.ends_with(", 2")
+    });
+    assert_eq!(moves.count(), 4);
+    assert_eq!(result.matches("\nscan_step").count(), 3);
+    assert!(result.contains("br label %scan_header"));
+}
+
+#[test]
+fn compile_scan_loop_not_unrolled_by_default() {
+    let instrs = vec![Loop(0, vec![PointerIncrement(2)])];
+    let result = compile_to_ir("foo", &instrs, &vec![0; 10], 0, &vec![],
+                               &CodegenOptions::default());
+    let result = String::from_utf8_lossy(result.as_bytes());
+
+    assert!(!result.contains("scan_header"));
+}
+
+#[test]
+fn compile_tape_align() {
+    let mut options = CodegenOptions::default();
+    options.tape_align = Some(64);
+    let result = compile_to_ir("foo", &vec![Write(0)], &vec![0; 10], 0, &vec![], &options);
+    let result = String::from_utf8_lossy(result.as_bytes());
+
+    assert!(result.contains("%cells = alloca i8, i32 10, align 64"));
+}
+
+/// Options for a 64-bit target, so `size_t` is `i64` whatever the
+/// host.
+fn x86_64_options() -> CodegenOptions {
+    CodegenOptions {
+        target_triple: Some(String::from("x86_64-unknown-linux-gnu")),
+        ..CodegenOptions::default()
+    }
+}
+
+#[test]
+fn compile_heap_tape_above_threshold() {
+    let cells = vec![0; HEAP_TAPE_THRESHOLD];
+    let result = compile_to_ir("foo", &vec![Write(0)], &cells, 0, &vec![],
+                               &x86_64_options());
+    let result = String::from_utf8_lossy(result.as_bytes());
+
+    assert!(result.contains("%cells = call i8* @calloc(i64 1048576, i64 1)"));
+    assert!(result.contains("br i1 %tape_is_null, label %tape_alloc_failed, \
+                             label %tape_allocated"));
+    assert!(!result.contains("alloca i8, i32 1048576"));
+    // calloc() zeroes the tape, so we don't need a memset.
+    assert!(!result.contains("call void @llvm.memset"));
+}
+
+#[test]
+fn compile_heap_tape_with_initial_values() {
+    let mut options = x86_64_options();
+    options.heap_tape = true;
+    let result = compile_to_ir("foo", &vec![Write(0)], &vec![0, 0, 5, 5, 0], 0, &vec![],
+                               &options);
+    let result = String::from_utf8_lossy(result.as_bytes());
+
+    assert!(result.contains("%cells = call i8* @calloc(i64 5, i64 1)"));
+    assert!(result.contains("%offset_cell_ptr = getelementptr i8* %cells, i32 2"));
+    assert_eq!(result.matches("call void @llvm.memset").count(), 1);
+}
+
+#[test]
+fn compile_aligned_heap_tape() {
+    let options = CodegenOptions {
+        heap_tape: true,
+        tape_align: Some(64),
+        target_triple: Some(String::from("i686-unknown-linux-gnu")),
+        ..CodegenOptions::default()
+    };
+    let result = compile_to_ir("foo", &vec![Write(0)], &vec![0; 10], 0, &vec![], &options);
+    let result = String::from_utf8_lossy(result.as_bytes());
+
+    assert!(result.contains("%cells = call i8* @calloc(i32 73, i32 1)"));
+    assert!(result.contains("add i32 %tape_address, 63"));
+    assert!(result.contains("and i32 %tape_address_rounded_up, -64"));
+}
+
+#[test]
+fn pointer_bits_for_target() {
+    assert_eq!(pointer_bits(&Some(String::from("x86_64-unknown-linux-gnu"))), 64);
+    assert_eq!(pointer_bits(&Some(String::from("aarch64-linux-gnu"))), 64);
+    assert_eq!(pointer_bits(&Some(String::from("i686-pc-windows-msvc"))), 32);
+    assert_eq!(pointer_bits(&Some(String::from("armv7-unknown-linux-gnueabihf"))), 32);
+}
+
+#[test]
+fn compile_flush_before_read() {
+    let instrs = vec![Write(0), Read(0), Loop(0, vec![Write(0), Read(0)])];
+    let result = compile_to_ir("foo", &instrs, &vec![0], 0, &vec![],
+                               &CodegenOptions::default());
+    let result = String::from_utf8_lossy(result.as_bytes());
+
+    let lines: Vec<_> = result.lines().collect();
+    let getchar_lines: Vec<_> = (0..lines.len()).filter(|&i| {
+        lines[i].contains("call i32 @getchar()")
+    }).collect();
+    assert_eq!(getchar_lines.len(), 2);
+    for i in getchar_lines {
+        assert!(lines[i - 1].contains("call i32 @fflush(i8* null)"));
+    }
+}
+
+#[test]
+fn compile_tape_size_symbol() {
+    let result = compile_to_ir("foo", &vec![Write(0)], &vec![0; 30000], 0, &vec![],
+                               &CodegenOptions::default());
+    let result = String::from_utf8_lossy(result.as_bytes());
+
+    assert!(result.contains("@bf_tape_size = constant i32 30000"));
+}
+
+#[test]
+fn compile_rodata_cells() {
+    let mut options = CodegenOptions::default();
+    options.rodata_cells = true;
+    let result = compile_to_ir("foo", &vec![Write(0)], &vec![1, 2, 0], 0, &vec![], &options);
+    let result = String::from_utf8_lossy(result.as_bytes());
+
+    assert!(result.contains("@initial_cells = constant [3 x i8] c\"\\01\\02\\00\""));
+    assert!(result.contains("call void @llvm.memcpy.p0i8.p0i8.i32(i8* %cells, \
+                             i8* getelementptr inbounds ([3 x i8]* @initial_cells, i32 0, i32 0), \
+                             i32 3, i32 1, i1 false)"));
+    assert!(!result.contains("call void @llvm.memset"));
+}
+
+#[test]
+fn compile_print_cells() {
+    let mut options = CodegenOptions::default();
+    options.print_cells = Some(5);
+    let result = compile_to_ir("foo", &vec![Increment(Wrapping(1), 0)], &vec![0; 3], 0, &vec![],
+                               &options);
+    let result = String::from_utf8_lossy(result.as_bytes());
+
+    assert!(result.contains("declare i32 @dprintf(i32, i8*, ...)"));
+    // We can't print more cells than we have.
+    assert!(result.contains("%more_cells = icmp ult i32 %print_index, 3"));
+    assert!(result.contains("print_cells_after:"));
+    assert!(result.contains("ret i32 0"));
+}
+
+#[test]
+fn compile_print_cells_without_instrs() {
+    // Even if speculative execution ran the whole program, we print
+    // the final tape.
+    let mut options = CodegenOptions::default();
+    options.print_cells = Some(2);
+    let result = compile_to_ir("foo", &vec![], &vec![1, 2], 1, &vec![], &options);
+    let result = String::from_utf8_lossy(result.as_bytes());
+
+    assert!(result.contains("print_cells_header:"));
+}
+
+#[test]
+fn compile_debug_cmd() {
+    let options = CodegenOptions { debug_cmd: true, ..CodegenOptions::default() };
+    let result = compile_to_ir("foo", &vec![Loop(0, vec![Debug])], &vec![0; 20], 0, &vec![],
+                               &options);
+    let result = String::from_utf8_lossy(result.as_bytes());
+
+    assert!(result.contains("declare i32 @dprintf(i32, i8*, ...)"));
+    assert!(result.contains("c\"cells %d..%d:\\00\""));
+    // We only print cells on the tape.
+    assert!(result.contains("icmp sgt i32 %debug_end, 20"));
+}
+
+#[test]
+fn compile_fuel() {
+    let options = CodegenOptions { fuel: Some(1000), ..CodegenOptions::default() };
+    let result = compile_to_ir("foo", &vec![Loop(0, vec![])], &vec![1], 0, &vec![], &options);
+    let result = String::from_utf8_lossy(result.as_bytes());
+
+    assert!(result.contains("@bf_fuel = internal global i64 1000"));
+    assert!(result.contains("fuel_exhausted:"));
+    assert!(result.contains("declare void @exit(i32)"));
+}
+
+#[test]
+fn compile_without_fuel() {
+    let result = compile_to_ir("foo", &vec![Loop(0, vec![])], &vec![1], 0, &vec![],
+                               &CodegenOptions::default());
+    let result = String::from_utf8_lossy(result.as_bytes());
+
+    assert!(!result.contains("bf_fuel"));
+}
+
+#[test]
+fn compile_debug_without_debug_cmd() {
+    let options = CodegenOptions::default();
+    let result = compile_to_ir("foo", &vec![Loop(0, vec![Debug])], &vec![0; 20], 0, &vec![],
+                               &options);
+    let result = String::from_utf8_lossy(result.as_bytes());
+
+    assert!(!result.contains("dprintf"));
+}
+
+#[test]
+fn compile_loop_condition_offset() {
+    let instrs = vec![Loop(1, vec![Increment(Wrapping(1), 0)])];
+    let result = compile_to_ir("foo", &instrs, &vec![0; 2], 0, &vec![],
+                               &CodegenOptions::default());
+    let result = String::from_utf8_lossy(result.as_bytes());
+
+    assert!(result.contains("loop_header:"));
+    assert!(result.contains("%offset_cell_index = add i32 %cell_index, 1"));
+}
+
+#[test]
+fn compile_debug_variables() {
+    let options = CodegenOptions { debug_lines: Some(vec![1, 1]), ..CodegenOptions::default() };
+    let instrs = vec![Increment(Wrapping(1), 0), Write(0)];
+    let result = compile_to_ir("foo.bf", &instrs, &vec![0, 0, 0], 0, &vec![], &options);
+    let result = String::from_utf8_lossy(result.as_bytes());
+
+    assert!(result.contains("!llvm.dbg.cu = !{"));
+    assert!(result.contains("!\"Debug Info Version\""));
+    assert!(result.contains("call void @llvm.dbg.declare(metadata i8* %cells, "));
+    assert!(result.contains("call void @llvm.dbg.declare(metadata i32* %cell_index_ptr, "));
+    assert!(result.contains("\\00tape\\00"));
+    assert!(result.contains("\\00ptr\\00"));
+    // An array of 3 unsigned chars.
+    assert!(result.contains("\\00unsigned char\\00"));
+    assert!(result.contains("!\"0x21\\000\\003\"}"));
+
+    // A tape on the heap is a pointer, which we keep on the stack.
+    let options = CodegenOptions { heap_tape: true, ..options };
+    let result = compile_to_ir("foo.bf", &instrs, &vec![0, 0, 0], 0, &vec![], &options);
+    let result = String::from_utf8_lossy(result.as_bytes());
+    assert!(result.contains("call void @llvm.dbg.declare(metadata i8** %tape_ptr, "));
+}
+
+#[test]
+fn compile_without_debug_info() {
+    let instrs = vec![Increment(Wrapping(1), 0), Write(0)];
+    let result = compile_to_ir("foo.bf", &instrs, &vec![0], 0, &vec![],
+                               &CodegenOptions::default());
+    let result = String::from_utf8_lossy(result.as_bytes());
+
+    assert!(!result.contains("llvm.dbg.cu"));
+    assert!(!result.contains("llvm.dbg.declare"));
+}
+
+#[test]
+fn compile_global_ctor() {
+    let mut options = CodegenOptions::default();
+    options.global_ctor = true;
+    let result = compile_to_ir("foo", &vec![Write(0)], &vec![0], 0, &vec![], &options);
+    let result = String::from_utf8_lossy(result.as_bytes());
+
+    assert!(result.contains("@llvm.global_ctors = appending global [1 x { i32, void ()* }] \
+                             [{ i32, void ()* } { i32 65535, void ()* @bf_main }]"));
+    assert!(result.contains("define void @bf_main()"));
+    assert!(result.contains("ret void"));
+    assert!(!result.contains("@main"));
+}
+
+#[test]
+fn compile_16_bit_cells() {
+    let mut options = CodegenOptions::default();
+    options.cell_width = Some(16);
+    let instrs = vec![Increment(Wrapping(-1), 0),
+                      Loop(0, vec![Increment(Wrapping(-1), 0)]),
+                      Read(0),
+                      Write(0)];
+    let result = compile_to_ir("foo", &instrs, &vec![0; 3], 0, &vec![], &options);
+    let result = String::from_utf8_lossy(result.as_bytes());
+
+    assert!(result.contains("%cells = alloca i16, i32 3"));
+    // We zero every byte of the tape.
+    assert!(result.contains("i8 0, i32 6"));
+    assert!(result.contains("%new_cell_value = add i16 %cell_value, -1"));
+    assert!(result.contains("icmp eq i16 0, %cell_value"));
+    assert!(result.contains("%input_byte = trunc i32 %input_char to i16"));
+    assert!(result.contains("%cell_val_as_char = sext i16 %cell_value"));
+}
+
+#[test]
+fn compile_32_bit_cells_on_heap() {
+    let mut options = x86_64_options();
+    options.cell_width = Some(32);
+    options.heap_tape = true;
+    let result = compile_to_ir("foo", &vec![Write(0)], &vec![0; 3], 0, &vec![], &options);
+    let result = String::from_utf8_lossy(result.as_bytes());
+
+    assert!(result.contains("%cells = call i8* @calloc(i64 12, i64 1)"));
+    assert!(result.contains("%wide_cells = bitcast i8* %cells to i32*"));
+}
+
+#[test]
+fn compile_16_bit_saturating_increment() {
+    let mut options = CodegenOptions::default();
+    options.cell_width = Some(16);
+    let result = compile_to_ir("foo", &vec![SaturatingIncrement(Wrapping(3))], &vec![0], 0,
+                               &vec![], &options);
+    let ir = String::from_utf8_lossy(result.as_bytes());
+
+    // 65532 and 65535, as signed 16-bit values.
+    assert!(ir.contains("%out_of_range = icmp ugt i16 %cell_value, -4"));
+    assert!(ir.contains("%new_cell_value = select i1 %out_of_range, i16 -1, i16 %wrapped_cell_value"));
+}
+
+#[test]
+fn compile_read_eof_behaviours() {
+    let mut options = CodegenOptions::default();
+    let instrs = vec![Read(0)];
+
+    let ir = compile_to_ir("foo", &instrs, &vec![0], 0, &vec![], &options);
+    assert!(!String::from_utf8_lossy(ir.as_bytes()).contains("%is_eof"));
+
+    options.eof = EofBehaviour::Zero;
+    let ir = compile_to_ir("foo", &instrs, &vec![0], 0, &vec![], &options);
+    let ir = String::from_utf8_lossy(ir.as_bytes());
+    assert!(ir.contains("%is_eof = icmp eq i32 %input_char, -1"));
+    assert!(ir.contains("%input_value = select i1 %is_eof, i8 0, i8 %input_byte"));
+
+    options.eof = EofBehaviour::Unchanged;
+    let ir = compile_to_ir("foo", &instrs, &vec![0], 0, &vec![], &options);
+    let ir = String::from_utf8_lossy(ir.as_bytes());
+    assert!(ir.contains("%input_value = select i1 %is_eof, i8 %old_cell_value, i8 %input_byte"));
+}
+
+#[test]
+fn run_jit_exit_code() {
+    let instrs = vec![Increment(Wrapping(2), 0), Loop(0, vec![Increment(Wrapping(-1), 0)])];
+    let result = run_jit("foo", &instrs, &vec![0], 0, &vec![], &CodegenOptions::default(), &[]);
+    assert_eq!(result, Ok(0));
+}
+
+#[test]
+fn run_jit_needs_main() {
+    let mut options = CodegenOptions::default();
+    options.global_ctor = true;
+    assert!(run_jit("foo", &vec![], &vec![0], 0, &vec![], &options, &[]).is_err());
+}
@@ -1,49 +1,134 @@
-#![feature(plugin)]
-#![plugin(quickcheck_macros)]
-
 #![warn(trivial_numeric_casts)]
 
-extern crate libc;
-extern crate llvm_sys;
-extern crate itertools;
-extern crate quickcheck;
-extern crate rand;
+extern crate bfc;
 extern crate tempfile;
 extern crate getopts;
+extern crate flate2;
+extern crate time;
 
 use std::env;
 use std::fs::File;
-use std::io::Write;
+use std::io::{BufRead, BufReader, BufWriter, Write};
 use std::io::prelude::Read;
 use std::num::Wrapping;
-use std::path::Path;
-use std::process::Command;
+use std::path::{Path, PathBuf};
+use std::process::{Child, Command, Stdio};
+use std::thread;
+use std::time::Duration;
 use getopts::{Options, Matches};
 use tempfile::NamedTempFile;
-
-mod bfir;
-mod llvm;
-mod peephole;
-mod bounds;
-mod execution;
-
+use flate2::read::GzDecoder;
 #[cfg(test)]
-mod peephole_tests;
+use flate2::Compression;
 #[cfg(test)]
-mod llvm_tests;
+use flate2::write::GzEncoder;
+
+use bfc::{bfir, llvm, peephole, bounds, execution, irbin, diagnostics, interpreter, stats};
+use bfc::bfir::{Instruction, EofBehaviour};
 
-/// Read the contents of the file at path, and return a string of its
-/// contents.
+/// Read the file at `path`, decompressing it first if it ends with
+/// `.gz`.
 fn slurp(path: &str) -> Result<String, std::io::Error> {
-    let mut file = try!(File::open(path));
+    let file = try!(File::open(path));
     let mut contents = String::new();
-    try!(file.read_to_string(&mut contents));
+    if path.ends_with(".gz") {
+        let mut decoder = try!(GzDecoder::new(file));
+        try!(decoder.read_to_string(&mut contents));
+    } else {
+        let mut file = file;
+        try!(file.read_to_string(&mut contents));
+    }
     Ok(contents)
 }
 
-/// Convert "foo.bf" to "foo".
+/// Where we read the BF program from.
+#[derive(Debug,Clone,PartialEq,Eq)]
+enum Source {
+    Stdin,
+    File(String),
+}
+
+impl Source {
+    /// Find the source named by the free argument on the command
+    /// line. A bare `-` or `--stdin` means stdin, but `--` ends the
+    /// options, so `bfc -- -` compiles a file named `-`.
+    fn from_args(args: &[String], matches: &Matches) -> Source {
+        if matches.opt_present("stdin") {
+            return Source::Stdin;
+        }
+
+        // Every argument after `--` is free, so the first free
+        // argument comes after `--` exactly when all of them do. We
+        // compare positions rather than text, as the program's own
+        // arguments may also be `-`.
+        let ref path = matches.free[0];
+        let after_separator = match args.iter().position(|arg| arg == "--") {
+            Some(separator) => args.len() - (separator + 1) == matches.free.len(),
+            None => false,
+        };
+
+        if path == "-" && !after_separator {
+            Source::Stdin
+        } else {
+            Source::File(path.clone())
+        }
+    }
+
+    /// A name for the program, used as the LLVM module name.
+    fn name(&self) -> &str {
+        match *self {
+            Source::Stdin => "<stdin>",
+            Source::File(ref path) => path,
+        }
+    }
+
+    /// Read the BF program.
+    fn read(&self) -> Result<String, String> {
+        match *self {
+            Source::Stdin => {
+                let mut contents = String::new();
+                try!(convert_io_error(std::io::stdin().read_to_string(&mut contents)));
+                Ok(contents)
+            }
+            Source::File(ref path) => convert_io_error(slurp(path)),
+        }
+    }
+
+    /// The name of the executable we compile to. With no file name to
+    /// go on, we use "a.out", like C compilers.
+    fn executable_name(&self) -> String {
+        match *self {
+            Source::Stdin => String::from("a.out"),
+            Source::File(ref path) => {
+                // TODO: do path munging in executable_name().
+                let bf_name = Path::new(path).file_name().unwrap();
+                executable_name(bf_name.to_str().unwrap())
+            }
+        }
+    }
+}
+
+/// The free arguments after the source, which `--run` and
+/// `--run-native` pass to the program. With `--stdin`, there's no
+/// source argument.
+fn program_args(matches: &Matches) -> &[String] {
+    if matches.opt_present("stdin") {
+        &matches.free[..]
+    } else {
+        &matches.free[1..]
+    }
+}
+
+/// Convert "foo.bf" to "foo". We also drop the extension from
+/// compressed sources, so "foo.bf.gz" is "foo" too.
 #[allow(deprecated)] // .connect is in stable 1.2, but beta has deprecated it.
 fn executable_name(bf_file_name: &str) -> String {
+    let bf_file_name = if bf_file_name.ends_with(".gz") {
+        &bf_file_name[..bf_file_name.len() - ".gz".len()]
+    } else {
+        bf_file_name
+    };
+
     let mut name_parts: Vec<_> = bf_file_name.split('.').collect();
     let parts_len = name_parts.len();
     if parts_len > 1 {
@@ -53,9 +138,30 @@ fn executable_name(bf_file_name: &str) -> String {
     name_parts.connect(".")
 }
 
-fn print_usage(bin_name: &str, opts: Options) {
-    let brief = format!("Usage: {} <BF source file> [options]", bin_name);
-    print!("{}", opts.usage(&brief));
+fn usage(bin_name: &str, opts: Options) -> String {
+    let brief = format!("Usage: {} <BF source file, or - for stdin> [options]", bin_name);
+    opts.usage(&brief)
+}
+
+/// Exit code when the command line arguments are invalid.
+const EXIT_BAD_ARGS: i32 = 1;
+/// Exit code when we couldn't compile (or run) the program.
+const EXIT_FAILED: i32 = 2;
+
+/// Print usage to stderr, after `message` if given, and exit.
+fn exit_with_usage(bin_name: &str, opts: Options, message: Option<String>) -> ! {
+    let mut stderr = std::io::stderr();
+    if let Some(message) = message {
+        let _ = writeln!(&mut stderr, "{}", message);
+    }
+    let _ = write!(&mut stderr, "{}", usage(bin_name, opts));
+    std::process::exit(EXIT_BAD_ARGS);
+}
+
+/// Print `message` to stderr and exit.
+fn exit_with_error(message: &str) -> ! {
+    let _ = writeln!(&mut std::io::stderr(), "{}", message);
+    std::process::exit(EXIT_FAILED);
 }
 
 fn convert_io_error<T>(result: Result<T, std::io::Error>) -> Result<T, String> {
@@ -69,7 +175,35 @@ fn convert_io_error<T>(result: Result<T, std::io::Error>) -> Result<T, String> {
     }
 }
 
-fn shell_command(command: &str, args: &[&str]) -> Result<String, String> {
+/// Format a command and its arguments as they would be typed in a
+/// shell.
+#[allow(deprecated)] // .connect is in stable 1.2, but beta has deprecated it.
+fn format_command(command: &str, args: &[&str]) -> String {
+    let mut parts = vec![shell_quote(command)];
+    parts.extend(args.iter().map(|arg| shell_quote(arg)));
+    parts.connect(" ")
+}
+
+/// Quote `arg` so a POSIX shell treats it as a single word. Arguments
+/// made only of characters that are never special are left alone, so
+/// typical commands stay readable.
+fn shell_quote(arg: &str) -> String {
+    let is_plain = |c: char| c.is_alphanumeric() || "-_./=:,+@%".contains(c);
+    if !arg.is_empty() && arg.chars().all(is_plain) {
+        arg.to_owned()
+    } else {
+        format!("'{}'", arg.replace("'", "'\\''"))
+    }
+}
+
+/// Run this command, returning its stdout. If `dry_run` is true, just
+/// print the command instead.
+fn shell_command(command: &str, args: &[&str], dry_run: bool) -> Result<String, String> {
+    if dry_run {
+        println!("{}", format_command(command, args));
+        return Ok(String::new());
+    }
+
     let mut c = Command::new(command);
     for arg in args {
         c.arg(arg);
@@ -86,122 +220,2736 @@ fn shell_command(command: &str, args: &[&str]) -> Result<String, String> {
 
 }
 
-fn compile_file(matches: &Matches) -> Result<(), String> {
-    let ref path = matches.free[0];
-    let src = try!(convert_io_error(slurp(path)));
+/// Create a new temporary file, in `temp_dir` if given or the system
+/// temporary directory (which honours `TMPDIR`) otherwise.
+fn new_temp_file(temp_dir: Option<&str>) -> Result<NamedTempFile, String> {
+    match temp_dir {
+        Some(dir) => convert_io_error(NamedTempFile::new_in(dir)),
+        None => convert_io_error(NamedTempFile::new()),
+    }
+}
+
+/// A temporary path for an intermediate build file with this
+/// extension. These usually have a unique random name, but with
+/// `deterministic` we name them after the output, so two builds of
+/// the same program run exactly the same commands.
+fn build_temp_path(temp_dir: Option<&str>,
+                   output_name: &str,
+                   extension: &str,
+                   deterministic: bool)
+                   -> Result<TempPath, String> {
+    let base = if deterministic {
+        let file_name = Path::new(output_name).file_name().and_then(|name| name.to_str())
+            .unwrap_or("bfc");
+        match temp_dir {
+            Some(dir) => Path::new(dir).join(file_name).to_str().unwrap().to_owned(),
+            None => output_name.to_owned(),
+        }
+    } else {
+        // Use the temporary file's unique name, but not the file
+        // itself, as llc writes the file itself.
+        let temp_file = try!(new_temp_file(temp_dir));
+        temp_file.path().to_str().unwrap().to_owned()
+    };
+    Ok(TempPath { path: format!("{}.bfc-tmp.{}", base, extension), keep: false })
+}
 
-    let mut instrs = try!(bfir::parse(&src));
+/// The path for an intermediate build file with this extension. With
+/// `--save-temps` this is `{output_name}.{extension}`, and we keep
+/// it, otherwise it's a temporary path.
+///
+/// With `--dry-run`, the user may run the commands we print later, so
+/// we use the same path as `--deterministic` and don't delete it.
+fn intermediate_path(matches: &Matches,
+                     output_name: &str,
+                     extension: &str)
+                     -> Result<TempPath, String> {
+    if matches.opt_present("save-temps") {
+        return Ok(TempPath { path: format!("{}.{}", output_name, extension), keep: true });
+    }
 
-    let opt_level = matches.opt_str("opt").unwrap_or(String::from("2"));
-    if opt_level != "0" {
-        instrs = peephole::optimize(instrs);
+    let temp_dir = matches.opt_str("temp-dir");
+    if matches.opt_present("dry-run") {
+        let mut path = try!(build_temp_path(temp_dir.as_ref().map(|s| &s[..]),
+                                            output_name,
+                                            extension,
+                                            true));
+        path.keep = true;
+        return Ok(path);
     }
+    build_temp_path(temp_dir.as_ref().map(|s| &s[..]),
+                    output_name,
+                    extension,
+                    matches.opt_present("deterministic"))
+}
 
-    let state = if opt_level == "2" {
-        execution::execute(&instrs, execution::MAX_STEPS)
-    } else {
-        execution::ExecutionState {
-            instr_ptr: 0,
-            cells: vec![Wrapping(0); bounds::highest_cell_index(&instrs) + 1],
-            cell_ptr: 0,
-            outputs: vec![],
-        }
+/// Search for an input that makes the program write `target`, and
+/// print it. Every input we try shares one budget of `--fuel` steps
+/// (`execution::MAX_STEPS` by default), so the search always finishes.
+fn search(instrs: &[Instruction], matches: &Matches, target: &str) -> Result<(), String> {
+    let alphabet = matches.opt_str("search-alphabet")
+        .unwrap_or(String::from("abcdefghijklmnopqrstuvwxyz0123456789"));
+    let max_len = match matches.opt_str("search-length") {
+        Some(len) => try!(len.parse::<usize>().map_err(
+            |_| format!("Invalid --search-length: {}", len))),
+        None => 3,
     };
-    let initial_cells: Vec<i8> = state.cells.iter()
-        .map(|x: &Wrapping<i8>| x.0).collect();
 
-    let remaining_instrs = &instrs[state.instr_ptr..];
+    let fuel = try!(parse_fuel(matches)).unwrap_or(execution::MAX_STEPS);
 
-    if matches.opt_present("dump-ir") {
-        if remaining_instrs.is_empty() {
-            println!("(optimized out)");
+    match try!(interpreter::search_inputs(instrs, alphabet.as_bytes(), max_len,
+                                          target.as_bytes(), fuel)) {
+        Some(input) => println!("{:?}", String::from_utf8_lossy(&input)),
+        None => println!("No input found."),
+    }
+    Ok(())
+}
+
+/// Run the executable we've compiled with the contents of
+/// `input_path` on stdin, and check it writes the same output as
+/// interpreting `instrs` starting with `tape`.
+fn verify(instrs: &[Instruction],
+          executable: &str,
+          input_path: &str,
+          tape: interpreter::Tape)
+          -> Result<(), String> {
+    let mut input = vec![];
+    let mut input_file = try!(convert_io_error(File::open(input_path)));
+    try!(convert_io_error(input_file.read_to_end(&mut input)));
+
+    let mut expected = vec![];
+    try!(interpreter::interpret_on_tape(instrs, &mut &input[..], &mut expected, None, tape));
+
+    let mut child = try!(convert_io_error(Command::new(runnable_path(executable))
+                                              .stdin(Stdio::piped())
+                                              .stdout(Stdio::piped())
+                                              .spawn()));
+    if let Some(mut stdin) = child.stdin.take() {
+        // Write the input from another thread while we read the
+        // output. Otherwise a program that writes as it reads could
+        // fill its stdout pipe before we'd finished, and we'd both
+        // block. Dropping stdin when we're done closes it, so the
+        // program sees EOF.
+        thread::spawn(move || {
+            let _ = stdin.write_all(&input);
+        });
+    }
+    let result = try!(convert_io_error(child.wait_with_output()));
+
+    check_outputs_match(&expected, &result.stdout)
+}
+
+/// The path to run `executable` by. A bare file name would be
+/// looked up in `PATH`, so we run it from the current directory.
+fn runnable_path(executable: &str) -> PathBuf {
+    let path = Path::new(executable);
+    match path.parent() {
+        Some(parent) if parent != Path::new("") => path.to_path_buf(),
+        _ => Path::new(".").join(path),
+    }
+}
+
+/// Compare the output of the interpreter with the output of the
+/// compiled program.
+fn check_outputs_match(interpreted: &[u8], compiled: &[u8]) -> Result<(), String> {
+    if interpreted == compiled {
+        return Ok(());
+    }
+
+    let mismatch_index = interpreted.iter().zip(compiled.iter())
+        .position(|(a, b)| a != b)
+        .unwrap_or(std::cmp::min(interpreted.len(), compiled.len()));
+    Err(format!("Verification failed: interpreter wrote {} bytes, compiled program wrote {} bytes, first difference at byte {}.",
+                interpreted.len(), compiled.len(), mismatch_index))
+}
+
+/// The line and column in `source` of the top-level instruction at
+/// `instr_ptr` in `instrs`. `positions` are the source offsets of
+/// every instruction in `walk` order, as `parse_with_positions`
+/// returns them.
+fn source_position(source: &str,
+                   instrs: &[Instruction],
+                   positions: &[usize],
+                   instr_ptr: usize)
+                   -> Option<String> {
+    if instr_ptr >= instrs.len() {
+        return None;
+    }
+    let mut index = 0;
+    bfir::walk(&instrs[..instr_ptr], &mut |_| index += 1);
+    positions.get(index).map(|&offset| {
+        let (line, column) = bfir::offset_to_line_col(source, offset);
+        format!("line {}, column {}", line, column)
+    })
+}
+
+/// Return an error if this program definitely reaches a loop that
+/// never exits. Loops that only might not terminate are fine.
+///
+/// `instrs` may be optimised, so we find the loop again in `source`
+/// to say where it is.
+fn check_no_infinite_loops(instrs: &[Instruction],
+                           source: &str,
+                           config: &bfir::ParseConfig)
+                           -> Result<(), String> {
+    match execution::halting(instrs, execution::MAX_STEPS) {
+        execution::Halting::LoopsForever(_) => {
+            let (unoptimized, positions) = try!(bfir::parse_with_positions(source, config));
+            let position = match execution::halting(&unoptimized, execution::MAX_STEPS) {
+                execution::Halting::LoopsForever(instr_ptr) => {
+                    source_position(source, &unoptimized, &positions, instr_ptr)
+                }
+                _ => None,
+            };
+            match position {
+                Some(position) => Err(format!("This program loops forever at {}.", position)),
+                None => Err("This program loops forever.".to_owned()),
+            }
         }
+        _ => Ok(()),
+    }
+}
+
+/// Without libc, we have no way of doing IO. Check the compiled
+/// program won't need to.
+fn check_freestanding(instrs: &[Instruction], static_outputs: &[i8]) -> Result<(), String> {
+    if !static_outputs.is_empty() || bfir::has_io(instrs) {
+        return Err("This program reads or writes, but --freestanding targets have no libc \
+                    to do IO with.".to_owned());
+    }
+    Ok(())
+}
 
-        for instr in remaining_instrs {
-            println!("{}", instr);
+/// Parse the comma-separated list of output types given with
+/// `--emit`. "llvm" is short for "llvm-ir".
+fn parse_emit(emit: &str) -> Result<Vec<String>, String> {
+    let mut kinds = vec![];
+    for kind in emit.split(',') {
+        let kind = match kind {
+            "llvm" => "llvm-ir",
+            "exe" | "obj" | "asm" | "llvm-ir" | "irbin" | "tokens" | "interp-trace" => kind,
+            _ => return Err(format!("Unknown --emit type: {}", kind)),
+        };
+        if !kinds.iter().any(|k| k == kind) {
+            kinds.push(kind.to_owned());
         }
-        return Ok(());
     }
+    Ok(kinds)
+}
 
-    let llvm_ir_raw = llvm::compile_to_ir(
-        path, &remaining_instrs.to_vec(), &initial_cells, state.cell_ptr as i32,
-        &state.outputs);
+/// Decode the bytes given with `--init-hex`, e.g. "4100" is [65, 0].
+fn parse_hex(hex: &str) -> Result<Vec<i8>, String> {
+    if !hex.chars().all(|c| c.is_digit(16)) {
+        return Err(format!("Invalid hex digits in --init-hex: {}", hex));
+    }
+    if hex.len() % 2 != 0 {
+        return Err(format!("--init-hex needs an even number of hex digits: {}", hex));
+    }
 
-    if matches.opt_present("dump-llvm") {
-        let llvm_ir = String::from_utf8_lossy(llvm_ir_raw.as_bytes());
-        println!("{}", llvm_ir);
-        return Ok(());
-    }                        
+    let mut bytes = vec![];
+    for i in 0..hex.len() / 2 {
+        let digits = &hex[2 * i..2 * i + 2];
+        // We've already checked these are hex digits.
+        bytes.push(u8::from_str_radix(digits, 16).unwrap() as i8);
+    }
+    Ok(bytes)
+}
 
-    // Write the LLVM IR to a temporary file.
-    let mut llvm_ir_file = try!(convert_io_error(NamedTempFile::new()));
-    let _ = llvm_ir_file.write(llvm_ir_raw.as_bytes());
+/// Parse the value given with `--init-cell-value`. We accept 0 to 255,
+/// and -128 to -1 for convenience.
+fn parse_cell_value(value: &str) -> Result<i8, String> {
+    match value.parse::<i16>() {
+        Ok(n) if n >= -128 && n <= 255 => Ok(n as i8),
+        _ => Err(format!("Invalid --init-cell-value (expected 0 to 255): {}", value)),
+    }
+}
 
-    // Compile the LLVM IR to a temporary object file.
-    let object_file = try!(convert_io_error(NamedTempFile::new()));
+/// The default size of the buffer for reading stdin with `--interpret`.
+const DEFAULT_STDIN_BUFSIZE: usize = 64 * 1024;
+
+/// Parse `--stdin-bufsize`, which must be at least one byte.
+fn parse_bufsize(matches: &Matches) -> Result<usize, String> {
+    match matches.opt_str("stdin-bufsize") {
+        Some(s) => {
+            match s.parse::<usize>() {
+                Ok(size) if size > 0 => Ok(size),
+                _ => Err(format!("Invalid --stdin-bufsize: {}", s)),
+            }
+        }
+        None => Ok(DEFAULT_STDIN_BUFSIZE),
+    }
+}
 
-    let llvm_opt_arg = format!("-O{}", matches.opt_str("llvm-opt").unwrap_or(String::from("3")));
+/// Parse `--eof`, what a read stores at EOF. Reading from
+/// `--input-fd` always leaves the cell unchanged.
+fn parse_eof(matches: &Matches) -> Result<EofBehaviour, String> {
+    let eof = match matches.opt_str("eof") {
+        Some(s) => {
+            match &s[..] {
+                "neg-one" => EofBehaviour::NegOne,
+                "zero" => EofBehaviour::Zero,
+                "unchanged" => EofBehaviour::Unchanged,
+                _ => return Err(format!("Invalid --eof (expected neg-one, zero or unchanged): {}",
+                                        s)),
+            }
+        }
+        None => EofBehaviour::NegOne,
+    };
 
-    let llc_args = [&llvm_opt_arg[..], "-filetype=obj",
-                    llvm_ir_file.path().to_str().unwrap(),
-                    "-o", object_file.path().to_str().unwrap()];
-    try!(shell_command("llc", &llc_args[..]));
+    if matches.opt_present("input-fd") {
+        if matches.opt_present("eof") && eof != EofBehaviour::Unchanged {
+            return Err("--input-fd always leaves the cell unchanged at EOF.".to_owned());
+        }
+        return Ok(EofBehaviour::Unchanged);
+    }
+    Ok(eof)
+}
 
-    // TODO: do path munging in executable_name().
-    let bf_name = Path::new(path).file_name().unwrap();
-    let output_name = executable_name(bf_name.to_str().unwrap());
+/// Options we can only compile with 8-bit cells, as they set cells
+/// to byte values or read and write cells in place.
+const BYTE_CELL_OPTIONS: &'static [&'static str] = &[
+    "argv-tape", "init-cell-value", "init-hex", "input-fd", "output-fd", "rodata-cells",
+    "print-cells"];
 
-    // Link the object file.
-    let clang_args = [object_file.path().to_str().unwrap(),
-                      "-o", &output_name[..]];
-    try!(shell_command("clang", &clang_args[..]));
+/// Parse `--cell-width`, the number of bits in each cell.
+fn parse_cell_width(matches: &Matches) -> Result<u32, String> {
+    match matches.opt_str("cell-width") {
+        Some(s) => {
+            match s.parse::<u32>() {
+                Ok(width) if interpreter::CELL_WIDTHS.contains(&width) => {
+                    if width != 8 && !matches.opt_present("interpret") {
+                        for option in BYTE_CELL_OPTIONS {
+                            if matches.opt_present(option) {
+                                return Err(format!("--{} can't be used with --cell-width {}.",
+                                                   option, width));
+                            }
+                        }
+                    }
+                    Ok(width)
+                }
+                _ => Err(format!("Invalid --cell-width (expected 8, 16 or 32): {}", s)),
+            }
+        }
+        None => Ok(8),
+    }
+}
+
+/// Parse `--tape-limit`, the most cells the interpreter's tape may
+/// grow to.
+fn parse_tape_limit(matches: &Matches) -> Result<usize, String> {
+    match matches.opt_str("tape-limit") {
+        Some(s) => {
+            match s.parse::<usize>() {
+                Ok(limit) if limit > 0 => Ok(limit),
+                _ => Err(format!("Invalid --tape-limit: {}", s)),
+            }
+        }
+        None => Ok(interpreter::DEFAULT_MAX_CELLS),
+    }
+}
 
-    // Strip the executable.
-    let strip_args = ["-s", &output_name[..]];
-    try!(shell_command("strip", &strip_args[..]));
+/// Parse `--max-binary-size`, in bytes.
+fn parse_max_binary_size(matches: &Matches) -> Result<Option<u64>, String> {
+    match matches.opt_str("max-binary-size") {
+        Some(s) => {
+            match s.parse::<u64>() {
+                Ok(size) => Ok(Some(size)),
+                _ => Err(format!("Invalid --max-binary-size: {}", s)),
+            }
+        }
+        None => Ok(None),
+    }
+}
 
+/// Check the executable at `path` is no larger than `max_size`
+/// bytes.
+fn check_binary_size(path: &str, max_size: u64) -> Result<(), String> {
+    let size = try!(convert_io_error(std::fs::metadata(path))).len();
+    if size > max_size {
+        return Err(format!("{} is {} bytes, larger than --max-binary-size {}. Try a smaller \
+                            tape or fewer unrolled loops.",
+                           path, size, max_size));
+    }
     Ok(())
 }
 
-#[cfg_attr(test, allow(dead_code))]
-fn main() {
-    let args: Vec<_> = env::args().collect();
+/// Parse `--max-loop-unroll-factor`. Without it, we don't unroll.
+fn parse_unroll_factor(matches: &Matches) -> Result<usize, String> {
+    match matches.opt_str("max-loop-unroll-factor") {
+        Some(s) => {
+            match s.parse::<usize>() {
+                Ok(factor) if factor > 0 => Ok(factor),
+                _ => Err(format!("Invalid --max-loop-unroll-factor: {}", s)),
+            }
+        }
+        None => Ok(1),
+    }
+}
 
-    let mut opts = Options::new();
+/// Parse `--tape-align`, which must be a power of two.
+fn parse_tape_align(matches: &Matches) -> Result<Option<u32>, String> {
+    match matches.opt_str("tape-align") {
+        Some(s) => {
+            match s.parse::<u32>() {
+                Ok(align) if align.is_power_of_two() => Ok(Some(align)),
+                _ => Err(format!("Invalid --tape-align (expected a power of two): {}", s)),
+            }
+        }
+        None => Ok(None),
+    }
+}
 
-    opts.optflag("h", "help", "show usage");
-    opts.optflag("", "dump-llvm", "print LLVM IR generated");
-    opts.optflag("", "dump-ir", "print BF IR generated");
+/// The BF dialect to parse. With `--whitespace-only`, the source may
+/// only contain commands and whitespace. Runs of `+` and `-` are only
+/// combined for 8-bit cells.
+fn parse_config(matches: &Matches) -> bfir::ParseConfig {
+    let whitespace_only = matches.opt_present("whitespace-only");
+    let byte_cells = matches.opt_str("cell-width").map_or(true, |width| width == "8");
+    bfir::ParseConfig {
+        reject_unknown: whitespace_only,
+        allow_whitespace: whitespace_only,
+        combine_increments: byte_cells,
+        // With -g we parse `#` so it still has a source line, even
+        // though we only compile it with --debug-cmd.
+        debug_command: matches.opt_present("debug-cmd") || matches.opt_present("debug"),
+        ..bfir::ParseConfig::default()
+    }
+}
 
-    opts.optopt("O", "opt", "optimization level (0 to 2)", "LEVEL");
-    opts.optopt("", "llvm-opt", "LLVM optimization level (0 to 3)", "LEVEL");
+/// Our passes should always reach a fixed point, so if they don't,
+/// it's a bug in bfc. The last iteration is still correct, so we
+/// report the pass at fault and carry on.
+fn converged_or_report(result: Result<Vec<Instruction>, peephole::NoFixedPoint>)
+                       -> Vec<Instruction> {
+    match result {
+        Ok(instrs) => instrs,
+        Err(e) => {
+            let _ = writeln!(&mut std::io::stderr(),
+                             "internal error: optimisations did not converge after {} \
+                              iterations (last changed by {})",
+                             peephole::MAX_OPT_ITERATIONS, e.pass_name);
+            e.instrs
+        }
+    }
+}
 
-    let matches = match opts.parse(&args[1..]) {
-        Ok(m) => {
-            m
+/// Parse `src`. Unless we're compiling `#` with `--debug-cmd`, it's
+/// just a comment, so we remove it.
+fn parse_program(src: &str,
+                 matches: &Matches,
+                 config: &bfir::ParseConfig)
+                 -> Result<Vec<Instruction>, String> {
+    let instrs = try!(bfir::parse_with_config(src, config));
+    if matches.opt_present("debug-cmd") {
+        Ok(instrs)
+    } else {
+        Ok(peephole::remove_debug(instrs))
+    }
+}
+
+/// Parse `--print-cells`, the number of cells to print at exit.
+fn parse_print_cells(matches: &Matches) -> Result<Option<usize>, String> {
+    match matches.opt_str("print-cells") {
+        Some(s) => {
+            match s.parse::<usize>() {
+                Ok(num_cells) => Ok(Some(num_cells)),
+                _ => Err(format!("Invalid --print-cells: {}", s)),
+            }
+        }
+        None => Ok(None),
+    }
+}
+
+/// Parse a file descriptor given with `--input-fd` or `--output-fd`.
+fn fd_option(matches: &Matches, name: &str) -> Result<Option<i32>, String> {
+    match matches.opt_str(name) {
+        Some(s) => {
+            match s.parse::<i32>() {
+                Ok(fd) if fd >= 0 => Ok(Some(fd)),
+                _ => Err(format!("Invalid file descriptor for --{}: {}", name, s)),
+            }
+        }
+        None => Ok(None),
+    }
+}
+
+/// The result of speculative execution.
+struct Speculation {
+    /// The state we should start the compiled program from.
+    state: execution::ExecutionState,
+    warning: Option<diagnostics::Warning>,
+    /// Why we stopped executing, or None if we didn't speculate.
+    outcome: Option<execution::Outcome>,
+    steps: u64,
+}
+
+/// The state we start speculative execution of `instrs` from: the
+/// tape starts with `init_cells`, and every other cell is `fill`.
+fn initial_tape(instrs: &[Instruction], init_cells: &[i8], fill: i8) -> execution::ExecutionState {
+    let mut state = execution::initial_state_filled(instrs, Wrapping(fill));
+    if state.cells.len() < init_cells.len() {
+        state.cells.resize(init_cells.len(), Wrapping(fill));
+    }
+    for (cell, &value) in state.cells.iter_mut().zip(init_cells) {
+        *cell = Wrapping(value);
+    }
+    state
+}
+
+/// Speculatively execute as much of `instrs` as `speculate` allows,
+/// returning the state we should start the compiled program from.
+///
+/// "off" doesn't execute anything, "fold" executes until the first
+/// read or write, and "fold+output" also executes writes so their
+/// output is compiled into the program.
+///
+/// The tape starts with `init_cells`, and every other cell is
+/// `fill`. If the program definitely accesses a cell outside the tape,
+/// we also return a warning. `instrs` may be optimised, so the warning
+/// runs `source` again as written to say where it happened.
+fn speculative_state(instrs: &[Instruction],
+                     source: &str,
+                     config: &bfir::ParseConfig,
+                     speculate: &str,
+                     init_cells: &[i8],
+                     fill: i8)
+                     -> Result<Speculation, String> {
+    let initial_state = initial_tape(instrs, init_cells, fill);
+
+    let io_mode = match speculate {
+        "off" => {
+            return Ok(Speculation { state: initial_state, warning: None, outcome: None, steps: 0 });
         }
-        Err(_) => {
-            print_usage(&args[0], opts);
-            std::process::exit(1);
+        "fold" => execution::IoMode::NoOutput,
+        "fold+output" => execution::IoMode::Normal,
+        _ => return Err(format!("Unknown --speculate mode: {}", speculate)),
+    };
+
+    let (state, outcome, steps) = execution::execute_with_outcome(
+        instrs, initial_state, execution::MAX_STEPS, io_mode);
+    let warning = match outcome {
+        execution::Outcome::RuntimeError(ref error) => {
+            let (unoptimized, positions) = try!(bfir::parse_with_positions(source, config));
+            let (_, unoptimized_outcome, _) = execution::execute_with_outcome(
+                &unoptimized, initial_tape(&unoptimized, init_cells, fill),
+                execution::MAX_STEPS, io_mode);
+            let position = match unoptimized_outcome {
+                execution::Outcome::RuntimeError(ref unoptimized_error) => {
+                    source_position(source, &unoptimized, &positions,
+                                    unoptimized_error.instr_ptr)
+                }
+                _ => None,
+            };
+            let message = match position {
+                Some(position) => {
+                    format!("pointer moved out of bounds during compile-time evaluation \
+                             (cell index {}, at {})",
+                            error.cell_ptr, position)
+                }
+                None => {
+                    format!("pointer moved out of bounds during compile-time evaluation \
+                             (cell index {})",
+                            error.cell_ptr)
+                }
+            };
+            Some(diagnostics::Warning { message: message })
         }
+        _ => None,
     };
+    Ok(Speculation { state: state, warning: warning, outcome: Some(outcome), steps: steps })
+}
 
-    if matches.opt_present("h") {
-        print_usage(&args[0], opts);
-        return;
+/// Format `stats` for `--dump-stats`, which is either "text" (the
+/// default) or "json".
+fn format_stats(stats: &stats::Stats, format: Option<String>) -> Result<String, String> {
+    match format.as_ref().map(|s| &s[..]) {
+        None | Some("text") => Ok(stats.to_text()),
+        Some("json") => Ok(format!("{}\n", try!(stats.to_json()))),
+        Some(format) => Err(format!("Unknown --dump-stats format: {}", format)),
+    }
+}
+
+/// Describe each loop that our peephole optimisations transform, for
+/// `--explain`. We parse `source` with `config`, so we know where
+/// each loop starts whatever characters the dialect uses.
+fn explain(source: &str, config: &bfir::ParseConfig) -> Result<String, String> {
+    let (instrs, positions) = try!(bfir::parse_with_positions(source, config));
+
+    let mut loop_offsets = vec![];
+    let mut index = 0;
+    bfir::walk(&instrs, &mut |instr| {
+        if let &bfir::Instruction::Loop(_, _) = instr {
+            loop_offsets.push(positions[index]);
+        }
+        index += 1;
+    });
+    let loop_lines = bfir::offsets_to_lines(source, &loop_offsets);
+
+    let mut result = String::new();
+    let explanations = peephole::explain_loops(&instrs);
+    for (line, explanation) in loop_lines.into_iter().zip(explanations) {
+        if let Some(explanation) = explanation {
+            result.push_str(&format!("loop at line {} recognized as {}\n", line, explanation));
+        }
     }
 
-    if matches.free.len() != 1 {
-        print_usage(&args[0], opts);
-        std::process::exit(1);
+    if result.is_empty() {
+        result.push_str("No loops were transformed.\n");
     }
+    Ok(result)
+}
 
-    match compile_file(&matches) {
-        Ok(_) => {}
-        Err(e) => {
-            // TODO: this should go to stderr.
-            println!("{}", e);
-            std::process::exit(2);
+/// List the source span of each loop that we optimise away and why,
+/// for `--dump-removed`. `instrs` are the unoptimised instructions
+/// parsed from `source` with `config`. If speculative execution ran
+/// the whole program, every other loop was speculatively executed.
+fn removed_report(source: &str,
+                  config: &bfir::ParseConfig,
+                  instrs: &[Instruction],
+                  zeroed_tape: bool,
+                  speculated_all: bool)
+                  -> String {
+    let mut result = String::new();
+    let reasons = peephole::removed_loops(instrs, zeroed_tape);
+
+    let mut reported_end = None;
+    for ((start, end), reason) in bfir::loop_spans(source, config).into_iter().zip(reasons) {
+        // Loops inside a loop we've reported are removed too.
+        if let Some(reported_end) = reported_end {
+            if end < reported_end {
+                continue;
+            }
+        }
+        let reason = match reason {
+            Some(reason) => reason,
+            None if speculated_all => "speculatively executed",
+            None => continue,
+        };
+
+        let (start_line, start_column) = bfir::offset_to_line_col(source, start);
+        let (end_line, end_column) = bfir::offset_to_line_col(source, end);
+        result.push_str(&format!("line {}, column {} to line {}, column {}: {}\n",
+                                 start_line, start_column, end_line, end_column, reason));
+        reported_end = Some(end);
+    }
+
+    if result.is_empty() {
+        result.push_str("No loops were removed.\n");
+    }
+    result
+}
+
+/// Describe how many cells `instrs` needs, for `--report-tape`.
+fn tape_report(instrs: &[Instruction]) -> String {
+    match bounds::min_tape_size(instrs) {
+        Some(cells) => format!("minimum tape size: {} cells (static)\n", cells),
+        None => {
+            if bfir::has_reads(instrs) {
+                "minimum tape size: unbounded (depends on input)\n".to_owned()
+            } else {
+                "minimum tape size: unbounded (depends on loop iterations)\n".to_owned()
+            }
+        }
+    }
+}
+
+/// The options that affect the code we generate. Other options only
+/// affect what we print or where we write files.
+const CACHE_KEY_OPTIONS: &'static [&'static str] = &[
+    "opt", "llvm-opt", "speculate", "argv-tape", "init-cell-value", "init-hex", "input-fd", "output-fd",
+    "named-values", "loop-functions", "debug-assert", "max-loop-unroll-factor",
+    "tape-align", "heap-tape", "rodata-cells", "print-cells", "whitespace-only",
+    "global-ctor", "cell-width", "passes", "fixpoint", "eof", "debug", "target",
+    "debug-cmd"];
+
+/// Compute a key for build caching from the source and the compile
+/// options that affect the output. If the key is the same, a cached
+/// artifact is still valid.
+///
+/// We use 64-bit FNV-1a, so the key is stable across Rust versions
+/// and platforms.
+fn cache_key(source: &str, matches: &Matches) -> u64 {
+    let mut hash = fnv1a(0xcbf29ce484222325, source.as_bytes());
+    for name in CACHE_KEY_OPTIONS {
+        // Separate each option with a NUL, so "--opt=1
+        // --llvm-opt=23" differs from "--opt=12 --llvm-opt=3".
+        hash = fnv1a(hash, &[0]);
+        hash = fnv1a(hash, name.as_bytes());
+        if let Some(value) = matches.opt_str(name) {
+            hash = fnv1a(hash, b"=");
+            hash = fnv1a(hash, value.as_bytes());
+        } else if matches.opt_present(name) {
+            hash = fnv1a(hash, b"!");
+        }
+    }
+    hash
+}
+
+/// Add `bytes` to an FNV-1a hash.
+fn fnv1a(hash: u64, bytes: &[u8]) -> u64 {
+    let mut hash = hash;
+    for byte in bytes {
+        hash ^= *byte as u64;
+        hash = hash.wrapping_mul(0x100000001b3);
+    }
+    hash
+}
+
+/// The file in `--cache-dir` where we store optimized BF IR with
+/// this cache key, if we have a cache directory.
+fn cache_dir_path(matches: &Matches, cache_key: u64) -> Option<String> {
+    matches.opt_str("cache-dir").map(|dir| {
+        Path::new(&dir).join(format!("{:016x}.irbin", cache_key)).to_str().unwrap().to_owned()
+    })
+}
+
+/// Load previously optimized BF IR from the file given with
+/// `--from-irbin`, or from `--cache-dir`, unless it was built from a
+/// different source or different options.
+fn load_cached_ir(matches: &Matches, cache_key: u64)
+                  -> Result<Option<Vec<Instruction>>, String> {
+    let cache_path = match matches.opt_str("from-irbin") {
+        Some(cache_path) => cache_path,
+        None => {
+            match cache_dir_path(matches, cache_key) {
+                Some(cache_path) => cache_path,
+                None => return Ok(None),
+            }
+        }
+    };
+
+    let mut bytes = vec![];
+    let mut file = match File::open(&cache_path) {
+        Ok(file) => file,
+        // No cache yet, so we have to compile from source.
+        Err(_) => return Ok(None),
+    };
+    try!(convert_io_error(file.read_to_end(&mut bytes)));
+
+    if try!(irbin::cache_key(&bytes)) != cache_key {
+        return Ok(None);
+    }
+
+    irbin::decode(&bytes).map(Some)
+}
+
+/// The executable to write: the path given with `-o`, or a name
+/// based on the source file in the current directory.
+fn output_name(matches: &Matches, source: &Source) -> Result<String, String> {
+    let output = match matches.opt_str("output") {
+        Some(output) => output,
+        None => return Ok(source.executable_name()),
+    };
+
+    let path = Path::new(&output);
+    if path.is_dir() {
+        return Err(format!("Output path {} is a directory.", output));
+    }
+    if let Some(parent) = path.parent() {
+        if parent != Path::new("") && !parent.is_dir() {
+            return Err(format!("Output directory {} does not exist.", parent.display()));
+        }
+    }
+    Ok(output.clone())
+}
+
+/// Where to write the `--emit` output with this file extension. If
+/// `-o` is given and this is the only file we're emitting, we write
+/// exactly there. Otherwise we add the extension to `output_name`.
+fn emit_path(matches: &Matches, emit: &[String], output_name: &str, extension: &str) -> String {
+    // Tokens go to stdout.
+    let emitted_files = emit.iter().filter(|kind| *kind != "tokens").count();
+    if matches.opt_present("output") && emitted_files == 1 {
+        output_name.to_owned()
+    } else {
+        format!("{}.{}", output_name, extension)
+    }
+}
+
+fn compile_file(matches: &Matches, source: &Source) -> Result<(), String> {
+    compile_file_to(matches, source, &try!(output_name(matches, source)))
+}
+
+/// A temporary file that we delete when we're done with it, unless
+/// we've been asked to `keep` it. We only track the path, so other
+/// programs can write it.
+struct TempPath {
+    path: String,
+    keep: bool,
+}
+
+impl Drop for TempPath {
+    fn drop(&mut self) {
+        if !self.keep {
+            let _ = std::fs::remove_file(&self.path);
+        }
+    }
+}
+
+/// Compile `source` to an executable in the temporary directory.
+fn compile_temp_executable(matches: &Matches, source: &Source) -> Result<TempPath, String> {
+    // Use the temporary file's unique name, but not the file itself:
+    // we can't run a file that's still open for writing.
+    let temp_file = try!(new_temp_file(matches.opt_str("temp-dir").as_ref().map(|s| &s[..])));
+    let executable = TempPath {
+        path: format!("{}.exe", temp_file.path().to_str().unwrap()),
+        keep: false,
+    };
+
+    try!(compile_file_to(matches, source, &executable.path));
+    Ok(executable)
+}
+
+/// How long running a compiled program took, for `--benchmark`.
+#[derive(Debug,Clone,Copy,PartialEq,Eq)]
+enum RunTime {
+    NotRun,
+    Finished(u64),
+    TimedOut,
+}
+
+/// How long each stage took for one program, in nanoseconds.
+#[derive(Debug,Clone,PartialEq,Eq)]
+struct BenchmarkRow {
+    name: String,
+    parse: u64,
+    optimize: u64,
+    speculate: u64,
+    run: RunTime,
+}
+
+/// The default for `--benchmark-timeout`, in seconds.
+const DEFAULT_BENCHMARK_TIMEOUT: u64 = 10;
+
+/// Time parsing, optimising and speculatively executing every `.bf`
+/// file in `dir`, for `--benchmark`. With `--input`, we also compile
+/// each program and time running it on that input.
+fn benchmark(matches: &Matches, dir: &str) -> Result<Vec<BenchmarkRow>, String> {
+    let timeout = match matches.opt_str("benchmark-timeout") {
+        Some(s) => {
+            match s.parse::<u64>() {
+                Ok(timeout) if timeout > 0 => timeout,
+                _ => return Err(format!("Invalid --benchmark-timeout: {}", s)),
+            }
         }
+        None => DEFAULT_BENCHMARK_TIMEOUT,
+    };
+
+    let mut paths = vec![];
+    for entry in try!(convert_io_error(std::fs::read_dir(dir))) {
+        let path = try!(convert_io_error(entry)).path();
+        if path.extension().and_then(|ext| ext.to_str()) == Some("bf") {
+            paths.push(path);
+        }
+    }
+    paths.sort();
+
+    let config = parse_config(matches);
+    let mut rows = vec![];
+    for path in paths {
+        let name = path.file_name().unwrap().to_str().unwrap().to_owned();
+        let path = path.to_str().unwrap().to_owned();
+        let src = try!(convert_io_error(slurp(&path)));
+
+        let start = time::precise_time_ns();
+        let instrs = try!(parse_program(&src, matches, &config)
+                              .map_err(|e| format!("{}: {}", path, e)));
+        let parsed = time::precise_time_ns();
+        let instrs = peephole::optimize(instrs);
+        let optimized = time::precise_time_ns();
+        execution::execute(&instrs, execution::MAX_STEPS);
+        let speculated = time::precise_time_ns();
+
+        let run = match matches.opt_str("input") {
+            Some(input_path) => try!(time_native_run(matches, &path, &input_path, timeout)),
+            None => RunTime::NotRun,
+        };
+
+        rows.push(BenchmarkRow {
+            name: name,
+            parse: parsed - start,
+            optimize: optimized - parsed,
+            speculate: speculated - optimized,
+            run: run,
+        });
     }
+    Ok(rows)
+}
+
+/// Compile the program at `path`, then time running it with the
+/// contents of `input_path` on stdin. We give up after `timeout`
+/// seconds.
+fn time_native_run(matches: &Matches,
+                   path: &str,
+                   input_path: &str,
+                   timeout: u64)
+                   -> Result<RunTime, String> {
+    let executable = try!(compile_temp_executable(matches, &Source::File(path.to_owned())));
+    let mut input = vec![];
+    let mut input_file = try!(convert_io_error(File::open(input_path)));
+    try!(convert_io_error(input_file.read_to_end(&mut input)));
+
+    let start = time::precise_time_ns();
+    let mut child = try!(convert_io_error(Command::new(&executable.path)
+                                              .stdin(Stdio::piped())
+                                              .stdout(Stdio::null())
+                                              .spawn()));
+    if let Some(mut stdin) = child.stdin.take() {
+        // Write the input from another thread, so a program that
+        // never reads can't keep us past the timeout. The program may
+        // also exit without reading all its input.
+        thread::spawn(move || {
+            let _ = stdin.write_all(&input);
+        });
+    }
+
+    let deadline = start.saturating_add(timeout.saturating_mul(1000000000));
+    if try!(wait_until(&mut child, deadline)) {
+        Ok(RunTime::Finished(time::precise_time_ns() - start))
+    } else {
+        Ok(RunTime::TimedOut)
+    }
+}
+
+/// Wait for `child` to exit, polling until `deadline`, a time from
+/// `time::precise_time_ns`. If it's still running then, we kill it
+/// and return false.
+fn wait_until(child: &mut Child, deadline: u64) -> Result<bool, String> {
+    loop {
+        if try!(convert_io_error(child.try_wait())).is_some() {
+            return Ok(true);
+        }
+        if time::precise_time_ns() >= deadline {
+            let _ = child.kill();
+            let _ = child.wait();
+            return Ok(false);
+        }
+        thread::sleep(Duration::from_millis(1));
+    }
+}
+
+/// Format benchmark results as a table, with times in milliseconds.
+fn format_benchmark(rows: &[BenchmarkRow]) -> String {
+    let name_width = rows.iter().map(|row| row.name.len()).fold("program".len(), std::cmp::max);
+    let ms = |ns: u64| format!("{:.3}", ns as f64 / 1000000.0);
+
+    let mut result = format!("{:name_width$} {:>10} {:>10} {:>10} {:>10}\n",
+                             "program", "parse", "optimize", "speculate", "run",
+                             name_width = name_width);
+    for row in rows {
+        let run = match row.run {
+            RunTime::NotRun => "-".to_owned(),
+            RunTime::Finished(ns) => ms(ns),
+            RunTime::TimedOut => "timeout".to_owned(),
+        };
+        result.push_str(&format!("{:name_width$} {:>10} {:>10} {:>10} {:>10}\n",
+                                 row.name, ms(row.parse), ms(row.optimize), ms(row.speculate), run,
+                                 name_width = name_width));
+    }
+    result
+}
+
+/// Compile `source`, then run it with `args` and our stdio, for
+/// `--run-native`. Returns the program's exit code.
+fn run_native(matches: &Matches, source: &Source, args: &[String]) -> Result<i32, String> {
+    let executable = try!(compile_temp_executable(matches, source));
+    let status = try!(convert_io_error(Command::new(&executable.path).args(args).status()));
+
+    // If the program was killed by a signal, there's no exit code.
+    Ok(status.code().unwrap_or(1))
+}
+
+fn compile_file_to(matches: &Matches, source: &Source, output_name: &str) -> Result<(), String> {
+    // We can only read stdin once, so read the source up front.
+    let src = try!(source.read());
+
+    let emit = try!(parse_emit(&matches.opt_str("emit").unwrap_or(String::from("exe"))));
+    let emits = |kind: &str| emit.iter().any(|e| e == kind);
+
+    let parse_config = parse_config(matches);
+
+    if emits("tokens") {
+        for (index, token) in bfir::tokens(&src, &parse_config) {
+            println!("{}: {}", index, token);
+        }
+        if emit.len() == 1 {
+            return Ok(());
+        }
+    }
+
+    // If the initial cells come from argv, we don't know their values
+    // at compile time.
+    let argv_tape = matches.opt_present("argv-tape");
+
+    if matches.opt_present("global-ctor") {
+        if argv_tape {
+            return Err("--global-ctor can't be used with --argv-tape.".to_owned());
+        }
+        if emits("exe") {
+            return Err("--global-ctor needs --emit=obj or --emit=llvm-ir, as the program \
+                        has no main.".to_owned());
+        }
+    }
+
+    let init_cells = match matches.opt_str("init-hex") {
+        Some(hex) => try!(parse_hex(&hex)),
+        None => vec![],
+    };
+    let fill = match matches.opt_str("init-cell-value") {
+        Some(value) => try!(parse_cell_value(&value)),
+        None => 0,
+    };
+    // Our analyses assume the cells start at zero unless we've been
+    // told otherwise.
+    let zeroed_tape = !argv_tape && init_cells.is_empty() && fill == 0;
+    // If a read can leave the cell unchanged, earlier changes to the
+    // cell aren't dead.
+    let eof = try!(parse_eof(matches));
+    let read_overwrites = eof != EofBehaviour::Unchanged;
+    // With --print-cells, the tape at exit is output, so code at the
+    // end of the program isn't dead.
+    let print_cells = try!(parse_print_cells(matches));
+
+    let opt_level = matches.opt_str("opt").unwrap_or(String::from("2"));
+    let cell_width = try!(parse_cell_width(matches));
+
+    // Debug info maps each instruction back to the source, so we
+    // compile the instructions exactly as written.
+    let debug = matches.opt_present("debug");
+    let debug_cmd = matches.opt_present("debug-cmd");
+    if debug && matches.opt_present("loop-functions") {
+        return Err("-g can't be used with --loop-functions.".to_owned());
+    }
+    if matches.opt_present("fixpoint") && !matches.opt_present("passes") {
+        return Err("--fixpoint requires --passes, as our default passes always run until \
+                    nothing changes.".to_owned());
+    }
+    let mut debug_lines = None;
+    let warnings_as_errors = matches.opt_present("warnings-as-errors");
+
+    let cache_key = cache_key(&src, matches);
+
+    // For --dump-stats. If we use cached IR, we only know the
+    // optimised instructions.
+    let mut instrs_before = None;
+    let mut pass_changes = vec![];
+
+    if matches.opt_present("explain") {
+        print!("{}", try!(explain(&src, &parse_config)));
+        return Ok(());
+    }
+
+    if matches.opt_present("report-tape") {
+        let instrs = try!(parse_program(&src, matches, &parse_config));
+        print!("{}", tape_report(&instrs));
+        return Ok(());
+    }
+
+    // Cached IR doesn't say where each instruction came from.
+    let cached = if debug {
+        None
+    } else {
+        try!(load_cached_ir(matches, cache_key))
+    };
+    let instrs = match cached {
+        Some(instrs) => instrs,
+        None => {
+            let (mut instrs, positions) = try!(bfir::parse_with_positions(&src, &parse_config));
+            instrs_before = Some(stats::count_instrs(&instrs));
+            // `positions` still lines up with `instrs` here, before we
+            // optimise.
+            let loop_warnings = if opt_level != "0" {
+                diagnostics::infinite_loops(&instrs, &positions, &src)
+            } else {
+                vec![]
+            };
+            if debug {
+                debug_lines = Some(bfir::offsets_to_lines(&src, &positions));
+            }
+
+            // Check for warnings before optimising, as we may
+            // optimise away the problematic code.
+            let mut warnings = diagnostics::check(&instrs);
+            warnings.extend(loop_warnings);
+            try!(diagnostics::report(&warnings, warnings_as_errors));
+            // The notes assume every cell starts at zero.
+            if matches.opt_present("verbose") && zeroed_tape {
+                diagnostics::report_notes(&diagnostics::unchanged_reads(&instrs));
+            }
+
+            if debug {
+                // Optimising would lose track of the source lines.
+            } else if cell_width != 8 {
+                // Our optimisations combine values as 8-bit cells,
+                // so run wider cells exactly as written.
+                if matches.opt_present("verbose") && opt_level != "0" {
+                    diagnostics::report_notes(&[diagnostics::Warning {
+                        message: format!("--cell-width {} turns off peephole optimisation and \
+                                          speculative execution.",
+                                         cell_width),
+                    }]);
+                }
+            } else if let Some(names) = matches.opt_str("passes") {
+                let names: Vec<_> = names.split(',').map(|name| name.trim()).collect();
+                let passes = try!(peephole::named_passes(&names, zeroed_tape, read_overwrites,
+                                                         print_cells.is_some()));
+                instrs = converged_or_report(peephole::run_passes(
+                    instrs, &passes, matches.opt_present("fixpoint")));
+            } else if opt_level != "0" && matches.opt_present("dump-stats") {
+                let (optimized, changes) = peephole::optimize_with_counts(instrs, zeroed_tape,
+                                                                           read_overwrites,
+                                                                           print_cells.is_some());
+                instrs = converged_or_report(optimized);
+                pass_changes = changes;
+            } else if opt_level != "0" {
+                instrs = converged_or_report(peephole::optimize_for_eof(instrs, zeroed_tape,
+                                                                         read_overwrites,
+                                                                         print_cells.is_some()));
+            } else if matches.opt_present("interpret") {
+                // -O0 controls the code we generate, but we always
+                // want to interpret quickly.
+                instrs = peephole::combine_only(instrs);
+            }
+
+            match cache_dir_path(matches, cache_key) {
+                Some(ref cache_path) if !debug => {
+                    try!(convert_io_error(std::fs::create_dir_all(
+                        matches.opt_str("cache-dir").unwrap())));
+                    let mut cache_file = try!(convert_io_error(File::create(cache_path)));
+                    try!(convert_io_error(cache_file.write_all(&irbin::encode(&instrs,
+                                                                              cache_key))));
+                }
+                _ => {}
+            }
+            instrs
+        }
+    };
+
+    if matches.opt_present("halting") {
+        let halting = if !zeroed_tape {
+            execution::Halting::MayNotTerminate
+        } else {
+            execution::halting(&instrs, execution::MAX_STEPS)
+        };
+        println!("{}", halting);
+        return Ok(());
+    }
+
+    if matches.opt_present("fail-on-infinite-loop") && zeroed_tape {
+        try!(check_no_infinite_loops(&instrs, &src, &parse_config));
+    }
+
+    if let Some(target) = matches.opt_str("search") {
+        return search(&instrs, matches, &target);
+    }
+
+    if matches.opt_present("interpret") {
+        let stdin = std::io::stdin();
+        let stdout = std::io::stdout();
+        let fuel = try!(parse_fuel(matches));
+        let bufsize = try!(parse_bufsize(matches));
+        let max_cells = try!(parse_tape_limit(matches));
+        let mut input = BufReader::with_capacity(bufsize, stdin.lock());
+        let tape = interpreter::Tape::for_program(&instrs, max_cells, cell_width)
+            .with_eof(eof)
+            .with_initial_cells(&init_cells, fill);
+        return interpreter::interpret_on_tape(&instrs, &mut input, &mut stdout.lock(), fuel,
+                                              tape);
+    }
+
+    if emits("irbin") {
+        let irbin_path = emit_path(matches, &emit, output_name, "irbin");
+        let mut irbin_file = try!(convert_io_error(File::create(irbin_path)));
+        try!(convert_io_error(irbin_file.write_all(&irbin::encode(&instrs, cache_key))));
+    }
+
+    if emits("interp-trace") {
+        try!(write_interp_trace(&instrs, matches, &emit_path(matches, &emit, output_name,
+                                                             "trace")));
+    }
+
+    // Everything else needs LLVM IR.
+    if !emits("llvm-ir") && !emits("asm") && !emits("obj") && !emits("exe")
+        && !matches.opt_present("dump-ir")
+        && !matches.opt_present("dump-llvm") && !matches.opt_present("dump-stats")
+        && !matches.opt_present("dump-removed") && !matches.opt_present("run") {
+        return Ok(());
+    }
+
+    // We can't speculatively execute if the initial cells depend on
+    // argv. Speculative execution also only models 8-bit cells, would
+    // leave instructions out of the debug info, and can't print the
+    // tape for `#`.
+    let speculate = if argv_tape || cell_width != 8 || debug || debug_cmd {
+        String::from("off")
+    } else {
+        matches.opt_str("speculate").unwrap_or(String::from(if opt_level == "2" {
+            "fold+output"
+        } else {
+            "off"
+        }))
+    };
+    let speculation = try!(speculative_state(&instrs, &src, &parse_config, &speculate,
+                                             &init_cells, fill));
+    let state = speculation.state;
+    if let Some(warning) = speculation.warning {
+        // Programs that start with "<" are already reported when
+        // we parse.
+        if !bounds::starts_out_of_bounds(&instrs) {
+            try!(diagnostics::report(&[warning], warnings_as_errors));
+        }
+    }
+    let mut initial_cells: Vec<i8> = state.cells.iter()
+        .map(|x: &Wrapping<i8>| x.0).collect();
+    // Make sure there are as many cells as --print-cells asks for.
+    if let Some(num_cells) = print_cells {
+        if num_cells > initial_cells.len() {
+            let extra = num_cells - initial_cells.len();
+            initial_cells.extend(std::iter::repeat(0).take(extra));
+        }
+    }
+
+    let remaining_instrs = &instrs[state.instr_ptr..];
+
+    if matches.opt_present("dump-removed") {
+        // Our reasons describe the default optimisations.
+        let optimised = cell_width == 8 && opt_level != "0" && !matches.opt_present("passes");
+        let source_instrs = try!(parse_program(&src, matches, &parse_config));
+        let speculated_all = speculation.outcome.is_some() && remaining_instrs.is_empty();
+        let report = if optimised {
+            removed_report(&src, &parse_config, &source_instrs, zeroed_tape, speculated_all)
+        } else {
+            removed_report(&src, &parse_config, &[], zeroed_tape, speculated_all)
+        };
+        print!("{}", report);
+        return Ok(());
+    }
+
+    if matches.opt_present("dump-stats") {
+        let stats = stats::Stats {
+            instrs_before: instrs_before.unwrap_or(stats::count_instrs(&instrs)),
+            instrs_after: stats::count_instrs(&instrs),
+            pass_changes: pass_changes,
+            speculation: match speculation.outcome {
+                Some(outcome) => format!("{}", outcome),
+                None => String::from("off"),
+            },
+            steps: speculation.steps,
+            tape_size: state.cells.len(),
+        };
+        print!("{}", try!(format_stats(&stats, matches.opt_str("dump-stats"))));
+        return Ok(());
+    }
+
+    if matches.opt_present("freestanding") {
+        try!(check_freestanding(remaining_instrs, &state.outputs));
+    }
+
+    if matches.opt_present("dump-ir") {
+        if remaining_instrs.is_empty() {
+            println!("(optimized out)");
+        }
+
+        print!("{}", bfir::format_aligned(remaining_instrs));
+        return Ok(());
+    }
+
+    let target = try!(parse_target(matches));
+    let codegen_options = llvm::CodegenOptions {
+        argv_tape: argv_tape,
+        input_fd: try!(fd_option(matches, "input-fd")),
+        eof: eof,
+        output_fd: try!(fd_option(matches, "output-fd")),
+        cache_key: Some(cache_key),
+        named_values: matches.opt_present("named-values"),
+        loop_functions: matches.opt_present("loop-functions"),
+        debug_assert: matches.opt_present("debug-assert"),
+        max_loop_unroll_factor: try!(parse_unroll_factor(matches)),
+        tape_align: try!(parse_tape_align(matches)),
+        heap_tape: matches.opt_present("heap-tape"),
+        rodata_cells: matches.opt_present("rodata-cells"),
+        print_cells: print_cells,
+        global_ctor: matches.opt_present("global-ctor"),
+        cell_width: if cell_width == 8 {
+            None
+        } else {
+            Some(cell_width)
+        },
+        debug_lines: debug_lines,
+        target_triple: target.clone(),
+        debug_cmd: debug_cmd,
+        fuel: if matches.opt_present("run") {
+            try!(parse_fuel(matches))
+        } else {
+            None
+        },
+    };
+    if matches.opt_present("verbose") {
+        let _ = writeln!(&mut std::io::stderr(), "tape size: {} cells", initial_cells.len());
+    }
+
+    if matches.opt_present("run") {
+        let exit_code = try!(llvm::run_jit(source.name(), remaining_instrs, &initial_cells,
+                                           state.cell_ptr as i32, &state.outputs,
+                                           &codegen_options, program_args(matches)));
+        // The program shares our stdout, so there's nothing to
+        // clean up before exiting with its exit code.
+        if exit_code != 0 {
+            std::process::exit(exit_code);
+        }
+        return Ok(());
+    }
+
+    let llvm_ir_raw = llvm::compile_to_ir(
+        source.name(), &remaining_instrs.to_vec(), &initial_cells, state.cell_ptr as i32,
+        &state.outputs, &codegen_options);
+
+    let llvm_opt_level = matches.opt_str("llvm-opt").unwrap_or(String::from("3"));
+    let llvm_ir = llvm_ir_for_llc(llvm_ir_raw.as_bytes(), &llvm_opt_level);
+
+    if matches.opt_present("dump-llvm") {
+        let stdout = std::io::stdout();
+        try!(convert_io_error(stdout.lock().write_all(&llvm_ir)));
+        return Ok(());
+    }
+
+    if emits("llvm-ir") {
+        let ll_path = emit_path(matches, &emit, output_name, "ll");
+        let mut ll_file = try!(convert_io_error(File::create(ll_path)));
+        try!(convert_io_error(ll_file.write_all(&llvm_ir)));
+    }
+
+    if !emits("asm") && !emits("obj") && !emits("exe") {
+        return Ok(());
+    }
+
+    let dry_run = matches.opt_present("dry-run");
+    let max_binary_size = try!(parse_max_binary_size(matches));
+
+    // Write the LLVM IR to a temporary file.
+    let llvm_ir_file = try!(intermediate_path(matches, output_name, "ll"));
+    {
+        let mut file = try!(convert_io_error(File::create(&llvm_ir_file.path)));
+        try!(convert_io_error(file.write_all(&llvm_ir)));
+    }
+
+    let llvm_opt_arg = format!("-O{}", llvm_opt_level);
+    let pie = try!(pie_option(matches));
+    let llc_target_arg = target.as_ref().map(|triple| format!("-mtriple={}", triple));
+    let clang_target_arg = target.as_ref().map(|triple| format!("--target={}", triple));
+
+    if emits("asm") {
+        let asm_path = emit_path(matches, &emit, output_name, "s");
+        let llc_args = llc_args(&llvm_opt_arg, "-filetype=asm", &llvm_ir_file.path, &asm_path,
+                                pie, llc_target_arg.as_ref().map(|arg| &arg[..]));
+        try!(shell_command("llc", &llc_args[..], dry_run));
+    }
+
+    if !emits("obj") && !emits("exe") {
+        return Ok(());
+    }
+
+    // Compile the LLVM IR to an object file. We only keep it if
+    // it was requested, or with --save-temps.
+    let temp_object_file;
+    let object_path = if emits("obj") {
+        emit_path(matches, &emit, output_name, "o")
+    } else {
+        temp_object_file = try!(intermediate_path(matches, output_name, "o"));
+        temp_object_file.path.clone()
+    };
+
+    let llc_args = llc_args(&llvm_opt_arg, "-filetype=obj", &llvm_ir_file.path, &object_path,
+                            pie, llc_target_arg.as_ref().map(|arg| &arg[..]));
+    try!(shell_command("llc", &llc_args[..], dry_run));
+
+    if !emits("exe") {
+        return Ok(());
+    }
+
+    // Link the object file.
+    let clang_args = clang_args(&object_path, &output_name, pie,
+                                clang_target_arg.as_ref().map(|arg| &arg[..]));
+    try!(shell_command("clang", &clang_args[..], dry_run));
+
+    // With --save-temps, we keep a half-finished executable too, so
+    // it can be debugged.
+    let keep_output = matches.opt_present("save-temps");
+    remove_on_failure(&output_name, keep_output, || {
+        // Strip the executable, unless we want to debug it.
+        if !debug {
+            let strip_args = ["-s", &output_name[..]];
+            try!(shell_command("strip", &strip_args[..], dry_run));
+        }
+
+        if let Some(max_size) = max_binary_size {
+            if !dry_run {
+                try!(check_binary_size(&output_name, max_size));
+            }
+        }
+
+        if matches.opt_present("verify") && !dry_run {
+            let input_path = try!(matches.opt_str("input").ok_or(
+                String::from("--verify requires --input")));
+            let tape = interpreter::Tape::for_program(&instrs, interpreter::DEFAULT_MAX_CELLS,
+                                                      cell_width)
+                .with_eof(eof)
+                .with_initial_cells(&init_cells, fill);
+            try!(verify(&instrs, &output_name, &input_path, tape));
+        }
+        Ok(())
+    })
+}
+
+/// Whether to link a position independent executable, from `--pie`
+/// or `--no-pie`. None means use clang's default.
+fn pie_option(matches: &Matches) -> Result<Option<bool>, String> {
+    match (matches.opt_present("pie"), matches.opt_present("no-pie")) {
+        (true, true) => Err("--pie and --no-pie are mutually exclusive.".to_owned()),
+        (true, false) => Ok(Some(true)),
+        (false, true) => Ok(Some(false)),
+        (false, false) => Ok(None),
+    }
+}
+
+/// The target triple from `--target`, if any. We check LLVM supports
+/// it up front, rather than letting llc fail.
+fn parse_target(matches: &Matches) -> Result<Option<String>, String> {
+    match matches.opt_str("target") {
+        Some(triple) => {
+            if matches.opt_present("run") {
+                return Err("--target can't be used with --run, which runs the program \
+                            on this machine.".to_owned());
+            }
+            try!(llvm::check_target(&triple));
+            Ok(Some(triple))
+        }
+        None => Ok(None),
+    }
+}
+
+/// The arguments for compiling `ir_path` with llc. `filetype_arg`
+/// chooses assembly or an object file, and `target_arg` is a
+/// `-mtriple=` argument for cross-compiling.
+fn llc_args<'a>(opt_arg: &'a str,
+                filetype_arg: &'a str,
+                ir_path: &'a str,
+                output_path: &'a str,
+                pie: Option<bool>,
+                target_arg: Option<&'a str>)
+                -> Vec<&'a str> {
+    let mut args = vec![opt_arg, filetype_arg, ir_path, "-o", output_path];
+    if pie == Some(true) {
+        // A PIE must be built from position independent code.
+        args.push("-relocation-model=pic");
+    }
+    if let Some(target_arg) = target_arg {
+        args.push(target_arg);
+    }
+    args
+}
+
+/// The arguments for linking `object_path` with clang. `target_arg`
+/// is a `--target=` argument for cross-compiling.
+fn clang_args<'a>(object_path: &'a str,
+                  output_name: &'a str,
+                  pie: Option<bool>,
+                  target_arg: Option<&'a str>)
+                  -> Vec<&'a str> {
+    let mut args = vec![object_path, "-o", output_name];
+    match pie {
+        Some(true) => args.push("-pie"),
+        Some(false) => args.push("-no-pie"),
+        None => {}
+    }
+    if let Some(target_arg) = target_arg {
+        args.push(target_arg);
+    }
+    args
+}
+
+/// Parse `--fuel`, the number of instructions we may interpret.
+fn parse_fuel(matches: &Matches) -> Result<Option<u64>, String> {
+    match matches.opt_str("fuel") {
+        Some(fuel) => {
+            fuel.parse::<u64>().map(Some).map_err(|_| format!("Invalid --fuel: {}", fuel))
+        }
+        None => Ok(None),
+    }
+}
+
+/// Without `--fuel`, we stop tracing after this many instructions, so
+/// a program that never terminates can't fill the disk.
+const DEFAULT_TRACE_FUEL: u64 = 10_000_000;
+
+/// Interpret `instrs` and write a trace of every instruction to
+/// `path` as we go. We read from `--input` if given, so the trace is
+/// deterministic.
+fn write_interp_trace(instrs: &[Instruction], matches: &Matches, path: &str) -> Result<(), String> {
+    let mut input = vec![];
+    if let Some(input_path) = matches.opt_str("input") {
+        let mut input_file = try!(convert_io_error(File::open(input_path)));
+        try!(convert_io_error(input_file.read_to_end(&mut input)));
+    }
+    let fuel = try!(parse_fuel(matches)).unwrap_or(DEFAULT_TRACE_FUEL);
+
+    let mut trace = BufWriter::new(try!(convert_io_error(File::create(path))));
+    let mut output = vec![];
+    let result = interpreter::interpret_with_trace(instrs, &mut &input[..], &mut output,
+                                                   &mut trace, Some(fuel));
+
+    // Keep the trace even if the program failed, as it shows where.
+    try!(convert_io_error(trace.flush()));
+    result
+}
+
+/// The LLVM IR we hand to llc. `--dump-llvm` and `--emit=llvm-ir`
+/// write exactly these bytes too, so what users see always matches
+/// what we compile.
+///
+/// We don't run any LLVM passes ourselves, so we add a comment saying
+/// the IR is unoptimised and how llc will optimise it.
+fn llvm_ir_for_llc(llvm_ir: &[u8], llvm_opt_level: &str) -> Vec<u8> {
+    let mut result = format!("; Unoptimised LLVM IR: bfc runs no LLVM passes, \
+                              llc optimises with -O{}.\n", llvm_opt_level).into_bytes();
+    result.extend(llvm_ir.iter().cloned());
+    result
+}
+
+/// Run `step` on the output file at `path`. If it fails, remove the
+/// output unless `keep_output` is set, so a failed compile never
+/// leaves a half-finished file behind.
+fn remove_on_failure<F>(path: &str, keep_output: bool, step: F) -> Result<(), String>
+    where F: FnOnce() -> Result<(), String>
+{
+    let result = step();
+    if result.is_err() && !keep_output {
+        let _ = std::fs::remove_file(path);
+    }
+    result
+}
+
+/// All the command line options we accept.
+fn options() -> Options {
+    let mut opts = Options::new();
+
+    opts.optflag("h", "help", "show usage");
+    opts.optopt("o", "output", "write the executable to PATH", "PATH");
+    opts.optflag("", "dump-llvm", "print LLVM IR generated");
+    opts.optflag("", "dump-ir", "print BF IR generated");
+    opts.optflag("", "dump-removed",
+                 "print the source span of each loop we optimise away, and why");
+    opts.optflagopt("", "dump-stats",
+                    "print statistics about optimisation and speculative execution \
+                     (FORMAT is text or json)",
+                    "FORMAT");
+    opts.optflag("", "pie", "link a position independent executable");
+    opts.optopt("", "target", "cross-compile for TRIPLE, e.g. armv7-linux-gnueabihf",
+                "TRIPLE");
+    opts.optflag("", "no-pie", "link an executable that isn't position independent");
+    opts.optflag("", "verbose", "print the tape size we chose, and notes about the program, to stderr");
+    opts.optopt("", "max-binary-size",
+                "remove the executable and fail if it's larger than SIZE bytes", "SIZE");
+    opts.optflag("", "deterministic",
+                 "make the output byte-identical across builds of the same source and options");
+    opts.optflag("", "save-temps",
+                 "keep the LLVM IR and object file as OUTPUT.ll and OUTPUT.o, \
+                  where OUTPUT is the executable's path, and keep OUTPUT even if \
+                  stripping or checking it fails");
+    opts.optflag("", "dry-run", "print the llc, clang and strip commands instead of running them");
+    opts.optflag("", "argv-tape", "initialise cells from the first argument of the compiled program");
+    opts.optflag("", "warnings-as-errors", "treat warnings as errors");
+    opts.optflag("", "explain", "describe how optimisation transforms each loop");
+    opts.optflag("", "report-tape", "print the smallest tape the program needs");
+    opts.optflag("", "whitespace-only",
+                 "reject any characters other than BF commands and whitespace");
+    opts.optflag("", "halting", "print whether the program terminates, if we can tell");
+    opts.optflag("", "named-values", "give descriptive names to LLVM values and loop blocks");
+    opts.optflag("", "loop-functions",
+                 "compile each loop as a separate function, for smaller code");
+    opts.optflag("g", "debug",
+                 "include DWARF line info for the BF source, and don't optimise or strip \
+                  the executable");
+    opts.optflag("", "debug-cmd",
+                 "compile # to print the cell pointer and the cells around it to stderr, \
+                  rather than treating it as a comment");
+    opts.optflag("", "debug-assert",
+                 "abort if the compiled program accesses a cell outside the tape, \
+                  where we can't prove it doesn't");
+    opts.optflag("", "fail-on-infinite-loop", "fail if the program provably loops forever");
+    opts.optflag("", "freestanding", "target has no libc: fail if the program needs IO");
+    opts.optflag("", "run-native",
+                 "compile to a temporary executable and run it, passing any further arguments");
+    opts.optflag("", "run",
+                 "compile in memory with LLVM's JIT and run, passing any further arguments");
+    opts.optflag("", "repl", "run BF interactively, a line at a time");
+    opts.optflag("", "benchmark",
+                 "time parsing, optimisation and speculative execution for every .bf file in \
+                  a directory, given instead of a source file");
+    opts.optopt("", "benchmark-timeout",
+                &format!("with --benchmark and --input, give up running a program after SECS \
+                          seconds (default: {})",
+                         DEFAULT_BENCHMARK_TIMEOUT),
+                "SECS");
+    opts.optflag("", "interpret", "run the program with an interpreter rather than compiling it");
+    opts.optflag("", "verify",
+                 "check the compiled program writes the same output as the interpreter for --input");
+
+    opts.optopt("O", "opt", "optimization level (0 to 2)", "LEVEL");
+    opts.optopt("", "passes",
+                "run exactly these peephole passes, in order, instead of the default pipeline",
+                "PASSES");
+    opts.optflag("", "fixpoint", "with --passes, repeat the passes until nothing changes");
+    opts.optopt("", "llvm-opt", "LLVM optimization level (0 to 3)", "LEVEL");
+    opts.optopt("", "speculate",
+                "compile time execution: off, fold or fold+output (default: fold+output at -O2, off otherwise)",
+                "MODE");
+    opts.optopt("", "init-cell-value", "initialise every cell to N (default: 0)", "N");
+    opts.optopt("", "init-hex", "initialise the first cells to these bytes, e.g. 4100", "HEX");
+    opts.optopt("", "eof",
+                "what reads store at EOF: neg-one (the default), zero or unchanged", "MODE");
+    opts.optopt("", "input-fd", "file descriptor the compiled program reads from (default: stdin)", "FD");
+    opts.optopt("", "output-fd", "file descriptor the compiled program writes to (default: stdout)", "FD");
+    opts.optopt("", "temp-dir", "directory for temporary files (default: $TMPDIR)", "DIR");
+    opts.optopt("", "emit",
+                "comma-separated output types: exe (default), obj, asm, llvm-ir (or llvm), \
+                 irbin (optimized BF IR), tokens or interp-trace (a trace of interpreting the \
+                 program)",
+                "TYPES");
+    opts.optopt("", "input", "input file for --verify and --emit=interp-trace", "FILE");
+    opts.optopt("", "search", "experimental: search for an input that makes the program write TEXT",
+                "TEXT");
+    opts.optopt("", "search-alphabet", "bytes to build --search inputs from", "CHARS");
+    opts.optopt("", "search-length", "longest input to try with --search (default: 3)", "N");
+    opts.optopt("", "fuel",
+                "with --interpret or --emit=interp-trace, stop after running N instructions; \
+                 with --run, stop after N loop iterations; with --search, run at most N instructions in total; with --repl, run at \
+                 most N instructions for each line",
+                "N");
+    opts.optflag("", "rodata-cells",
+                 "copy the initial cell values from a constant, rather than setting them in code");
+    opts.optflag("", "global-ctor",
+                 "compile the program as a constructor that runs at startup, rather than main");
+    opts.optflag("", "heap-tape", "allocate the tape with calloc rather than on the stack");
+    opts.optopt("", "print-cells",
+                "print the first N cells and the cell pointer to stderr when the program exits",
+                "N");
+    opts.optopt("", "tape-align", "align the tape to N bytes, e.g. 64 for a cache line", "N");
+    opts.optopt("", "max-loop-unroll-factor",
+                "check N cells per iteration of scan loops like [>] (default: 1)", "N");
+    opts.optflag("", "stdin", "read the BF program from stdin, like a source of -");
+    opts.optopt("", "stdin-bufsize",
+                &format!("with --interpret, read stdin N bytes at a time (default: {})",
+                         DEFAULT_STDIN_BUFSIZE),
+                "N");
+    opts.optopt("", "cell-width", "use N-bit cells: 8, 16 or 32 (default: 8)", "N");
+    opts.optopt("", "tape-limit",
+                &format!("with --interpret, grow the tape to at most N cells (default: {})",
+                         interpreter::DEFAULT_MAX_CELLS),
+                "N");
+    opts.optopt("", "from-irbin", "use optimized BF IR from FILE if built from the same source and options", "FILE");
+    opts.optopt("", "cache-dir",
+                "store optimized BF IR in DIR, and reuse it when the source and options are unchanged",
+                "DIR");
+
+    opts
+}
+
+/// Run an interactive session for `--repl`, running each line of BF
+/// from stdin once its loops are closed. Each line stops after
+/// `--fuel` instructions, so a line that never finishes can't hang
+/// the session.
+fn repl(matches: &Matches) -> Result<(), String> {
+    let fuel = try!(parse_fuel(matches)).unwrap_or(interpreter::DEFAULT_REPL_FUEL);
+    let stdin = std::io::stdin();
+    let mut input = stdin.lock();
+    let mut repl = interpreter::Repl::with_fuel(fuel);
+
+    loop {
+        print!("{}", if repl.is_pending() { "... " } else { "bf> " });
+        try!(convert_io_error(std::io::stdout().flush()));
+
+        let mut line = String::new();
+        if try!(convert_io_error(input.read_line(&mut line))) == 0 {
+            println!("");
+            return Ok(());
+        }
+
+        // Reads in the fragment take the following bytes of stdin.
+        let mut output = vec![];
+        match repl.feed(&line, &mut input, &mut output) {
+            Ok(true) => {
+                if !output.is_empty() {
+                    println!("{}", String::from_utf8_lossy(&output));
+                }
+                println!("{}", repl.cell_window());
+            }
+            Ok(false) => {}
+            Err(e) => println!("error: {}", e),
+        }
+    }
+}
+
+#[cfg_attr(test, allow(dead_code))]
+fn main() {
+    let args: Vec<_> = env::args().collect();
+
+    let opts = options();
+
+    let matches = match opts.parse(&args[1..]) {
+        Ok(m) => {
+            m
+        }
+        Err(e) => exit_with_usage(&args[0], opts, Some(format!("{}", e))),
+    };
+
+    // Asking for help isn't an error, so the usage goes to stdout.
+    if matches.opt_present("h") {
+        print!("{}", usage(&args[0], opts));
+        return;
+    }
+
+    if matches.opt_present("repl") {
+        if let Err(e) = repl(&matches) {
+            exit_with_error(&e);
+        }
+        return;
+    }
+
+    // With --run-native or --run, any further arguments go to the
+    // program.
+    let runs = matches.opt_present("run-native") || matches.opt_present("run");
+    let source_args = if matches.opt_present("stdin") { 0 } else { 1 };
+    if matches.free.len() < source_args || (matches.free.len() > source_args && !runs) {
+        exit_with_usage(&args[0], opts, None);
+    }
+    if matches.opt_present("benchmark") && matches.opt_present("stdin") {
+        exit_with_usage(&args[0], opts,
+                        Some(String::from("--benchmark needs a directory, not --stdin.")));
+    }
+
+    if matches.opt_present("benchmark") {
+        match benchmark(&matches, &matches.free[0]) {
+            Ok(rows) => print!("{}", format_benchmark(&rows)),
+            Err(e) => exit_with_error(&e),
+        }
+        return;
+    }
+
+    if matches.opt_present("run-native") {
+        let source = Source::from_args(&args[1..], &matches);
+        match run_native(&matches, &source, program_args(&matches)) {
+            Ok(code) => std::process::exit(code),
+            Err(e) => exit_with_error(&e),
+        }
+    }
+
+    if let Err(e) = compile_file(&matches, &Source::from_args(&args[1..], &matches)) {
+        exit_with_error(&e);
+    }
+}
+
+#[test]
+fn cache_key_reproducible() {
+    let matches = options().parse(&["foo.bf", "-O1"]).unwrap();
+    let same_matches = options().parse(&["-O1", "foo.bf"]).unwrap();
+    assert_eq!(cache_key("+.", &matches), cache_key("+.", &same_matches));
+}
+
+#[test]
+fn cache_key_depends_on_options() {
+    let matches = options().parse(&["foo.bf", "-O1"]).unwrap();
+    let other_matches = options().parse(&["foo.bf", "-O2"]).unwrap();
+    assert!(cache_key("+.", &matches) != cache_key("+.", &other_matches));
+
+    let argv_matches = options().parse(&["foo.bf", "-O1", "--argv-tape"]).unwrap();
+    assert!(cache_key("+.", &matches) != cache_key("+.", &argv_matches));
+
+    // Options that don't change the output don't change the key.
+    let dry_run_matches = options().parse(&["foo.bf", "-O1", "--dry-run"]).unwrap();
+    assert_eq!(cache_key("+.", &matches), cache_key("+.", &dry_run_matches));
+}
+
+#[test]
+fn cache_key_depends_on_source() {
+    let matches = options().parse(&["foo.bf"]).unwrap();
+    assert!(cache_key("+.", &matches) != cache_key("-.", &matches));
+}
+
+#[test]
+fn verify_matching_outputs() {
+    let instrs = bfir::parse("++++++++[>++++[>++>+++>+++>+<<<<-]>+>+>->>+[<]<-]>>.>---.+++++++..+++.>>.<-.<.+++.------.--------.>>+.>++.").unwrap();
+    let mut interpreted = vec![];
+    interpreter::interpret(&instrs, &mut &b""[..], &mut interpreted).unwrap();
+
+    assert!(check_outputs_match(&interpreted, b"Hello World!\n").is_ok());
+}
+
+#[test]
+fn runnable_paths() {
+    assert_eq!(runnable_path("hello"), PathBuf::from("./hello"));
+    assert_eq!(runnable_path("out/hello"), PathBuf::from("out/hello"));
+    assert_eq!(runnable_path("/tmp/hello"), PathBuf::from("/tmp/hello"));
+}
+
+/// A zeroed tape for `instrs` with `cell_width`-bit cells.
+#[cfg(test)]
+fn test_tape(instrs: &[Instruction], cell_width: u32) -> interpreter::Tape {
+    interpreter::Tape::for_program(instrs, interpreter::DEFAULT_MAX_CELLS, cell_width)
+}
+
+#[test]
+fn verify_wide_cells() {
+    let dir = env::temp_dir().join("bfc_verify_wide_cells_test");
+    let _ = std::fs::create_dir_all(&dir);
+    let input_path = dir.join("input").to_str().unwrap().to_owned();
+    File::create(&input_path).unwrap();
+    let output_name = dir.join("wrap").to_str().unwrap().to_owned();
+
+    // 256 is only zero with 8-bit cells, so this writes a byte with
+    // 16-bit cells and nothing with 8-bit cells.
+    let source = format!("{}[[-].]", std::iter::repeat('+').take(256).collect::<String>());
+    let program = dir.join("wrap.bf");
+    File::create(&program).unwrap().write_all(source.as_bytes()).unwrap();
+
+    let args = [String::from("--verify"), format!("--input={}", input_path),
+                String::from("--cell-width=16"), program.to_str().unwrap().to_owned()];
+    let matches = options().parse(&args).unwrap();
+    compile_file_to(&matches, &Source::from_args(&args, &matches), &output_name).unwrap();
+
+    let instrs = bfir::parse_with_config(&source, &parse_config(&matches)).unwrap();
+    assert!(verify(&instrs, &output_name, &input_path, test_tape(&instrs, 16)).is_ok());
+    assert!(verify(&instrs, &output_name, &input_path, test_tape(&instrs, 8)).is_err());
+}
+
+#[test]
+fn verify_large_output() {
+    let dir = env::temp_dir().join("bfc_verify_large_output_test");
+    let _ = std::fs::create_dir_all(&dir);
+    // Far more than a pipe holds, so the program blocks writing
+    // unless we read its output while it runs.
+    let input_path = dir.join("input").to_str().unwrap().to_owned();
+    let input = std::iter::repeat('a').take(1 << 20).collect::<String>();
+    File::create(&input_path).unwrap().write_all(input.as_bytes()).unwrap();
+    let output_name = dir.join("cat").to_str().unwrap().to_owned();
+
+    // At EOF, a read sets the cell to -1, which ends the loop.
+    let source = ",+[-.,+]";
+    let program = dir.join("cat.bf");
+    File::create(&program).unwrap().write_all(source.as_bytes()).unwrap();
+
+    let args = [String::from("--verify"), format!("--input={}", input_path),
+                program.to_str().unwrap().to_owned()];
+    let matches = options().parse(&args).unwrap();
+    compile_file_to(&matches, &Source::from_args(&args, &matches), &output_name).unwrap();
+
+    let instrs = bfir::parse(source).unwrap();
+    assert!(verify(&instrs, &output_name, &input_path, test_tape(&instrs, 8)).is_ok());
+
+    // The executable doesn't write what this program does.
+    let altered = bfir::parse(",+[.,+]").unwrap();
+    assert!(verify(&altered, &output_name, &input_path, test_tape(&altered, 8)).is_err());
+}
+
+#[test]
+fn verify_initial_cells() {
+    let dir = env::temp_dir().join("bfc_verify_initial_cells_test");
+    let _ = std::fs::create_dir_all(&dir);
+    let input_path = dir.join("input").to_str().unwrap().to_owned();
+    File::create(&input_path).unwrap();
+    let output_name = dir.join("init").to_str().unwrap().to_owned();
+
+    // The interpreter must start with the same cells as the compiled
+    // program, or the outputs differ.
+    let program = dir.join("init.bf");
+    File::create(&program).unwrap().write_all(b".>.").unwrap();
+
+    let args = [String::from("--verify"), format!("--input={}", input_path),
+                String::from("--init-hex=41"), String::from("--init-cell-value=66"),
+                program.to_str().unwrap().to_owned()];
+    let matches = options().parse(&args).unwrap();
+    assert!(compile_file_to(&matches, &Source::from_args(&args, &matches), &output_name).is_ok());
+}
+
+#[test]
+fn verify_catches_mismatches() {
+    // E.g. if we miscompiled an increment.
+    assert!(check_outputs_match(b"Hello World!\n", b"Hellp World!\n").is_err());
+    // E.g. if we dropped the final write.
+    assert!(check_outputs_match(b"Hello World!\n", b"Hello World!").is_err());
+}
+
+#[test]
+fn interpret_long_runs_at_o0() {
+    let source: String = std::iter::repeat('+').take(1000).chain(Some('.')).collect();
+    let instrs = peephole::combine_only(bfir::parse(&source).unwrap());
+    assert_eq!(instrs.len(), 2);
+
+    let mut output = vec![];
+    interpreter::interpret(&instrs, &mut &b""[..], &mut output).unwrap();
+    // 1000 % 256 == 232
+    assert_eq!(output, vec![232]);
+}
+
+#[test]
+fn infinite_loop_fails() {
+    let source = "+\n>+<[>+<].";
+    let instrs = peephole::optimize(bfir::parse(source).unwrap());
+    assert_eq!(check_no_infinite_loops(&instrs, source, &bfir::ParseConfig::default()),
+               Err("This program loops forever at line 2, column 4.".to_owned()));
+}
+
+#[test]
+fn conditional_loop_passes() {
+    let instrs = bfir::parse(",[.]").unwrap();
+    assert!(check_no_infinite_loops(&instrs, ",[.]", &bfir::ParseConfig::default()).is_ok());
+}
+
+#[test]
+fn freestanding_without_io() {
+    let instrs = bfir::parse("+[->+<]").unwrap();
+    assert!(check_freestanding(&instrs, &[]).is_ok());
+}
+
+#[test]
+fn freestanding_with_io() {
+    let instrs = bfir::parse(",[.,]").unwrap();
+    assert!(check_freestanding(&instrs, &[]).is_err());
+
+    // Output we computed at compile time still needs write().
+    assert!(check_freestanding(&[], &[72, 105]).is_err());
+}
+
+#[test]
+fn fixpoint_requires_passes() {
+    let args = [String::from("--fixpoint"), String::from("--emit=llvm-ir"),
+                String::from("tests/programs/hello_world.bf")];
+    let matches = options().parse(&args).unwrap();
+    let result = compile_file_to(&matches, &Source::from_args(&args, &matches), "hello");
+    assert!(result.unwrap_err().starts_with("--fixpoint requires --passes"));
+}
+
+#[test]
+fn wait_until_deadline() {
+    let mut child = Command::new("true").spawn().unwrap();
+    let deadline = time::precise_time_ns() + 10 * 1000000000;
+    assert_eq!(wait_until(&mut child, deadline), Ok(true));
+
+    let mut child = Command::new("sleep").arg("10").spawn().unwrap();
+    let start = time::precise_time_ns();
+    assert_eq!(wait_until(&mut child, start + 100000000), Ok(false));
+    // We killed the child rather than waiting for it to finish.
+    assert!(time::precise_time_ns() - start < 5 * 1000000000);
+}
+
+#[test]
+fn failed_step_removes_output() {
+    let output = NamedTempFile::new().unwrap();
+    let path = output.path().to_str().unwrap().to_owned();
+
+    let result = remove_on_failure(&path, false, || {
+        try!(shell_command("false", &[], false));
+        Ok(())
+    });
+    assert!(result.is_err());
+    assert!(!Path::new(&path).exists());
+}
+
+#[test]
+fn failed_step_keeps_output_if_asked() {
+    let output = NamedTempFile::new().unwrap();
+    let path = output.path().to_str().unwrap().to_owned();
+
+    let result = remove_on_failure(&path, true, || {
+        try!(shell_command("false", &[], false));
+        Ok(())
+    });
+    assert!(result.is_err());
+    assert!(Path::new(&path).exists());
+}
+
+#[test]
+fn successful_step_keeps_output() {
+    let output = NamedTempFile::new().unwrap();
+    let path = output.path().to_str().unwrap().to_owned();
+
+    assert!(remove_on_failure(&path, false, || Ok(())).is_ok());
+    assert!(Path::new(&path).exists());
+}
+
+#[test]
+fn parse_emit_list() {
+    assert_eq!(parse_emit("llvm-ir,obj").unwrap(),
+               vec![String::from("llvm-ir"), String::from("obj")]);
+    assert_eq!(parse_emit("exe,exe").unwrap(), vec![String::from("exe")]);
+}
+
+#[test]
+fn run_native_hello_world() {
+    let matches = options().parse(&["--run-native", "tests/programs/hello_world.bf"]).unwrap();
+    let source = Source::from_args(&[String::from("--run-native"),
+                                     String::from("tests/programs/hello_world.bf")],
+                                   &matches);
+    let executable = compile_temp_executable(&matches, &source).unwrap();
+
+    let output = Command::new(&executable.path).output().unwrap();
+    assert!(output.status.success());
+    assert_eq!(output.stdout, b"Hello World!\n".to_vec());
+
+    // The executable is deleted once we're done with it.
+    let path = executable.path.clone();
+    drop(executable);
+    assert!(!Path::new(&path).exists());
+}
+
+#[test]
+fn deterministic_builds_are_identical() {
+    let dir = env::temp_dir().join("bfc_deterministic_test");
+    let _ = std::fs::create_dir_all(&dir);
+    let output_name = dir.join("count").to_str().unwrap().to_owned();
+
+    // Don't speculate, so the program keeps its loops.
+    let args = [String::from("--deterministic"), String::from("--speculate=off"),
+                String::from("--emit=llvm-ir,exe"), String::from("tests/programs/count.bf")];
+    let matches = options().parse(&args).unwrap();
+    let source = Source::from_args(&args, &matches);
+
+    let read = |path: &str| {
+        let mut bytes = vec![];
+        File::open(path).unwrap().read_to_end(&mut bytes).unwrap();
+        bytes
+    };
+
+    compile_file_to(&matches, &source, &output_name).unwrap();
+    let first_ir = read(&format!("{}.ll", output_name));
+    let first_exe = read(&output_name);
+
+    compile_file_to(&matches, &source, &output_name).unwrap();
+    assert_eq!(read(&format!("{}.ll", output_name)), first_ir);
+    assert_eq!(read(&output_name), first_exe);
+
+    // We don't leave any temporary files behind.
+    assert!(!Path::new(&format!("{}.bfc-tmp.ll", output_name)).exists());
+    assert!(!Path::new(&format!("{}.bfc-tmp.o", output_name)).exists());
+}
+
+#[test]
+fn intermediate_path_with_save_temps() {
+    let args = [String::from("--save-temps"), String::from("foo.bf")];
+    let matches = options().parse(&args).unwrap();
+    let path = intermediate_path(&matches, "/tmp/foo", "ll").unwrap();
+    assert_eq!(path.path, "/tmp/foo.ll");
+    assert!(path.keep);
+
+    let matches = options().parse(&[String::from("foo.bf")]).unwrap();
+    let path = intermediate_path(&matches, "/tmp/foo", "ll").unwrap();
+    assert!(path.path.ends_with(".bfc-tmp.ll"));
+    assert!(!path.keep);
+}
+
+#[test]
+fn intermediate_path_with_dry_run() {
+    let args = [String::from("--dry-run"), String::from("foo.bf")];
+    let matches = options().parse(&args).unwrap();
+    let path = intermediate_path(&matches, "/tmp/foo", "ll").unwrap();
+    assert_eq!(path.path, "/tmp/foo.bfc-tmp.ll");
+    assert!(path.keep);
+}
+
+#[test]
+fn save_temps_keeps_ir_and_object() {
+    let dir = env::temp_dir().join("bfc_save_temps_test");
+    let _ = std::fs::create_dir_all(&dir);
+    let output_name = dir.join("hello").to_str().unwrap().to_owned();
+    let ll_path = format!("{}.ll", output_name);
+    let object_path = format!("{}.o", output_name);
+    let _ = std::fs::remove_file(&ll_path);
+    let _ = std::fs::remove_file(&object_path);
+
+    let args = [String::from("--save-temps"), String::from("tests/programs/hello_world.bf")];
+    let matches = options().parse(&args).unwrap();
+    let source = Source::from_args(&args, &matches);
+    compile_file_to(&matches, &source, &output_name).unwrap();
+
+    assert!(Path::new(&output_name).exists());
+    assert!(Path::new(&ll_path).exists());
+    assert!(Path::new(&object_path).exists());
+}
+
+#[test]
+fn cache_dir_skips_optimisation() {
+    let dir = env::temp_dir().join("bfc_cache_dir_test");
+    let _ = std::fs::remove_dir_all(&dir);
+
+    let args = [String::from("--dump-stats"),
+                format!("--cache-dir={}", dir.to_str().unwrap()),
+                String::from("tests/programs/hello_world.bf")];
+    let matches = options().parse(&args).unwrap();
+    let source = Source::from_args(&args, &matches);
+    let key = cache_key(&slurp("tests/programs/hello_world.bf").unwrap(), &matches);
+    assert_eq!(load_cached_ir(&matches, key), Ok(None));
+
+    // The first compile populates the cache with the optimized IR.
+    // tests/cli.rs checks that the second compile runs no passes.
+    compile_file(&matches, &source).unwrap();
+    let cached = load_cached_ir(&matches, key).unwrap().unwrap();
+    let parsed = bfir::parse(&slurp("tests/programs/hello_world.bf").unwrap()).unwrap();
+    assert_eq!(cached, peephole::optimize(parsed));
+    compile_file(&matches, &source).unwrap();
+
+    // A different source doesn't use the cached IR.
+    assert_eq!(load_cached_ir(&matches, key + 1), Ok(None));
+}
+
+#[test]
+fn output_name_option() {
+    let source = Source::File(String::from("foo.bf"));
+
+    let matches = options().parse(&["foo.bf"]).unwrap();
+    assert_eq!(output_name(&matches, &source), Ok(String::from("foo")));
+
+    let matches = options().parse(&["-o", "bar", "foo.bf"]).unwrap();
+    assert_eq!(output_name(&matches, &source), Ok(String::from("bar")));
+
+    let dir = env::temp_dir().join("bfc_output_test");
+    let _ = std::fs::create_dir_all(&dir);
+    let absolute = dir.join("baz").to_str().unwrap().to_owned();
+    let matches = options().parse(&["--output", &absolute, "foo.bf"]).unwrap();
+    assert_eq!(output_name(&matches, &source), Ok(absolute.clone()));
+}
+
+#[test]
+fn output_name_missing_directory() {
+    let source = Source::File(String::from("foo.bf"));
+    let missing = env::temp_dir().join("bfc_no_such_dir").join("foo");
+    let _ = std::fs::remove_dir_all(env::temp_dir().join("bfc_no_such_dir"));
+
+    let matches = options().parse(&["-o", missing.to_str().unwrap(), "foo.bf"]).unwrap();
+    let err = output_name(&matches, &source).unwrap_err();
+    assert!(err.starts_with("Output directory "));
+
+    let matches = options().parse(&["-o", env::temp_dir().to_str().unwrap(), "foo.bf"]).unwrap();
+    assert!(output_name(&matches, &source).unwrap_err().ends_with("is a directory."));
+}
+
+#[test]
+fn compile_gzipped_source() {
+    let dir = env::temp_dir().join("bfc_gzip_test");
+    let _ = std::fs::create_dir_all(&dir);
+
+    let plain_path = "tests/programs/hello_world.bf";
+    let gz_path = dir.join("hello_world.bf.gz").to_str().unwrap().to_owned();
+    {
+        let mut encoder = GzEncoder::new(File::create(&gz_path).unwrap(), Compression::Default);
+        encoder.write_all(slurp(plain_path).unwrap().as_bytes()).unwrap();
+        encoder.finish().unwrap();
+    }
+    assert_eq!(slurp(&gz_path).unwrap(), slurp(plain_path).unwrap());
+    assert_eq!(Source::File(gz_path.clone()).executable_name(), "hello_world");
+
+    // The IR only differs in the module name.
+    let compile_ir = |path: &str, name: &str| {
+        let args = [String::from("--emit=llvm-ir"), String::from(path)];
+        let matches = options().parse(&args).unwrap();
+        let output_name = dir.join(name).to_str().unwrap().to_owned();
+        compile_file_to(&matches, &Source::from_args(&args, &matches), &output_name).unwrap();
+
+        let ir = slurp(&format!("{}.ll", output_name)).unwrap();
+        ir.lines().filter(|line| !line.starts_with("; ModuleID")).collect::<Vec<_>>().join("\n")
+    };
+    assert_eq!(compile_ir(&gz_path, "from_gz"), compile_ir(plain_path, "from_plain"));
+}
+
+#[test]
+fn debug_info() {
+    let dir = env::temp_dir().join("bfc_debug_info_test");
+    let _ = std::fs::create_dir_all(&dir);
+    let path = dir.join("lines.bf").to_str().unwrap().to_owned();
+    File::create(&path).unwrap().write_all(b"+\n>+\n.").unwrap();
+    let output_name = dir.join("lines").to_str().unwrap().to_owned();
+
+    let args = [String::from("-g"), String::from("--emit=llvm-ir"), path.clone()];
+    let matches = options().parse(&args).unwrap();
+    compile_file_to(&matches, &Source::from_args(&args, &matches), &output_name).unwrap();
+
+    // Without -g, we'd execute the whole program at compile time, and
+    // there would be no instructions left to give lines to.
+    let ir = slurp(&format!("{}.ll", output_name)).unwrap();
+    assert!(ir.contains("!llvm.dbg.cu"));
+    for line in 1..4 {
+        assert!(ir.contains(&format!("!{{i32 {}, i32 0, ", line)));
+    }
+
+    let args = [String::from("-g"), String::from("--loop-functions"), path.clone()];
+    let matches = options().parse(&args).unwrap();
+    let result = compile_file_to(&matches, &Source::from_args(&args, &matches), &output_name);
+    assert_eq!(result, Err("-g can't be used with --loop-functions.".to_owned()));
+}
+
+#[test]
+fn debug_cmd() {
+    let dir = env::temp_dir().join("bfc_debug_cmd_test");
+    let _ = std::fs::create_dir_all(&dir);
+    let path = dir.join("debug.bf").to_str().unwrap().to_owned();
+    File::create(&path).unwrap().write_all(b"+#>+").unwrap();
+    let output_name = dir.join("debug").to_str().unwrap().to_owned();
+
+    // Without --debug-cmd, `#` is a comment.
+    let args = [String::from("--emit=llvm-ir"), path.clone()];
+    let matches = options().parse(&args).unwrap();
+    compile_file_to(&matches, &Source::from_args(&args, &matches), &output_name).unwrap();
+    assert!(!slurp(&format!("{}.ll", output_name)).unwrap().contains("dprintf"));
+
+    // With it, we print the tape at runtime, rather than executing
+    // the program at compile time.
+    let args = [String::from("--debug-cmd"), String::from("--emit=llvm-ir"), path.clone()];
+    let matches = options().parse(&args).unwrap();
+    compile_file_to(&matches, &Source::from_args(&args, &matches), &output_name).unwrap();
+    assert!(slurp(&format!("{}.ll", output_name)).unwrap().contains("cells %d..%d:"));
+}
+
+#[test]
+fn max_binary_size() {
+    let dir = env::temp_dir().join("bfc_max_binary_size_test");
+    let _ = std::fs::create_dir_all(&dir);
+
+    let small_path = dir.join("small.bf").to_str().unwrap().to_owned();
+    let large_path = dir.join("large.bf").to_str().unwrap().to_owned();
+    {
+        let mut small = File::create(&small_path).unwrap();
+        small.write_all(b"+.").unwrap();
+        let mut large = File::create(&large_path).unwrap();
+        for _ in 0..5000 {
+            large.write_all(b"+.>").unwrap();
+        }
+    }
+
+    let compile = |path: &str, max_size: Option<u64>| {
+        let mut args = vec![String::from("--speculate=off"), String::from(path)];
+        if let Some(max_size) = max_size {
+            args.push(format!("--max-binary-size={}", max_size));
+        }
+        let matches = options().parse(&args).unwrap();
+        let output_name = path.trim_right_matches(".bf").to_owned();
+        let result = compile_file_to(&matches, &Source::from_args(&args, &matches), &output_name);
+        (result, output_name)
+    };
+
+    let (result, small_output) = compile(&small_path, None);
+    result.unwrap();
+    let small_size = std::fs::metadata(&small_output).unwrap().len();
+
+    // The limit is inclusive.
+    let (result, _) = compile(&small_path, Some(small_size));
+    assert!(result.is_ok());
+
+    let (result, large_output) = compile(&large_path, Some(small_size));
+    assert!(result.unwrap_err().contains("--max-binary-size"));
+    assert!(!Path::new(&large_output).exists());
+}
+
+#[test]
+fn max_binary_size_invalid() {
+    let matches = options().parse(&["--max-binary-size=big", "foo.bf"]).unwrap();
+    assert!(parse_max_binary_size(&matches).is_err());
+}
+
+#[test]
+fn parse_eof_modes() {
+    let matches = options().parse(&["foo.bf"]).unwrap();
+    assert_eq!(parse_eof(&matches), Ok(EofBehaviour::NegOne));
+
+    let matches = options().parse(&["--eof=zero", "foo.bf"]).unwrap();
+    assert_eq!(parse_eof(&matches), Ok(EofBehaviour::Zero));
+
+    let matches = options().parse(&["--eof=sometimes", "foo.bf"]).unwrap();
+    assert!(parse_eof(&matches).is_err());
+}
+
+#[test]
+fn parse_eof_with_input_fd() {
+    let matches = options().parse(&["--input-fd=3", "foo.bf"]).unwrap();
+    assert_eq!(parse_eof(&matches), Ok(EofBehaviour::Unchanged));
+
+    let matches = options().parse(&["--input-fd=3", "--eof=zero", "foo.bf"]).unwrap();
+    assert!(parse_eof(&matches).is_err());
+}
+
+#[test]
+fn benchmark_two_programs() {
+    let dir = env::temp_dir().join("bfc_benchmark_test");
+    let _ = std::fs::create_dir_all(&dir);
+    for &(name, src) in &[("a.bf", "+++[->++<]>."), ("b.bf", ",[.,]"), ("notes.txt", "+")] {
+        let mut file = File::create(dir.join(name)).unwrap();
+        file.write_all(src.as_bytes()).unwrap();
+    }
+
+    let dir = dir.to_str().unwrap();
+    let matches = options().parse(&["--benchmark", dir]).unwrap();
+    let rows = benchmark(&matches, dir).unwrap();
+
+    let names: Vec<_> = rows.iter().map(|row| &row.name[..]).collect();
+    assert_eq!(names, vec!["a.bf", "b.bf"]);
+    assert!(rows.iter().all(|row| row.run == RunTime::NotRun));
+
+    let table = format_benchmark(&rows);
+    let lines: Vec<_> = table.lines().collect();
+    assert_eq!(lines.len(), 3);
+    assert!(lines[0].starts_with("program"));
+    assert!(lines[1].starts_with("a.bf"));
+    assert!(lines[2].starts_with("b.bf"));
+}
+
+#[test]
+fn parse_emit_asm_and_llvm() {
+    assert_eq!(parse_emit("asm").unwrap(), vec![String::from("asm")]);
+    // "llvm" is the same as "llvm-ir".
+    assert_eq!(parse_emit("llvm,llvm-ir").unwrap(), vec![String::from("llvm-ir")]);
+}
+
+#[test]
+fn emit_path_honours_output() {
+    let obj = vec![String::from("obj")];
+    let matches = options().parse(&["--emit=obj", "foo.bf"]).unwrap();
+    assert_eq!(emit_path(&matches, &obj, "foo", "o"), "foo.o");
+
+    let matches = options().parse(&["--emit=obj", "-o", "bar.obj", "foo.bf"]).unwrap();
+    assert_eq!(emit_path(&matches, &obj, "bar.obj", "o"), "bar.obj");
+
+    // Two files can't share the -o path, so they both get extensions.
+    let obj_and_ir = vec![String::from("obj"), String::from("llvm-ir")];
+    let matches = options().parse(&["--emit=obj,llvm-ir", "-o", "bar", "foo.bf"]).unwrap();
+    assert_eq!(emit_path(&matches, &obj_and_ir, "bar", "o"), "bar.o");
+    assert_eq!(emit_path(&matches, &obj_and_ir, "bar", "ll"), "bar.ll");
+}
+
+#[test]
+fn llc_args_for_asm() {
+    assert_eq!(format_command("llc", &llc_args("-O3", "-filetype=asm", "foo.ll", "foo.s", None,
+                                               None)),
+               "llc -O3 -filetype=asm foo.ll -o foo.s");
+    assert_eq!(format_command("llc", &llc_args("-O3", "-filetype=obj", "foo.ll", "foo.o",
+                                               Some(true), Some("-mtriple=armv7-linux"))),
+               "llc -O3 -filetype=obj foo.ll -o foo.o -relocation-model=pic \
+                -mtriple=armv7-linux");
+}
+
+#[test]
+fn emit_asm_to_output() {
+    let dir = env::temp_dir().join("bfc_emit_asm_test");
+    let _ = std::fs::create_dir_all(&dir);
+    let asm_path = dir.join("hello.asm").to_str().unwrap().to_owned();
+    let _ = std::fs::remove_file(&asm_path);
+
+    let args = [String::from("--emit=asm"), String::from("-o"), asm_path.clone(),
+                String::from("tests/programs/hello_world.bf")];
+    let matches = options().parse(&args).unwrap();
+    compile_file_to(&matches, &Source::from_args(&args, &matches), &asm_path).unwrap();
+
+    assert!(Path::new(&asm_path).exists());
+    assert!(!Path::new(&format!("{}.s", asm_path)).exists());
+}
+
+#[test]
+fn parse_emit_interp_trace() {
+    assert_eq!(parse_emit("interp-trace").unwrap(), vec![String::from("interp-trace")]);
+}
+
+#[test]
+fn options_accept_equals_values() {
+    // getopts accepts --flag=value as well as --flag value.
+    let spaced = options().parse(&["--opt", "1", "--emit", "llvm-ir", "foo.bf"]).unwrap();
+    let equals = options().parse(&["--opt=1", "--emit=llvm-ir", "foo.bf"]).unwrap();
+
+    assert_eq!(spaced.opt_str("opt"), Some(String::from("1")));
+    assert_eq!(equals.opt_str("opt"), spaced.opt_str("opt"));
+    assert_eq!(spaced.opt_str("emit"), Some(String::from("llvm-ir")));
+    assert_eq!(equals.opt_str("emit"), spaced.opt_str("emit"));
+    assert_eq!(equals.free, spaced.free);
+}
+
+#[test]
+fn parse_emit_unknown() {
+    assert!(parse_emit("exe,wasm").is_err());
+    assert!(parse_emit("").is_err());
+}
+
+#[cfg(test)]
+fn speculate_source(source: &str, speculate: &str, init_cells: &[i8], fill: i8)
+                    -> Result<Speculation, String> {
+    let instrs = bfir::parse(source).unwrap();
+    speculative_state(&instrs, source, &bfir::ParseConfig::default(), speculate, init_cells, fill)
+}
+
+#[test]
+fn speculate_off() {
+    let state = speculate_source("+.+.", "off", &[], 0).unwrap().state;
+    assert_eq!(state.instr_ptr, 0);
+    assert_eq!(state.outputs, vec![]);
+}
+
+#[test]
+fn speculate_fold() {
+    let state = speculate_source("+.+.", "fold", &[], 0).unwrap().state;
+    assert_eq!(state.instr_ptr, 1);
+    assert_eq!(state.cells, vec![Wrapping(1)]);
+    assert_eq!(state.outputs, vec![]);
+}
+
+#[test]
+fn speculate_fold_and_output() {
+    let state = speculate_source("+.+.", "fold+output", &[], 0).unwrap().state;
+    assert_eq!(state.instr_ptr, 4);
+    assert_eq!(state.outputs, vec![1, 2]);
+}
+
+#[test]
+fn speculate_reports_out_of_bounds() {
+    let Speculation { state, warning, .. } = speculate_source("+>+[<<]", "fold+output", &[], 0).unwrap();
+    assert_eq!(state.instr_ptr, 3);
+    assert_eq!(warning.unwrap().message,
+               "pointer moved out of bounds during compile-time evaluation \
+                (cell index -1, at line 1, column 4)");
+}
+
+#[test]
+fn speculate_reports_source_position_when_optimised() {
+    let source = "+>+\n  [<<]";
+    let instrs = peephole::optimize(bfir::parse(source).unwrap());
+    let Speculation { warning, .. } = speculative_state(&instrs, source,
+                                                        &bfir::ParseConfig::default(),
+                                                        "fold+output", &[], 0).unwrap();
+    assert_eq!(warning.unwrap().message,
+               "pointer moved out of bounds during compile-time evaluation \
+                (cell index -1, at line 2, column 3)");
+}
+
+#[test]
+fn init_hex_seeds_cells() {
+    let init_cells = parse_hex("4100").unwrap();
+    assert_eq!(init_cells, vec![65, 0]);
+
+    let state = speculate_source(".", "off", &init_cells, 0).unwrap().state;
+    assert_eq!(state.cells, vec![Wrapping(65), Wrapping(0)]);
+
+    let state = speculate_source(".", "fold+output", &init_cells, 0).unwrap().state;
+    assert_eq!(state.outputs, vec![65]);
+}
+
+#[test]
+fn init_cell_value_fills_tape() {
+    let fill = parse_cell_value("255").unwrap();
+    assert_eq!(fill, -1);
+
+    // Cell #1 is never written before we write it out.
+    let state = speculate_source(">.", "fold+output", &[], fill).unwrap().state;
+    assert_eq!(state.outputs, vec![-1]);
+
+    // --init-hex takes priority over the fill value.
+    let state = speculate_source(">.", "off", &[7], fill).unwrap().state;
+    assert_eq!(state.cells, vec![Wrapping(7), Wrapping(-1)]);
+}
+
+#[test]
+fn init_cell_value_invalid() {
+    assert!(parse_cell_value("256").is_err());
+    assert!(parse_cell_value("-129").is_err());
+    assert!(parse_cell_value("x").is_err());
+}
+
+#[cfg(test)]
+fn source_from_args(args: &[&str]) -> Source {
+    let args: Vec<String> = args.iter().map(|&arg| String::from(arg)).collect();
+    let matches = options().parse(&args).unwrap();
+    Source::from_args(&args, &matches)
+}
+
+#[test]
+fn source_stdin() {
+    assert_eq!(source_from_args(&["-"]), Source::Stdin);
+    assert_eq!(source_from_args(&["-O1", "-"]), Source::Stdin);
+    assert_eq!(source_from_args(&["-", "--"]), Source::Stdin);
+    // The program's arguments don't name the source.
+    assert_eq!(source_from_args(&["--run", "-", "--", "-"]), Source::Stdin);
+    assert_eq!(Source::Stdin.executable_name(), "a.out");
+}
+
+#[test]
+fn source_stdin_flag() {
+    assert_eq!(source_from_args(&["--stdin"]), Source::Stdin);
+    assert_eq!(source_from_args(&["--stdin", "--run", "foo"]), Source::Stdin);
+
+    let matches = options().parse(&["--stdin", "--run", "foo"]).unwrap();
+    assert_eq!(program_args(&matches), &[String::from("foo")][..]);
+    let matches = options().parse(&["--run", "foo.bf", "bar"]).unwrap();
+    assert_eq!(program_args(&matches), &[String::from("bar")][..]);
+}
+
+#[test]
+fn source_file_named_dash() {
+    assert_eq!(source_from_args(&["--", "-"]), Source::File(String::from("-")));
+    assert_eq!(source_from_args(&["-O1", "--", "-"]), Source::File(String::from("-")));
+    assert_eq!(source_from_args(&["--run", "--", "-", "-"]), Source::File(String::from("-")));
+    assert_eq!(source_from_args(&["foo.bf"]), Source::File(String::from("foo.bf")));
+    assert_eq!(source_from_args(&["foo.bf"]).executable_name(), "foo");
+}
+
+#[test]
+fn dump_stats_format() {
+    let matches = options().parse(&["--dump-stats=json", "foo.bf"]).unwrap();
+    assert_eq!(matches.opt_str("dump-stats"), Some(String::from("json")));
+    assert_eq!(matches.free, vec![String::from("foo.bf")]);
+
+    let stats = stats::Stats {
+        instrs_before: 2,
+        instrs_after: 1,
+        pass_changes: vec![],
+        speculation: String::from("off"),
+        steps: 0,
+        tape_size: 1,
+    };
+    assert_eq!(format_stats(&stats, None), Ok(stats.to_text()));
+    assert!(format_stats(&stats, Some(String::from("xml"))).is_err());
+}
+
+#[test]
+fn stdin_bufsize() {
+    let matches = options().parse(&["--stdin-bufsize", "1024", "foo.bf"]).unwrap();
+    assert_eq!(parse_bufsize(&matches), Ok(1024));
+
+    let matches = options().parse(&["foo.bf"]).unwrap();
+    assert_eq!(parse_bufsize(&matches), Ok(DEFAULT_STDIN_BUFSIZE));
+
+    let matches = options().parse(&["--stdin-bufsize", "0", "foo.bf"]).unwrap();
+    assert!(parse_bufsize(&matches).is_err());
+}
+
+#[test]
+fn tape_limit() {
+    let matches = options().parse(&["--tape-limit", "100", "foo.bf"]).unwrap();
+    assert_eq!(parse_tape_limit(&matches), Ok(100));
+
+    let matches = options().parse(&["foo.bf"]).unwrap();
+    assert_eq!(parse_tape_limit(&matches), Ok(interpreter::DEFAULT_MAX_CELLS));
+
+    let matches = options().parse(&["--tape-limit", "0", "foo.bf"]).unwrap();
+    assert!(parse_tape_limit(&matches).is_err());
+}
+
+#[test]
+fn cell_width() {
+    let matches = options().parse(&["--interpret", "--cell-width", "16", "foo.bf"]).unwrap();
+    assert_eq!(parse_cell_width(&matches), Ok(16));
+
+    let matches = options().parse(&["foo.bf"]).unwrap();
+    assert_eq!(parse_cell_width(&matches), Ok(8));
+
+    let matches = options().parse(&["--interpret", "--cell-width", "12", "foo.bf"]).unwrap();
+    assert!(parse_cell_width(&matches).is_err());
+
+    let matches = options().parse(&["--cell-width", "32", "foo.bf"]).unwrap();
+    assert_eq!(parse_cell_width(&matches), Ok(32));
+
+    // We can only set cells to byte values.
+    let matches = options().parse(&["--cell-width", "16", "--init-hex", "41", "foo.bf"]).unwrap();
+    assert!(parse_cell_width(&matches).is_err());
+}
+
+#[test]
+fn max_loop_unroll_factor() {
+    let matches = options().parse(&["--max-loop-unroll-factor", "4", "foo.bf"]).unwrap();
+    assert_eq!(parse_unroll_factor(&matches), Ok(4));
+
+    let matches = options().parse(&["foo.bf"]).unwrap();
+    assert_eq!(parse_unroll_factor(&matches), Ok(1));
+
+    let matches = options().parse(&["--max-loop-unroll-factor", "0", "foo.bf"]).unwrap();
+    assert!(parse_unroll_factor(&matches).is_err());
+}
+
+#[test]
+fn tape_align() {
+    let matches = options().parse(&["--tape-align", "64", "foo.bf"]).unwrap();
+    assert_eq!(parse_tape_align(&matches), Ok(Some(64)));
+
+    let matches = options().parse(&["foo.bf"]).unwrap();
+    assert_eq!(parse_tape_align(&matches), Ok(None));
+
+    let matches = options().parse(&["--tape-align", "48", "foo.bf"]).unwrap();
+    assert!(parse_tape_align(&matches).is_err());
+}
+
+#[test]
+fn print_cells() {
+    let matches = options().parse(&["--print-cells", "10", "foo.bf"]).unwrap();
+    assert_eq!(parse_print_cells(&matches), Ok(Some(10)));
+
+    let matches = options().parse(&["--print-cells", "ten", "foo.bf"]).unwrap();
+    assert!(parse_print_cells(&matches).is_err());
+}
+
+#[test]
+fn whitespace_only_config() {
+    let matches = options().parse(&["--whitespace-only", "foo.bf"]).unwrap();
+    let config = parse_config(&matches);
+    assert!(bfir::parse_with_config("+ +\n", &config).is_ok());
+    assert!(bfir::parse_with_config("+ comment", &config).is_err());
+
+    let matches = options().parse(&["foo.bf"]).unwrap();
+    assert!(bfir::parse_with_config("+ comment", &parse_config(&matches)).is_ok());
+}
+
+#[test]
+fn wide_cells_config() {
+    let matches = options().parse(&["foo.bf"]).unwrap();
+    assert!(parse_config(&matches).combine_increments);
+
+    let matches = options().parse(&["--interpret", "--cell-width", "16", "foo.bf"]).unwrap();
+    assert!(!parse_config(&matches).combine_increments);
+}
+
+#[test]
+fn removed_report_dead_loop_at_start() {
+    let source = "[comment, with. punctuation]\n+[->+<]";
+    let instrs = bfir::parse(source).unwrap();
+    assert_eq!(removed_report(source, &bfir::ParseConfig::default(), &instrs, true, false),
+               "line 1, column 1 to line 1, column 28: dead loop at start\n\
+                line 2, column 2 to line 2, column 7: folded into MultiplyMove\n");
+}
+
+#[test]
+fn removed_report_speculated() {
+    let source = "+[[-]>]";
+    let instrs = bfir::parse(source).unwrap();
+    assert_eq!(removed_report(source, &bfir::ParseConfig::default(), &instrs, true, false),
+               "line 1, column 3 to line 1, column 5: folded into Set\n");
+    assert_eq!(removed_report(source, &bfir::ParseConfig::default(), &instrs, true, true),
+               "line 1, column 2 to line 1, column 7: speculatively executed\n");
+}
+
+#[test]
+fn explain_clear_cell() {
+    let source = "+\n[-]";
+    assert_eq!(explain(source, &bfir::ParseConfig::default()).unwrap(),
+               "loop at line 2 recognized as clear-cell, replaced with Set(0)\n");
+}
+
+#[test]
+fn explain_with_replaced_loop_commands() {
+    let mut config = bfir::ParseConfig::default();
+    config.commands.remove(&'[');
+    config.commands.remove(&']');
+    config.commands.insert('(', bfir::Command::LoopStart);
+    config.commands.insert(')', bfir::Command::LoopEnd);
+
+    // The brackets are comments here, so only the second loop counts.
+    let source = "[\n+\n(-)]";
+    assert_eq!(explain(source, &config).unwrap(),
+               "loop at line 3 recognized as clear-cell, replaced with Set(0)\n");
+}
+
+#[test]
+fn explain_multiply_move() {
+    let source = "+[->+>+++<<]";
+    assert_eq!(explain(source, &bfir::ParseConfig::default()).unwrap(),
+               "loop at line 1 recognized as multiply-move into +1 (\u{d7}1), +2 (\u{d7}3)\n");
+}
+
+#[test]
+fn explain_nothing_transformed() {
+    let source = ",[.,]";
+    assert_eq!(explain(source, &bfir::ParseConfig::default()).unwrap(),
+               "No loops were transformed.\n");
+}
+
+#[test]
+fn tape_report_static() {
+    let instrs = bfir::parse(">>+[-<+>]<<.").unwrap();
+    assert_eq!(tape_report(&instrs), "minimum tape size: 3 cells (static)\n");
+}
+
+#[test]
+fn tape_report_input_driven() {
+    let instrs = bfir::parse(",[>,]").unwrap();
+    assert_eq!(tape_report(&instrs),
+               "minimum tape size: unbounded (depends on input)\n");
+}
+
+#[test]
+fn init_hex_invalid() {
+    assert!(parse_hex("410").is_err());
+    assert!(parse_hex("4g").is_err());
+    assert!(parse_hex("+1").is_err());
+}
+
+#[test]
+fn speculate_unknown_mode() {
+    assert!(speculate_source("", "everything", &[], 0).is_err());
+}
+
+#[test]
+fn format_command_with_args() {
+    let llc_args = ["-O3", "-filetype=obj", "/tmp/foo.ll", "-o", "/tmp/foo.o"];
+    assert_eq!(format_command("llc", &llc_args),
+               "llc -O3 -filetype=obj /tmp/foo.ll -o /tmp/foo.o");
+    assert_eq!(format_command("strip", &["-s", "foo"]), "strip -s foo");
+}
+
+#[test]
+fn format_command_quotes_args() {
+    assert_eq!(format_command("strip", &["-s", "my program"]), "strip -s 'my program'");
+    assert_eq!(format_command("strip", &["-s", "it's"]), "strip -s 'it'\\''s'");
+    assert_eq!(format_command("clang", &["$HOME/a;b", ""]), "clang '$HOME/a;b' ''");
+}
+
+#[test]
+fn pie_reaches_clang() {
+    let matches = options().parse(&["foo.bf"]).unwrap();
+    let pie = pie_option(&matches).unwrap();
+    assert_eq!(format_command("clang", &clang_args("foo.o", "foo", pie, None)),
+               "clang foo.o -o foo");
+
+    let matches = options().parse(&["--pie", "foo.bf"]).unwrap();
+    let pie = pie_option(&matches).unwrap();
+    assert_eq!(format_command("clang", &clang_args("foo.o", "foo", pie, None)),
+               "clang foo.o -o foo -pie");
+
+    let matches = options().parse(&["--no-pie", "foo.bf"]).unwrap();
+    let pie = pie_option(&matches).unwrap();
+    assert_eq!(format_command("clang", &clang_args("foo.o", "foo", pie, None)),
+               "clang foo.o -o foo -no-pie");
+}
+
+#[test]
+fn pie_and_no_pie_conflict() {
+    let matches = options().parse(&["--pie", "--no-pie", "foo.bf"]).unwrap();
+    assert!(pie_option(&matches).is_err());
+}
+
+#[test]
+fn target_reaches_clang() {
+    assert_eq!(format_command("clang", &clang_args("foo.o", "foo", None,
+                                                   Some("--target=armv7-linux-gnueabihf"))),
+               "clang foo.o -o foo --target=armv7-linux-gnueabihf");
+}
+
+#[test]
+fn parse_target_options() {
+    let matches = options().parse(&["foo.bf"]).unwrap();
+    assert_eq!(parse_target(&matches), Ok(None));
+
+    let matches = options().parse(&["--target=nosucharch-linux-gnu", "foo.bf"]).unwrap();
+    assert!(parse_target(&matches).unwrap_err().contains("not available"));
+
+    let matches = options().parse(&["--target=x86_64-linux-gnu", "--run", "foo.bf"]).unwrap();
+    assert!(parse_target(&matches).unwrap_err().starts_with("--target can't be used with --run"));
+}
+
+#[test]
+fn temp_file_in_custom_dir() {
+    let dir = env::temp_dir().join("bfc_temp_dir_test");
+    let _ = std::fs::create_dir_all(&dir);
+
+    let temp_file = new_temp_file(dir.to_str()).unwrap();
+    assert_eq!(temp_file.path().parent(), Some(dir.as_path()));
 }
@@ -4,55 +4,290 @@ use std::num::Wrapping;
 
 use itertools::Itertools;
 
-use bfir::{Instruction, Cell};
+use bfir::{Instruction, Cell, walk};
 use bfir::Instruction::*;
 
+/// A named peephole optimisation.
+pub struct Pass {
+    pub name: &'static str,
+    pub run: fn(Vec<Instruction>) -> Vec<Instruction>,
+}
+
+/// Our optimisations should reach a fixed point quickly. If we're
+/// still changing the IR after this many iterations, a pass is
+/// probably undoing the work of another.
+pub const MAX_OPT_ITERATIONS: u64 = 1000;
+
+/// Running our passes didn't reach a fixed point.
+#[derive(Debug)]
+pub struct NoFixedPoint {
+    /// The instructions after the last iteration.
+    pub instrs: Vec<Instruction>,
+    /// The last pass that changed the instructions.
+    pub pass_name: &'static str,
+}
+
 /// Given a sequence of BF instructions, apply peephole optimisations
 /// (repeatedly if necessary).
+///
+/// If we don't reach a fixed point, we return the instructions from
+/// the last iteration, which are still correct. Use
+/// `optimize_for_eof` to find out which pass was at fault.
 pub fn optimize(instrs: Vec<Instruction>) -> Vec<Instruction> {
-    // Many of our individual peephole optimisations remove
-    // instructions, creating new opportunities to combine. We run
-    // until we've found a fixed-point where no further optimisations
-    // can be made.
+    optimize_with_tape(instrs, true)
+}
+
+/// Apply peephole optimisations, as with `optimize`. If `zeroed_tape`
+/// is false, we make no assumptions about the initial cell values
+/// (e.g. when they're set from argv at runtime).
+pub fn optimize_with_tape(instrs: Vec<Instruction>, zeroed_tape: bool) -> Vec<Instruction> {
+    optimize_for_eof(instrs, zeroed_tape, true, false).unwrap_or_else(|e| e.instrs)
+}
+
+/// Apply peephole optimisations, as with `optimize_with_tape`. If
+/// `read_overwrites` is false, a read may leave the cell unchanged
+/// (e.g. at EOF with `--input-fd`), so changes to the cell before a
+/// read aren't dead. If `final_tape_observed` is true, the cells and
+/// cell pointer can be seen when the program exits (e.g. with
+/// `--print-cells`), so code at the end of the program isn't dead
+/// either.
+pub fn optimize_for_eof(instrs: Vec<Instruction>,
+                        zeroed_tape: bool,
+                        read_overwrites: bool,
+                        final_tape_observed: bool)
+                        -> Result<Vec<Instruction>, NoFixedPoint> {
+    optimize_with_passes(instrs, &passes(zeroed_tape, read_overwrites, final_tape_observed))
+}
+
+/// As `optimize_for_eof`, but also return the number of iterations
+/// in which each pass changed the instructions, in the order we run
+/// the passes.
+pub fn optimize_with_counts(instrs: Vec<Instruction>,
+                            zeroed_tape: bool,
+                            read_overwrites: bool,
+                            final_tape_observed: bool)
+                            -> (Result<Vec<Instruction>, NoFixedPoint>,
+                                Vec<(&'static str, u64)>) {
+    optimize_with_pass_counts(instrs,
+                              &passes(zeroed_tape, read_overwrites, final_tape_observed))
+}
+
+/// Only combine adjacent increments and adjacent pointer
+/// increments. This is cheap, never changes the behaviour of the
+/// program, and makes interpreting much faster.
+pub fn combine_only(instrs: Vec<Instruction>) -> Vec<Instruction> {
+    let passes = [
+        Pass { name: "combine_increments", run: combine_increments },
+        Pass { name: "combine_ptr_increments", run: combine_ptr_increments },
+    ];
+    optimize_with_passes(instrs, &passes).unwrap_or_else(|e| e.instrs)
+}
+
+/// Remove every `Debug` instruction. Unless we're compiling `#` with
+/// `--debug-cmd`, it's just a comment, and removing it lets us
+/// combine the instructions around it.
+pub fn remove_debug(instrs: Vec<Instruction>) -> Vec<Instruction> {
+    instrs.into_iter().filter(|instr| *instr != Debug).map(|instr| {
+        match instr {
+            Loop(offset, body) => Loop(offset, remove_debug(body)),
+            i => i
+        }
+    }).collect()
+}
+
+/// Apply `passes` repeatedly until we reach a fixed point, giving up
+/// after `MAX_OPT_ITERATIONS`. If we give up, the error says which
+/// pass was at fault and holds the instructions from the last
+/// iteration, which are still correct.
+pub fn optimize_with_passes(instrs: Vec<Instruction>,
+                            passes: &[Pass])
+                            -> Result<Vec<Instruction>, NoFixedPoint> {
+    optimize_to_fixpoint(instrs, passes, MAX_OPT_ITERATIONS)
+}
+
+/// As `optimize_with_passes`, but also return the number of
+/// iterations in which each pass changed the instructions, in the
+/// order of `passes`.
+pub fn optimize_with_pass_counts(instrs: Vec<Instruction>,
+                                 passes: &[Pass])
+                                 -> (Result<Vec<Instruction>, NoFixedPoint>,
+                                     Vec<(&'static str, u64)>) {
+    let mut counts = vec![0; passes.len()];
+    let result = fixpoint(instrs, passes, MAX_OPT_ITERATIONS, Some(&mut counts[..]));
+    (result, passes.iter().map(|pass| pass.name).zip(counts).collect())
+}
+
+/// Our default optimisations, in the order we run them.
+fn passes(zeroed_tape: bool, read_overwrites: bool, final_tape_observed: bool) -> Vec<Pass> {
+    let mut passes = vec![
+        Pass { name: "combine_increments", run: combine_increments },
+        Pass { name: "combine_ptr_increments", run: combine_ptr_increments },
+        Pass { name: "sink_ptr_increments", run: sink_ptr_increments },
+        if zeroed_tape {
+            Pass { name: "annotate_known_zero", run: annotate_known_zero }
+        } else {
+            Pass { name: "annotate_known_zero", run: annotate_known_zero_inner }
+        },
+        Pass { name: "extract_multiply", run: extract_multiply },
+        Pass { name: "extract_scan", run: extract_scan },
+        Pass { name: "remove_copy_restore", run: remove_copy_restore },
+        Pass { name: "simplify_multiply_moves", run: simplify_multiply_moves },
+        Pass { name: "simplify_loops", run: simplify_loops },
+        Pass { name: "combine_set_and_increments", run: combine_set_and_increments },
+        Pass { name: "remove_dead_loops", run: remove_dead_loops },
+        if zeroed_tape {
+            Pass { name: "remove_redundant_sets", run: remove_redundant_sets }
+        } else {
+            Pass { name: "remove_redundant_sets", run: remove_redundant_sets_inner }
+        },
+        Pass { name: "combine_before_read", run: combine_before_read },
+        Pass { name: "remove_pure_code", run: remove_pure_code },
+    ];
+    if !read_overwrites {
+        passes.retain(|pass| pass.name != "combine_before_read");
+    }
+    if final_tape_observed {
+        passes.retain(|pass| pass.name != "remove_pure_code");
+    }
+    passes
+}
+
+/// Look up our default passes by name, for `--passes`. Names may use
+/// hyphens or underscores, e.g. "combine-increments". We return the
+/// passes in the order given, and the same pass may appear twice.
+pub fn named_passes(names: &[&str],
+                    zeroed_tape: bool,
+                    read_overwrites: bool,
+                    final_tape_observed: bool)
+                    -> Result<Vec<Pass>, String> {
+    let available = passes(zeroed_tape, read_overwrites, final_tape_observed);
+    let mut result = vec![];
+    for name in names {
+        match available.iter().find(|pass| pass.name == name.replace("-", "_")) {
+            Some(pass) => {
+                result.push(Pass {
+                    name: pass.name,
+                    run: pass.run,
+                })
+            }
+            None => {
+                let valid: Vec<_> = available.iter().map(|pass| pass.name.replace("_", "-")).collect();
+                return Err(format!("Unknown pass '{}'. Valid passes are: {}",
+                                   name,
+                                   valid.join(", ")));
+            }
+        }
+    }
+    Ok(result)
+}
+
+/// Run `passes` once in order, or until we reach a fixed point if
+/// `fixpoint` is set.
+pub fn run_passes(instrs: Vec<Instruction>,
+                  passes: &[Pass],
+                  fixpoint: bool)
+                  -> Result<Vec<Instruction>, NoFixedPoint> {
+    if fixpoint {
+        optimize_with_passes(instrs, passes)
+    } else {
+        Ok(optimize_once(instrs, passes, None))
+    }
+}
+
+/// Many of our individual peephole optimisations remove
+/// instructions, creating new opportunities to combine. We run until
+/// we've found a fixed-point where no further optimisations can be
+/// made, giving up after `max_iterations`.
+pub fn optimize_to_fixpoint(instrs: Vec<Instruction>,
+                            passes: &[Pass],
+                            max_iterations: u64)
+                            -> Result<Vec<Instruction>, NoFixedPoint> {
+    fixpoint(instrs, passes, max_iterations, None)
+}
+
+/// As `optimize_to_fixpoint`. If `counts` is given, we also count
+/// the iterations in which each pass changed the instructions.
+fn fixpoint(instrs: Vec<Instruction>,
+            passes: &[Pass],
+            max_iterations: u64,
+            mut counts: Option<&mut [u64]>)
+            -> Result<Vec<Instruction>, NoFixedPoint> {
     let mut prev = instrs.clone();
-    let mut result = optimize_once(instrs);
+    let mut result = optimize_once(instrs, passes, counts.as_mut().map(|counts| &mut **counts));
+    let mut iterations = 1;
+
     while prev != result {
+        if iterations >= max_iterations {
+            // Run the passes one more time, one by one, so we can
+            // say which pass is still changing things.
+            let mut pass_name = "";
+            let mut current = result.clone();
+            for pass in passes {
+                let next = (pass.run)(current.clone());
+                if next != current {
+                    pass_name = pass.name;
+                }
+                current = next;
+            }
+
+            return Err(NoFixedPoint { instrs: result, pass_name: pass_name });
+        }
+
         prev = result.clone();
-        result = optimize_once(result);
+        result = optimize_once(result, passes, counts.as_mut().map(|counts| &mut **counts));
+        iterations += 1;
     }
-    result
+    Ok(result)
 }
 
 /// Apply all our peephole optimisations once and return the result.
-fn optimize_once(instrs: Vec<Instruction>) -> Vec<Instruction> {
-    let combined = combine_ptr_increments(combine_increments(instrs));
-    let annotated = annotate_known_zero(combined);
-    let extracted = extract_multiply(annotated);
-    let simplified = remove_dead_loops(combine_set_and_increments(simplify_loops(extracted)));
-    remove_pure_code(combine_before_read(remove_redundant_sets(simplified)))
+/// If `counts` is given, add one for each pass that changed the
+/// instructions.
+fn optimize_once(instrs: Vec<Instruction>,
+                 passes: &[Pass],
+                 counts: Option<&mut [u64]>)
+                 -> Vec<Instruction> {
+    let mut result = instrs;
+    match counts {
+        Some(counts) => {
+            for (pass, count) in passes.iter().zip(counts.iter_mut()) {
+                let next = (pass.run)(result.clone());
+                if next != result {
+                    *count += 1;
+                }
+                result = next;
+            }
+        }
+        None => {
+            for pass in passes {
+                result = (pass.run)(result);
+            }
+        }
+    }
+    result
 }
 
-/// Combine consecutive increments into a single increment
-/// instruction.
+/// Combine consecutive increments of the same cell into a single
+/// increment instruction.
 pub fn combine_increments(instrs: Vec<Instruction>) -> Vec<Instruction> {
     instrs.into_iter().coalesce(|prev_instr, instr| {
         // Collapse consecutive increments.
-        if let (&Increment(prev_amount), &Increment(amount)) = (&prev_instr, &instr) {
-            Ok(Increment(amount + prev_amount))
-        } else {
-            Err((prev_instr, instr))
+        match (&prev_instr, &instr) {
+            (&Increment(prev_amount, prev_offset), &Increment(amount, offset))
+                if prev_offset == offset => Ok(Increment(amount + prev_amount, offset)),
+            _ => Err((prev_instr, instr)),
         }
     }).filter(|instr| {
         // Remove any increments of 0.
-        if let &Increment(Wrapping(0)) = instr {
+        if let &Increment(Wrapping(0), _) = instr {
             return false;
         }
         true
     }).map(|instr| {
         // Combine increments in nested loops too.
         match instr {
-            Loop(body) => {
-                Loop(combine_increments(body))
+            Loop(offset, body) => {
+                Loop(offset, combine_increments(body))
             },
             i => i
         }
@@ -76,23 +311,70 @@ pub fn combine_ptr_increments(instrs: Vec<Instruction>) -> Vec<Instruction> {
     }).map(|instr| {
         // Combine increments in nested loops too.
         match instr {
-            Loop(body) => {
-                Loop(combine_ptr_increments(body))
+            Loop(offset, body) => {
+                Loop(offset, combine_ptr_increments(body))
             },
             i => i
         }
     }).collect()
 }
 
+/// Move pointer increments after reads, writes, increments and sets,
+/// adjusting their offset to match. This brings pointer increments
+/// together so we can combine them, e.g. ">.<" becomes a write to the
+/// next cell without moving the pointer.
+pub fn sink_ptr_increments(instrs: Vec<Instruction>) -> Vec<Instruction> {
+    let mut result = vec![];
+
+    for instr in instrs {
+        let prev_amount = match result.last() {
+            Some(&PointerIncrement(amount)) => Some(amount),
+            _ => None,
+        };
+
+        match (prev_amount, instr) {
+            (Some(amount), Read(offset)) => {
+                result.pop();
+                result.push(Read(offset + amount));
+                result.push(PointerIncrement(amount));
+            }
+            (Some(amount), Write(offset)) => {
+                result.pop();
+                result.push(Write(offset + amount));
+                result.push(PointerIncrement(amount));
+            }
+            (Some(amount), Increment(inc_amount, offset)) => {
+                result.pop();
+                result.push(Increment(inc_amount, offset + amount));
+                result.push(PointerIncrement(amount));
+            }
+            (Some(amount), Set(set_amount, offset)) => {
+                result.pop();
+                result.push(Set(set_amount, offset + amount));
+                result.push(PointerIncrement(amount));
+            }
+            // Do the same in nested loops.
+            (_, Loop(offset, body)) => {
+                result.push(Loop(offset, sink_ptr_increments(body)));
+            }
+            (_, i) => {
+                result.push(i);
+            }
+        }
+    }
+
+    result
+}
+
 fn combine_before_read(instrs: Vec<Instruction>) -> Vec<Instruction> {
     instrs.into_iter().coalesce(|prev_instr, instr| {
         // Remove redundant code before a read.
         match (prev_instr.clone(), instr.clone()) {
-            (Increment(_), Read) => {
-                Ok(Read)
+            (Increment(_, offset), Read(read_offset)) if offset == read_offset => {
+                Ok(Read(read_offset))
             },
-            (Set(_), Read) => {
-                Ok(Read)
+            (Set(_, offset), Read(read_offset)) if offset == read_offset => {
+                Ok(Read(read_offset))
             },
             _ => {
                 Err((prev_instr, instr))
@@ -101,8 +383,8 @@ fn combine_before_read(instrs: Vec<Instruction>) -> Vec<Instruction> {
     }).map(|instr| {
         // Do the same in nested loops.
         match instr {
-            Loop(body) => {
-                Loop(combine_before_read(body))
+            Loop(offset, body) => {
+                Loop(offset, combine_before_read(body))
             },
             i => i
         }
@@ -111,63 +393,85 @@ fn combine_before_read(instrs: Vec<Instruction>) -> Vec<Instruction> {
 
 pub fn simplify_loops(instrs: Vec<Instruction>) -> Vec<Instruction> {
     instrs.into_iter().map(|instr| {
-        if let &Loop(ref body) = &instr {
+        if let &Loop(0, ref body) = &instr {
             // If the loop is [-]
-            if *body == vec![Increment(Wrapping(-1))] {
-                return Set(Wrapping(0))
+            if *body == vec![Increment(Wrapping(-1), 0)] {
+                return Set(Wrapping(0), 0)
             }
         }
         instr
     }).map(|instr| {
         // Simplify zeroing loops nested in other loops.
         match instr {
-            Loop(body) => {
-                Loop(simplify_loops(body))
+            Loop(offset, body) => {
+                Loop(offset, simplify_loops(body))
             },
             i => i
         }
     }).collect()
 }
 
-/// Remove any loops where we know the current cell is zero.
+/// Remove any loops where we know the current cell is zero. That's
+/// the case straight after a `Set(0)`, or after a loop, scan or
+/// multiply-move, which all leave the current cell at zero. Scans and
+/// multiply-moves were loops too, so we remove them in the same way.
 pub fn remove_dead_loops(instrs: Vec<Instruction>) -> Vec<Instruction> {
     instrs.into_iter().coalesce(|prev_instr, instr| {
-        if let (&Set(Wrapping(0)), &Loop(_)) = (&prev_instr, &instr) {
-            return Ok(Set(Wrapping(0)));
+        let is_loop = match &instr {
+            &Loop(0, _) | &Scan(_) | &MultiplyMove(_) => true,
+            _ => false,
+        };
+        if is_loop && leaves_cell_zero(&prev_instr) {
+            return Ok(prev_instr);
         }
         Err((prev_instr, instr))
     }).map(|instr| {
         match instr {
-            Loop(body) => {
-                Loop(remove_dead_loops(body))
+            Loop(offset, body) => {
+                Loop(offset, remove_dead_loops(body))
             },
             i => i
         }
     }).collect()
 }
 
+/// Do we know the current cell is zero after `instr`?
+fn leaves_cell_zero(instr: &Instruction) -> bool {
+    match instr {
+        &Set(Wrapping(0), 0) | &Loop(0, _) | &Scan(_) | &MultiplyMove(_) => true,
+        _ => false,
+    }
+}
+
 /// Combine set instructions with other set instructions or
-/// increments.
+/// increments of the same cell.
 pub fn combine_set_and_increments(instrs: Vec<Instruction>) -> Vec<Instruction> {
     instrs.into_iter().coalesce(|prev_instr, instr| {
-        if let (&Increment(_), &Set(amount)) = (&prev_instr, &instr) {
-            return Ok(Set(amount));
+        if let (&Increment(_, inc_offset), &Set(amount, offset)) = (&prev_instr, &instr) {
+            if inc_offset == offset {
+                return Ok(Set(amount, offset));
+            }
         }
         Err((prev_instr, instr))
     }).coalesce(|prev_instr, instr| {
-        if let (&Set(set_amount), &Increment(inc_amount)) = (&prev_instr, &instr) {
-            return Ok(Set(set_amount + inc_amount));
+        if let (&Set(set_amount, offset), &Increment(inc_amount, inc_offset)) =
+               (&prev_instr, &instr) {
+            if inc_offset == offset {
+                return Ok(Set(set_amount + inc_amount, offset));
+            }
         }
         Err((prev_instr, instr))
     }).coalesce(|prev_instr, instr| {
-        if let (&Set(_), &Set(amount)) = (&prev_instr, &instr) {
-            return Ok(Set(amount));
+        if let (&Set(_, prev_offset), &Set(amount, offset)) = (&prev_instr, &instr) {
+            if prev_offset == offset {
+                return Ok(Set(amount, offset));
+            }
         }
         Err((prev_instr, instr))
     }).map(|instr| {
         match instr {
-            Loop(body) => {
-                Loop(combine_set_and_increments(body))
+            Loop(offset, body) => {
+                Loop(offset, combine_set_and_increments(body))
             },
             i => i
         }
@@ -177,7 +481,8 @@ pub fn combine_set_and_increments(instrs: Vec<Instruction>) -> Vec<Instruction>
 pub fn remove_redundant_sets(instrs: Vec<Instruction>) -> Vec<Instruction> {
     let mut reduced = remove_redundant_sets_inner(instrs);
 
-    if let Some(&Set(Wrapping(0))) = reduced.first() {
+    // Every cell starts at zero, so zeroing any cell is redundant.
+    if let Some(&Set(Wrapping(0), _)) = reduced.first() {
         reduced.remove(0);
     }
 
@@ -185,23 +490,40 @@ pub fn remove_redundant_sets(instrs: Vec<Instruction>) -> Vec<Instruction> {
 }
 
 fn remove_redundant_sets_inner(instrs: Vec<Instruction>) -> Vec<Instruction> {
-    instrs.into_iter().coalesce(|prev_instr, instr| {
-        if let (loop_instr @ Loop(_), &Set(Wrapping(0))) = (prev_instr.clone(), &instr) {
-            return Ok(loop_instr);
-        }
-        if let (multiply_instr @ MultiplyMove(_), &Set(Wrapping(0))) = (prev_instr.clone(), &instr) {
-            return Ok(multiply_instr);
-        }
+    let mut result = vec![];
+    // A loop or multiply-move always leaves the current cell at
+    // zero. We can skip over instructions that don't move the cell
+    // pointer or modify the current cell, including increments and
+    // sets of other cells.
+    let mut known_zero = false;
 
-        Err((prev_instr, instr))
-    }).map(|instr| {
+    for instr in instrs {
         match instr {
-            Loop(body) => {
-                Loop(remove_redundant_sets_inner(body))
-            },
-            i => i
+            Set(Wrapping(0), 0) if known_zero => {}
+            Loop(offset, body) => {
+                result.push(Loop(offset, remove_redundant_sets_inner(body)));
+                known_zero = offset == 0;
+            }
+            MultiplyMove(changes) => {
+                result.push(MultiplyMove(changes));
+                known_zero = true;
+            }
+            Scan(stride) => {
+                result.push(Scan(stride));
+                known_zero = true;
+            }
+            Write(offset) => result.push(Write(offset)),
+            Debug => result.push(Debug),
+            Read(offset) if offset != 0 => result.push(Read(offset)),
+            Increment(amount, offset) if offset != 0 => result.push(Increment(amount, offset)),
+            Set(amount, offset) if offset != 0 => result.push(Set(amount, offset)),
+            i => {
+                result.push(i);
+                known_zero = false;
+            }
         }
-    }).collect()
+    }
+    result
 }
 
 pub fn annotate_known_zero(instrs: Vec<Instruction>) -> Vec<Instruction> {
@@ -209,7 +531,7 @@ pub fn annotate_known_zero(instrs: Vec<Instruction>) -> Vec<Instruction> {
 
     // Cells in BF are initialised to zero, so we know the current
     // cell is zero at the start of execution.
-    result.push(Set(Wrapping(0)));
+    result.push(Set(Wrapping(0), 0));
 
     result.extend(annotate_known_zero_inner(instrs));
     result
@@ -220,10 +542,17 @@ fn annotate_known_zero_inner(instrs: Vec<Instruction>) -> Vec<Instruction> {
 
     for instr in instrs {
         match instr {
-            // After a loop, we know the cell is currently zero.
-            Loop(body) => {
-                result.push(Loop(annotate_known_zero_inner(body)));
-                result.push(Set(Wrapping(0)))
+            // After a loop, we know the cell it tested is zero.
+            Loop(offset, body) => {
+                result.push(Loop(offset, annotate_known_zero_inner(body)));
+                if offset == 0 {
+                    result.push(Set(Wrapping(0), 0))
+                }
+            }
+            // A scan stops at a zero cell.
+            Scan(stride) => {
+                result.push(Scan(stride));
+                result.push(Set(Wrapping(0), 0));
             }
             i => {
                 result.push(i);
@@ -235,19 +564,31 @@ fn annotate_known_zero_inner(instrs: Vec<Instruction>) -> Vec<Instruction> {
 
 /// Remove code at the end of the program that has no side
 /// effects. This means we have no write commands afterwards, nor
-/// loops (which may not terminate so we should not remove).
-fn remove_pure_code(instrs: Vec<Instruction>) -> Vec<Instruction> {
+/// loops that may not terminate. The final cell values and cell
+/// pointer aren't observable once the program has exited, so trailing
+/// pointer moves (e.g. ">>>") are removed too. That isn't true with
+/// `--print-cells`, so we don't run this pass then.
+pub fn remove_pure_code(instrs: Vec<Instruction>) -> Vec<Instruction> {
     let mut seen_side_effect = false;
     let truncated: Vec<Instruction> = instrs.into_iter().rev().skip_while(|instr| {
         match instr {
-            &Write => {
+            &Write(_) => {
                 seen_side_effect = true;
             },
-            &Read => {
+            &Read(_) => {
                 seen_side_effect = true;
             },
-            &Loop(_) => {
+            // A scan may run off the end of the tape.
+            &Scan(_) => {
                 seen_side_effect = true;
+            },
+            &Debug => {
+                seen_side_effect = true;
+            },
+            &Loop(_, _) => {
+                if !always_terminates(instr) {
+                    seen_side_effect = true;
+                }
             }
             _ => {}
         }
@@ -257,14 +598,42 @@ fn remove_pure_code(instrs: Vec<Instruction>) -> Vec<Instruction> {
     truncated.into_iter().rev().collect()
 }
 
+/// Is this a loop that terminates regardless of the initial cell
+/// values? We only recognise loops without IO or nested loops, that
+/// return to the same cell, and that change that cell by an odd
+/// amount. An odd step will reach zero from any starting value,
+/// e.g. "[--->+<]".
+pub fn always_terminates(instr: &Instruction) -> bool {
+    if let &Loop(0, ref body) = instr {
+        let mut net_movement = 0;
+        for body_instr in body {
+            match body_instr {
+                &Increment(_, _) => {}
+                &PointerIncrement(amount) => {
+                    net_movement += amount;
+                }
+                _ => return false,
+            }
+        }
+        if net_movement != 0 {
+            return false;
+        }
+
+        if let Some(change) = cell_changes(body).get(&0) {
+            return change.0 % 2 != 0;
+        }
+    }
+    false
+}
+
 /// Does this loop represent a multiplication operation?
 /// E.g. "[->>>++]" sets cell #3 to 2*cell #0.
 fn is_multiply_loop(instr: &Instruction) -> bool {
-    if let &Loop(ref body) = instr {
+    if let &Loop(0, ref body) = instr {
         // A multiply loop may only contain increments and pointer increments.
         for body_instr in body {
             match body_instr {
-                &Increment(_) => {}
+                &Increment(_, _) => {}
                 &PointerIncrement(_) => {}
                 _ => return false,
             }
@@ -289,7 +658,10 @@ fn is_multiply_loop(instr: &Instruction) -> bool {
             return false;
         }
 
-        if changes.len() < 2 {
+        // A multiply loop must change at least one other cell. Cells
+        // we visit but leave unchanged (e.g. "[->+-<]") don't count.
+        let changed_cells = changes.values().filter(|amount| amount.0 != 0).count();
+        if changed_cells < 2 {
             return false;
         }
 
@@ -307,9 +679,10 @@ fn cell_changes(instrs: &[Instruction]) -> HashMap<isize, Cell> {
 
     for instr in instrs {
         match instr {
-            &Increment(amount) => {
-                let current_amount = *changes.get(&cell_index).unwrap_or(&Wrapping(0));
-                changes.insert(cell_index, current_amount + amount);
+            &Increment(amount, offset) => {
+                let cell = cell_index + offset;
+                let current_amount = *changes.get(&cell).unwrap_or(&Wrapping(0));
+                changes.insert(cell, current_amount + amount);
             }
             &PointerIncrement(amount) => {
                 cell_index += amount;
@@ -322,22 +695,238 @@ fn cell_changes(instrs: &[Instruction]) -> HashMap<isize, Cell> {
     changes
 }
 
+/// Describe how we optimise a loop with this body, if we recognise
+/// it, e.g. "clear-cell, replaced with Set(0)".
+fn explain_loop(body: &[Instruction]) -> Option<String> {
+    if *body == [Increment(Wrapping(-1), 0)] {
+        return Some("clear-cell, replaced with Set(0)".to_owned());
+    }
+
+    if is_multiply_loop(&Loop(0, body.to_vec())) {
+        let mut changes: Vec<_> = cell_changes(body).into_iter().filter(|&(offset, amount)| {
+            offset != 0 && amount.0 != 0
+        }).collect();
+        changes.sort_by(|a, b| a.0.cmp(&b.0));
+
+        let targets: Vec<_> = changes.iter().map(|&(offset, amount)| {
+            format!("{:+} (\u{d7}{})", offset, amount.0)
+        }).collect();
+        return Some(format!("multiply-move into {}", targets.join(", ")));
+    }
+
+    if let Some(stride) = scan_stride(body) {
+        return Some(format!("scan, replaced with Scan({})", stride));
+    }
+    None
+}
+
+/// For every loop in `instrs`, in source order, describe how our
+/// optimisations transform it, or None if we leave it alone.
+pub fn explain_loops(instrs: &[Instruction]) -> Vec<Option<String>> {
+    // Combining increments never adds or removes loops, so the
+    // loops stay in source order.
+    let combined = combine_only(instrs.to_vec());
+
+    let mut result = vec![];
+    walk(&combined, &mut |instr| {
+        match instr {
+            &Loop(0, ref body) => result.push(explain_loop(body)),
+            &Loop(_, _) => result.push(None),
+            _ => {}
+        }
+    });
+    result
+}
+
+/// For every loop in `instrs`, in source order, say why our
+/// optimisations remove it, or None if the loop stays (or is inside
+/// a loop we've already given a reason for).
+pub fn removed_loops(instrs: &[Instruction], zeroed_tape: bool) -> Vec<Option<&'static str>> {
+    // As with `explain_loops`, combining increments keeps the loops
+    // in source order.
+    let combined = combine_only(instrs.to_vec());
+
+    let mut result = vec![];
+    removed_loops_inner(&combined, zeroed_tape, &mut result);
+    result
+}
+
+fn removed_loops_inner(instrs: &[Instruction],
+                       at_start: bool,
+                       result: &mut Vec<Option<&'static str>>) {
+    let mut prev_instr: Option<&Instruction> = None;
+    for instr in instrs {
+        if let &Loop(offset, ref body) = instr {
+            let reason = if at_start && prev_instr.is_none() && offset == 0 {
+                Some("dead loop at start")
+            } else if offset == 0 && prev_instr.map_or(false, |prev| {
+                match prev {
+                    &Loop(0, _) => true,
+                    _ => false,
+                }
+            }) {
+                Some("dead loop after another loop")
+            } else if offset == 0 && *body == vec![Increment(Wrapping(-1), 0)] {
+                Some("folded into Set")
+            } else if is_multiply_loop(instr) {
+                Some("folded into MultiplyMove")
+            } else if offset == 0 && scan_stride(body).is_some() {
+                Some("folded into Scan")
+            } else {
+                None
+            };
+            result.push(reason);
+
+            if reason.is_some() {
+                // The span of this loop covers any loops inside it.
+                walk(body, &mut |nested| {
+                    if let &Loop(_, _) = nested {
+                        result.push(None);
+                    }
+                });
+            } else {
+                removed_loops_inner(body, false, result);
+            }
+        }
+        prev_instr = Some(instr);
+    }
+}
+
 pub fn extract_multiply(instrs: Vec<Instruction>) -> Vec<Instruction> {
     instrs.into_iter().map(|instr| {
         match instr {
-            Loop(body) => {
-                if is_multiply_loop(&Loop(body.clone())) {
-                    let mut changes = cell_changes(&body);
+            Loop(offset, body) => {
+                if is_multiply_loop(&Loop(offset, body.clone())) {
                     // MultiplyMove is for where we move to, so ignore
-                    // the cell we're moving from.
-                    changes.remove(&0);
+                    // the cell we're moving from. Cells whose changes
+                    // cancel out aren't targets either.
+                    let changes = cell_changes(&body).into_iter().filter(|&(offset, amount)| {
+                        offset != 0 && amount.0 != 0
+                    }).collect();
 
                     MultiplyMove(changes)
                 } else {
-                    Loop(extract_multiply(body))
+                    Loop(offset, extract_multiply(body))
                 }
             }
             i => i
         }
     }).collect()
 }
+
+/// If `body` only moves the cell pointer, e.g. `[>]` or `[<<]`,
+/// return how far it moves.
+pub fn scan_stride(body: &[Instruction]) -> Option<isize> {
+    if body.len() != 1 {
+        return None;
+    }
+    match body[0] {
+        PointerIncrement(stride) if stride != 0 => Some(stride),
+        _ => None,
+    }
+}
+
+/// Replace loops that only move the cell pointer with a `Scan`.
+pub fn extract_scan(instrs: Vec<Instruction>) -> Vec<Instruction> {
+    instrs.into_iter().map(|instr| {
+        match instr {
+            Loop(0, body) => {
+                match scan_stride(&body) {
+                    Some(stride) => Scan(stride),
+                    None => Loop(0, extract_scan(body)),
+                }
+            }
+            Loop(offset, body) => Loop(offset, extract_scan(body)),
+            i => i
+        }
+    }).collect()
+}
+
+/// Remove a `MultiplyMove` into a temporary cell when the next
+/// `MultiplyMove` moves the value straight back, e.g.
+/// "[->+<]>[-<+>]<". The second move on its own has the same effect:
+/// the source cell ends up as its old value plus the old temporary
+/// value, and the temporary cell ends up zero.
+pub fn remove_copy_restore(instrs: Vec<Instruction>) -> Vec<Instruction> {
+    let mut result = vec![];
+    for instr in instrs {
+        let instr = match instr {
+            Loop(offset, body) => Loop(offset, remove_copy_restore(body)),
+            i => i,
+        };
+        result.push(instr);
+
+        let len = result.len();
+        if len >= 3 && is_copy_restore(&result[len - 3], &result[len - 2], &result[len - 1]) {
+            result.remove(len - 3);
+        }
+    }
+    result
+}
+
+/// Does `copy` move the current cell to one other cell, and
+/// `restore` (after moving there with `step`) move it back? The
+/// factors must cancel out, so the value is unchanged.
+fn is_copy_restore(copy: &Instruction, step: &Instruction, restore: &Instruction) -> bool {
+    if let (&MultiplyMove(ref copy_changes),
+            &PointerIncrement(amount),
+            &MultiplyMove(ref restore_changes)) = (copy, step, restore) {
+        if copy_changes.len() != 1 || restore_changes.len() != 1 {
+            return false;
+        }
+
+        if let (Some(&copy_factor), Some(&restore_factor)) =
+               (copy_changes.get(&amount), restore_changes.get(&-amount)) {
+            return (copy_factor * restore_factor).0 == 1;
+        }
+    }
+    false
+}
+
+/// Tidy up `MultiplyMove`s. Destinations with a factor of zero don't
+/// change anything, so we drop them, and a move without any
+/// destinations just zeroes the current cell.
+///
+/// A `Set` on one of the destinations straight after the move
+/// overwrites whatever the move added there, so we drop that
+/// destination too. We can't fold an increment on a destination, as
+/// the move adds a multiple of the current cell, which we don't know.
+/// A `Set` on the current cell doesn't affect the destinations, so we
+/// keep the move as it is.
+pub fn simplify_multiply_moves(instrs: Vec<Instruction>) -> Vec<Instruction> {
+    let mut instrs: Vec<_> = instrs.into_iter().map(|instr| {
+        match instr {
+            Loop(offset, body) => Loop(offset, simplify_multiply_moves(body)),
+            i => i,
+        }
+    }).collect();
+
+    for index in 0..instrs.len() {
+        let overwritten = match (instrs.get(index + 1), instrs.get(index + 2)) {
+            (Some(&Set(_, offset)), _) if offset != 0 => Some(offset),
+            (Some(&PointerIncrement(step)), Some(&Set(_, offset))) => Some(step + offset),
+            _ => None,
+        };
+
+        let no_destinations = match instrs[index] {
+            MultiplyMove(ref mut changes) => {
+                if let Some(offset) = overwritten {
+                    changes.remove(&offset);
+                }
+                let zero_factors: Vec<isize> = changes.iter()
+                    .filter(|&(_, factor)| factor.0 == 0)
+                    .map(|(&offset, _)| offset)
+                    .collect();
+                for offset in zero_factors {
+                    changes.remove(&offset);
+                }
+                changes.is_empty()
+            }
+            _ => false,
+        };
+        if no_destinations {
+            instrs[index] = Set(Wrapping(0), 0);
+        }
+    }
+    instrs
+}
@@ -6,7 +6,8 @@ use bfir::Instruction;
 use bfir::Instruction::*;
 
 use peephole::*;
-use bfir::parse;
+use bfir::{parse, parse_with_config, walk, ParseConfig};
+use interpreter::interpret;
 use rand::Rng;
 use quickcheck::{Arbitrary, Gen, TestResult};
 
@@ -14,20 +15,20 @@ impl Arbitrary for Instruction {
     fn arbitrary<G: Gen>(g: &mut G) -> Instruction {
         let i = g.next_u32();
         match i % 13 {
-            0 => Increment(Wrapping(Arbitrary::arbitrary(g))),
+            0 => Increment(Wrapping(Arbitrary::arbitrary(g)), 0),
             1 => PointerIncrement(Arbitrary::arbitrary(g)),
-            2 => Set(Wrapping(Arbitrary::arbitrary(g))),
-            3 => Read,
-            4 => Write,
+            2 => Set(Wrapping(Arbitrary::arbitrary(g)), 0),
+            3 => Read(0),
+            4 => Write(0),
             // TODO: we should be able to generate arbitrary nested
             // instructions, instead of this limited range. See
             // https://github.com/BurntSushi/quickcheck/issues/23
-            5 => Loop(vec![]),
-            6 => Loop(vec![Increment(Wrapping(Arbitrary::arbitrary(g)))]),
-            7 => Loop(vec![PointerIncrement(Arbitrary::arbitrary(g))]),
-            8 => Loop(vec![Set(Wrapping(Arbitrary::arbitrary(g)))]),
-            9 => Loop(vec![Read]),
-            10 => Loop(vec![Read]),
+            5 => Loop(0, vec![]),
+            6 => Loop(0, vec![Increment(Wrapping(Arbitrary::arbitrary(g)), 0)]),
+            7 => Loop(0, vec![PointerIncrement(Arbitrary::arbitrary(g))]),
+            8 => Loop(0, vec![Set(Wrapping(Arbitrary::arbitrary(g)), 0)]),
+            9 => Loop(0, vec![Read(0)]),
+            10 => Loop(0, vec![Read(0)]),
             11 => {
                 let mut changes = HashMap::new();
                 changes.insert(1, Wrapping(-1));
@@ -46,8 +47,8 @@ impl Arbitrary for Instruction {
 
 #[test]
 fn combine_increments_flat() {
-    let initial = parse("++").unwrap();
-    let expected = vec![Increment(Wrapping(2))];
+    let initial = vec![Increment(Wrapping(1), 0), Increment(Wrapping(1), 0)];
+    let expected = vec![Increment(Wrapping(2), 0)];
     assert_eq!(combine_increments(initial), expected);
 }
 
@@ -60,32 +61,40 @@ fn combine_increments_unrelated() {
 
 #[test]
 fn combine_increments_nested() {
-    let initial = parse("[++]").unwrap();
-    let expected = vec![Loop(vec![Increment(Wrapping(2))])];
+    let initial = vec![Loop(0, vec![Increment(Wrapping(1), 0), Increment(Wrapping(1), 0)])];
+    let expected = vec![Loop(0, vec![Increment(Wrapping(2), 0)])];
     assert_eq!(combine_increments(initial), expected);
 }
 
 #[test]
 fn combine_increments_remove_redundant() {
-    let initial = parse("+-").unwrap();
+    let initial = vec![Increment(Wrapping(1), 0), Increment(Wrapping(-1), 0)];
     assert_eq!(combine_increments(initial), vec![]);
 }
 
+#[test]
+fn combine_increments_at_offset() {
+    let initial = vec![Increment(Wrapping(1), 2), Increment(Wrapping(2), 2),
+                       Increment(Wrapping(1), 0)];
+    let expected = vec![Increment(Wrapping(3), 2), Increment(Wrapping(1), 0)];
+    assert_eq!(combine_increments(initial), expected);
+}
+
 #[test]
 fn combine_increment_sum_to_zero() {
-    let initial = vec![Increment(Wrapping(-1)), Increment(Wrapping(1))];
+    let initial = vec![Increment(Wrapping(-1), 0), Increment(Wrapping(1), 0)];
     assert_eq!(combine_increments(initial), vec![]);
 }
 
 #[test]
 fn combine_set_sum_to_zero() {
-    let initial = vec![Set(Wrapping(-1)), Increment(Wrapping(1))];
-    assert_eq!(combine_set_and_increments(initial), vec![Set(Wrapping(0))]);
+    let initial = vec![Set(Wrapping(-1), 0), Increment(Wrapping(1), 0)];
+    assert_eq!(combine_set_and_increments(initial), vec![Set(Wrapping(0), 0)]);
 }
 
 #[test]
 fn combine_ptr_increments_flat() {
-    let initial = parse(">>").unwrap();
+    let initial = vec![PointerIncrement(1), PointerIncrement(1)];
     let expected = vec![PointerIncrement(2)];
     assert_eq!(combine_ptr_increments(initial), expected);
 }
@@ -99,15 +108,15 @@ fn combine_ptr_increments_unrelated() {
 
 #[test]
 fn combine_ptr_increments_nested() {
-    let initial = parse("[>>]").unwrap();
-    let expected = vec![Loop(vec![
+    let initial = vec![Loop(0, vec![PointerIncrement(1), PointerIncrement(1)])];
+    let expected = vec![Loop(0, vec![
         PointerIncrement(2)])];
     assert_eq!(combine_ptr_increments(initial), expected);
 }
 
 #[test]
 fn combine_ptr_increments_remove_redundant() {
-    let initial = parse("><").unwrap();
+    let initial = vec![PointerIncrement(1), PointerIncrement(-1)];
     assert_eq!(combine_ptr_increments(initial), vec![]);
 }
 
@@ -115,28 +124,143 @@ fn combine_ptr_increments_remove_redundant() {
 fn should_combine_before_read() {
     // The increment before the read is dead and can be removed.
     let initial = parse("+,.").unwrap();
-    let expected = vec![Read, Write];
+    let expected = vec![Read(0), Write(0)];
+    assert_eq!(optimize(initial), expected);
+}
+
+#[test]
+fn should_combine_before_read_at_offset() {
+    let initial = parse(">+,<.").unwrap();
+    let expected = vec![Read(1), Write(0)];
     assert_eq!(optimize(initial), expected);
 }
 
 #[test]
 fn should_combine_before_read_nested() {
     let initial = parse("+[+,]").unwrap();
-    let expected = vec![Set(Wrapping(1)), Loop(vec![Read])];
+    let expected = vec![Set(Wrapping(1), 0), Loop(0, vec![Read(0)])];
+    assert_eq!(optimize(initial), expected);
+}
+
+#[test]
+fn should_combine_set_before_read() {
+    // Reads overwrite the cell, even at EOF, so the set is dead.
+    let initial = vec![Read(0), Set(Wrapping(5), 0), Read(0), Write(0)];
+    let expected = vec![Read(0), Read(0), Write(0)];
+    assert_eq!(optimize_for_eof(initial, true, true, false).unwrap(), expected);
+}
+
+#[test]
+fn should_not_combine_before_read_without_overwrite() {
+    // If a read can leave the cell unchanged, the set and increment
+    // are live.
+    let initial = vec![Read(0), Set(Wrapping(5), 0), Read(0), Write(0)];
+    assert_eq!(optimize_for_eof(initial.clone(), true, false, false).unwrap(), initial);
+
+    let initial = vec![Read(0), Increment(Wrapping(1), 0), Read(0), Write(0)];
+    assert_eq!(optimize_for_eof(initial.clone(), true, false, false).unwrap(), initial);
+}
+
+#[test]
+fn should_keep_pure_code_when_final_tape_observed() {
+    // With --print-cells, the final cells are output.
+    let initial = parse("+++>++").unwrap();
+    assert_eq!(optimize_for_eof(initial.clone(), true, true, false).unwrap(), vec![]);
+    assert_eq!(optimize_for_eof(initial.clone(), true, true, true).unwrap(),
+               vec![Set(Wrapping(3), 0), Increment(Wrapping(2), 1), PointerIncrement(1)]);
+}
+
+#[test]
+fn should_not_combine_before_read_at_offset() {
+    // The read is to a different cell, so the increment is live.
+    let initial = vec![Read(0), Increment(Wrapping(1), 0), Read(1), Write(0)];
+    assert_eq!(optimize(initial.clone()), initial);
+}
+
+#[test]
+fn sink_ptr_increments_around_write() {
+    let initial = parse(">.<").unwrap();
+    let expected = vec![Write(1), PointerIncrement(1), PointerIncrement(-1)];
+    assert_eq!(sink_ptr_increments(initial), expected);
+}
+
+#[test]
+fn sink_ptr_increments_past_several_io() {
+    let initial = parse(">,.").unwrap();
+    let expected = vec![Read(1), Write(1), PointerIncrement(1)];
+    assert_eq!(sink_ptr_increments(initial), expected);
+}
+
+#[test]
+fn sink_ptr_increments_nested() {
+    let initial = parse("[<.>]").unwrap();
+    let expected = vec![Loop(0, vec![Write(-1), PointerIncrement(-1), PointerIncrement(1)])];
+    assert_eq!(sink_ptr_increments(initial), expected);
+}
+
+#[test]
+fn sink_ptr_increments_past_increments() {
+    let initial = parse(">+<").unwrap();
+    let expected = vec![Increment(Wrapping(1), 1), PointerIncrement(1), PointerIncrement(-1)];
+    assert_eq!(sink_ptr_increments(initial), expected);
+
+    let initial = vec![PointerIncrement(-2), Set(Wrapping(3), 0)];
+    let expected = vec![Set(Wrapping(3), -2), PointerIncrement(-2)];
+    assert_eq!(sink_ptr_increments(initial), expected);
+}
+
+#[test]
+fn sink_ptr_increments_combines_moves() {
+    let initial = parse(">+<<->.").unwrap();
+    let expected = vec![Increment(Wrapping(1), 1), PointerIncrement(1),
+                        Increment(Wrapping(-1), -2), PointerIncrement(-2),
+                        Write(1), PointerIncrement(1)];
+    assert_eq!(sink_ptr_increments(initial.clone()), expected);
+
+    // Once the moves are together, they cancel out.
+    let expected = vec![Increment(Wrapping(1), 1), Increment(Wrapping(-1), -1), Write(0)];
+    assert_eq!(optimize_with_tape(initial, false), expected);
+}
+
+#[test]
+fn sink_ptr_increments_keeps_write_order() {
+    // The moves cancel out, but the first write must still see only
+    // the first increment of cell #0, so we can't combine the
+    // increments around it.
+    let initial = parse("+.>+<+.").unwrap();
+    let expected = vec![Increment(Wrapping(1), 0), Write(0), Increment(Wrapping(1), 1),
+                        Increment(Wrapping(1), 0), Write(0)];
+    assert_eq!(optimize_with_tape(initial, false), expected);
+}
+
+#[test]
+fn sink_ptr_increments_blocked() {
+    // Loops run relative to the cell we've moved to, so pointer moves
+    // can't sink past them.
+    let initial = parse(">[-]<.").unwrap();
+    let expected = vec![PointerIncrement(1), Loop(0, vec![Increment(Wrapping(-1), 0)]),
+                        Write(-1), PointerIncrement(-1)];
+    assert_eq!(sink_ptr_increments(initial), expected);
+}
+
+#[test]
+fn should_fold_write_at_offset() {
+    let initial = parse(",>.<.").unwrap();
+    let expected = vec![Read(0), Write(1), Write(0)];
     assert_eq!(optimize(initial), expected);
 }
 
 #[test]
 fn simplify_zeroing_loop() {
     let initial = parse("[-]").unwrap();
-    let expected = vec![Set(Wrapping(0))];
+    let expected = vec![Set(Wrapping(0), 0)];
     assert_eq!(simplify_loops(initial), expected);
 }
 
 #[test]
 fn simplify_nested_zeroing_loop() {
     let initial = parse("[[-]]").unwrap();
-    let expected = vec![Loop(vec![Set(Wrapping(0))])];
+    let expected = vec![Loop(0, vec![Set(Wrapping(0), 0)])];
     assert_eq!(simplify_loops(initial), expected);
 }
 
@@ -149,61 +273,138 @@ fn dont_simplify_multiple_decrement_loop() {
     assert_eq!(simplify_loops(initial.clone()), initial);
 }
 
+#[test]
+fn dont_simplify_loop_on_other_cell() {
+    // This loop never terminates if cell #1 is non-zero, so it isn't
+    // a Set(0).
+    let initial = vec![Loop(1, vec![Increment(Wrapping(-1), 0)])];
+    assert_eq!(simplify_loops(initial.clone()), initial);
+    assert_eq!(extract_multiply(initial.clone()), initial);
+}
+
 #[test]
 fn should_remove_dead_loops() {
     let initial = vec![
-        Set(Wrapping(0)),
-        Loop(vec![]),
-        Loop(vec![])];
-    let expected = vec![Set(Wrapping(0))];
+        Set(Wrapping(0), 0),
+        Loop(0, vec![]),
+        Loop(0, vec![])];
+    let expected = vec![Set(Wrapping(0), 0)];
     assert_eq!(remove_dead_loops(initial), expected);
 }
 
 #[test]
 fn should_remove_dead_loops_nested() {
-    let initial = vec![Loop(vec![Set(Wrapping(0)),Loop(vec![])])];
-    let expected = vec![Loop(vec![Set(Wrapping(0))])];
+    let initial = vec![Loop(0, vec![Set(Wrapping(0), 0),Loop(0, vec![])])];
+    let expected = vec![Loop(0, vec![Set(Wrapping(0), 0)])];
+    assert_eq!(remove_dead_loops(initial), expected);
+}
+
+#[test]
+fn should_remove_loop_after_loop() {
+    let initial = parse("[-][.]").unwrap();
+    let expected = vec![Loop(0, vec![Increment(Wrapping(-1), 0)])];
+    assert_eq!(remove_dead_loops(initial), expected);
+
+    // Chains of dead loops are all removed.
+    let initial = parse("[-][.][,][>]").unwrap();
+    let expected = vec![Loop(0, vec![Increment(Wrapping(-1), 0)])];
+    assert_eq!(remove_dead_loops(initial), expected);
+}
+
+#[test]
+fn should_remove_loop_after_loop_nested() {
+    let initial = parse("+[>[.][,]<-]").unwrap();
+    let expected = vec![Increment(Wrapping(1), 0),
+                        Loop(0, vec![PointerIncrement(1),
+                                     Loop(0, vec![Write(0)]),
+                                     PointerIncrement(-1),
+                                     Increment(Wrapping(-1), 0)])];
+    assert_eq!(remove_dead_loops(initial), expected);
+}
+
+#[test]
+fn should_remove_loop_after_multiply_move() {
+    let mut changes = HashMap::new();
+    changes.insert(1, Wrapping(1));
+    let initial = vec![Read(0), MultiplyMove(changes.clone()), Loop(0, vec![Write(0)])];
+    let expected = vec![Read(0), MultiplyMove(changes)];
+    assert_eq!(remove_dead_loops(initial), expected);
+}
+
+#[test]
+fn should_remove_multiply_move_after_multiply_move() {
+    let mut changes = HashMap::new();
+    changes.insert(1, Wrapping(1));
+    let mut other_changes = HashMap::new();
+    other_changes.insert(2, Wrapping(1));
+    let initial = vec![Read(0), MultiplyMove(changes.clone()), MultiplyMove(other_changes)];
+    let expected = vec![Read(0), MultiplyMove(changes)];
     assert_eq!(remove_dead_loops(initial), expected);
 }
 
+#[test]
+fn should_remove_leading_loop() {
+    let instrs = parse("[.]+.").unwrap();
+    assert_eq!(optimize(instrs), vec![Set(Wrapping(1), 0), Write(0)]);
+
+    let instrs = parse("[[.]>+<]+.").unwrap();
+    assert_eq!(optimize(instrs), vec![Set(Wrapping(1), 0), Write(0)]);
+}
+
+#[test]
+fn should_not_remove_loop_after_loop_on_other_cell() {
+    let initial = vec![Loop(1, vec![Increment(Wrapping(-1), 0)]), Loop(0, vec![Write(0)])];
+    assert_eq!(remove_dead_loops(initial.clone()), initial);
+
+    // The cell we loop on may be nonzero after moving.
+    let initial = parse("[-]>[.]").unwrap();
+    assert_eq!(remove_dead_loops(initial.clone()), initial);
+}
+
+#[test]
+fn should_not_remove_loops_on_other_cells() {
+    let initial = vec![Set(Wrapping(0), 0), Loop(1, vec![])];
+    assert_eq!(remove_dead_loops(initial.clone()), initial);
+}
+
 #[quickcheck]
 fn should_combine_set_and_increment(set_amount: i8, increment_amount: i8) -> bool {
     let set_amount = Wrapping(set_amount);
     let increment_amount = Wrapping(increment_amount);
     let initial = vec![
-        Set(set_amount),
-        Increment(increment_amount)];
-    let expected = vec![Set(set_amount + increment_amount)];
+        Set(set_amount, 0),
+        Increment(increment_amount, 0)];
+    let expected = vec![Set(set_amount + increment_amount, 0)];
     return combine_set_and_increments(initial) == expected;
 }
 
 #[quickcheck]
 fn should_combine_set_and_set(set_amount_before: i8, set_amount_after: i8) -> bool {
     let initial = vec![
-        Set(Wrapping(set_amount_before)),
-        Set(Wrapping(set_amount_after))];
-    let expected = vec![Set(Wrapping(set_amount_after))];
+        Set(Wrapping(set_amount_before), 0),
+        Set(Wrapping(set_amount_after), 0)];
+    let expected = vec![Set(Wrapping(set_amount_after), 0)];
     return combine_set_and_increments(initial) == expected;
 }
 
 #[test]
 fn should_combine_set_and_set_nested() {
-    let initial = vec![Loop(vec![Set(Wrapping(0)), Set(Wrapping(1))])];
-    let expected = vec![Loop(vec![Set(Wrapping(1))])];
+    let initial = vec![Loop(0, vec![Set(Wrapping(0), 0), Set(Wrapping(1), 0)])];
+    let expected = vec![Loop(0, vec![Set(Wrapping(1), 0)])];
     assert_eq!(combine_set_and_increments(initial), expected);
 }
 
 #[test]
 fn should_combine_increment_and_set() {
-    let initial = vec![Increment(Wrapping(2)), Set(Wrapping(3))];
-    let expected = vec![Set(Wrapping(3))];
+    let initial = vec![Increment(Wrapping(2), 0), Set(Wrapping(3), 0)];
+    let expected = vec![Set(Wrapping(3), 0)];
     assert_eq!(combine_set_and_increments(initial), expected);
 }
 
 #[test]
 fn should_remove_redundant_set() {
-    let initial = vec![Loop(vec![]), Set(Wrapping(0))];
-    let expected = vec![Loop(vec![])];
+    let initial = vec![Loop(0, vec![]), Set(Wrapping(0), 0)];
+    let expected = vec![Loop(0, vec![])];
     assert_eq!(remove_redundant_sets(initial), expected);
 }
 
@@ -212,21 +413,53 @@ fn should_remove_redundant_set_multiply() {
     let mut changes = HashMap::new();
     changes.insert(1, Wrapping(1));
 
-    let initial = vec![MultiplyMove(changes.clone()), Set(Wrapping(0))];
+    let initial = vec![MultiplyMove(changes.clone()), Set(Wrapping(0), 0)];
     let expected = vec![MultiplyMove(changes)];
     assert_eq!(remove_redundant_sets(initial), expected);
 }
 
+#[test]
+fn should_remove_redundant_set_after_zeroing_loop() {
+    let initial = vec![Loop(0, vec![Increment(Wrapping(-1), 0)]), Set(Wrapping(0), 0)];
+    let expected = vec![Loop(0, vec![Increment(Wrapping(-1), 0)])];
+    assert_eq!(remove_redundant_sets(initial), expected);
+}
+
+#[test]
+fn should_remove_redundant_set_after_write() {
+    let initial = vec![Loop(0, vec![]), Write(0), Read(1), Set(Wrapping(0), 0)];
+    let expected = vec![Loop(0, vec![]), Write(0), Read(1)];
+    assert_eq!(remove_redundant_sets(initial), expected);
+}
+
+#[test]
+fn should_keep_set_after_pointer_increment() {
+    let initial = vec![Loop(0, vec![]), PointerIncrement(1), Set(Wrapping(0), 0)];
+    assert_eq!(remove_redundant_sets(initial.clone()), initial);
+}
+
+#[test]
+fn should_keep_set_after_loop_on_other_cell() {
+    let initial = vec![Loop(1, vec![]), Set(Wrapping(0), 0)];
+    assert_eq!(remove_redundant_sets(initial.clone()), initial);
+}
+
 fn is_pure(instrs: &[Instruction]) -> bool {
     for instr in instrs {
         match instr {
-            &Loop(_) => {
+            &Loop(_, _) => {
+                return false;
+            }
+            &Read(_) => {
+                return false;
+            }
+            &Write(_) => {
                 return false;
             }
-            &Read => {
+            &Scan(_) => {
                 return false;
             }
-            &Write => {
+            &Debug => {
                 return false;
             }
             _ => (),
@@ -240,7 +473,7 @@ fn should_annotate_known_zero_at_start(instrs: Vec<Instruction>) -> TestResult {
     let annotated = annotate_known_zero(instrs);
 
     match annotated[0] {
-        Set(Wrapping(0)) => TestResult::from_bool(true),
+        Set(Wrapping(0), 0) => TestResult::from_bool(true),
         _ => TestResult::from_bool(false),
     }
 }
@@ -249,10 +482,10 @@ fn should_annotate_known_zero_at_start(instrs: Vec<Instruction>) -> TestResult {
 fn should_annotate_known_zero() {
     let initial = parse("+[]").unwrap();
     let expected = vec![
-        Set(Wrapping(0)),
-        Increment(Wrapping(1)),
-        Loop(vec![]),
-        Set(Wrapping(0))];
+        Set(Wrapping(0), 0),
+        Increment(Wrapping(1), 0),
+        Loop(0, vec![]),
+        Set(Wrapping(0), 0)];
     assert_eq!(annotate_known_zero(initial), expected);
 }
 
@@ -260,11 +493,11 @@ fn should_annotate_known_zero() {
 fn should_annotate_known_zero_nested() {
     let initial = parse("[[]]").unwrap();
     let expected = vec![
-        Set(Wrapping(0)),
-        Loop(vec![
-            Loop(vec![]),
-            Set(Wrapping(0))]),
-        Set(Wrapping(0))];
+        Set(Wrapping(0), 0),
+        Loop(0, vec![
+            Loop(0, vec![]),
+            Set(Wrapping(0), 0)]),
+        Set(Wrapping(0), 0)];
     assert_eq!(annotate_known_zero(initial), expected);
 }
 
@@ -273,14 +506,14 @@ fn should_annotate_known_zero_nested() {
 /// remove the Set 0 if we haven't combined it.
 #[test]
 fn should_annotate_known_zero_cleaned_up() {
-    let initial = vec![Write];
+    let initial = vec![Write(0)];
     assert_eq!(optimize(initial.clone()), initial);
 }
 
 #[test]
 fn should_preserve_set_0_in_loop() {
     // Regression test.
-    let initial = vec![Read, Loop(vec![Set(Wrapping(0))])];
+    let initial = vec![Read(0), Loop(0, vec![Set(Wrapping(0), 0)])];
     assert_eq!(optimize(initial.clone()), initial);
 }
 
@@ -290,11 +523,45 @@ fn should_remove_pure_code() {
     // removed.
     let initial = parse("+.+").unwrap();
     let expected = vec![
-        Set(Wrapping(1)),
-        Write];
+        Set(Wrapping(1), 0),
+        Write(0)];
+    assert_eq!(optimize(initial), expected);
+}
+
+#[test]
+fn should_remove_trailing_pointer_moves() {
+    let initial = parse("+.>>>").unwrap();
+    let expected = vec![Set(Wrapping(1), 0), Write(0)];
+    assert_eq!(optimize(initial), expected);
+}
+
+#[test]
+fn should_keep_pointer_moves_before_write() {
+    let initial = parse("+.>>>.").unwrap();
+    assert_eq!(remove_pure_code(initial.clone()), initial);
+
+    // We keep the write, but sink the pointer move past it and
+    // remove it.
+    let expected = vec![Set(Wrapping(1), 0), Write(0), Write(3)];
     assert_eq!(optimize(initial), expected);
 }
 
+#[test]
+fn should_remove_trailing_terminating_loop() {
+    let initial = parse(".[--->+<]").unwrap();
+    assert_eq!(remove_pure_code(initial), vec![Write(0)]);
+}
+
+#[test]
+fn should_not_remove_trailing_loop_that_may_not_terminate() {
+    // Decrementing by 2 never reaches zero from an odd value.
+    let initial = parse(".[-->+<]").unwrap();
+    assert_eq!(remove_pure_code(initial.clone()), initial);
+
+    let initial = parse(".[>]").unwrap();
+    assert_eq!(remove_pure_code(initial.clone()), initial);
+}
+
 #[quickcheck]
 fn should_remove_dead_pure_code(instrs: Vec<Instruction>) -> TestResult {
     if !is_pure(&instrs) {
@@ -312,32 +579,129 @@ fn optimize_should_be_idempotent(instrs: Vec<Instruction>) -> bool {
     return optimize(minimal.clone()) == minimal;
 }
 
+/// If we don't know the initial cell values, we can't assume a
+/// leading loop is dead or that increments start from zero.
+#[test]
+fn should_not_assume_zeroed_tape() {
+    let instrs = parse("[.]+.").unwrap();
+    let expected = vec![Loop(0, vec![Write(0)]), Set(Wrapping(1), 0), Write(0)];
+    assert_eq!(optimize_with_tape(instrs, false), expected);
+
+    let instrs = parse("[-].").unwrap();
+    let expected = vec![Set(Wrapping(0), 0), Write(0)];
+    assert_eq!(optimize_with_tape(instrs, false), expected);
+}
+
+/// "[-]" only becomes a Set after simplify_loops, so the Set must
+/// still be combined with the surrounding increments before we reach
+/// a fixed point. We end with a write so the cell isn't pure code.
+#[test]
+fn combine_zeroing_loop_with_increments() {
+    let cases = [
+        ("++[-]++.", vec![Set(Wrapping(2), 0), Write(0)]),
+        ("[-]+++++.", vec![Set(Wrapping(5), 0), Write(0)]),
+        ("+++[-].", vec![Set(Wrapping(0), 0), Write(0)]),
+    ];
+    for &(source, ref expected) in cases.iter() {
+        let result = optimize_with_tape(parse(source).unwrap(), false);
+        assert_eq!(result, *expected, "optimizing {}", source);
+        assert_eq!(optimize_with_tape(result.clone(), false), result);
+    }
+}
+
+/// On a zeroed tape, the same programs optimize to the same Sets,
+/// except that a leading Set(0) is redundant.
+#[test]
+fn combine_zeroing_loop_with_increments_zeroed_tape() {
+    assert_eq!(optimize(parse("++[-]++.").unwrap()), vec![Set(Wrapping(2), 0), Write(0)]);
+    assert_eq!(optimize(parse("[-]+++++.").unwrap()), vec![Set(Wrapping(5), 0), Write(0)]);
+    assert_eq!(optimize(parse("+++[-].").unwrap()), vec![Write(0)]);
+}
+
+#[test]
+fn optimize_with_counts_matches_optimize() {
+    let instrs = parse("++[-]++[->+<].").unwrap();
+    let (result, counts) = optimize_with_counts(instrs.clone(), true, true, false);
+    assert_eq!(result.unwrap(), optimize(instrs));
+
+    let count = |name: &str| counts.iter().find(|&&(pass_name, _)| pass_name == name).unwrap().1;
+    assert_eq!(count("simplify_loops"), 1);
+    assert_eq!(count("extract_multiply"), 1);
+    assert_eq!(count("combine_before_read"), 0);
+}
+
+/// A deliberately broken pass that undoes its own work on every run.
+fn swap_read_and_write(instrs: Vec<Instruction>) -> Vec<Instruction> {
+    instrs.into_iter().map(|instr| {
+        match instr {
+            Read(offset) => Write(offset),
+            Write(offset) => Read(offset),
+            i => i,
+        }
+    }).collect()
+}
+
+#[test]
+fn oscillating_pass_hits_iteration_limit() {
+    let passes = [Pass { name: "swap_read_and_write", run: swap_read_and_write },
+                  Pass { name: "combine_increments", run: combine_increments }];
+
+    match optimize_to_fixpoint(vec![Read(0)], &passes, 10) {
+        Ok(_) => panic!("Oscillating passes should never reach a fixed point"),
+        Err(e) => assert_eq!(e.pass_name, "swap_read_and_write"),
+    }
+}
+
+#[test]
+fn oscillating_pass_counts_hit_iteration_limit() {
+    let passes = [Pass { name: "swap_read_and_write", run: swap_read_and_write },
+                  Pass { name: "combine_increments", run: combine_increments }];
+
+    let (result, counts) = optimize_with_pass_counts(vec![Read(0)], &passes);
+    match result {
+        Ok(_) => panic!("Oscillating passes should never reach a fixed point"),
+        Err(e) => assert_eq!(e.pass_name, "swap_read_and_write"),
+    }
+    assert_eq!(counts, vec![("swap_read_and_write", MAX_OPT_ITERATIONS),
+                            ("combine_increments", 0)]);
+}
+
+#[test]
+fn oscillating_pass_still_completes() {
+    let passes = [Pass { name: "swap_read_and_write", run: swap_read_and_write }];
+
+    // We stop on a read or a write, depending on how many iterations
+    // we allow, but both are correct results of the pass.
+    match optimize_with_passes(vec![Read(0)], &passes) {
+        Ok(_) => panic!("Oscillating passes should never reach a fixed point"),
+        Err(e) => {
+            assert_eq!(e.pass_name, "swap_read_and_write");
+            assert!(e.instrs == vec![Read(0)] || e.instrs == vec![Write(0)]);
+        }
+    }
+}
+
 #[test]
 fn pathological_optimisation_opportunity() {
-    let instrs = vec![Read,
-                      Increment(Wrapping(1)),
+    let instrs = vec![Read(0),
+                      Increment(Wrapping(1), 0),
                       PointerIncrement(1),
-                      Increment(Wrapping(1)),
+                      Increment(Wrapping(1), 0),
                       PointerIncrement(1),
                       PointerIncrement(-1),
-                      Increment(Wrapping(-1)),
+                      Increment(Wrapping(-1), 0),
                       PointerIncrement(-1),
-                      Increment(Wrapping(-1)),
-                      Write];
+                      Increment(Wrapping(-1), 0),
+                      Write(0)];
 
-    let expected = vec![Read, Write];
+    let expected = vec![Read(0), Write(0)];
 
     assert_eq!(optimize(instrs), expected);
 }
 
 fn count_instrs(instrs: &[Instruction]) -> u64 {
     let mut count = 0;
-    for instr in instrs {
-        if let &Loop(ref body) = instr {
-            count += count_instrs(body);
-        }
-        count += 1;
-    }
+    walk(instrs, &mut |_| count += 1);
     count
 }
 
@@ -360,6 +724,18 @@ fn should_extract_multiply_simple() {
     assert_eq!(extract_multiply(instrs), expected);
 }
 
+#[test]
+fn should_extract_multiply_with_offsets() {
+    // "[->+++<]" once the pointer moves have sunk.
+    let instrs = vec![Loop(0, vec![Increment(Wrapping(-1), 0), Increment(Wrapping(3), 1)])];
+
+    let mut dest_cells = HashMap::new();
+    dest_cells.insert(1, Wrapping(3));
+    let expected = vec![MultiplyMove(dest_cells)];
+
+    assert_eq!(extract_multiply(instrs), expected);
+}
+
 #[test]
 fn should_extract_multiply_nested() {
     let instrs = parse("[[->+<]]").unwrap();
@@ -367,7 +743,7 @@ fn should_extract_multiply_nested() {
     let mut dest_cells = HashMap::new();
     dest_cells.insert(1, Wrapping(1));
     let expected = vec![
-        Loop(vec![
+        Loop(0, vec![
             MultiplyMove(dest_cells)])];
 
     assert_eq!(extract_multiply(instrs), expected);
@@ -396,6 +772,106 @@ fn should_extract_multiply_multiple_cells() {
     assert_eq!(extract_multiply(instrs), expected);
 }
 
+#[test]
+fn should_remove_copy_restore() {
+    let instrs = extract_multiply(parse("[->+<]>[-<+>]<").unwrap());
+
+    // Only the restore is left: cell #0 gets back its value, plus
+    // anything that was already in cell #1.
+    let mut restore = HashMap::new();
+    restore.insert(-1, Wrapping(1));
+    let expected = vec![PointerIncrement(1), MultiplyMove(restore), PointerIncrement(-1)];
+
+    assert_eq!(remove_copy_restore(instrs), expected);
+}
+
+#[test]
+fn should_remove_copy_restore_with_inverse_factors() {
+    // Multiplying by -1 twice restores the value too.
+    let instrs = extract_multiply(parse("[->-<]>[-<->]<").unwrap());
+    assert_eq!(remove_copy_restore(instrs).len(), 3);
+}
+
+#[test]
+fn should_not_remove_copy_without_restore() {
+    // Doubling the value on the way back doesn't restore it.
+    let instrs = extract_multiply(parse("[->+<]>[-<++>]<").unwrap());
+    assert_eq!(remove_copy_restore(instrs.clone()), instrs);
+
+    // Copying to two cells keeps the second copy.
+    let instrs = extract_multiply(parse("[->+>+<<]>[-<+>]<").unwrap());
+    assert_eq!(remove_copy_restore(instrs.clone()), instrs);
+}
+
+#[test]
+fn copy_restore_same_output() {
+    let instrs = parse(",>,<[->+<]>[-<+>]<.").unwrap();
+
+    let mut output = vec![];
+    interpret(&instrs, &mut &[3, 4][..], &mut output).unwrap();
+    let mut optimized_output = vec![];
+    interpret(&optimize(instrs), &mut &[3, 4][..], &mut optimized_output).unwrap();
+
+    assert_eq!(output, vec![7]);
+    assert_eq!(optimized_output, output);
+}
+
+#[test]
+fn should_extract_multiply_fan_out_copy() {
+    let instrs = parse("[->+>+<<]").unwrap();
+
+    let mut dest_cells = HashMap::new();
+    dest_cells.insert(1, Wrapping(1));
+    dest_cells.insert(2, Wrapping(1));
+    let expected = vec![MultiplyMove(dest_cells)];
+
+    assert_eq!(extract_multiply(instrs), expected);
+}
+
+/// The pointer may wander around the loop body in any order, as long
+/// as it ends up back where it started.
+#[test]
+fn should_extract_multiply_interleaved_movement() {
+    let instrs = parse("[>>+<+<<+>->+<]").unwrap();
+
+    let mut dest_cells = HashMap::new();
+    dest_cells.insert(-1, Wrapping(1));
+    dest_cells.insert(1, Wrapping(2));
+    dest_cells.insert(2, Wrapping(1));
+    let expected = vec![MultiplyMove(dest_cells)];
+
+    assert_eq!(extract_multiply(instrs), expected);
+}
+
+#[test]
+fn should_extract_multiply_ignoring_cancelled_cells() {
+    let instrs = parse("[->+>+<-<]").unwrap();
+
+    let mut dest_cells = HashMap::new();
+    dest_cells.insert(2, Wrapping(1));
+    let expected = vec![MultiplyMove(dest_cells)];
+
+    assert_eq!(extract_multiply(instrs), expected);
+}
+
+/// The control cell is decremented twice per iteration, so this
+/// isn't a simple multiply.
+#[test]
+fn should_not_extract_multiply_double_decrement() {
+    let instrs = parse("[->+<->+<]").unwrap();
+    assert_eq!(extract_multiply(instrs.clone()), instrs);
+}
+
+/// If every other cell change cancels out, this is really a clear
+/// loop, not a multiply.
+#[test]
+fn should_not_extract_multiply_all_cancelled() {
+    let instrs = vec![Loop(0, vec![Increment(Wrapping(-1), 0), PointerIncrement(1),
+                                   Increment(Wrapping(1), 0), Increment(Wrapping(-1), 0),
+                                   PointerIncrement(-1)])];
+    assert_eq!(extract_multiply(instrs.clone()), instrs);
+}
+
 #[test]
 fn should_not_extract_multiply_net_movement() {
     let instrs = parse("[->+++<<]").unwrap();
@@ -433,3 +909,188 @@ fn should_not_extract_multiply_with_write() {
     let instrs = parse("[+>++<.]").unwrap();
     assert_eq!(extract_multiply(instrs.clone()), instrs);
 }
+
+#[test]
+fn should_extract_scan() {
+    let instrs = parse("+[>]").unwrap();
+    assert_eq!(extract_scan(instrs), vec![Increment(Wrapping(1), 0), Scan(1)]);
+
+    let instrs = parse("+[>>]").unwrap();
+    assert_eq!(extract_scan(instrs), vec![Increment(Wrapping(1), 0), Scan(2)]);
+
+    let instrs = parse("+[<]").unwrap();
+    assert_eq!(extract_scan(instrs), vec![Increment(Wrapping(1), 0), Scan(-1)]);
+}
+
+#[test]
+fn should_extract_scan_nested() {
+    let instrs = parse("+[[>]-]").unwrap();
+    let expected = vec![Increment(Wrapping(1), 0),
+                        Loop(0, vec![Scan(1), Increment(Wrapping(-1), 0)])];
+    assert_eq!(extract_scan(instrs), expected);
+}
+
+#[test]
+fn should_not_extract_scan_with_other_instructions() {
+    let instrs = parse("+[>+]").unwrap();
+    assert_eq!(extract_scan(instrs.clone()), instrs);
+
+    let instrs = parse("+[>.]").unwrap();
+    assert_eq!(extract_scan(instrs.clone()), instrs);
+}
+
+#[test]
+fn optimize_scan() {
+    let instrs = parse(",[>]<.").unwrap();
+    assert_eq!(optimize(instrs), vec![Read(0), Scan(1), Write(-1)]);
+}
+
+#[test]
+fn named_passes_run_in_order() {
+    let instrs = parse("[-]+").unwrap();
+
+    let passes = named_passes(&["combine-set-and-increments", "simplify-loops"],
+                              true, true, false).unwrap();
+    assert_eq!(run_passes(instrs.clone(), &passes, false).unwrap(),
+               vec![Set(Wrapping(0), 0), Increment(Wrapping(1), 0)]);
+
+    let passes = named_passes(&["simplify_loops", "combine-set-and-increments"],
+                              true, true, false).unwrap();
+    assert_eq!(run_passes(instrs.clone(), &passes, false).unwrap(),
+               vec![Set(Wrapping(1), 0)]);
+
+    // With a fixed point, the order no longer matters here.
+    let passes = named_passes(&["combine-set-and-increments", "simplify-loops"],
+                              true, true, false).unwrap();
+    assert_eq!(run_passes(instrs, &passes, true).unwrap(), vec![Set(Wrapping(1), 0)]);
+}
+
+#[test]
+fn named_passes_unknown() {
+    match named_passes(&["simplify-loops", "no-such-pass"], true, true, false) {
+        Err(message) => {
+            assert!(message.starts_with("Unknown pass 'no-such-pass'."));
+            assert!(message.contains("combine-increments"));
+        }
+        Ok(_) => panic!("Expected an unknown pass error"),
+    }
+}
+
+#[test]
+fn removed_loops_reasons() {
+    let instrs = parse("[[-]]+[-][.]>[->++<]").unwrap();
+    assert_eq!(removed_loops(&instrs, true),
+               vec![Some("dead loop at start"), None, Some("folded into Set"),
+                    Some("dead loop after another loop"), Some("folded into MultiplyMove")]);
+}
+
+#[test]
+fn removed_loops_after_loop_on_other_cell() {
+    // A loop testing another cell doesn't leave the current cell
+    // zero.
+    let instrs = vec![Loop(1, vec![Increment(Wrapping(-1), 0)]), Loop(0, vec![Write(0)])];
+    assert_eq!(removed_loops(&instrs, false), vec![None, None]);
+}
+
+#[test]
+fn removed_loops_without_zeroed_tape() {
+    let instrs = parse("[.]").unwrap();
+    assert_eq!(removed_loops(&instrs, false), vec![None]);
+}
+
+#[test]
+fn should_remove_debug() {
+    let config = ParseConfig { debug_command: true, ..ParseConfig::default() };
+    let instrs = parse_with_config("+#+[#-]", &config).unwrap();
+    let expected = vec![Increment(Wrapping(1), 0),
+                        Increment(Wrapping(1), 0),
+                        Loop(0, vec![Increment(Wrapping(-1), 0)])];
+    assert_eq!(remove_debug(instrs), expected);
+}
+
+#[test]
+fn should_not_remove_trailing_debug() {
+    let instrs = vec![Increment(Wrapping(1), 0), Debug, PointerIncrement(1)];
+    assert_eq!(remove_pure_code(instrs), vec![Increment(Wrapping(1), 0), Debug]);
+}
+
+#[test]
+fn should_remove_destination_overwritten_by_set() {
+    let mut changes = HashMap::new();
+    changes.insert(1, Wrapping(1));
+    changes.insert(2, Wrapping(3));
+    let initial = vec![MultiplyMove(changes), PointerIncrement(1), Set(Wrapping(5), 0)];
+
+    let mut expected_changes = HashMap::new();
+    expected_changes.insert(2, Wrapping(3));
+    let expected = vec![MultiplyMove(expected_changes), PointerIncrement(1), Set(Wrapping(5), 0)];
+    assert_eq!(simplify_multiply_moves(initial), expected);
+}
+
+#[test]
+fn should_zero_when_only_destination_overwritten() {
+    let mut changes = HashMap::new();
+    changes.insert(-1, Wrapping(2));
+    let initial = vec![MultiplyMove(changes), PointerIncrement(-1), Set(Wrapping(0), 0)];
+    let expected = vec![Set(Wrapping(0), 0), PointerIncrement(-1), Set(Wrapping(0), 0)];
+    assert_eq!(simplify_multiply_moves(initial), expected);
+}
+
+#[test]
+fn should_not_fold_set_after_multiply_move() {
+    // The set is on the current cell, so the destinations still need
+    // the move.
+    let mut changes = HashMap::new();
+    changes.insert(1, Wrapping(1));
+    let initial = vec![MultiplyMove(changes), Set(Wrapping(5), 0)];
+    assert_eq!(simplify_multiply_moves(initial.clone()), initial);
+}
+
+#[test]
+fn should_not_fold_set_on_other_cell() {
+    let mut changes = HashMap::new();
+    changes.insert(1, Wrapping(1));
+    let initial = vec![MultiplyMove(changes), PointerIncrement(2), Set(Wrapping(5), 0)];
+    assert_eq!(simplify_multiply_moves(initial.clone()), initial);
+}
+
+#[test]
+fn should_not_fold_increment_on_destination() {
+    // After moving the pointer, the increment is on a cell the move
+    // wrote to, which isn't zero.
+    let mut changes = HashMap::new();
+    changes.insert(1, Wrapping(1));
+    let initial = vec![MultiplyMove(changes), PointerIncrement(1), Increment(Wrapping(1), 0)];
+    assert_eq!(simplify_multiply_moves(initial.clone()), initial);
+}
+
+#[test]
+fn should_remove_zero_factors() {
+    let mut changes = HashMap::new();
+    changes.insert(1, Wrapping(0));
+    changes.insert(2, Wrapping(3));
+    let mut expected_changes = HashMap::new();
+    expected_changes.insert(2, Wrapping(3));
+    assert_eq!(simplify_multiply_moves(vec![MultiplyMove(changes)]),
+               vec![MultiplyMove(expected_changes)]);
+
+    let mut changes = HashMap::new();
+    changes.insert(1, Wrapping(0));
+    assert_eq!(simplify_multiply_moves(vec![MultiplyMove(changes)]), vec![Set(Wrapping(0), 0)]);
+}
+
+#[test]
+fn optimize_move_then_clear_destination() {
+    let mut changes = HashMap::new();
+    changes.insert(2, Wrapping(1));
+    let instrs = optimize(parse(",[->+>+<<]>[-]>.").unwrap());
+    assert!(instrs.contains(&MultiplyMove(changes)));
+}
+
+#[test]
+fn optimize_adjacent_multiply_moves() {
+    let mut changes = HashMap::new();
+    changes.insert(1, Wrapping(1));
+    let expected = vec![Read(0), MultiplyMove(changes), Set(Wrapping(1), 0), Write(0)];
+    assert_eq!(optimize(parse(",[->+<][->>+<<]+.").unwrap()), expected);
+}
@@ -0,0 +1,124 @@
+#![warn(trivial_numeric_casts)]
+
+#[cfg(feature = "json")]
+use std::collections::BTreeMap;
+
+#[cfg(feature = "json")]
+use serde_json;
+#[cfg(feature = "json")]
+use serde_json::Value;
+
+use bfir::{Instruction, walk};
+
+#[cfg(test)]
+use bfir::parse;
+
+/// Statistics about how we compiled a program, for `--dump-stats`.
+#[derive(Debug,Clone,PartialEq,Eq)]
+pub struct Stats {
+    /// The number of instructions before and after optimisation,
+    /// including instructions inside loops.
+    pub instrs_before: usize,
+    pub instrs_after: usize,
+    /// How many iterations each peephole pass changed the
+    /// instructions in, in the order we ran them.
+    pub pass_changes: Vec<(&'static str, u64)>,
+    /// Why speculative execution stopped, or "off".
+    pub speculation: String,
+    pub steps: u64,
+    pub tape_size: usize,
+}
+
+/// Count `instrs`, including instructions inside loops.
+pub fn count_instrs(instrs: &[Instruction]) -> usize {
+    let mut count = 0;
+    walk(instrs, &mut |_| count += 1);
+    count
+}
+
+impl Stats {
+    /// A human-readable summary, one statistic per line.
+    pub fn to_text(&self) -> String {
+        let mut lines = vec![
+            format!("instructions before optimisation: {}", self.instrs_before),
+            format!("instructions after optimisation: {}", self.instrs_after),
+        ];
+        for &(name, count) in &self.pass_changes {
+            lines.push(format!("pass {}: {} changes", name, count));
+        }
+        lines.push(format!("speculative execution: {}", self.speculation));
+        lines.push(format!("steps: {}", self.steps));
+        lines.push(format!("tape size: {}", self.tape_size));
+
+        let mut text = lines.join("\n");
+        text.push('\n');
+        text
+    }
+
+    /// The same statistics as a JSON object.
+    #[cfg(feature = "json")]
+    pub fn to_json(&self) -> Result<String, String> {
+        let mut pass_changes = BTreeMap::new();
+        for &(name, count) in &self.pass_changes {
+            pass_changes.insert(name.to_owned(), Value::U64(count));
+        }
+
+        let mut object = BTreeMap::new();
+        object.insert("instrs_before".to_owned(), Value::U64(self.instrs_before as u64));
+        object.insert("instrs_after".to_owned(), Value::U64(self.instrs_after as u64));
+        object.insert("pass_changes".to_owned(), Value::Object(pass_changes));
+        object.insert("speculation".to_owned(), Value::String(self.speculation.clone()));
+        object.insert("steps".to_owned(), Value::U64(self.steps));
+        object.insert("tape_size".to_owned(), Value::U64(self.tape_size as u64));
+
+        serde_json::to_string(&Value::Object(object)).map_err(|e| format!("{}", e))
+    }
+
+    #[cfg(not(feature = "json"))]
+    pub fn to_json(&self) -> Result<String, String> {
+        Err("bfc was built without JSON support (use --features json).".to_owned())
+    }
+}
+
+#[cfg(test)]
+fn example_stats() -> Stats {
+    Stats {
+        instrs_before: 10,
+        instrs_after: 3,
+        pass_changes: vec![("combine_increments", 1), ("simplify_loops", 2)],
+        speculation: "completed".to_owned(),
+        steps: 42,
+        tape_size: 2,
+    }
+}
+
+#[test]
+fn stats_text() {
+    let text = example_stats().to_text();
+    assert!(text.contains("instructions after optimisation: 3\n"));
+    assert!(text.contains("pass simplify_loops: 2 changes\n"));
+    assert!(text.ends_with("tape size: 2\n"));
+}
+
+#[cfg(feature = "json")]
+#[test]
+fn stats_json_parses() {
+    let json = example_stats().to_json().unwrap();
+    let value: Value = serde_json::from_str(&json).unwrap();
+    let object = value.as_object().unwrap();
+
+    let keys: Vec<_> = object.keys().cloned().collect();
+    assert_eq!(keys, vec!["instrs_after", "instrs_before", "pass_changes", "speculation",
+                          "steps", "tape_size"]);
+    assert_eq!(object["steps"], Value::U64(42));
+    assert_eq!(object["speculation"], Value::String("completed".to_owned()));
+
+    let pass_changes = object["pass_changes"].as_object().unwrap();
+    assert_eq!(pass_changes["simplify_loops"], Value::U64(2));
+}
+
+#[test]
+fn count_nested_instrs() {
+    let instrs = parse("+[>[-]]").unwrap();
+    assert_eq!(count_instrs(&instrs), 5);
+}
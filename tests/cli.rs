@@ -0,0 +1,259 @@
+//! Run the bfc binary itself, to check what it writes to stdout and
+//! stderr, and how it exits.
+
+use std::env;
+use std::fs::{self, File};
+use std::io::{Read, Write};
+use std::os::unix::fs::PermissionsExt;
+use std::process::{Command, Output, Stdio};
+
+/// A command for the bfc binary cargo built alongside this test.
+fn bfc_command(args: &[&str]) -> Command {
+    let mut path = env::current_exe().unwrap();
+    path.pop();
+    // Newer cargo versions put test binaries in a deps directory.
+    if path.ends_with("deps") {
+        path.pop();
+    }
+    let mut command = Command::new(path.join("bfc"));
+    command.args(args);
+    command
+}
+
+/// Run the bfc binary.
+fn bfc(args: &[&str]) -> Output {
+    bfc_command(args).output().unwrap()
+}
+
+/// Run the bfc binary with `input` on its stdin.
+fn bfc_with_stdin(args: &[&str], input: &str) -> Output {
+    let mut child = bfc_command(args)
+        .stdin(Stdio::piped()).stdout(Stdio::piped()).stderr(Stdio::piped())
+        .spawn().unwrap();
+    child.stdin.take().unwrap().write_all(input.as_bytes()).unwrap();
+    child.wait_with_output().unwrap()
+}
+
+#[test]
+fn bad_argument_only_writes_stderr() {
+    let output = bfc(&["--no-such-option", "foo.bf"]);
+    assert_eq!(output.status.code(), Some(1));
+    assert!(output.stdout.is_empty());
+
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(stderr.contains("no-such-option"));
+    assert!(stderr.contains("Usage: "));
+}
+
+#[test]
+fn missing_source_only_writes_stderr() {
+    let output = bfc(&[]);
+    assert_eq!(output.status.code(), Some(1));
+    assert!(output.stdout.is_empty());
+}
+
+#[test]
+fn compile_error_only_writes_stderr() {
+    let output = bfc(&["tests/programs/no_such_program.bf"]);
+    assert_eq!(output.status.code(), Some(2));
+    assert!(output.stdout.is_empty());
+    assert!(!output.stderr.is_empty());
+}
+
+#[test]
+fn help_writes_stdout() {
+    let output = bfc(&["-h"]);
+    assert_eq!(output.status.code(), Some(0));
+    assert!(String::from_utf8_lossy(&output.stdout).starts_with("Usage: "));
+    assert!(output.stderr.is_empty());
+}
+
+/// Write `source` to a BF file in a fresh temporary directory, and
+/// return the path of the file and the output name to compile to.
+fn temp_program(test_name: &str, source: &str) -> (String, String) {
+    let dir = env::temp_dir().join(test_name);
+    let _ = fs::create_dir_all(&dir);
+    let path = dir.join("prog.bf");
+    File::create(&path).unwrap().write_all(source.as_bytes()).unwrap();
+    let output_name = dir.join("prog");
+    (path.to_str().unwrap().to_owned(), output_name.to_str().unwrap().to_owned())
+}
+
+#[test]
+fn out_of_bounds_at_compile_time_warns() {
+    let (path, output_name) = temp_program("bfc_cli_oob_warning", "+>+[<<]");
+    let output = bfc(&["--emit=llvm-ir", "-o", &output_name, &path]);
+    assert_eq!(output.status.code(), Some(0));
+
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(stderr.contains("warning: pointer moved out of bounds during compile-time \
+                             evaluation (cell index -1"));
+}
+
+#[test]
+fn out_of_bounds_at_compile_time_with_warnings_as_errors() {
+    let (path, output_name) = temp_program("bfc_cli_oob_error", "+>+[<<]");
+    let output = bfc(&["--warnings-as-errors", "--emit=llvm-ir", "-o", &output_name, &path]);
+    assert_eq!(output.status.code(), Some(2));
+
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(stderr.contains("warning: pointer moved out of bounds"));
+    assert!(stderr.contains("1 warning(s) treated as errors"));
+}
+
+#[test]
+fn infinite_loop_warns() {
+    let (path, output_name) = temp_program("bfc_cli_infinite_loop", "+\n>+<[>.<]");
+    let output = bfc(&["--emit=llvm-ir", "--speculate=off", "-o", &output_name, &path]);
+    assert_eq!(output.status.code(), Some(0));
+
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(stderr.contains("warning: loop at line 2, column 4 never terminates once entered"));
+
+    let output = bfc(&["-O0", "--emit=llvm-ir", "-o", &output_name, &path]);
+    assert!(!String::from_utf8_lossy(&output.stderr).contains("never terminates"));
+}
+
+#[test]
+fn dump_ir_from_stdin() {
+    for flag in &["-", "--stdin"] {
+        let output = bfc_with_stdin(&["--dump-ir", "--speculate=off", flag], ",+.");
+        assert_eq!(output.status.code(), Some(0));
+        let stdout = String::from_utf8_lossy(&output.stdout);
+        assert!(stdout.contains("Read"));
+        assert!(stdout.contains("Write"));
+    }
+}
+
+#[test]
+fn dump_llvm_from_stdin() {
+    let output = bfc_with_stdin(&["--dump-llvm", "-"], ",+.");
+    assert_eq!(output.status.code(), Some(0));
+    assert!(String::from_utf8_lossy(&output.stdout).contains("<stdin>"));
+}
+
+#[test]
+fn dump_llvm_matches_llc_input() {
+    let (path, output_name) = temp_program("bfc_cli_dump_llvm", ",[.,]");
+    let dumped = bfc(&["--dump-llvm", &path]);
+    assert_eq!(dumped.status.code(), Some(0));
+
+    // --dry-run doesn't run llc, but --save-temps keeps the IR we
+    // would have given it.
+    let ll_path = format!("{}.ll", output_name);
+    let _ = fs::remove_file(&ll_path);
+    let output = bfc(&["--save-temps", "--dry-run", "-o", &output_name, &path]);
+    assert_eq!(output.status.code(), Some(0));
+    assert!(String::from_utf8_lossy(&output.stdout).contains(&ll_path[..]));
+
+    let mut llc_input = vec![];
+    File::open(&ll_path).unwrap().read_to_end(&mut llc_input).unwrap();
+    assert_eq!(dumped.stdout, llc_input);
+}
+
+#[test]
+fn cache_hit_runs_no_passes() {
+    let dir = env::temp_dir().join("bfc_cli_cache_dir");
+    let _ = fs::remove_dir_all(&dir);
+    let cache_dir = format!("--cache-dir={}", dir.to_str().unwrap());
+    let args = ["--dump-stats", &cache_dir[..], "tests/programs/hello_world.bf"];
+
+    // --dump-stats lists the changes each pass made, but only for the
+    // passes we actually ran.
+    let first = bfc(&args);
+    assert_eq!(first.status.code(), Some(0));
+    assert!(String::from_utf8_lossy(&first.stdout).contains("pass combine_increments: "));
+
+    let second = bfc(&args);
+    assert_eq!(second.status.code(), Some(0));
+    let stdout = String::from_utf8_lossy(&second.stdout);
+    assert!(!stdout.contains("pass "));
+    assert_eq!(stdout.lines().last(), String::from_utf8_lossy(&first.stdout).lines().last());
+}
+
+#[test]
+fn print_cells_shows_the_final_tape() {
+    let (path, output_name) = temp_program("bfc_cli_print_cells", "+++>++");
+    let output = bfc(&["--print-cells", "2", "-o", &output_name, &path]);
+    assert_eq!(output.status.code(), Some(0));
+
+    let output = Command::new(&output_name).output().unwrap();
+    assert_eq!(String::from_utf8_lossy(&output.stderr), "cells: 3 2\npointer: 1\n");
+
+    // The tape grows to fit the cells we print.
+    let output = bfc(&["--print-cells", "4", "-o", &output_name, &path]);
+    assert_eq!(output.status.code(), Some(0));
+    let output = Command::new(&output_name).output().unwrap();
+    assert_eq!(String::from_utf8_lossy(&output.stderr), "cells: 3 2 0 0\npointer: 1\n");
+}
+
+#[test]
+fn run_stops_when_fuel_is_exhausted() {
+    let (path, _) = temp_program("bfc_cli_run_fuel", "+[]");
+    let output = bfc(&["--run", "--fuel", "1000", &path]);
+    assert_eq!(output.status.code(), Some(2));
+    assert!(String::from_utf8_lossy(&output.stderr).contains("Fuel exhausted."));
+}
+
+#[test]
+fn interpret_initial_cells() {
+    let (path, _) = temp_program("bfc_cli_interpret_init", ".>.");
+    let output = bfc(&["--interpret", "--init-hex", "41", "--init-cell-value", "66", &path]);
+    assert_eq!(output.status.code(), Some(0));
+    assert_eq!(String::from_utf8_lossy(&output.stdout), "AB");
+}
+
+/// A directory holding a `strip` that always fails, to put at the
+/// front of bfc's `PATH`.
+fn failing_strip_dir(test_name: &str) -> String {
+    let dir = env::temp_dir().join(test_name).join("bin");
+    let _ = fs::create_dir_all(&dir);
+    let strip = dir.join("strip");
+    File::create(&strip).unwrap().write_all(b"#!/bin/sh\nexit 1\n").unwrap();
+    fs::set_permissions(&strip, fs::Permissions::from_mode(0o755)).unwrap();
+    dir.to_str().unwrap().to_owned()
+}
+
+/// Run bfc with `args`, using a `strip` that fails.
+fn compile_with_failing_strip(test_name: &str, args: &[&str]) -> Output {
+    let path = format!("{}:{}", failing_strip_dir(test_name), env::var("PATH").unwrap());
+    bfc_command(args).env("PATH", path).output().unwrap()
+}
+
+#[test]
+fn failed_strip_removes_executable() {
+    let (path, output_name) = temp_program("bfc_cli_failed_strip", "+.");
+    let output = compile_with_failing_strip("bfc_cli_failed_strip",
+                                            &["-o", &output_name, &path]);
+    assert_eq!(output.status.code(), Some(2));
+    assert!(fs::metadata(&output_name).is_err());
+}
+
+#[test]
+fn failed_strip_with_save_temps_keeps_executable() {
+    let (path, output_name) = temp_program("bfc_cli_failed_strip_save_temps", "+.");
+    let output = compile_with_failing_strip("bfc_cli_failed_strip_save_temps",
+                                            &["--save-temps", "-o", &output_name, &path]);
+    assert_eq!(output.status.code(), Some(2));
+    assert!(fs::metadata(&output_name).is_ok());
+}
+
+#[test]
+fn verbose_notes_wide_cells_are_unoptimised() {
+    let (path, _) = temp_program("bfc_cli_wide_cells_verbose", "+++[-]");
+    let output = bfc(&["--verbose", "--cell-width", "16", "--dump-ir", &path]);
+    assert_eq!(output.status.code(), Some(0));
+    assert!(String::from_utf8_lossy(&output.stderr)
+        .contains("note: --cell-width 16 turns off peephole optimisation"));
+
+    let output = bfc(&["--verbose", "--dump-ir", &path]);
+    assert!(!String::from_utf8_lossy(&output.stderr).contains("--cell-width"));
+}
+
+#[test]
+fn empty_stdin_is_an_empty_program() {
+    let output = bfc_with_stdin(&["--dump-ir", "--stdin"], "");
+    assert_eq!(output.status.code(), Some(0));
+    assert_eq!(String::from_utf8_lossy(&output.stdout), "(optimized out)\n");
+    assert!(output.stderr.is_empty());
+}
@@ -0,0 +1,5 @@
+Print the digits 0 to 9 then a newline
+
+++++++++ [>++++++<-]    set cell #1 to 48 (the digit 0)
+++++++++++ [>.+<-]      print ten digits
+++++++++++ .            print a newline